@@ -9,7 +9,7 @@ fn main() -> Result<(), systray2::Error> {
     }
     // w.set_icon_from_file(&"C:\\Users\\qdot\\code\\git-projects\\systray-rs\\resources\\rust.ico".to_string());
     // w.set_tooltip(&"Whatever".to_string());
-    app.set_icon_from_file("/usr/share/gxkb/flags/ua.png")?;
+    app.set_icon(systray2::IconSource::Path(std::path::Path::new("/usr/share/gxkb/flags/ua.png")))?;
 
     app.add_menu_item("Print a thing", |_| {
         println!("Printing a thing!");
@@ -0,0 +1,70 @@
+// Tray icons that cycle through frames, e.g. while syncing.
+use crate::{Application, Error, Result};
+use std::time::Duration;
+
+/// One frame of an icon animation, as RGBA pixels in rows top to bottom.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IconFrame {
+    pub(crate) rgba: Vec<u8>,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+}
+
+impl IconFrame {
+    pub fn from_rgba(rgba: Vec<u8>, width: u32, height: u32) -> Result<IconFrame> {
+        if width == 0 || height == 0 {
+            return Err(Error::os(format!("Empty {}x{} icon frame", width, height)));
+        }
+        if rgba.len() != (width as usize) * (height as usize) * 4 {
+            return Err(Error::os(format!(
+                "Icon frame is {} bytes, expected {} for a {}x{} RGBA image",
+                rgba.len(),
+                (width as usize) * (height as usize) * 4,
+                width,
+                height
+            )));
+        }
+        Ok(IconFrame {
+            rgba,
+            width,
+            height,
+        })
+    }
+
+    #[cfg(feature = "image-icons")]
+    pub fn from_image(image: &image::DynamicImage) -> IconFrame {
+        let rgba = image.to_rgba8();
+        IconFrame {
+            width: rgba.width(),
+            height: rgba.height(),
+            rgba: rgba.into_raw(),
+        }
+    }
+}
+
+impl Application {
+    /// Shows `frames` in turn, one every `interval`, starting with the first
+    /// right away. The frames are converted for the platform once, here.
+    ///
+    /// Setting an icon in any other way stops the animation, and so does
+    /// stop_icon_animation, which puts the last icon set back.
+    pub fn start_icon_animation(&self, frames: Vec<IconFrame>, interval: Duration) -> Result<()> {
+        if frames.is_empty() {
+            return Err(Error::os("No icon frames given"));
+        }
+        if interval.is_zero() {
+            return Err(Error::os("Icon animation interval is zero"));
+        }
+        debug!("Animating icon with {} frames every {:?}", frames.len(), interval);
+        // The animation stops on the next icon, even the same one.
+        self.forget_shown_icon();
+        self.window.start_icon_animation(&frames, interval)?;
+        self.animating.set(true);
+        Ok(())
+    }
+
+    pub fn stop_icon_animation(&self) -> Result<()> {
+        self.animating.set(false);
+        self.window.stop_icon_animation()
+    }
+}
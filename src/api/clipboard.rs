@@ -0,0 +1,25 @@
+// The clipboard through arboard where there's no native code for it here.
+// The Clipboard is kept once opened, as on X11 the app serves what it
+// copied for as long as it owns the selection.
+use crate::Error;
+use std::cell::RefCell;
+
+thread_local! {
+    static CLIPBOARD: RefCell<Option<arboard::Clipboard>> = const { RefCell::new(None) };
+}
+
+pub(crate) fn set_text(text: &str) -> Result<(), Error> {
+    CLIPBOARD.with(|clipboard| {
+        let mut clipboard = clipboard.borrow_mut();
+        if clipboard.is_none() {
+            let opened = arboard::Clipboard::new()
+                .map_err(|e| Error::os(format!("Failed to open the clipboard: {}", e)))?;
+            *clipboard = Some(opened);
+        }
+        clipboard
+            .as_mut()
+            .expect("the clipboard was just opened")
+            .set_text(text)
+            .map_err(|e| Error::os(format!("Failed to set the clipboard text: {}", e)))
+    })
+}
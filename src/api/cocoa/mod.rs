@@ -1,28 +1,789 @@
-use crate::Error;
-use std;
+// The cocoa crate is deprecated in favour of objc2, but it is what we depend on.
+#![allow(deprecated)]
 
-pub struct Window {}
+use crate::api::{strip_mnemonic, BatchEntry, EventSender};
+use crate::lazy_menu::LazyBuilder;
+use crate::tooltip::ProviderSlot;
+use crate::{
+    Error, HotkeyModifiers, IconFrame, IconSource, InitOptions, MenuItemId, MenuItemStyle, Rect,
+    SystrayEvent, Theme,
+};
+use cocoa::{
+    appkit::{
+        NSApp, NSApplication, NSApplicationActivationPolicy, NSButton, NSEventMask, NSImage,
+        NSMenu, NSMenuItem, NSStatusBar, NSStatusItem, NSVariableStatusItemLength,
+    },
+    base::{id, nil, NO, YES},
+    foundation::{NSAutoreleasePool, NSData, NSDefaultRunLoopMode, NSInteger, NSSize, NSString},
+};
+use objc::{
+    class,
+    declare::ClassDecl,
+    msg_send,
+    runtime::{Class, Object, Sel},
+    sel, sel_impl,
+};
+use std::{
+    self,
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    path::Path,
+    sync::Once,
+    time::Duration,
+};
+
+// AppKit has to be driven from the main thread, so unlike the other platforms
+// there's no separate loop thread. Menu actions fire while the run loop is
+// pumped from wait_for_message, on this same thread.
+thread_local!(static MENU_TX: RefCell<Option<EventSender>> = const { RefCell::new(None) });
+
+// Frames the animation timer steps through, on the same thread.
+thread_local!(static ANIMATION: RefCell<Option<Animation>> = const { RefCell::new(None) });
+
+struct Animation {
+    button: id,
+    frames: Vec<id>,
+    next: usize,
+    timer: id,
+}
+
+impl Drop for Animation {
+    fn drop(&mut self) {
+        unsafe {
+            let _: () = msg_send![self.timer, invalidate];
+            for image in &self.frames {
+                let _: () = msg_send![*image, release];
+            }
+        }
+    }
+}
+
+// Menu bar icons are drawn at 18pt, menu item icons at 16pt.
+const ICON_POINTS: f64 = 18.0;
+const MENU_ICON_POINTS: f64 = 16.0;
+
+fn send_event(event: SystrayEvent) {
+    MENU_TX.with(|tx| {
+        if let Some(tx) = tx.borrow().as_ref() {
+            tx.send(event).ok();
+        }
+    });
+}
+
+extern "C" fn menu_item_clicked(_this: &Object, _cmd: Sel, sender: id) {
+    let tag: NSInteger = unsafe { msg_send![sender, tag] };
+    send_event(SystrayEvent::MenuItem(MenuItemId(tag as u32)));
+}
+
+extern "C" fn menu_will_open(_this: &Object, _cmd: Sel, _menu: id) {
+    send_event(SystrayEvent::MenuOpened);
+}
+
+extern "C" fn menu_did_close(_this: &Object, _cmd: Sel, _menu: id) {
+    send_event(SystrayEvent::MenuClosed);
+}
+
+extern "C" fn animation_tick(_this: &Object, _cmd: Sel, _timer: id) {
+    ANIMATION.with(|animation| {
+        if let Some(a) = animation.borrow_mut().as_mut() {
+            unsafe { a.button.setImage_(a.frames[a.next]) };
+            a.next = (a.next + 1) % a.frames.len();
+        }
+    });
+}
+
+extern "C" fn theme_changed(_this: &Object, _cmd: Sel, _notification: id) {
+    send_event(SystrayEvent::ThemeChanged(system_theme()));
+}
+
+// AppleInterfaceStyle is only set, to "Dark", in dark mode.
+fn system_theme() -> Theme {
+    unsafe {
+        let defaults: id = msg_send![class!(NSUserDefaults), standardUserDefaults];
+        let style: id = msg_send![defaults, stringForKey: to_nsstring("AppleInterfaceStyle")];
+        if style == nil {
+            Theme::Light
+        } else {
+            Theme::Dark
+        }
+    }
+}
+
+fn menu_target_class() -> &'static Class {
+    static REGISTER: Once = Once::new();
+    REGISTER.call_once(|| {
+        let mut decl = ClassDecl::new("Systray2MenuTarget", class!(NSObject))
+            .expect("Systray2MenuTarget already registered");
+        unsafe {
+            decl.add_method(
+                sel!(menuItemClicked:),
+                menu_item_clicked as extern "C" fn(&Object, Sel, id),
+            );
+            decl.add_method(
+                sel!(menuWillOpen:),
+                menu_will_open as extern "C" fn(&Object, Sel, id),
+            );
+            decl.add_method(
+                sel!(menuDidClose:),
+                menu_did_close as extern "C" fn(&Object, Sel, id),
+            );
+            decl.add_method(
+                sel!(themeChanged:),
+                theme_changed as extern "C" fn(&Object, Sel, id),
+            );
+            decl.add_method(
+                sel!(animationTick:),
+                animation_tick as extern "C" fn(&Object, Sel, id),
+            );
+        }
+        decl.register();
+    });
+    Class::get("Systray2MenuTarget").unwrap()
+}
+
+unsafe fn to_nsstring(s: &str) -> id {
+    NSString::alloc(nil).init_str(s)
+}
+
+fn image_from_rgba(buffer: &[u8], width: u32, height: u32) -> Result<id, Error> {
+    use image::ImageEncoder;
+
+    let mut png = Vec::new();
+    image::codecs::png::PngEncoder::new(&mut png)
+        .write_image(buffer, width, height, image::ColorType::Rgba8)
+        .map_err(|e| Error::from_image("Failed to encode icon", &e))?;
+    let image: id = unsafe {
+        let data = NSData::dataWithBytes_length_(
+            nil,
+            png.as_ptr() as *const std::ffi::c_void,
+            png.len() as u64,
+        );
+        msg_send![NSImage::alloc(nil), initWithData: data]
+    };
+    if image == nil {
+        return Err(Error::os("Error creating icon from buffer"));
+    }
+    Ok(image)
+}
+
+// Sized for the menu bar, like every icon we show there.
+unsafe fn fit_to_bar(image: id) {
+    let size = image.size();
+    if size.height > 0.0 {
+        let _: () = msg_send![image, setSize: NSSize::new(
+            size.width * ICON_POINTS / size.height,
+            ICON_POINTS
+        )];
+    }
+}
+
+pub struct Window {
+    status_item: Cell<id>,
+    // The icon last set, retained, to go back to when an animation stops.
+    icon: Cell<id>,
+    menu: id,
+    target: id,
+    menu_items: RefCell<HashMap<u32, id>>,
+    // The NSMenu each item was added to, keyed like menu_items.
+    parents: RefCell<HashMap<u32, id>>,
+    submenus: RefCell<HashMap<u32, id>>,
+    // Whether icons are template images, see set_icon_template.
+    template: Cell<bool>,
+}
 
 impl Window {
-    pub fn new() -> Result<Window, Error> {
+    // NSStatusItem says nothing about hovers, so the tooltip provider is only
+    // ever called by set_tooltip_from_fn itself.
+    pub fn new(
+        event_tx: EventSender,
+        _tooltip: ProviderSlot,
+        _options: &InitOptions,
+    ) -> Result<Window, Error> {
+        unsafe {
+            let pool = NSAutoreleasePool::new(nil);
+            let app = NSApp();
+            app.setActivationPolicy_(
+                NSApplicationActivationPolicy::NSApplicationActivationPolicyAccessory,
+            );
+            app.finishLaunching();
+
+            let status_item =
+                NSStatusBar::systemStatusBar(nil).statusItemWithLength_(NSVariableStatusItemLength);
+            if status_item == nil {
+                pool.drain();
+                return Err(Error::os("Error creating status item"));
+            }
+            let _: id = msg_send![status_item, retain];
+
+            let menu = NSMenu::new(nil);
+            menu.setAutoenablesItems(NO);
+            status_item.setMenu_(menu);
+
+            let target: id = msg_send![menu_target_class(), new];
+            let _: () = msg_send![menu, setDelegate: target];
+            let center: id = msg_send![class!(NSDistributedNotificationCenter), defaultCenter];
+            let _: () = msg_send![center,
+                addObserver: target
+                selector: sel!(themeChanged:)
+                name: to_nsstring("AppleInterfaceThemeChangedNotification")
+                object: nil];
+            MENU_TX.with(|tx| *tx.borrow_mut() = Some(event_tx));
+            pool.drain();
+
+            Ok(Window {
+                status_item: Cell::new(status_item),
+                icon: Cell::new(nil),
+                menu,
+                target,
+                menu_items: RefCell::new(HashMap::new()),
+                parents: RefCell::new(HashMap::new()),
+                submenus: RefCell::new(HashMap::new()),
+                template: Cell::new(false),
+            })
+        }
+    }
+
+    // Runs the AppKit event loop for up to 100ms, or until an event was handled.
+    pub fn pump_events(&self) {
+        unsafe {
+            let pool = NSAutoreleasePool::new(nil);
+            let app = NSApp();
+            let until: id = msg_send![class!(NSDate), dateWithTimeIntervalSinceNow: 0.1_f64];
+            let event = app.nextEventMatchingMask_untilDate_inMode_dequeue_(
+                NSEventMask::NSAnyEventMask.bits(),
+                until,
+                NSDefaultRunLoopMode,
+                YES,
+            );
+            if event != nil {
+                app.sendEvent_(event);
+            }
+            pool.drain();
+        }
+    }
+
+    fn container(&self, parent: Option<u32>) -> id {
+        parent
+            .and_then(|p| self.submenus.borrow().get(&p).copied())
+            .unwrap_or(self.menu)
+    }
+
+    fn insert_item(&self, item_idx: u32, item: id, parent: Option<u32>) {
+        let menu = self.container(parent);
+        unsafe {
+            menu.addItem_(item);
+        }
+        self.parents.borrow_mut().insert(item_idx, menu);
+        self.menu_items.borrow_mut().insert(item_idx, item);
+    }
+
+    unsafe fn new_item(&self, item_idx: u32, item_name: &str) -> id {
+        let item = NSMenuItem::alloc(nil).initWithTitle_action_keyEquivalent_(
+            to_nsstring(&strip_mnemonic(item_name)),
+            sel!(menuItemClicked:),
+            to_nsstring(""),
+        );
+        let _: () = msg_send![item, setTarget: self.target];
+        let _: () = msg_send![item, setTag: item_idx as NSInteger];
+        item
+    }
+
+    pub fn add_menu_entry(
+        &self,
+        item_idx: u32,
+        item_name: &str,
+        parent: Option<u32>,
+    ) -> Result<(), Error> {
+        unsafe {
+            if let Some(item) = self.menu_items.borrow().get(&item_idx) {
+                let _: () = msg_send![*item, setTitle: to_nsstring(&strip_mnemonic(item_name))];
+                return Ok(());
+            }
+            let item = self.new_item(item_idx, item_name);
+            self.insert_item(item_idx, item, parent);
+        }
+        Ok(())
+    }
+
+    pub fn add_check_menu_entry(
+        &self,
+        item_idx: u32,
+        item_name: &str,
+        checked: bool,
+        parent: Option<u32>,
+    ) -> Result<(), Error> {
+        unsafe {
+            let item = self.new_item(item_idx, item_name);
+            let _: () = msg_send![item, setState: checked as NSInteger];
+            self.insert_item(item_idx, item, parent);
+        }
+        Ok(())
+    }
+
+    pub fn add_submenu(
+        &self,
+        item_idx: u32,
+        item_name: &str,
+        parent: Option<u32>,
+    ) -> Result<(), Error> {
+        unsafe {
+            // Items with a submenu never fire their action.
+            let item = self.new_item(item_idx, item_name);
+            let submenu = NSMenu::new(nil);
+            submenu.setAutoenablesItems(NO);
+            item.setSubmenu_(submenu);
+            self.submenus.borrow_mut().insert(item_idx, submenu);
+            self.insert_item(item_idx, item, parent);
+        }
+        Ok(())
+    }
+
+    // Already on the AppKit thread, so there's nothing to save on.
+    pub(crate) fn add_menu_entries(&self, entries: &[BatchEntry]) -> Result<(), Error> {
+        for entry in entries {
+            match entry {
+                BatchEntry::Item {
+                    idx,
+                    label,
+                    checked: None,
+                    parent,
+                } => self.add_menu_entry(*idx, label, *parent)?,
+                BatchEntry::Item {
+                    idx,
+                    label,
+                    checked: Some(checked),
+                    parent,
+                } => self.add_check_menu_entry(*idx, label, *checked, *parent)?,
+                BatchEntry::Separator { idx, parent } => self.add_menu_separator(*idx, *parent)?,
+                BatchEntry::Submenu { idx, label, parent } => {
+                    self.add_submenu(*idx, label, *parent)?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn add_menu_separator(&self, item_idx: u32, parent: Option<u32>) -> Result<(), Error> {
+        unsafe {
+            self.insert_item(item_idx, NSMenuItem::separatorItem(nil), parent);
+        }
+        Ok(())
+    }
+
+    pub fn set_menu_item_checked(&self, item_idx: u32, checked: bool) -> Result<(), Error> {
+        if let Some(item) = self.menu_items.borrow().get(&item_idx) {
+            unsafe {
+                let _: () = msg_send![*item, setState: checked as NSInteger];
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_menu_item_enabled(&self, item_idx: u32, enabled: bool) -> Result<(), Error> {
+        if let Some(item) = self.menu_items.borrow().get(&item_idx) {
+            unsafe {
+                let _: () = msg_send![*item, setEnabled: if enabled { YES } else { NO }];
+            }
+        }
+        Ok(())
+    }
+
+    // NSMenu has no default item.
+    pub fn set_default_menu_item(&self, _item_idx: u32) -> Result<(), Error> {
+        Ok(())
+    }
+
+    pub fn set_menu_item_style(
+        &self,
+        _item_idx: u32,
+        _label: &str,
+        _style: MenuItemStyle,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+
+    pub fn set_lazy_submenu(&self, _item_idx: u32, _builder: LazyBuilder) -> Result<(), Error> {
         Err(Error::NotImplementedError)
     }
-    pub fn quit(&self) {
-        unimplemented!()
+
+    pub fn remove_menu_item(&self, item_idx: u32) -> Result<(), Error> {
+        let item = self.menu_items.borrow_mut().remove(&item_idx);
+        let menu = self.parents.borrow_mut().remove(&item_idx);
+        if let (Some(item), Some(menu)) = (item, menu) {
+            unsafe {
+                let _: () = msg_send![menu, removeItem: item];
+            }
+        }
+        // Forget the items of a removed submenu too; they went with it.
+        let submenu = self.submenus.borrow_mut().remove(&item_idx);
+        if let Some(submenu) = submenu {
+            let children: Vec<u32> = self
+                .parents
+                .borrow()
+                .iter()
+                .filter(|(_, m)| **m == submenu)
+                .map(|(c, _)| *c)
+                .collect();
+            for c in children {
+                self.remove_menu_item(c)?;
+            }
+        }
+        Ok(())
     }
-    pub fn set_tooltip(&self, _: &str) -> Result<(), Error> {
-        unimplemented!()
+
+    // The menu releases items it gives up, so the item is kept through the
+    // move.
+    pub fn move_menu_item(&self, item_idx: u32, position: u32) -> Result<(), Error> {
+        let item = match self.menu_items.borrow().get(&item_idx) {
+            Some(item) => *item,
+            None => return Err(Error::os(format!("No menu item {}", item_idx))),
+        };
+        let menu = match self.parents.borrow().get(&item_idx) {
+            Some(menu) => *menu,
+            None => return Err(Error::os(format!("No menu item {}", item_idx))),
+        };
+        unsafe {
+            let _: id = msg_send![item, retain];
+            let _: () = msg_send![menu, removeItem: item];
+            let count: NSInteger = msg_send![menu, numberOfItems];
+            let index = (position as NSInteger).min(count);
+            let _: () = msg_send![menu, insertItem: item atIndex: index];
+            let _: () = msg_send![item, release];
+        }
+        Ok(())
     }
-    pub fn add_menu_item<F>(&self, _: &str, _: F) -> Result<u32, Error>
-    where
-        F: std::ops::Fn(&Window) -> () + 'static,
-    {
-        unimplemented!()
+
+    // A static icon ends any animation.
+    fn set_icon(&self, image: id) -> Result<(), Error> {
+        ANIMATION.with(|animation| animation.borrow_mut().take());
+        unsafe {
+            fit_to_bar(image);
+            self.apply_template(image);
+            let _: id = msg_send![image, retain];
+            let old = self.icon.replace(image);
+            if old != nil {
+                let _: () = msg_send![old, release];
+            }
+            let button = self.status_item.get().button();
+            button.setImage_(image);
+        }
+        Ok(())
     }
-    pub fn wait_for_message(&mut self) {
-        unimplemented!()
+
+    // Template images are drawn as a mask, in the menu bar's colors.
+    unsafe fn apply_template(&self, image: id) {
+        let template = if self.template.get() { YES } else { NO };
+        let _: () = msg_send![image, setTemplate: template];
     }
-    pub fn set_icon_from_buffer(&self, _: &[u8], _: u32, _: u32) -> Result<(), Error> {
-        unimplemented!()
+
+    // An animation keeps its frames, and the icon changes once it's over.
+    pub fn set_icon_template(&self, template: bool) -> Result<(), Error> {
+        self.template.set(template);
+        let icon = self.icon.get();
+        if icon == nil {
+            return Ok(());
+        }
+        unsafe {
+            self.apply_template(icon);
+            if ANIMATION.with(|animation| animation.borrow().is_none()) {
+                let button = self.status_item.get().button();
+                button.setImage_(icon);
+                let _: () = msg_send![button, setNeedsDisplay: YES];
+            }
+        }
+        Ok(())
+    }
+
+    // The timer fires while wait_for_message pumps the run loop.
+    pub fn start_icon_animation(
+        &self,
+        frames: &[IconFrame],
+        interval: Duration,
+    ) -> Result<(), Error> {
+        let mut images = Vec::with_capacity(frames.len());
+        for frame in frames {
+            match image_from_rgba(&frame.rgba, frame.width, frame.height) {
+                Ok(image) => {
+                    unsafe {
+                        fit_to_bar(image);
+                        self.apply_template(image);
+                    }
+                    images.push(image);
+                }
+                Err(e) => {
+                    for image in images {
+                        let _: () = unsafe { msg_send![image, release] };
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        ANIMATION.with(|animation| animation.borrow_mut().take());
+        unsafe {
+            let button = self.status_item.get().button();
+            button.setImage_(images[0]);
+            let timer: id = msg_send![class!(NSTimer),
+                scheduledTimerWithTimeInterval: interval.as_secs_f64()
+                target: self.target
+                selector: sel!(animationTick:)
+                userInfo: nil
+                repeats: YES];
+            let _: id = msg_send![timer, retain];
+            ANIMATION.with(|animation| {
+                *animation.borrow_mut() = Some(Animation {
+                    button,
+                    next: 1 % images.len(),
+                    frames: images,
+                    timer,
+                })
+            });
+        }
+        Ok(())
+    }
+
+    pub fn stop_icon_animation(&self) -> Result<(), Error> {
+        if ANIMATION.with(|animation| animation.borrow_mut().take()).is_some() {
+            unsafe {
+                let button = self.status_item.get().button();
+                button.setImage_(self.icon.get());
+            }
+        }
+        Ok(())
+    }
+
+    // NSString only holds UTF-8 paths.
+    pub fn set_icon_from_file(&self, file: &Path) -> Result<(), Error> {
+        let file = file
+            .to_str()
+            .ok_or_else(|| Error::os(format!("{} isn't valid UTF-8", file.display())))?;
+        let image: id =
+            unsafe { msg_send![NSImage::alloc(nil), initWithContentsOfFile: to_nsstring(file)] };
+        if image == nil {
+            return Err(Error::os(format!("Error loading icon from {}", file)));
+        }
+        self.set_icon(image)
+    }
+
+    // Resources on macOS are images in the application bundle.
+    pub fn set_icon_from_resource_id(&self, _id: u16) -> Result<(), Error> {
+        Err(Error::NotImplementedError)
+    }
+
+    pub fn set_icon_from_theme(&self, _icon_name: &str) -> Result<(), Error> {
+        Err(Error::NotImplementedError)
+    }
+
+    pub fn clear_icon(&self) -> Result<(), Error> {
+        self.set_icon_from_resource("NSApplicationIcon")
+    }
+
+    pub fn set_icon_from_resource(&self, resource: &str) -> Result<(), Error> {
+        let image = unsafe { NSImage::imageNamed_(nil, to_nsstring(resource)) };
+        if image == nil {
+            return Err(Error::os(format!(
+                "Error loading icon resource {}",
+                resource
+            )));
+        }
+        self.set_icon(image)
+    }
+
+    pub fn set_icon_from_image_buffer(
+        &self,
+        buffer: &[u8],
+        width: u32,
+        height: u32,
+    ) -> Result<(), Error> {
+        if buffer.len() != (width as usize) * (height as usize) * 4 {
+            return Err(Error::os(format!(
+                "Icon buffer is {} bytes, expected {} for a {}x{} RGBA image",
+                buffer.len(),
+                (width as usize) * (height as usize) * 4,
+                width,
+                height
+            )));
+        }
+        self.set_icon(image_from_rgba(buffer, width, height)?)
+    }
+
+    // AppKit gets the pixels as PNG, so they're encoded either way.
+    pub fn set_icon_from_rgba(&self, rgba: Vec<u8>, width: u32, height: u32) -> Result<(), Error> {
+        self.set_icon_from_image_buffer(&rgba, width, height)
+    }
+
+    // One source at a time, the way the matching set_icon_from_* does it,
+    // for the Application to show what it has no pixels of.
+    pub fn apply_icon(&self, icon: IconSource<'_>) -> Result<(), Error> {
+        match icon {
+            IconSource::Path(file) => self.set_icon_from_file(file),
+            IconSource::Resource(resource) => self.set_icon_from_resource(resource),
+            IconSource::ResourceId(id) => self.set_icon_from_resource_id(id),
+            IconSource::ThemeName(name) => self.set_icon_from_theme(name),
+            IconSource::Rgba {
+                data,
+                width,
+                height,
+            } => self.set_icon_from_rgba(data.into_owned(), width, height),
+            // The Application decodes these, with the image crate.
+            IconSource::Encoded(_) => Err(Error::NotImplementedError),
+        }
+    }
+
+    pub fn set_menu_item_icon_rgba(
+        &self,
+        item_idx: u32,
+        rgba: &[u8],
+        width: u32,
+        height: u32,
+    ) -> Result<(), Error> {
+        let item = match self.menu_items.borrow().get(&item_idx) {
+            Some(item) => *item,
+            None => return Err(Error::os(format!("No menu item {}", item_idx))),
+        };
+        let image = image_from_rgba(rgba, width, height)?;
+        unsafe {
+            let _: () = msg_send![image, setSize: NSSize::new(MENU_ICON_POINTS, MENU_ICON_POINTS)];
+            let _: () = msg_send![item, setImage: image];
+        }
+        Ok(())
+    }
+
+    pub fn scale_factor(&self) -> f64 {
+        unsafe {
+            let screen: id = msg_send![class!(NSScreen), mainScreen];
+            if screen == nil {
+                return 1.0;
+            }
+            msg_send![screen, backingScaleFactor]
+        }
+    }
+
+    pub fn icon_size(&self) -> u32 {
+        (ICON_POINTS * self.scale_factor()).round() as u32
+    }
+
+    // Dropping the sender disconnects the event channel, which ends
+    // wait_for_message. Safe to call more than once.
+    pub fn shutdown(&self) -> Result<(), Error> {
+        MENU_TX.with(|tx| tx.borrow_mut().take());
+        ANIMATION.with(|animation| animation.borrow_mut().take());
+        let status_item = self.status_item.replace(nil);
+        if status_item != nil {
+            unsafe {
+                NSStatusBar::systemStatusBar(nil).removeStatusItem_(status_item);
+                let _: () = msg_send![status_item, release];
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_tooltip(&self, tooltip: &str) -> Result<(), Error> {
+        unsafe {
+            let button = self.status_item.get().button();
+            let _: () = msg_send![button, setToolTip: to_nsstring(tooltip)];
+        }
+        Ok(())
+    }
+
+    pub fn system_theme(&self) -> Theme {
+        system_theme()
+    }
+
+    pub fn show_notification(
+        &self,
+        _summary: &str,
+        _body: &str,
+        _image: Option<&str>,
+        _actions: &[(String, String)],
+    ) -> Result<(), Error> {
+        Err(Error::NotImplementedError)
+    }
+
+    pub fn set_app_id(&self, _app_id: &str) -> Result<(), Error> {
+        Err(Error::NotImplementedError)
+    }
+
+    // AppKit is called right here, on the main thread.
+    pub fn set_waiting(&self, _waiting: bool) -> bool {
+        true
+    }
+
+    pub fn event_sender(&self) -> Option<EventSender> {
+        MENU_TX.with(|tx| tx.borrow().clone())
+    }
+
+    pub fn is_elevated(&self) -> Result<bool, Error> {
+        Err(Error::NotImplementedError)
+    }
+
+    pub fn request_always_visible(&self) -> Result<(), Error> {
+        Err(Error::NotImplementedError)
+    }
+
+    #[cfg(feature = "clipboard")]
+    pub fn set_clipboard_text(&self, text: &str) -> Result<(), Error> {
+        crate::api::clipboard::set_text(text)
+    }
+
+    #[cfg(not(feature = "clipboard"))]
+    pub fn set_clipboard_text(&self, _text: &str) -> Result<(), Error> {
+        Err(Error::NotImplementedError)
+    }
+
+    // The icon goes along with the process here.
+    pub fn remove_icon_on_panic(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    // The status item's button, the view the icon is drawn in.
+    #[cfg(feature = "rwh")]
+    pub fn raw_window_handle(&self) -> Result<raw_window_handle::RawWindowHandle, Error> {
+        let button = unsafe { self.status_item.get().button() };
+        let view = std::ptr::NonNull::new(button as *mut std::ffi::c_void)
+            .ok_or_else(|| Error::os("The status item has no button"))?;
+        Ok(raw_window_handle::AppKitWindowHandle::new(view).into())
+    }
+
+    pub fn tray_icon_rect(&self) -> Result<Rect, Error> {
+        Err(Error::NotImplementedError)
+    }
+
+    pub fn request_attention(&self, _icon: Option<&IconFrame>) -> Result<(), Error> {
+        Err(Error::NotImplementedError)
+    }
+
+    pub fn clear_attention(&self) -> Result<(), Error> {
+        Err(Error::NotImplementedError)
+    }
+
+    pub fn set_icon_visible(&self, visible: bool) -> Result<(), Error> {
+        unsafe {
+            let visible = if visible { YES } else { NO };
+            let _: () = msg_send![self.status_item.get(), setVisible: visible];
+        }
+        Ok(())
+    }
+
+    // Status items with a menu open it on either button.
+    pub fn set_menu_on_left_click(&self, _enabled: bool) {}
+
+    // The status item opens its menu itself, there's no click to take over.
+    pub fn set_suppress_default_menu(&self, _suppress: bool) {}
+
+    pub fn register_hotkey(
+        &self,
+        _id: u32,
+        _modifiers: HotkeyModifiers,
+        _key: u32,
+    ) -> Result<(), Error> {
+        Err(Error::NotImplementedError)
+    }
+
+    pub fn unregister_hotkey(&self, _id: u32) -> Result<(), Error> {
+        Err(Error::NotImplementedError)
+    }
+
+    pub fn quit(&self) {
+        self.shutdown().ok();
     }
 }
@@ -0,0 +1,180 @@
+// Events go from the platform to the Application over this channel, which is
+// crossbeam's with the `crossbeam` feature.
+use crate::SystrayEvent;
+
+#[cfg(feature = "crossbeam")]
+use crossbeam_channel as event_mpsc;
+#[cfg(not(feature = "crossbeam"))]
+use std::sync::mpsc as event_mpsc;
+
+use event_mpsc::{SendError, TrySendError};
+pub(crate) use event_mpsc::{RecvError, RecvTimeoutError, TryRecvError};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+pub(crate) type EventReceiver = event_mpsc::Receiver<SystrayEvent>;
+
+#[cfg(feature = "crossbeam")]
+type BoundedSender = crossbeam_channel::Sender<SystrayEvent>;
+#[cfg(not(feature = "crossbeam"))]
+type BoundedSender = std::sync::mpsc::SyncSender<SystrayEvent>;
+
+#[derive(Clone)]
+pub enum EventSender {
+    Unbounded(event_mpsc::Sender<SystrayEvent>),
+    Bounded {
+        tx: BoundedSender,
+        overflow: Arc<Mutex<Overflow>>,
+    },
+}
+
+// Events that didn't fit, waiting for room in the channel.
+#[derive(Default)]
+pub struct Overflow {
+    queue: VecDeque<SystrayEvent>,
+    // Set while a thread hands them over, with one of them taken already.
+    spilling: bool,
+}
+
+impl EventSender {
+    // Never waits, as the platform's thread may be the one the Application
+    // waits on. Events that don't fit a full bounded channel queue up
+    // behind it, for a thread of their own to wait for room. Events that
+    // only say something changed are kept once there, the latest of each
+    // kind, everything else is kept in full.
+    pub(crate) fn send(&self, event: SystrayEvent) -> Result<(), SendError<SystrayEvent>> {
+        let (tx, overflow) = match self {
+            EventSender::Unbounded(tx) => return tx.send(event),
+            EventSender::Bounded { tx, overflow } => (tx, overflow),
+        };
+        let mut held = overflow.lock().unwrap();
+        // Nothing goes past the events already waiting.
+        let event = if !held.spilling {
+            match tx.try_send(event) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Full(event)) => event,
+                Err(TrySendError::Disconnected(event)) => return Err(SendError(event)),
+            }
+        } else {
+            event
+        };
+        if event.coalesces() {
+            let kind = std::mem::discriminant(&event);
+            held.queue.retain(|e| std::mem::discriminant(e) != kind);
+        }
+        debug!("The event queue is full, holding {:?} back", event);
+        held.queue.push_back(event);
+        if !held.spilling {
+            held.spilling = true;
+            spill(tx.clone(), overflow.clone());
+        }
+        Ok(())
+    }
+}
+
+// Hands the held back events over as room comes up, until there are none
+// left or the Application is gone.
+fn spill(tx: BoundedSender, overflow: Arc<Mutex<Overflow>>) {
+    thread::spawn(move || loop {
+        let event = {
+            let mut held = overflow.lock().unwrap();
+            match held.queue.pop_front() {
+                Some(event) => event,
+                None => {
+                    held.spilling = false;
+                    return;
+                }
+            }
+        };
+        if tx.send(event).is_err() {
+            let mut held = overflow.lock().unwrap();
+            held.queue.clear();
+            held.spilling = false;
+            return;
+        }
+    });
+}
+
+// Bounded to `capacity` events, at least one, if there is one.
+#[cfg(feature = "crossbeam")]
+pub(crate) fn event_channel(capacity: Option<usize>) -> (EventSender, EventReceiver) {
+    match capacity {
+        Some(capacity) => {
+            let (tx, rx) = crossbeam_channel::bounded(capacity.max(1));
+            let overflow = Arc::default();
+            (EventSender::Bounded { tx, overflow }, rx)
+        }
+        None => {
+            let (tx, rx) = crossbeam_channel::unbounded();
+            (EventSender::Unbounded(tx), rx)
+        }
+    }
+}
+
+#[cfg(not(feature = "crossbeam"))]
+pub(crate) fn event_channel(capacity: Option<usize>) -> (EventSender, EventReceiver) {
+    match capacity {
+        Some(capacity) => {
+            let (tx, rx) = std::sync::mpsc::sync_channel(capacity.max(1));
+            let overflow = Arc::default();
+            (EventSender::Bounded { tx, overflow }, rx)
+        }
+        None => {
+            let (tx, rx) = std::sync::mpsc::channel();
+            (EventSender::Unbounded(tx), rx)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MenuItemId, Theme};
+    use std::time::Duration;
+
+    fn click(idx: u32) -> SystrayEvent {
+        SystrayEvent::MenuItem(MenuItemId(idx))
+    }
+
+    #[test]
+    fn full_channel_keeps_every_click_in_order() {
+        let (tx, rx) = event_channel(Some(1));
+        for idx in 0..5 {
+            tx.send(click(idx)).unwrap();
+        }
+        for idx in 0..5 {
+            assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), click(idx));
+        }
+    }
+
+    #[test]
+    fn full_channel_keeps_the_latest_change_of_each_kind() {
+        let (tx, rx) = event_channel(Some(1));
+        // The first event held back may be on its way already.
+        tx.send(click(0)).unwrap();
+        tx.send(click(1)).unwrap();
+        tx.send(SystrayEvent::ThemeChanged(Theme::Dark)).unwrap();
+        tx.send(SystrayEvent::ScaleFactorChanged).unwrap();
+        tx.send(SystrayEvent::ThemeChanged(Theme::Light)).unwrap();
+        tx.send(SystrayEvent::ScaleFactorChanged).unwrap();
+        drop(tx);
+        let events: Vec<SystrayEvent> = rx.iter().collect();
+        assert_eq!(
+            events,
+            [
+                click(0),
+                click(1),
+                SystrayEvent::ThemeChanged(Theme::Light),
+                SystrayEvent::ScaleFactorChanged,
+            ]
+        );
+    }
+
+    #[test]
+    fn disconnected_channel_gives_the_event_back() {
+        let (tx, rx) = event_channel(Some(1));
+        drop(rx);
+        assert!(tx.send(click(0)).is_err());
+    }
+}
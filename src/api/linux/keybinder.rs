@@ -0,0 +1,73 @@
+// Global hotkeys through libkeybinder-3.0, loaded at runtime like
+// AppIndicator. It grabs keys on the X root window and calls back on the GTK
+// main loop, so there's nothing to be had on Wayland.
+use crate::{Error, HotkeyModifiers};
+use std::ffi::{c_char, c_void, CStr, CString};
+
+type Handler = unsafe extern "C" fn(*const c_char, *mut c_void);
+type BindFn = unsafe extern "C" fn(*const c_char, Handler, *mut c_void) -> i32;
+type UnbindAllFn = unsafe extern "C" fn(*const c_char);
+
+// Goes by the keystring, which is what the key was bound with.
+unsafe extern "C" fn pressed(keystring: *const c_char, _user_data: *mut c_void) {
+    super::hotkey_pressed(CStr::from_ptr(keystring));
+}
+
+// Keystrings as gtk_accelerator_parse takes them, e.g. "<Ctrl><Shift>space".
+pub fn keystring(modifiers: HotkeyModifiers, key: u32) -> Result<CString, Error> {
+    let name = gtk::gdk::keys::Key::from(key)
+        .name()
+        .ok_or_else(|| Error::os(format!("No key has keysym {:#x}", key)))?;
+    let mut keystring = String::new();
+    for (flag, prefix) in [
+        (HotkeyModifiers::CTRL, "<Ctrl>"),
+        (HotkeyModifiers::SHIFT, "<Shift>"),
+        (HotkeyModifiers::ALT, "<Alt>"),
+        (HotkeyModifiers::WIN, "<Super>"),
+    ] {
+        if modifiers.contains(flag) {
+            keystring += prefix;
+        }
+    }
+    keystring += &name;
+    Ok(CString::new(keystring)?)
+}
+
+pub struct Keybinder {
+    bind: BindFn,
+    unbind_all: UnbindAllFn,
+    // Keeps the functions above loaded.
+    _lib: libloading::Library,
+}
+
+impl Keybinder {
+    pub fn load() -> Result<Keybinder, Error> {
+        unsafe {
+            let lib = libloading::Library::new("libkeybinder-3.0.so.0")
+                .map_err(|e| Error::os(format!("Hotkeys need libkeybinder-3.0: {}", e)))?;
+            let symbol_error = |e| Error::os(format!("Broken libkeybinder-3.0: {}", e));
+            let init = *lib
+                .get::<unsafe extern "C" fn()>(b"keybinder_init\0")
+                .map_err(symbol_error)?;
+            let bind = *lib.get::<BindFn>(b"keybinder_bind\0").map_err(symbol_error)?;
+            let unbind_all = *lib
+                .get::<UnbindAllFn>(b"keybinder_unbind_all\0")
+                .map_err(symbol_error)?;
+            init();
+            Ok(Keybinder {
+                bind,
+                unbind_all,
+                _lib: lib,
+            })
+        }
+    }
+
+    // False when the key is grabbed already, or there's no X server.
+    pub fn bind(&self, keystring: &CString) -> bool {
+        unsafe { (self.bind)(keystring.as_ptr(), pressed, std::ptr::null_mut()) != 0 }
+    }
+
+    pub fn unbind(&self, keystring: &CStr) {
+        unsafe { (self.unbind_all)(keystring.as_ptr()) }
+    }
+}
@@ -1,182 +1,1871 @@
-use crate::{Error, SystrayEvent};
-use glib;
-use gtk::{
-    self, MenuShellExt, GtkMenuItemExt, WidgetExt
+use crate::api::{BatchEntry, EventSender};
+use crate::lazy_menu::{self, LazyBuilder};
+use crate::tooltip::{self, ProviderSlot};
+use crate::{
+    Error, HotkeyModifiers, IconFrame, IconSource, InitOptions, MenuItemId, MenuItemStyle, Rect,
+    SystrayEvent, Theme,
 };
+mod keybinder;
+#[cfg(feature = "dbus-notifications")]
+mod notify;
+#[cfg(feature = "sni")]
+mod sni;
+mod status_icon;
+
+use glib;
+use gtk::{self, gio, prelude::*};
+#[cfg(feature = "appindicator")]
 use libappindicator::{AppIndicator, AppIndicatorStatus};
+use keybinder::Keybinder;
+use status_icon::StatusIcon;
 use std::{
     self,
-    cell::RefCell,
+    cell::{Cell, RefCell},
     collections::HashMap,
-    sync::mpsc::{channel, Sender},
+    ffi::{CStr, CString},
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::channel,
+        OnceLock,
+    },
     thread,
+    time::Duration,
 };
 
 // Gtk specific struct that will live only in the Gtk thread, since a lot of the
 // base types involved don't implement Send (for good reason).
 pub struct GtkSystrayApp {
+    // Which Application this is, as every one shares the GTK thread.
+    app: usize,
     menu: gtk::Menu,
-    ai: RefCell<AppIndicator>,
+    indicator: Indicator,
     menu_items: RefCell<HashMap<u32, gtk::MenuItem>>,
-    event_tx: Sender<SystrayEvent>,
+    // Items with an icon hold a box of image and label instead of the label.
+    icons: RefCell<HashMap<u32, (gtk::Image, gtk::Label)>>,
+    submenus: RefCell<HashMap<u32, gtk::Menu>>,
+    // The item shown in bold by set_default_menu_item.
+    default_item: Cell<Option<u32>>,
+    styles: RefCell<HashMap<u32, MenuItemStyle>>,
+    // Parent submenu of every item that isn't in the top level menu.
+    parents: RefCell<HashMap<u32, u32>>,
+    // Set while we toggle check items ourselves, so that doesn't count as a
+    // click.
+    updating: Rc<Cell<bool>>,
+    // The icon last set, to go back to when an animation stops.
+    icon: RefCell<Option<IconHandle>>,
+    animation: RefCell<Option<glib::SourceId>>,
+    attention: RefCell<Option<Attention>>,
+    visible: Cell<bool>,
+    // Whether themed icons show as their symbolic variant.
+    template: Cell<bool>,
+    theme: Cell<Theme>,
+    // GNOME's color-scheme setting, where the schema is installed.
+    interface_settings: Option<gio::Settings>,
+    // Last seen scale factor, to tell real changes from monitor shuffles.
+    scale: Cell<i32>,
+    // The keys bound for every hotkey id.
+    hotkeys: RefCell<HashMap<u32, CString>>,
+    // Handlers on objects every app shares, taken off with the app.
+    signals: Vec<(gtk::glib::Object, gtk::glib::SignalHandlerId)>,
+    event_tx: EventSender,
+}
+
+thread_local! {
+    // Every Application's tray, by the id its GtkWindow holds.
+    static GTK_STASH: RefCell<HashMap<usize, GtkSystrayApp>> = RefCell::new(HashMap::new());
+    // Loaded with the first hotkey of any app.
+    static KEYBINDER: RefCell<Option<Keybinder>> = const { RefCell::new(None) };
+}
+
+static APP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// Started by the first Application that wants GTK initialized, and kept
+// running for the ones after it. Holds gtk::init's error if it failed.
+static GTK_THREAD: OnceLock<Result<(), String>> = OnceLock::new();
+
+fn start_gtk_thread() -> Result<(), Error> {
+    GTK_THREAD
+        .get_or_init(|| {
+            let (tx, rx) = channel();
+            thread::spawn(move || {
+                if let Err(e) = gtk::init() {
+                    tx.send(Err(e.to_string())).ok();
+                    return;
+                }
+                tx.send(Ok(())).ok();
+                gtk::main();
+            });
+            rx.recv()
+                .unwrap_or_else(|_| Err("The GTK thread died starting up".to_owned()))
+        })
+        .clone()
+        .map_err(|e| Error::os(format!("Gtk init error: {}", e)))
+}
+
+fn with_stash<F>(app: usize, f: F)
+where
+    F: FnOnce(&GtkSystrayApp),
+{
+    GTK_STASH.with(|stash| {
+        if let Some(stash) = stash.borrow().get(&app) {
+            f(stash);
+        }
+    });
+}
+
+enum Indicator {
+    #[cfg(feature = "appindicator")]
+    AppIndicator(RefCell<AppIndicator>),
+    StatusIcon(StatusIcon),
+}
+
+// The name trays know the icon by, from InitOptions::tray_id.
+fn tray_name(id: u32) -> String {
+    let exe = std::env::current_exe()
+        .ok()
+        .and_then(|p| p.file_stem().map(|s| s.to_string_lossy().into_owned()))
+        .unwrap_or_else(|| "systray2".to_string());
+    format!("{}-{}", exe, id)
+}
+
+impl Indicator {
+    fn new(
+        menu: &mut gtk::Menu,
+        name: Option<&str>,
+        tooltip: ProviderSlot,
+        event_tx: EventSender,
+    ) -> Indicator {
+        #[cfg(feature = "appindicator")]
+        if appindicator_available() {
+            let mut ai = AppIndicator::new(name.unwrap_or(""), "");
+            ai.set_status(AppIndicatorStatus::Active);
+            ai.set_menu(menu);
+            return Indicator::AppIndicator(RefCell::new(ai));
+        }
+        debug!("Using GtkStatusIcon");
+        let icon = StatusIcon::new(menu);
+        if let Some(name) = name {
+            icon.set_name(name);
+        }
+        icon.connect_query_tooltip(move || tooltip::provide(&tooltip));
+        icon.connect_click(move |event| {
+            event_tx.send(event).ok();
+        });
+        Indicator::StatusIcon(icon)
+    }
+
+    // Only GtkStatusIcon takes pixels; AppIndicator is handed a file instead.
+    fn set_icon(&self, handle: &IconHandle) {
+        match (self, handle) {
+            #[cfg(feature = "appindicator")]
+            (Indicator::AppIndicator(ai), IconHandle::File(file)) => {
+                ai.borrow_mut().set_icon_full(file, "icon")
+            }
+            // Takes names where it takes paths.
+            #[cfg(feature = "appindicator")]
+            (Indicator::AppIndicator(ai), IconHandle::Named(name)) => {
+                ai.borrow_mut().set_icon_full(name, "icon")
+            }
+            #[cfg(feature = "appindicator")]
+            (Indicator::AppIndicator(_), IconHandle::Pixbuf(_)) => {}
+            (Indicator::StatusIcon(icon), IconHandle::File(file)) => icon.set_from_file(file),
+            (Indicator::StatusIcon(icon), IconHandle::Pixbuf(pixbuf)) => {
+                icon.set_from_pixbuf(pixbuf)
+            }
+            (Indicator::StatusIcon(icon), IconHandle::Named(name)) => icon.set_from_icon_name(name),
+        }
+    }
+
+    // Indicator hosts that show a tooltip at all take it from the title.
+    fn set_tooltip(&self, tooltip: &str) {
+        match self {
+            #[cfg(feature = "appindicator")]
+            Indicator::AppIndicator(ai) => ai.borrow_mut().set_title(tooltip),
+            Indicator::StatusIcon(icon) => icon.set_tooltip_text(tooltip),
+        }
+    }
+
+    // AppIndicator always opens the menu, whichever button was pressed.
+    fn set_menu_on_left_click(&self, enabled: bool) {
+        match self {
+            #[cfg(feature = "appindicator")]
+            Indicator::AppIndicator(_) => {}
+            Indicator::StatusIcon(icon) => icon.set_menu_on_left_click(enabled),
+        }
+    }
+
+    // AppIndicator doesn't say when it's clicked, so its menu can't be
+    // swapped for anything else.
+    fn set_suppress_default_menu(&self, suppress: bool) {
+        match self {
+            #[cfg(feature = "appindicator")]
+            Indicator::AppIndicator(_) => {}
+            Indicator::StatusIcon(icon) => icon.set_suppress_default_menu(suppress),
+        }
+    }
+
+    // What the tray gives a GtkStatusIcon, in logical pixels. AppIndicator
+    // hosts don't say.
+    fn size(&self) -> Option<u32> {
+        match self {
+            #[cfg(feature = "appindicator")]
+            Indicator::AppIndicator(_) => None,
+            Indicator::StatusIcon(icon) => Some(icon.size()).filter(|s| *s > 0).map(|s| s as u32),
+        }
+    }
+
+    // AppIndicator hosts keep where they put the icon to themselves.
+    fn geometry(&self) -> Result<Rect, Error> {
+        match self {
+            #[cfg(feature = "appindicator")]
+            Indicator::AppIndicator(_) => Err(Error::NotImplementedError),
+            Indicator::StatusIcon(icon) => icon
+                .geometry()
+                .ok_or_else(|| Error::os("The status icon isn't in a tray")),
+        }
+    }
+
+    // GtkStatusIcon has no attention status, the stash blinks it instead.
+    fn set_visible(&self, visible: bool, attention: bool) {
+        match self {
+            #[cfg(feature = "appindicator")]
+            Indicator::AppIndicator(ai) => ai.borrow_mut().set_status(match (visible, attention) {
+                (false, _) => AppIndicatorStatus::Passive,
+                (true, true) => AppIndicatorStatus::Attention,
+                (true, false) => AppIndicatorStatus::Active,
+            }),
+            Indicator::StatusIcon(icon) => {
+                let _ = attention;
+                icon.set_visible(visible)
+            }
+        }
+    }
+}
+
+// libappindicator-sys loads the library on first use and panics if it isn't
+// there, so look for it the same way first.
+#[cfg(feature = "appindicator")]
+fn appindicator_available() -> bool {
+    ["libayatana-appindicator3.so.1", "libappindicator3.so.1"]
+        .iter()
+        .any(|name| unsafe { libloading::Library::new(name) }.is_ok())
+}
+
+enum IconHandle {
+    File(String),
+    Pixbuf(gtk::gdk_pixbuf::Pixbuf),
+    // From the icon theme, so it follows theme and panel size changes.
+    Named(String),
+}
+
+// Icon files written for AppIndicator's animation frames, deleted once the
+// animation is over.
+struct IconFiles(Vec<PathBuf>);
+
+impl Drop for IconFiles {
+    fn drop(&mut self) {
+        for file in &self.0 {
+            std::fs::remove_file(file).ok();
+        }
+    }
+}
+
+// What request_attention put up, until clear_attention.
+struct Attention {
+    // None for AppIndicator when the attention icon is the icon itself.
+    icon: Option<IconHandle>,
+    // The timer GtkStatusIcon blinks on.
+    blink: Option<glib::SourceId>,
+    _files: IconFiles,
+}
+
+const ATTENTION_BLINK: Duration = Duration::from_millis(500);
+
+// What clear_icon goes back to, as generic as icon themes have.
+const DEFAULT_ICON: &str = "application-x-executable";
+
+struct AnimationFrames {
+    icons: Vec<IconHandle>,
+    _files: IconFiles,
+}
+
+// Tightly packed rows, so the rowstride is just the width.
+fn rgba_pixbuf(rgba: Vec<u8>, width: u32, height: u32) -> gtk::gdk_pixbuf::Pixbuf {
+    gtk::gdk_pixbuf::Pixbuf::from_bytes(
+        &gtk::glib::Bytes::from_owned(rgba),
+        gtk::gdk_pixbuf::Colorspace::Rgb,
+        true,
+        8,
+        width as i32,
+        height as i32,
+        width as i32 * 4,
+    )
+}
+
+// GResources the app registered, as with gio::resources_register, by their
+// path. Given back as the tightly packed RGBA rows icons are set from.
+fn resource_rgba(path: &str) -> Result<(Vec<u8>, u32, u32), Error> {
+    let failed = |e: &dyn std::fmt::Display| {
+        Error::os(format!("Failed to load icon resource {}: {}", path, e))
+    };
+    let pixbuf = gtk::gdk_pixbuf::Pixbuf::from_resource(path)
+        .map_err(|e| failed(&e))?
+        .add_alpha(false, 0, 0, 0)
+        .map_err(|e| failed(&e))?;
+    let (width, height) = (pixbuf.width() as usize, pixbuf.height() as usize);
+    let stride = pixbuf.rowstride() as usize;
+    let pixels = pixbuf.read_pixel_bytes();
+    let rgba = (0..height)
+        .flat_map(|y| &pixels[y * stride..y * stride + width * 4])
+        .copied()
+        .collect();
+    Ok((rgba, width as u32, height as u32))
+}
+
+const INTERFACE_SCHEMA: &str = "org.gnome.desktop.interface";
+
+// GNOME's color-scheme wins when it states a preference, otherwise go by the
+// GTK theme.
+fn current_theme(interface_settings: Option<&gio::Settings>) -> Theme {
+    match interface_settings.map(|s| s.string("color-scheme")).as_deref() {
+        Some("prefer-dark") => return Theme::Dark,
+        Some("prefer-light") => return Theme::Light,
+        _ => {}
+    }
+    let dark = gtk::Settings::default().is_some_and(|s| {
+        s.is_gtk_application_prefer_dark_theme()
+            || s
+                .gtk_theme_name()
+                .is_some_and(|name| name.to_lowercase().contains("dark"))
+    });
+    if dark {
+        Theme::Dark
+    } else {
+        Theme::Light
+    }
+}
+
+fn theme_changed(app: usize) {
+    with_stash(app, |stash| {
+        let theme = current_theme(stash.interface_settings.as_ref());
+        if stash.theme.replace(theme) != theme {
+            stash.event_tx.send(SystrayEvent::ThemeChanged(theme)).ok();
+        }
+    });
 }
 
-thread_local!(static GTK_STASH: RefCell<Option<GtkSystrayApp>> = RefCell::new(None));
+fn scale_changed(app: usize) {
+    with_stash(app, |stash| {
+        let scale = stash.scale_factor();
+        if stash.scale.replace(scale) != scale {
+            stash.event_tx.send(SystrayEvent::ScaleFactorChanged).ok();
+        }
+    });
+}
 
-pub struct MenuItemInfo {
-    mid: u32,
-    title: String,
-    tooltip: String,
-    disabled: bool,
-    checked: bool,
+// A key is bound once however many apps and ids want it, and its presses
+// go to all of them.
+fn hotkey_pressed(keystring: &CStr) {
+    GTK_STASH.with(|stash| {
+        for stash in stash.borrow().values() {
+            for (id, key) in stash.hotkeys.borrow().iter() {
+                if key.as_c_str() == keystring {
+                    stash.event_tx.send(SystrayEvent::HotkeyPressed(*id)).ok();
+                }
+            }
+        }
+    });
 }
 
-type Callback = Box<(Fn(&GtkSystrayApp) -> () + 'static)>;
+// Stashes can be dropped with the thread, when the thread locals are out of
+// reach already.
+fn key_bound(keystring: &CStr) -> bool {
+    GTK_STASH
+        .try_with(|stash| {
+            stash
+                .borrow()
+                .values()
+                .any(|stash| stash.hotkeys.borrow().values().any(|k| k.as_c_str() == keystring))
+        })
+        .unwrap_or(false)
+}
+
+// Unbinds the key once no app has it anymore.
+fn release_key(keystring: &CStr) {
+    if key_bound(keystring) {
+        return;
+    }
+    KEYBINDER
+        .try_with(|keybinder| {
+            if let Some(keybinder) = keybinder.borrow().as_ref() {
+                keybinder.unbind(keystring);
+            }
+        })
+        .ok();
+}
+
+// AppIndicator caches icons by name, so every buffer-backed icon gets its own
+// file name.
+static ICON_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// AppIndicator wants an icon name or path, so hand it a PNG.
+fn write_icon_file(buffer: &[u8], width: u32, height: u32) -> Result<PathBuf, Error> {
+    let path = std::env::temp_dir().join(format!(
+        "systray2-{}-{}.png",
+        std::process::id(),
+        ICON_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    rgba_pixbuf(buffer.to_vec(), width, height)
+        .savev(&path, "png", &[])
+        .map_err(|e| Error::os(format!("Failed to write icon file: {}", e)))?;
+    Ok(path)
+}
 
 // Convenience function to clean up thread local unwrapping
-fn run_on_gtk_thread<F>(f: F)
+fn run_on_gtk_thread<F>(app: usize, f: F)
 where
-    F: std::ops::Fn(&GtkSystrayApp) -> () + Send + 'static,
+    F: std::ops::FnOnce(&GtkSystrayApp) + Send + 'static,
 {
     // Note this is glib, not gtk. Calling gtk::idle_add will panic us due to
     // being on different threads. glib::idle_add can run across threads.
-    glib::idle_add(move || {
-        GTK_STASH.with(|stash| {
-            let stash = stash.borrow();
-            let stash = stash.as_ref();
-            if let Some(stash) = stash {
-                f(stash);
-            }
+    glib::idle_add_once(move || with_stash(app, f));
+}
+
+// Like run_on_gtk_thread, but blocks until the GTK thread hands back a value.
+// On the GTK thread itself, as when the tray lives in the app's own main
+// loop, `f` runs right away. It's queued at idle priority otherwise, behind
+// what run_on_gtk_thread queued before.
+fn query_gtk_thread<T, F>(app: usize, f: F) -> Option<T>
+where
+    F: std::ops::FnOnce(&GtkSystrayApp) -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (tx, rx) = channel();
+    glib::MainContext::default().invoke_with_priority(glib::Priority::DEFAULT_IDLE, move || {
+        with_stash(app, move |stash: &GtkSystrayApp| {
+            tx.send(f(stash)).ok();
         });
-        gtk::prelude::Continue(false)
     });
+    rx.recv().ok()
 }
 
 impl GtkSystrayApp {
-    pub fn new(event_tx: Sender<SystrayEvent>) -> Result<GtkSystrayApp, Error> {
-        if let Err(e) = gtk::init() {
-            return Err(Error::OsError(format!("{}", "Gtk init error!")));
-        }
+    // Runs on the GTK thread, which is initialized by then.
+    pub fn new(
+        app: usize,
+        name: Option<String>,
+        event_tx: EventSender,
+        tooltip: ProviderSlot,
+    ) -> GtkSystrayApp {
         let mut m = gtk::Menu::new();
-        let mut ai = AppIndicator::new("", "");
-        ai.set_status(AppIndicatorStatus::Active);
-        ai.set_menu(&mut m);
-        Ok(GtkSystrayApp {
+        let tx = event_tx.clone();
+        m.connect_show(move |_| {
+            tx.send(SystrayEvent::MenuOpened).ok();
+        });
+        let tx = event_tx.clone();
+        m.connect_hide(move |_| {
+            tx.send(SystrayEvent::MenuClosed).ok();
+        });
+        let indicator = Indicator::new(&mut m, name.as_deref(), tooltip, event_tx.clone());
+        let interface_settings = gio::SettingsSchemaSource::default()
+            .and_then(|source| source.lookup(INTERFACE_SCHEMA, true))
+            .filter(|schema| schema.has_key("color-scheme"))
+            .map(|_| gio::Settings::new(INTERFACE_SCHEMA));
+        if let Some(settings) = &interface_settings {
+            settings.connect_changed(Some("color-scheme"), move |_, _| theme_changed(app));
+        }
+        let mut signals = Vec::new();
+        if let Some(settings) = gtk::Settings::default() {
+            let dark = settings
+                .connect_gtk_application_prefer_dark_theme_notify(move |_| theme_changed(app));
+            let name = settings.connect_gtk_theme_name_notify(move |_| theme_changed(app));
+            signals.push((settings.clone().upcast(), dark));
+            signals.push((settings.upcast(), name));
+        }
+        let theme = Cell::new(current_theme(interface_settings.as_ref()));
+        if let Some(screen) = gtk::gdk::Screen::default() {
+            let monitors = screen.connect_monitors_changed(move |_| scale_changed(app));
+            signals.push((screen.upcast(), monitors));
+        }
+        let stash = GtkSystrayApp {
+            app,
             menu: m,
-            ai: RefCell::new(ai),
+            indicator,
             menu_items: RefCell::new(HashMap::new()),
-            event_tx: event_tx,
-        })
+            icons: RefCell::new(HashMap::new()),
+            submenus: RefCell::new(HashMap::new()),
+            default_item: Cell::new(None),
+            styles: RefCell::new(HashMap::new()),
+            parents: RefCell::new(HashMap::new()),
+            updating: Rc::new(Cell::new(false)),
+            icon: RefCell::new(None),
+            animation: RefCell::new(None),
+            attention: RefCell::new(None),
+            visible: Cell::new(true),
+            template: Cell::new(false),
+            theme,
+            interface_settings,
+            scale: Cell::new(1),
+            hotkeys: RefCell::new(HashMap::new()),
+            signals,
+            event_tx,
+        };
+        stash.scale.set(stash.scale_factor());
+        stash
     }
 
-    pub fn systray_menu_selected(&self, menu_id: u32) {
-        self.event_tx
-            .send(SystrayEvent {
-                menu_index: menu_id as u32,
-            })
-            .ok();
+    fn container(&self, parent: Option<u32>) -> gtk::Menu {
+        parent
+            .and_then(|p| self.submenus.borrow().get(&p).cloned())
+            .unwrap_or_else(|| self.menu.clone())
     }
 
-    pub fn add_menu_separator(&self, item_idx: u32) {
-        //let mut menu_items = self.menu_items.borrow_mut();
-        let m = gtk::SeparatorMenuItem::new();
-        self.menu.append(&m);
-        //menu_items.insert(item_idx, m);
+    fn insert_item(&self, item_idx: u32, m: gtk::MenuItem, parent: Option<u32>) {
+        self.container(parent).append(&m);
+        if let Some(p) = parent {
+            self.parents.borrow_mut().insert(item_idx, p);
+        }
+        self.menu_items.borrow_mut().insert(item_idx, m);
         self.menu.show_all();
     }
 
-    pub fn add_menu_entry(&self, item_idx: u32, item_name: &str) {
-        let mut menu_items = self.menu_items.borrow_mut();
-        if menu_items.contains_key(&item_idx) {
-            let m: &gtk::MenuItem = menu_items.get(&item_idx).unwrap();
+    // The handler keeps the id the item was added with, not its position,
+    // so removing items before it doesn't change what its clicks report.
+    fn connect_selected(&self, item_idx: u32, m: &gtk::MenuItem) {
+        let tx = self.event_tx.clone();
+        let updating = self.updating.clone();
+        m.connect_activate(move |_| {
+            if !updating.get() {
+                tx.send(SystrayEvent::MenuItem(MenuItemId(item_idx))).ok();
+            }
+        });
+    }
+
+    pub fn add_menu_separator(&self, item_idx: u32, parent: Option<u32>) {
+        let m = gtk::SeparatorMenuItem::new();
+        self.insert_item(item_idx, m.upcast(), parent);
+    }
+
+    pub fn add_menu_entry(&self, item_idx: u32, item_name: &str, parent: Option<u32>) {
+        let item_name = &crate::api::underscore_mnemonic(item_name);
+        if let Some((_, label)) = self.icons.borrow().get(&item_idx) {
+            label.set_text_with_mnemonic(item_name);
+            return;
+        }
+        if let Some(m) = self.menu_items.borrow().get(&item_idx) {
             m.set_label(item_name);
             self.menu.show_all();
             return;
         }
-        let m = gtk::MenuItem::new_with_label(item_name);
-        self.menu.append(&m);
-        m.connect_activate(move |_| {
-            run_on_gtk_thread(move |stash: &GtkSystrayApp| {
-                stash.systray_menu_selected(item_idx);
-            });
+        let m = gtk::MenuItem::with_mnemonic(item_name);
+        self.connect_selected(item_idx, &m);
+        self.insert_item(item_idx, m, parent);
+    }
+
+    pub fn add_check_menu_entry(
+        &self,
+        item_idx: u32,
+        item_name: &str,
+        checked: bool,
+        parent: Option<u32>,
+    ) {
+        let m = gtk::CheckMenuItem::with_mnemonic(&crate::api::underscore_mnemonic(item_name));
+        m.set_active(checked);
+        let m: gtk::MenuItem = m.upcast();
+        self.connect_selected(item_idx, &m);
+        self.insert_item(item_idx, m, parent);
+    }
+
+    pub fn add_submenu(&self, item_idx: u32, item_name: &str, parent: Option<u32>) {
+        let m = gtk::MenuItem::with_mnemonic(&crate::api::underscore_mnemonic(item_name));
+        let sub = gtk::Menu::new();
+        m.set_submenu(Some(&sub));
+        self.submenus.borrow_mut().insert(item_idx, sub);
+        self.insert_item(item_idx, m, parent);
+    }
+
+    fn add_menu_entries(&self, entries: Vec<BatchEntry>) {
+        for entry in entries {
+            match entry {
+                BatchEntry::Item {
+                    idx,
+                    label,
+                    checked: None,
+                    parent,
+                } => self.add_menu_entry(idx, &label, parent),
+                BatchEntry::Item {
+                    idx,
+                    label,
+                    checked: Some(checked),
+                    parent,
+                } => self.add_check_menu_entry(idx, &label, checked, parent),
+                BatchEntry::Separator { idx, parent } => self.add_menu_separator(idx, parent),
+                BatchEntry::Submenu { idx, label, parent } => self.add_submenu(idx, &label, parent),
+            }
+        }
+    }
+
+    pub fn set_menu_item_checked(&self, item_idx: u32, checked: bool) {
+        let item = self.menu_items.borrow().get(&item_idx).cloned();
+        if let Some(m) = item.and_then(|m| m.downcast::<gtk::CheckMenuItem>().ok()) {
+            self.updating.set(true);
+            m.set_active(checked);
+            self.updating.set(false);
+        }
+    }
+
+    pub fn set_menu_item_enabled(&self, item_idx: u32, enabled: bool) {
+        if let Some(m) = self.menu_items.borrow().get(&item_idx) {
+            m.set_sensitive(enabled);
+        }
+    }
+
+    pub fn set_menu_item_icon(&self, item_idx: u32, rgba: Vec<u8>, width: u32, height: u32) {
+        let pixbuf = rgba_pixbuf(rgba, width, height);
+        if let Some((image, _)) = self.icons.borrow().get(&item_idx) {
+            image.set_from_pixbuf(Some(&pixbuf));
+            return;
+        }
+        let m = match self.menu_items.borrow().get(&item_idx) {
+            Some(m) => m.clone(),
+            None => return,
+        };
+        let label = gtk::Label::with_mnemonic(m.label().as_deref().unwrap_or(""));
+        if let Some(child) = m.child() {
+            m.remove(&child);
+        }
+        let image = gtk::Image::from_pixbuf(Some(&pixbuf));
+        let hbox = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+        hbox.pack_start(&image, false, false, 0);
+        hbox.pack_start(&label, false, false, 0);
+        m.add(&hbox);
+        m.show_all();
+        self.icons.borrow_mut().insert(item_idx, (image, label));
+        self.style_label(item_idx);
+    }
+
+    // The label inside an item, next to its icon if it has one.
+    fn item_label(&self, item_idx: u32) -> Option<gtk::Label> {
+        if let Some((_, label)) = self.icons.borrow().get(&item_idx) {
+            return Some(label.clone());
+        }
+        self.menu_items
+            .borrow()
+            .get(&item_idx)?
+            .child()?
+            .downcast::<gtk::Label>()
+            .ok()
+    }
+
+    // Styles go on as pango attributes rather than markup, so the label text
+    // stays as it is. The default item is bold on top of its style.
+    fn style_label(&self, item_idx: u32) {
+        let label = match self.item_label(item_idx) {
+            Some(label) => label,
+            None => return,
+        };
+        let style = self.styles.borrow().get(&item_idx).copied().unwrap_or_default();
+        let attrs = gtk::pango::AttrList::new();
+        if style.bold || self.default_item.get() == Some(item_idx) {
+            attrs.insert(gtk::pango::AttrInt::new_weight(gtk::pango::Weight::Bold));
+        }
+        if style.italic {
+            attrs.insert(gtk::pango::AttrInt::new_style(gtk::pango::Style::Italic));
+        }
+        if let Some([r, g, b]) = style.color {
+            // Pango colors are 16 bits a channel.
+            let [r, g, b] = [r, g, b].map(|c| c as u16 * 257);
+            attrs.insert(gtk::pango::AttrColor::new_foreground(r, g, b));
+        }
+        label.set_attributes(Some(&attrs));
+    }
+
+    pub fn set_default_menu_item(&self, item_idx: u32) {
+        let previous = self.default_item.replace(Some(item_idx));
+        if let Some(previous) = previous {
+            self.style_label(previous);
+        }
+        self.style_label(item_idx);
+    }
+
+    pub fn set_menu_item_style(&self, item_idx: u32, style: MenuItemStyle) {
+        self.styles.borrow_mut().insert(item_idx, style);
+        self.style_label(item_idx);
+    }
+
+    // Filled from `builder` whenever the submenu shows, and emptied when it
+    // hides. GTK hides the menus before it activates the item, so the items
+    // are only taken out, not destroyed, and their handlers still run.
+    pub fn set_lazy_submenu(&self, item_idx: u32, builder: LazyBuilder) {
+        let submenu = match self.submenus.borrow().get(&item_idx) {
+            Some(submenu) => submenu.clone(),
+            None => return,
+        };
+        submenu.connect_show(move |submenu| {
+            for (label, f) in lazy_menu::build(&builder) {
+                let item = gtk::MenuItem::with_label(&label);
+                let f = RefCell::new(f);
+                item.connect_activate(move |_| lazy_menu::activate(&mut f.borrow_mut()));
+                submenu.append(&item);
+            }
+            submenu.show_all();
         });
-        menu_items.insert(item_idx, m);
-        self.menu.show_all();
+        submenu.connect_hide(|submenu| {
+            for child in submenu.children() {
+                submenu.remove(&child);
+            }
+        });
+    }
+
+    pub fn remove_menu_item(&self, item_idx: u32) {
+        self.icons.borrow_mut().remove(&item_idx);
+        self.styles.borrow_mut().remove(&item_idx);
+        let item = self.menu_items.borrow_mut().remove(&item_idx);
+        if let Some(m) = item {
+            let container = self.container(self.parents.borrow_mut().remove(&item_idx));
+            container.remove(&m);
+        }
+        // Anything inside a removed submenu went with it.
+        if self.submenus.borrow_mut().remove(&item_idx).is_some() {
+            let children: Vec<u32> = self
+                .parents
+                .borrow()
+                .iter()
+                .filter(|(_, p)| **p == item_idx)
+                .map(|(c, _)| *c)
+                .collect();
+            for c in children {
+                self.remove_menu_item(c);
+            }
+        }
+    }
+
+    // Positions past the end put the item last.
+    fn move_menu_item(&self, item_idx: u32, position: u32) {
+        let item = self.menu_items.borrow().get(&item_idx).cloned();
+        if let Some(m) = item {
+            let container = self.container(self.parents.borrow().get(&item_idx).copied());
+            let count = container.children().len() as u32;
+            container.reorder_child(&m, position.min(count - 1) as i32);
+        }
+    }
+
+    // A static icon ends any animation.
+    fn set_icon(&self, icon: IconHandle) {
+        self.end_animation();
+        self.show_icon(&icon);
+        self.icon.replace(Some(icon));
+    }
+
+    // Themed icons come as their "-symbolic" variant, which panels recolor,
+    // while the icon is a template and the theme has one.
+    fn show_icon(&self, icon: &IconHandle) {
+        if let IconHandle::Named(name) = icon {
+            let symbolic = format!("{}-symbolic", name);
+            let known = || gtk::IconTheme::default().is_some_and(|t| t.has_icon(&symbolic));
+            if self.template.get() && !name.ends_with("-symbolic") && known() {
+                self.indicator.set_icon(&IconHandle::Named(symbolic));
+                return;
+            }
+        }
+        self.indicator.set_icon(icon);
+    }
+
+    // An animation keeps its frames, and the icon changes once it's over.
+    fn set_icon_template(&self, template: bool) {
+        self.template.set(template);
+        if self.animation.borrow().is_none() {
+            if let Some(icon) = self.icon.borrow().as_ref() {
+                self.show_icon(icon);
+            }
+        }
+    }
+
+    // Both backends would show a missing file as a blank or broken icon.
+    // GTK and AppIndicator take UTF-8 paths only.
+    pub fn set_icon_from_file(&self, file: &Path) -> Result<(), Error> {
+        if !file.is_file() {
+            return Err(Error::os(format!("No icon file {}", file.display())));
+        }
+        let file = file
+            .to_str()
+            .ok_or_else(|| Error::os(format!("{} isn't valid UTF-8", file.display())))?;
+        self.set_icon(IconHandle::File(file.to_string()));
+        Ok(())
+    }
+
+    // Unknown names would show as the broken image icon, so they're turned
+    // down instead.
+    fn set_icon_from_theme(&self, icon_name: &str) -> Result<(), Error> {
+        let known = gtk::IconTheme::default().is_some_and(|theme| theme.has_icon(icon_name));
+        if !known {
+            return Err(Error::os(format!("No icon named {} in the icon theme", icon_name)));
+        }
+        self.set_icon(IconHandle::Named(icon_name.to_string()));
+        Ok(())
+    }
+
+    fn clear_icon(&self) {
+        self.set_icon(IconHandle::Named(DEFAULT_ICON.to_string()));
+    }
+
+    // Removing the source drops its frames, and with them any files.
+    fn end_animation(&self) -> bool {
+        match self.animation.borrow_mut().take() {
+            Some(source) => {
+                source.remove();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn start_icon_animation(&self, frames: AnimationFrames, interval: Duration) {
+        self.end_animation();
+        self.indicator.set_icon(&frames.icons[0]);
+        let mut next = 1 % frames.icons.len();
+        let app = self.app;
+        let source = glib::timeout_add_local(interval, move || {
+            with_stash(app, |stash| stash.indicator.set_icon(&frames.icons[next]));
+            next = (next + 1) % frames.icons.len();
+            glib::ControlFlow::Continue
+        });
+        self.animation.replace(Some(source));
+    }
+
+    pub fn stop_icon_animation(&self) {
+        if self.end_animation() {
+            if let Some(icon) = self.icon.borrow().as_ref() {
+                self.show_icon(icon);
+            }
+        }
     }
 
-    pub fn set_icon_from_file(&self, file: &str) {
-        let mut ai = self.ai.borrow_mut();
-        ai.set_icon_full(file, "icon");
+    pub fn set_tooltip(&self, tooltip: &str) {
+        self.indicator.set_tooltip(tooltip);
+    }
+
+    pub fn set_icon_visible(&self, visible: bool) {
+        self.visible.set(visible);
+        let attention = self.attention.borrow().is_some();
+        self.indicator.set_visible(visible, attention);
+    }
+
+    // AppIndicator shows its attention icon by itself, GtkStatusIcon lost
+    // blinking with GTK 3 and gets the icons swapped on a timer.
+    fn request_attention(&self, icon: Option<IconHandle>, files: IconFiles) {
+        self.clear_attention();
+        let blink = match &self.indicator {
+            #[cfg(feature = "appindicator")]
+            Indicator::AppIndicator(ai) => {
+                let base = self.icon.borrow();
+                if let Some(IconHandle::File(file) | IconHandle::Named(file)) =
+                    icon.as_ref().or(base.as_ref())
+                {
+                    ai.borrow_mut().set_attention_icon_full(file, "attention");
+                }
+                None
+            }
+            Indicator::StatusIcon(_) => {
+                let mut showing = false;
+                let app = self.app;
+                Some(glib::timeout_add_local(ATTENTION_BLINK, move || {
+                    showing = !showing;
+                    with_stash(app, |stash| stash.blink_attention(showing));
+                    glib::ControlFlow::Continue
+                }))
+            }
+        };
+        self.attention.replace(Some(Attention {
+            icon,
+            blink,
+            _files: files,
+        }));
+        self.indicator.set_visible(self.visible.get(), true);
+    }
+
+    fn blink_attention(&self, showing: bool) {
+        let attention = self.attention.borrow();
+        let icon = match attention.as_ref().and_then(|a| a.icon.as_ref()) {
+            Some(icon) if showing => Some(icon),
+            _ => None,
+        };
+        match icon {
+            Some(icon) => self.indicator.set_icon(icon),
+            None => {
+                if let Some(icon) = self.icon.borrow().as_ref() {
+                    self.show_icon(icon);
+                }
+            }
+        }
+    }
+
+    fn clear_attention(&self) {
+        let attention = match self.attention.take() {
+            Some(attention) => attention,
+            None => return,
+        };
+        if let Some(source) = attention.blink {
+            source.remove();
+            self.blink_attention(false);
+        }
+        self.indicator.set_visible(self.visible.get(), false);
+    }
+
+    fn register_hotkey(&self, id: u32, modifiers: HotkeyModifiers, key: u32) -> Result<(), Error> {
+        let keystring = keybinder::keystring(modifiers, key)?;
+        if !key_bound(&keystring) {
+            KEYBINDER.with(|keybinder| {
+                let mut keybinder = keybinder.borrow_mut();
+                if keybinder.is_none() {
+                    *keybinder = Some(Keybinder::load()?);
+                }
+                if !keybinder.as_ref().unwrap().bind(&keystring) {
+                    return Err(Error::os(format!(
+                        "Couldn't grab {:?}, another app may hold it",
+                        keystring
+                    )));
+                }
+                Ok(())
+            })?;
+        }
+        self.hotkeys.borrow_mut().insert(id, keystring);
+        Ok(())
+    }
+
+    fn unregister_hotkey(&self, id: u32) {
+        if let Some(keystring) = self.hotkeys.borrow_mut().remove(&id) {
+            release_key(&keystring);
+        }
+    }
+
+    pub fn scale_factor(&self) -> i32 {
+        gtk::gdk::Display::default()
+            .and_then(|d| d.primary_monitor().or_else(|| d.monitor(0)))
+            .map(|m| m.scale_factor())
+            .unwrap_or(1)
     }
 }
 
-pub struct Window {
-    gtk_loop: Option<thread::JoinHandle<()>>,
+// The GTK thread outlives the app, so everything the app left with GTK goes
+// here, out of the stash first.
+impl Drop for GtkSystrayApp {
+    fn drop(&mut self) {
+        self.end_animation();
+        self.clear_attention();
+        self.set_icon_visible(false);
+        for (object, handler) in self.signals.drain(..) {
+            object.disconnect(handler);
+        }
+        for (_, keystring) in self.hotkeys.take() {
+            release_key(&keystring);
+        }
+        // Takes the menu's handlers, and the event senders in them, along.
+        unsafe { self.menu.destroy() };
+    }
 }
 
-impl Window {
-    pub fn new(event_tx: Sender<SystrayEvent>) -> Result<Window, Error> {
+pub struct GtkWindow {
+    app: usize,
+    // False once shut down.
+    running: Cell<bool>,
+    // Set while the tray lives in the app's own main loop.
+    hosted: bool,
+    icon_file: RefCell<Option<PathBuf>>,
+    // GtkStatusIcon takes pixbufs; AppIndicator only names and paths.
+    status_icon: bool,
+    // Off for the try_* setters, which only queue their calls.
+    waiting: Cell<bool>,
+}
+
+impl GtkWindow {
+    pub fn new(
+        event_tx: EventSender,
+        tooltip: ProviderSlot,
+        options: &InitOptions,
+    ) -> Result<GtkWindow, Error> {
+        // Whoever initialized GTK runs the default main context, be it the
+        // thread started here or the app.
+        let hosted = gtk::is_initialized() && GTK_THREAD.get().is_none();
+        if options.init_gtk && !hosted {
+            start_gtk_thread()?;
+        } else if !gtk::is_initialized() {
+            return Err(Error::os("GTK isn't initialized, though init_gtk is off"));
+        }
+        let app = APP_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let name = options.tray_id.map(tray_name);
         let (tx, rx) = channel();
-        let gtk_loop = thread::spawn(move || {
-            GTK_STASH.with(|stash| match GtkSystrayApp::new(event_tx) {
-                Ok(data) => {
-                    (*stash.borrow_mut()) = Some(data);
-                    tx.send(Ok(()));
-                }
-                Err(e) => {
-                    tx.send(Err(e));
-                    return;
-                }
-            });
-            gtk::main();
+        // Runs right away on the loop's thread, and otherwise waits for the
+        // loop to get to it.
+        glib::MainContext::default().invoke(move || {
+            let stash = GtkSystrayApp::new(app, name, event_tx, tooltip);
+            let status_icon = matches!(stash.indicator, Indicator::StatusIcon(_));
+            GTK_STASH.with(|s| s.borrow_mut().insert(app, stash));
+            tx.send(status_icon).ok();
         });
-        match rx.recv().unwrap() {
-            Ok(()) => Ok(Window {
-                gtk_loop: Some(gtk_loop),
+        match rx.recv() {
+            Ok(status_icon) => Ok(GtkWindow {
+                app,
+                running: Cell::new(true),
+                hosted,
+                icon_file: RefCell::new(None),
+                status_icon,
+                waiting: Cell::new(true),
             }),
-            Err(e) => Err(e),
+            Err(_) => Err(Error::os("The GTK main loop is gone")),
         }
     }
 
-    pub fn add_menu_entry(&self, item_idx: u32, item_name: &str) -> Result<(), Error> {
-        let n = item_name.to_owned().clone();
-        run_on_gtk_thread(move |stash: &GtkSystrayApp| {
-            stash.add_menu_entry(item_idx, &n);
-        });
+    // Runs `f` on the GTK thread and waits for its result, so errors there
+    // reach the caller. Without waiting they're only logged.
+    fn call<F>(&self, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&GtkSystrayApp) -> Result<(), Error> + Send + 'static,
+    {
+        if !self.waiting.get() {
+            run_on_gtk_thread(self.app, move |stash: &GtkSystrayApp| {
+                if let Err(e) = f(stash) {
+                    warn!("{}", e);
+                }
+            });
+            return Ok(());
+        }
+        query_gtk_thread(self.app, f).unwrap_or_else(|| Err(Error::os("The GTK thread is gone")))
+    }
+
+    pub fn set_waiting(&self, waiting: bool) -> bool {
+        self.waiting.replace(waiting)
+    }
+
+    pub fn event_sender(&self) -> Option<EventSender> {
+        query_gtk_thread(self.app, |stash: &GtkSystrayApp| stash.event_tx.clone())
+    }
+
+    pub fn add_menu_entry(
+        &self,
+        item_idx: u32,
+        item_name: &str,
+        parent: Option<u32>,
+    ) -> Result<(), Error> {
+        let n = item_name.to_owned();
+        self.call(move |stash: &GtkSystrayApp| {
+            stash.add_menu_entry(item_idx, &n, parent);
+            Ok(())
+        })
+    }
+
+    pub fn add_check_menu_entry(
+        &self,
+        item_idx: u32,
+        item_name: &str,
+        checked: bool,
+        parent: Option<u32>,
+    ) -> Result<(), Error> {
+        let n = item_name.to_owned();
+        self.call(move |stash: &GtkSystrayApp| {
+            stash.add_check_menu_entry(item_idx, &n, checked, parent);
+            Ok(())
+        })
+    }
+
+    pub fn add_submenu(
+        &self,
+        item_idx: u32,
+        item_name: &str,
+        parent: Option<u32>,
+    ) -> Result<(), Error> {
+        let n = item_name.to_owned();
+        self.call(move |stash: &GtkSystrayApp| {
+            stash.add_submenu(item_idx, &n, parent);
+            Ok(())
+        })
+    }
+
+    // All of them in one trip to the GTK thread.
+    pub(crate) fn add_menu_entries(&self, entries: &[BatchEntry]) -> Result<(), Error> {
+        let entries = entries.to_vec();
+        self.call(move |stash: &GtkSystrayApp| {
+            stash.add_menu_entries(entries);
+            Ok(())
+        })
+    }
+
+    pub fn add_menu_separator(&self, item_idx: u32, parent: Option<u32>) -> Result<(), Error> {
+        self.call(move |stash: &GtkSystrayApp| {
+            stash.add_menu_separator(item_idx, parent);
+            Ok(())
+        })
+    }
+
+    pub fn set_menu_item_checked(&self, item_idx: u32, checked: bool) -> Result<(), Error> {
+        self.call(move |stash: &GtkSystrayApp| {
+            stash.set_menu_item_checked(item_idx, checked);
+            Ok(())
+        })
+    }
+
+    pub fn set_menu_item_enabled(&self, item_idx: u32, enabled: bool) -> Result<(), Error> {
+        self.call(move |stash: &GtkSystrayApp| {
+            stash.set_menu_item_enabled(item_idx, enabled);
+            Ok(())
+        })
+    }
+
+    pub fn set_menu_item_icon_rgba(
+        &self,
+        item_idx: u32,
+        rgba: &[u8],
+        width: u32,
+        height: u32,
+    ) -> Result<(), Error> {
+        let rgba = rgba.to_vec();
+        self.call(move |stash: &GtkSystrayApp| {
+            stash.set_menu_item_icon(item_idx, rgba, width, height);
+            Ok(())
+        })
+    }
+
+    pub fn set_default_menu_item(&self, item_idx: u32) -> Result<(), Error> {
+        self.call(move |stash: &GtkSystrayApp| {
+            stash.set_default_menu_item(item_idx);
+            Ok(())
+        })
+    }
+
+    pub fn set_menu_item_style(
+        &self,
+        item_idx: u32,
+        _label: &str,
+        style: MenuItemStyle,
+    ) -> Result<(), Error> {
+        self.call(move |stash: &GtkSystrayApp| {
+            stash.set_menu_item_style(item_idx, style);
+            Ok(())
+        })
+    }
+
+    pub fn set_lazy_submenu(&self, item_idx: u32, builder: LazyBuilder) -> Result<(), Error> {
+        self.call(move |stash: &GtkSystrayApp| {
+            stash.set_lazy_submenu(item_idx, builder);
+            Ok(())
+        })
+    }
+
+    pub fn remove_menu_item(&self, item_idx: u32) -> Result<(), Error> {
+        self.call(move |stash: &GtkSystrayApp| {
+            stash.remove_menu_item(item_idx);
+            Ok(())
+        })
+    }
+
+    pub fn with_gtk_menu<F>(&self, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&gtk::Menu) + Send + 'static,
+    {
+        self.call(move |stash: &GtkSystrayApp| {
+            f(&stash.menu);
+            Ok(())
+        })
+    }
+
+    pub fn set_icon_template(&self, template: bool) -> Result<(), Error> {
+        self.call(move |stash: &GtkSystrayApp| {
+            stash.set_icon_template(template);
+            Ok(())
+        })
+    }
+
+    pub fn move_menu_item(&self, item_idx: u32, position: u32) -> Result<(), Error> {
+        self.call(move |stash: &GtkSystrayApp| {
+            stash.move_menu_item(item_idx, position);
+            Ok(())
+        })
+    }
+
+    pub fn set_icon_from_file(&self, file: &Path) -> Result<(), Error> {
+        let n = file.to_owned();
+        self.call(move |stash: &GtkSystrayApp| {
+            stash.set_icon_from_file(&n)
+        })
+    }
+
+    pub fn set_icon_from_theme(&self, icon_name: &str) -> Result<(), Error> {
+        let n = icon_name.to_owned();
+        self.call(move |stash: &GtkSystrayApp| stash.set_icon_from_theme(&n))
+    }
+
+    pub fn clear_icon(&self) -> Result<(), Error> {
+        self.call(|stash: &GtkSystrayApp| {
+            stash.clear_icon();
+            Ok(())
+        })
+    }
+
+    pub fn set_icon_from_image_buffer(
+        &self,
+        buffer: &[u8],
+        width: u32,
+        height: u32,
+    ) -> Result<(), Error> {
+        self.set_icon_from_rgba(buffer.to_vec(), width, height)
+    }
+
+    // The pixels go to the GTK thread as they are and become the pixbuf's.
+    pub fn set_icon_from_rgba(&self, rgba: Vec<u8>, width: u32, height: u32) -> Result<(), Error> {
+        if rgba.len() != (width as usize) * (height as usize) * 4 {
+            return Err(Error::os(format!(
+                "Icon buffer is {} bytes, expected {} for a {}x{} RGBA image",
+                rgba.len(),
+                (width as usize) * (height as usize) * 4,
+                width,
+                height
+            )));
+        }
+        if self.status_icon {
+            return self.call(move |stash: &GtkSystrayApp| {
+                stash.set_icon(IconHandle::Pixbuf(rgba_pixbuf(rgba, width, height)));
+                Ok(())
+            });
+        }
+        let path = write_icon_file(&rgba, width, height)?;
+        self.set_icon_from_file(&path)?;
+        if let Some(old) = self.icon_file.replace(Some(path)) {
+            std::fs::remove_file(old).ok();
+        }
         Ok(())
     }
 
-    pub fn add_menu_separator(&self, item_idx: u32) -> Result<(), Error> {
-        run_on_gtk_thread(move |stash: &GtkSystrayApp| {
-            stash.add_menu_separator(item_idx);
+    // Pixbufs can't leave the GTK thread, so they're made there; AppIndicator
+    // gets a file per frame, written here.
+    pub fn start_icon_animation(
+        &self,
+        frames: &[IconFrame],
+        interval: Duration,
+    ) -> Result<(), Error> {
+        if self.status_icon {
+            let frames = frames.to_vec();
+            return self.call(move |stash: &GtkSystrayApp| {
+                let icons = frames
+                    .into_iter()
+                    .map(|f| IconHandle::Pixbuf(rgba_pixbuf(f.rgba, f.width, f.height)))
+                    .collect();
+                let frames = AnimationFrames {
+                    icons,
+                    _files: IconFiles(Vec::new()),
+                };
+                stash.start_icon_animation(frames, interval);
+                Ok(())
+            });
+        }
+        let mut files = IconFiles(Vec::with_capacity(frames.len()));
+        for f in frames {
+            files.0.push(write_icon_file(&f.rgba, f.width, f.height)?);
+        }
+        self.call(move |stash: &GtkSystrayApp| {
+            let icons = files
+                .0
+                .iter()
+                .map(|p| IconHandle::File(p.to_string_lossy().into_owned()))
+                .collect();
+            let frames = AnimationFrames {
+                icons,
+                _files: files,
+            };
+            stash.start_icon_animation(frames, interval);
+            Ok(())
+        })
+    }
+
+    pub fn stop_icon_animation(&self) -> Result<(), Error> {
+        self.call(move |stash: &GtkSystrayApp| {
+            stash.stop_icon_animation();
+            Ok(())
+        })
+    }
+
+    // Without an icon of its own GtkStatusIcon blinks a blank one, and
+    // AppIndicator leaves it to the host.
+    pub fn request_attention(&self, icon: Option<&IconFrame>) -> Result<(), Error> {
+        if self.status_icon {
+            let frame = icon.cloned();
+            return self.call(move |stash: &GtkSystrayApp| {
+                let pixbuf = match frame {
+                    Some(f) => rgba_pixbuf(f.rgba, f.width, f.height),
+                    None => rgba_pixbuf(vec![0; 16 * 16 * 4], 16, 16),
+                };
+                stash.request_attention(Some(IconHandle::Pixbuf(pixbuf)), IconFiles(Vec::new()));
+                Ok(())
+            });
+        }
+        let files = match icon {
+            Some(f) => IconFiles(vec![write_icon_file(&f.rgba, f.width, f.height)?]),
+            None => IconFiles(Vec::new()),
+        };
+        self.call(move |stash: &GtkSystrayApp| {
+            let icon = files
+                .0
+                .first()
+                .map(|p| IconHandle::File(p.to_string_lossy().into_owned()));
+            stash.request_attention(icon, files);
+            Ok(())
+        })
+    }
+
+    pub fn clear_attention(&self) -> Result<(), Error> {
+        self.call(move |stash: &GtkSystrayApp| {
+            stash.clear_attention();
+            Ok(())
+        })
+    }
+
+    pub fn scale_factor(&self) -> f64 {
+        query_gtk_thread(self.app, |stash: &GtkSystrayApp| stash.scale_factor()).unwrap_or(1) as f64
+    }
+
+    // The tray's size for a GtkStatusIcon, else the 22px per scale unit
+    // panels usually render indicators at.
+    pub fn icon_size(&self) -> u32 {
+        let size = query_gtk_thread(self.app, |stash: &GtkSystrayApp| stash.indicator.size());
+        let size = size.flatten().unwrap_or(22);
+        (size as f64 * self.scale_factor()).round() as u32
+    }
+
+    pub fn set_icon_from_resource(&self, resource: &str) -> Result<(), Error> {
+        let (rgba, width, height) = resource_rgba(resource)?;
+        self.set_icon_from_image_buffer(&rgba, width, height)
+    }
+
+    // Takes the icon and its event senders off the GTK thread, leaving the
+    // main loop running for other apps. Safe to call more than once.
+    pub fn shutdown(&self) -> Result<(), Error> {
+        if !self.running.replace(false) {
+            return Ok(());
+        }
+        let app = self.app;
+        let (tx, rx) = channel();
+        glib::MainContext::default().invoke(move || {
+            let stash = GTK_STASH.with(|stash| stash.borrow_mut().remove(&app));
+            drop(stash);
+            tx.send(()).ok();
         });
+        // The app's own loop may be what's calling, and gets to it later.
+        let timeout = crate::api::SHUTDOWN_TIMEOUT;
+        if !self.hosted && rx.recv_timeout(timeout).is_err() {
+            warn!("GTK thread did not take the tray down within {:?}", timeout);
+        }
+        if let Some(old) = self.icon_file.take() {
+            std::fs::remove_file(old).ok();
+        }
         Ok(())
     }
 
-    pub fn set_icon_from_file(&self, file: &str) -> Result<(), Error> {
-        let n = file.to_owned().clone();
-        run_on_gtk_thread(move |stash: &GtkSystrayApp| {
-            stash.set_icon_from_file(&n);
+    pub fn set_tooltip(&self, tooltip: &str) -> Result<(), Error> {
+        let t = tooltip.to_owned();
+        self.call(move |stash: &GtkSystrayApp| {
+            stash.set_tooltip(&t);
+            Ok(())
+        })
+    }
+
+    pub fn system_theme(&self) -> Theme {
+        query_gtk_thread(self.app, |stash: &GtkSystrayApp| stash.theme.get())
+            .unwrap_or(Theme::Light)
+    }
+
+    pub fn tray_icon_rect(&self) -> Result<Rect, Error> {
+        query_gtk_thread(self.app, |stash: &GtkSystrayApp| stash.indicator.geometry())
+            .unwrap_or_else(|| Err(Error::os("The GTK thread is gone")))
+    }
+
+    pub fn set_icon_visible(&self, visible: bool) -> Result<(), Error> {
+        self.call(move |stash: &GtkSystrayApp| {
+            stash.set_icon_visible(visible);
+            Ok(())
+        })
+    }
+
+    pub fn set_menu_on_left_click(&self, enabled: bool) {
+        run_on_gtk_thread(self.app, move |stash: &GtkSystrayApp| {
+            stash.indicator.set_menu_on_left_click(enabled);
         });
-        Ok(())
+    }
+
+    pub fn set_suppress_default_menu(&self, suppress: bool) {
+        run_on_gtk_thread(self.app, move |stash: &GtkSystrayApp| {
+            stash.indicator.set_suppress_default_menu(suppress);
+        });
+    }
+
+    pub fn register_hotkey(
+        &self,
+        id: u32,
+        modifiers: HotkeyModifiers,
+        key: u32,
+    ) -> Result<(), Error> {
+        self.call(move |stash: &GtkSystrayApp| stash.register_hotkey(id, modifiers, key))
+    }
+
+    pub fn unregister_hotkey(&self, id: u32) -> Result<(), Error> {
+        self.call(move |stash: &GtkSystrayApp| {
+            stash.unregister_hotkey(id);
+            Ok(())
+        })
+    }
+
+    pub fn quit(&self) {
+        if let Err(e) = self.shutdown() {
+            warn!("Error shutting down: {}", e);
+        }
+    }
+}
+
+enum Backend {
+    Gtk(GtkWindow),
+    #[cfg(feature = "sni")]
+    Sni(sni::SniWindow),
+}
+
+macro_rules! with_backend {
+    ($self:ident, $w:ident => $body:expr) => {
+        match &$self.backend {
+            Backend::Gtk($w) => $body,
+            #[cfg(feature = "sni")]
+            Backend::Sni($w) => $body,
+        }
+    };
+}
+
+pub struct Window {
+    backend: Backend,
+    #[cfg(feature = "dbus-notifications")]
+    notifier: notify::Notifier,
+}
+
+impl Window {
+    // Prefer a StatusNotifierItem when a watcher is on the session bus, which
+    // is the only kind of tray pure Wayland desktops have.
+    pub fn new(
+        event_tx: EventSender,
+        tooltip: ProviderSlot,
+        options: &InitOptions,
+    ) -> Result<Window, Error> {
+        #[cfg(feature = "sni")]
+        match sni::SniWindow::new(
+            event_tx.clone(),
+            tooltip.clone(),
+            options.tray_id.map(tray_name),
+        ) {
+            Ok(w) => {
+                return Ok(Window {
+                    backend: Backend::Sni(w),
+                    #[cfg(feature = "dbus-notifications")]
+                    notifier: notify::Notifier::new(event_tx),
+                })
+            }
+            Err(e) => debug!("StatusNotifierItem unavailable, falling back to GTK: {}", e),
+        }
+        Ok(Window {
+            #[cfg(feature = "dbus-notifications")]
+            notifier: notify::Notifier::new(event_tx.clone()),
+            backend: Backend::Gtk(GtkWindow::new(event_tx, tooltip, options)?),
+        })
+    }
+
+    pub fn add_menu_entry(
+        &self,
+        item_idx: u32,
+        item_name: &str,
+        parent: Option<u32>,
+    ) -> Result<(), Error> {
+        with_backend!(self, w => w.add_menu_entry(item_idx, item_name, parent))
+    }
+
+    pub fn add_check_menu_entry(
+        &self,
+        item_idx: u32,
+        item_name: &str,
+        checked: bool,
+        parent: Option<u32>,
+    ) -> Result<(), Error> {
+        with_backend!(self, w => w.add_check_menu_entry(item_idx, item_name, checked, parent))
+    }
+
+    pub fn add_submenu(
+        &self,
+        item_idx: u32,
+        item_name: &str,
+        parent: Option<u32>,
+    ) -> Result<(), Error> {
+        with_backend!(self, w => w.add_submenu(item_idx, item_name, parent))
+    }
+
+    pub(crate) fn add_menu_entries(&self, entries: &[BatchEntry]) -> Result<(), Error> {
+        with_backend!(self, w => w.add_menu_entries(entries))
+    }
+
+    pub fn add_menu_separator(&self, item_idx: u32, parent: Option<u32>) -> Result<(), Error> {
+        with_backend!(self, w => w.add_menu_separator(item_idx, parent))
+    }
+
+    pub fn set_menu_item_checked(&self, item_idx: u32, checked: bool) -> Result<(), Error> {
+        with_backend!(self, w => w.set_menu_item_checked(item_idx, checked))
+    }
+
+    pub fn set_menu_item_enabled(&self, item_idx: u32, enabled: bool) -> Result<(), Error> {
+        with_backend!(self, w => w.set_menu_item_enabled(item_idx, enabled))
+    }
+
+    pub fn set_menu_item_icon_rgba(
+        &self,
+        item_idx: u32,
+        rgba: &[u8],
+        width: u32,
+        height: u32,
+    ) -> Result<(), Error> {
+        with_backend!(self, w => w.set_menu_item_icon_rgba(item_idx, rgba, width, height))
+    }
+
+    pub fn set_default_menu_item(&self, item_idx: u32) -> Result<(), Error> {
+        with_backend!(self, w => w.set_default_menu_item(item_idx))
+    }
+
+    pub fn set_menu_item_style(
+        &self,
+        item_idx: u32,
+        label: &str,
+        style: MenuItemStyle,
+    ) -> Result<(), Error> {
+        with_backend!(self, w => w.set_menu_item_style(item_idx, label, style))
+    }
+
+    pub fn set_lazy_submenu(&self, item_idx: u32, builder: LazyBuilder) -> Result<(), Error> {
+        with_backend!(self, w => w.set_lazy_submenu(item_idx, builder))
+    }
+
+    pub fn remove_menu_item(&self, item_idx: u32) -> Result<(), Error> {
+        with_backend!(self, w => w.remove_menu_item(item_idx))
+    }
+
+    pub fn move_menu_item(&self, item_idx: u32, position: u32) -> Result<(), Error> {
+        with_backend!(self, w => w.move_menu_item(item_idx, position))
+    }
+
+    pub fn set_icon_template(&self, template: bool) -> Result<(), Error> {
+        with_backend!(self, w => w.set_icon_template(template))
+    }
+
+    pub fn with_gtk_menu<F>(&self, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&gtk::Menu) + Send + 'static,
+    {
+        match &self.backend {
+            Backend::Gtk(w) => w.with_gtk_menu(f),
+            #[cfg(feature = "sni")]
+            Backend::Sni(_) => Err(Error::NotImplementedError),
+        }
+    }
+
+    pub fn set_icon_from_file(&self, file: &Path) -> Result<(), Error> {
+        with_backend!(self, w => w.set_icon_from_file(file))
+    }
+
+    // Ordinal resources are a Windows thing.
+    pub fn set_icon_from_resource_id(&self, _id: u16) -> Result<(), Error> {
+        Err(Error::NotImplementedError)
+    }
+
+    pub fn set_icon_from_theme(&self, icon_name: &str) -> Result<(), Error> {
+        with_backend!(self, w => w.set_icon_from_theme(icon_name))
+    }
+
+    pub fn clear_icon(&self) -> Result<(), Error> {
+        with_backend!(self, w => w.clear_icon())
+    }
+
+    pub fn set_icon_from_image_buffer(
+        &self,
+        buffer: &[u8],
+        width: u32,
+        height: u32,
+    ) -> Result<(), Error> {
+        with_backend!(self, w => w.set_icon_from_image_buffer(buffer, width, height))
+    }
+
+    pub fn set_icon_from_rgba(&self, rgba: Vec<u8>, width: u32, height: u32) -> Result<(), Error> {
+        with_backend!(self, w => w.set_icon_from_rgba(rgba, width, height))
+    }
+
+    // One source at a time, the way the matching set_icon_from_* does it,
+    // for the Application to show what it has no pixels of.
+    pub fn apply_icon(&self, icon: IconSource<'_>) -> Result<(), Error> {
+        match icon {
+            IconSource::Path(file) => self.set_icon_from_file(file),
+            IconSource::Resource(resource) => self.set_icon_from_resource(resource),
+            IconSource::ResourceId(id) => self.set_icon_from_resource_id(id),
+            IconSource::ThemeName(name) => self.set_icon_from_theme(name),
+            IconSource::Rgba {
+                data,
+                width,
+                height,
+            } => self.set_icon_from_rgba(data.into_owned(), width, height),
+            // The Application decodes these, with the image crate.
+            IconSource::Encoded(_) => Err(Error::NotImplementedError),
+        }
+    }
+
+    pub fn start_icon_animation(
+        &self,
+        frames: &[IconFrame],
+        interval: Duration,
+    ) -> Result<(), Error> {
+        with_backend!(self, w => w.start_icon_animation(frames, interval))
+    }
+
+    pub fn stop_icon_animation(&self) -> Result<(), Error> {
+        with_backend!(self, w => w.stop_icon_animation())
+    }
+
+    pub fn request_attention(&self, icon: Option<&IconFrame>) -> Result<(), Error> {
+        with_backend!(self, w => w.request_attention(icon))
+    }
+
+    pub fn clear_attention(&self) -> Result<(), Error> {
+        with_backend!(self, w => w.clear_attention())
+    }
+
+    pub fn scale_factor(&self) -> f64 {
+        with_backend!(self, w => w.scale_factor())
+    }
+
+    pub fn icon_size(&self) -> u32 {
+        with_backend!(self, w => w.icon_size())
     }
 
     pub fn set_icon_from_resource(&self, resource: &str) -> Result<(), Error> {
-        panic!("Not implemented on this platform!");
+        with_backend!(self, w => w.set_icon_from_resource(resource))
     }
 
     pub fn shutdown(&self) -> Result<(), Error> {
-        Ok(())
+        with_backend!(self, w => w.shutdown())
     }
 
     pub fn set_tooltip(&self, tooltip: &str) -> Result<(), Error> {
-        panic!("Not implemented on this platform!");
+        with_backend!(self, w => w.set_tooltip(tooltip))
+    }
+
+    pub fn system_theme(&self) -> Theme {
+        with_backend!(self, w => w.system_theme())
+    }
+
+    #[cfg(feature = "dbus-notifications")]
+    pub fn show_notification(
+        &self,
+        summary: &str,
+        body: &str,
+        image: Option<&str>,
+        actions: &[(String, String)],
+    ) -> Result<(), Error> {
+        self.notifier.show(summary, body, image, actions)
+    }
+
+    #[cfg(not(feature = "dbus-notifications"))]
+    pub fn show_notification(
+        &self,
+        _summary: &str,
+        _body: &str,
+        _image: Option<&str>,
+        _actions: &[(String, String)],
+    ) -> Result<(), Error> {
+        Err(Error::NotImplementedError)
+    }
+
+    pub fn set_app_id(&self, _app_id: &str) -> Result<(), Error> {
+        Err(Error::NotImplementedError)
+    }
+
+    pub fn set_waiting(&self, waiting: bool) -> bool {
+        with_backend!(self, w => w.set_waiting(waiting))
+    }
+
+    pub fn event_sender(&self) -> Option<EventSender> {
+        with_backend!(self, w => w.event_sender())
+    }
+
+    pub fn is_elevated(&self) -> Result<bool, Error> {
+        Err(Error::NotImplementedError)
+    }
+
+    pub fn request_always_visible(&self) -> Result<(), Error> {
+        Err(Error::NotImplementedError)
+    }
+
+    #[cfg(feature = "clipboard")]
+    pub fn set_clipboard_text(&self, text: &str) -> Result<(), Error> {
+        crate::api::clipboard::set_text(text)
+    }
+
+    #[cfg(not(feature = "clipboard"))]
+    pub fn set_clipboard_text(&self, _text: &str) -> Result<(), Error> {
+        Err(Error::NotImplementedError)
+    }
+
+    // The icon goes along with the process here.
+    pub fn remove_icon_on_panic(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    // The icon is drawn by the panel, in its own process, or embedded into
+    // its window with GtkStatusIcon, so there's no window of the app's own.
+    #[cfg(feature = "rwh")]
+    pub fn raw_window_handle(&self) -> Result<raw_window_handle::RawWindowHandle, Error> {
+        Err(Error::NotImplementedError)
+    }
+
+    pub fn tray_icon_rect(&self) -> Result<Rect, Error> {
+        with_backend!(self, w => w.tray_icon_rect())
+    }
+
+    pub fn set_icon_visible(&self, visible: bool) -> Result<(), Error> {
+        with_backend!(self, w => w.set_icon_visible(visible))
+    }
+
+    pub fn set_menu_on_left_click(&self, enabled: bool) {
+        with_backend!(self, w => w.set_menu_on_left_click(enabled))
+    }
+
+    pub fn set_suppress_default_menu(&self, suppress: bool) {
+        with_backend!(self, w => w.set_suppress_default_menu(suppress))
+    }
+
+    // keybinder runs on the GTK main loop, which the StatusNotifierItem
+    // backend doesn't have.
+    pub fn register_hotkey(
+        &self,
+        id: u32,
+        modifiers: HotkeyModifiers,
+        key: u32,
+    ) -> Result<(), Error> {
+        match &self.backend {
+            Backend::Gtk(w) => w.register_hotkey(id, modifiers, key),
+            #[cfg(feature = "sni")]
+            Backend::Sni(_) => Err(Error::NotImplementedError),
+        }
+    }
+
+    pub fn unregister_hotkey(&self, id: u32) -> Result<(), Error> {
+        match &self.backend {
+            Backend::Gtk(w) => w.unregister_hotkey(id),
+            #[cfg(feature = "sni")]
+            Backend::Sni(_) => Err(Error::NotImplementedError),
+        }
     }
 
     pub fn quit(&self) {
-        glib::idle_add(|| {
-            gtk::main_quit();
-            glib::Continue(false)
+        with_backend!(self, w => w.quit())
+    }
+}
+
+// Those that need a display for GTK are skipped without one.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{event_channel, EventReceiver};
+
+    fn clicks(rx: &EventReceiver) -> Vec<u32> {
+        std::iter::from_fn(|| rx.try_recv().ok())
+            .filter_map(|event| match event {
+                SystrayEvent::MenuItem(MenuItemId(idx)) => Some(idx),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn activate(stash: &GtkSystrayApp, ids: &[u32]) {
+        for idx in ids {
+            stash.menu_items.borrow()[idx].activate();
+        }
+    }
+
+    // Runs `f` on the GTK thread the backend starts, where the GTK objects
+    // of every test have to live, as GTK is only initialized once. None
+    // without a display.
+    fn on_gtk_thread<T, F>(f: F) -> Option<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        start_gtk_thread().ok()?;
+        let (tx, rx) = channel();
+        glib::MainContext::default().invoke(move || {
+            tx.send(f()).ok();
+        });
+        Some(rx.recv().expect("The test failed on the GTK thread"))
+    }
+
+    // A stash as a new Application gets it, with items 0 to `count - 1`.
+    fn stash_with_items(app: usize, tx: EventSender, count: u32) -> GtkSystrayApp {
+        let stash = GtkSystrayApp::new(app, None, tx, ProviderSlot::default());
+        for idx in 0..count {
+            stash.add_menu_entry(idx, &format!("Item {}", idx), None);
+        }
+        stash
+    }
+
+    #[test]
+    fn activated_items_report_the_ids_they_were_added_with() {
+        let (tx, rx) = event_channel(None);
+        let clicked = on_gtk_thread(move || {
+            let stash = stash_with_items(1000, tx, 3);
+            activate(&stash, &[0, 1, 2]);
+            let before = clicks(&rx);
+            // Items after a removed one keep their ids, and new ones don't
+            // take the removed one's.
+            stash.remove_menu_item(1);
+            stash.add_menu_entry(3, "Item 3", None);
+            activate(&stash, &[0, 2, 3]);
+            (before, clicks(&rx))
         });
+        if let Some((before, after)) = clicked {
+            assert_eq!(before, [0, 1, 2]);
+            assert_eq!(after, [0, 2, 3]);
+        }
+    }
+
+    #[test]
+    fn two_apps_get_only_their_own_clicks() {
+        let (tx_a, rx_a) = event_channel(None);
+        let (tx_b, rx_b) = event_channel(None);
+        let clicked = on_gtk_thread(move || {
+            // The same ids in both, as two Applications hand them out.
+            let a = stash_with_items(1001, tx_a, 2);
+            let b = stash_with_items(1002, tx_b, 2);
+            a.set_tooltip("Account A");
+            b.set_tooltip("Account B");
+            a.set_icon(IconHandle::Pixbuf(rgba_pixbuf(vec![255; 16 * 16 * 4], 16, 16)));
+            b.set_icon(IconHandle::Pixbuf(rgba_pixbuf(vec![0; 16 * 16 * 4], 16, 16)));
+            activate(&a, &[1]);
+            activate(&b, &[0, 1]);
+            activate(&a, &[0]);
+            (clicks(&rx_a), clicks(&rx_b))
+        });
+        if let Some((a, b)) = clicked {
+            assert_eq!(a, [1, 0]);
+            assert_eq!(b, [0, 1]);
+        }
+    }
+
+    #[test]
+    fn pixbufs_keep_odd_width_and_non_square_rows_apart() {
+        for (width, height) in [(15, 15), (16, 16), (17, 17), (17, 5), (5, 17), (1, 9)] {
+            let rgba: Vec<u8> = (0..height)
+                .flat_map(|y| (0..width).flat_map(move |x| [x as u8, y as u8, (x ^ y) as u8, 255]))
+                .collect();
+            let pixbuf = rgba_pixbuf(rgba.clone(), width, height);
+            assert_eq!((pixbuf.width(), pixbuf.height()), (width as i32, height as i32));
+            assert_eq!(pixbuf.rowstride(), width as i32 * 4);
+            assert!(*pixbuf.read_pixel_bytes() == rgba[..], "{}x{}", width, height);
+        }
     }
 }
@@ -0,0 +1,108 @@
+// Desktop notifications through org.freedesktop.Notifications, so they show
+// up in the notification center like any other app's, with its theming and
+// history. Clicked actions come back as ActionInvoked signals.
+use crate::api::EventSender;
+use crate::{Error, SystrayEvent};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::{Arc, Mutex},
+    thread,
+};
+use zbus::{
+    blocking::{Connection, Proxy},
+    zvariant::Value,
+};
+
+const NOTIFICATIONS: &str = "org.freedesktop.Notifications";
+const NOTIFICATIONS_PATH: &str = "/org/freedesktop/Notifications";
+
+fn dbus_error<E: fmt::Display>(e: E) -> Error {
+    Error::os(format!("D-Bus error: {}", e))
+}
+
+fn app_name() -> String {
+    std::env::current_exe()
+        .ok()
+        .and_then(|p| p.file_stem().map(|s| s.to_string_lossy().into_owned()))
+        .unwrap_or_default()
+}
+
+pub struct Notifier {
+    event_tx: EventSender,
+    // Opened with the first notification.
+    conn: RefCell<Option<Connection>>,
+    // ActionInvoked goes to every client, so only ids we got back count.
+    ids: Arc<Mutex<HashSet<u32>>>,
+}
+
+impl Notifier {
+    pub fn new(event_tx: EventSender) -> Notifier {
+        Notifier {
+            event_tx,
+            conn: RefCell::new(None),
+            ids: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    // Connects and starts following ActionInvoked on its own thread, for as
+    // long as the connection is up.
+    fn connection(&self) -> Result<Connection, Error> {
+        if let Some(conn) = self.conn.borrow().as_ref() {
+            return Ok(conn.clone());
+        }
+        let conn = Connection::session().map_err(dbus_error)?;
+        let proxy = Proxy::new(&conn, NOTIFICATIONS, NOTIFICATIONS_PATH, NOTIFICATIONS)
+            .map_err(dbus_error)?;
+        let signals = proxy.receive_signal("ActionInvoked").map_err(dbus_error)?;
+        let (tx, ids) = (self.event_tx.clone(), self.ids.clone());
+        thread::spawn(move || {
+            for msg in signals {
+                let (id, action_key): (u32, String) = match msg.body().deserialize() {
+                    Ok(args) => args,
+                    Err(_) => continue,
+                };
+                if ids.lock().unwrap().contains(&id)
+                    && tx.send(SystrayEvent::NotificationAction { action_key }).is_err()
+                {
+                    break;
+                }
+            }
+        });
+        self.conn.replace(Some(conn.clone()));
+        Ok(conn)
+    }
+
+    pub fn show(
+        &self,
+        summary: &str,
+        body: &str,
+        image: Option<&str>,
+        actions: &[(String, String)],
+    ) -> Result<(), Error> {
+        let conn = self.connection()?;
+        // Actions go over the wire as key, label, key, label...
+        let actions: Vec<&str> = actions
+            .iter()
+            .flat_map(|(key, label)| [key.as_str(), label.as_str()])
+            .collect();
+        let mut hints: HashMap<&str, Value> = HashMap::new();
+        if let Some(image) = image {
+            hints.insert("image-path", Value::from(image));
+        }
+        let reply = conn
+            .call_method(
+                Some(NOTIFICATIONS),
+                NOTIFICATIONS_PATH,
+                Some(NOTIFICATIONS),
+                "Notify",
+                // No notification to replace, no icon, server's default timeout.
+                &(app_name(), 0_u32, "", summary, body, actions, hints, -1_i32),
+            )
+            .map_err(dbus_error)?;
+        let id: u32 = reply.body().deserialize().map_err(dbus_error)?;
+        self.ids.lock().unwrap().insert(id);
+        Ok(())
+    }
+}
@@ -0,0 +1,967 @@
+// StatusNotifierItem backend, talking D-Bus directly. The item lives at
+// /StatusNotifierItem and exports its menu through com.canonical.dbusmenu at
+// /MenuBar. zbus answers method calls on its own executor thread, so all the
+// state hosts can ask about is shared behind a mutex.
+use crate::api::{underscore_mnemonic, BatchEntry, EventSender};
+use crate::lazy_menu::LazyBuilder;
+use crate::tooltip::{self, ProviderSlot};
+use crate::{Error, IconFrame, MenuItemId, MenuItemStyle, Rect, SystrayEvent, Theme};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    env, fmt, fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{channel, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+use zbus::{
+    blocking::{fdo::DBusProxy, Connection, Proxy},
+    interface,
+    names::BusName,
+    zvariant::{ObjectPath, OwnedObjectPath, OwnedValue, Structure, Value},
+};
+
+const ITEM_PATH: &str = "/StatusNotifierItem";
+const ITEM_INTERFACE: &str = "org.kde.StatusNotifierItem";
+const MENU_PATH: &str = "/MenuBar";
+const MENU_INTERFACE: &str = "com.canonical.dbusmenu";
+const WATCHER: &str = "org.kde.StatusNotifierWatcher";
+const PORTAL: &str = "org.freedesktop.portal.Desktop";
+const PORTAL_PATH: &str = "/org/freedesktop/portal/desktop";
+const PORTAL_SETTINGS: &str = "org.freedesktop.portal.Settings";
+
+static ITEM_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// Width, height and ARGB32 pixels in network byte order.
+type Pixmap = (i32, i32, Vec<u8>);
+
+// dbusmenu layout node: id, properties, children (each a boxed layout node).
+type Layout = (i32, HashMap<String, OwnedValue>, Vec<OwnedValue>);
+
+fn dbus_error<E: fmt::Display>(e: E) -> Error {
+    Error::os(format!("D-Bus error: {}", e))
+}
+
+fn owned<'a, V: Into<Value<'a>>>(v: V) -> OwnedValue {
+    v.into()
+        .try_to_owned()
+        .expect("plain values always convert")
+}
+
+#[derive(Default)]
+struct MenuEntry {
+    idx: u32,
+    parent: Option<u32>,
+    label: String,
+    separator: bool,
+    submenu: bool,
+    disabled: bool,
+    // PNG data.
+    icon: Option<Vec<u8>>,
+    // Some(checked) for check items.
+    checked: Option<bool>,
+}
+
+impl MenuEntry {
+    // dbusmenu reserves id 0 for the root, so items are shifted by one.
+    fn dbus_id(&self) -> i32 {
+        self.idx as i32 + 1
+    }
+
+    fn properties(&self) -> HashMap<String, OwnedValue> {
+        let mut props = HashMap::new();
+        if self.separator {
+            props.insert("type".to_string(), owned("separator"));
+        } else {
+            props.insert("label".to_string(), owned(self.label.as_str()));
+        }
+        if let Some(icon) = &self.icon {
+            props.insert("icon-data".to_string(), owned(icon.clone()));
+        }
+        if self.disabled {
+            props.insert("enabled".to_string(), owned(false));
+        }
+        if self.submenu {
+            props.insert("children-display".to_string(), owned("submenu"));
+        }
+        if let Some(checked) = self.checked {
+            props.insert("toggle-type".to_string(), owned("checkmark"));
+            props.insert("toggle-state".to_string(), owned(checked as i32));
+        }
+        props
+    }
+
+    fn layout(&self, menu: &[MenuEntry]) -> Layout {
+        (
+            self.dbus_id(),
+            self.properties(),
+            children(menu, Some(self.idx)),
+        )
+    }
+}
+
+fn children(menu: &[MenuEntry], parent: Option<u32>) -> Vec<OwnedValue> {
+    menu.iter()
+        .filter(|e| e.parent == parent)
+        .map(|e| owned(Structure::from(e.layout(menu))))
+        .collect()
+}
+
+#[derive(Default)]
+struct State {
+    tooltip: String,
+    hidden: bool,
+    menu_on_left_click: bool,
+    // Right clicks come to ContextMenu instead of showing our menu.
+    suppress_menu: bool,
+    theme: Option<Theme>,
+    // Hosts go by the name over the pixels, so it's empty unless the icon is
+    // from the icon theme.
+    icon_name: String,
+    icon: Vec<Pixmap>,
+    // Animation frames, and the one showing instead of `icon`.
+    frames: Vec<Pixmap>,
+    frame: Option<usize>,
+    // Set by request_attention, empty for hosts to mark the icon their way.
+    attention: Option<Vec<Pixmap>>,
+    menu: Vec<MenuEntry>,
+    revision: u32,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    tx: Mutex<Option<EventSender>>,
+    tooltip: ProviderSlot,
+}
+
+impl Shared {
+    fn send(&self, event: SystrayEvent) {
+        if let Some(tx) = self.tx.lock().unwrap().as_ref() {
+            tx.send(event).ok();
+        }
+    }
+}
+
+struct StatusNotifierItem {
+    id: String,
+    shared: Arc<Shared>,
+}
+
+#[interface(name = "org.kde.StatusNotifierItem")]
+impl StatusNotifierItem {
+    fn activate(&self, x: i32, y: i32) {
+        self.shared.send(SystrayEvent::LeftClick { x, y });
+    }
+
+    fn secondary_activate(&self, _x: i32, _y: i32) {}
+
+    // Only called on hosts that don't show the menu themselves.
+    fn context_menu(&self, x: i32, y: i32) {
+        self.shared.send(SystrayEvent::RightClick { x, y });
+    }
+
+    fn scroll(&self, _delta: i32, _orientation: String) {}
+
+    #[zbus(property)]
+    fn category(&self) -> String {
+        "ApplicationStatus".to_string()
+    }
+
+    #[zbus(property)]
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    #[zbus(property)]
+    fn title(&self) -> String {
+        self.id.clone()
+    }
+
+    #[zbus(property)]
+    fn status(&self) -> String {
+        status(&self.shared.state.lock().unwrap()).to_string()
+    }
+
+    #[zbus(property)]
+    fn icon_name(&self) -> String {
+        let state = self.shared.state.lock().unwrap();
+        match state.frame {
+            Some(_) => String::new(),
+            None => state.icon_name.clone(),
+        }
+    }
+
+    #[zbus(property)]
+    fn icon_pixmap(&self) -> Vec<Pixmap> {
+        let state = self.shared.state.lock().unwrap();
+        match state.frame {
+            Some(i) => vec![state.frames[i].clone()],
+            None => state.icon.clone(),
+        }
+    }
+
+    #[zbus(property)]
+    fn attention_icon_pixmap(&self) -> Vec<Pixmap> {
+        let state = self.shared.state.lock().unwrap();
+        state.attention.clone().unwrap_or_default()
+    }
+
+    #[zbus(property)]
+    fn tool_tip(&self) -> (String, Vec<Pixmap>, String, String) {
+        // Hosts read this when they're about to show it, for most on hover.
+        let text = tooltip::provide(&self.shared.tooltip)
+            .unwrap_or_else(|| self.shared.state.lock().unwrap().tooltip.clone());
+        (String::new(), Vec::new(), text, String::new())
+    }
+
+    // Hosts open the menu on any click for menu-only items, and otherwise
+    // call Activate on left clicks and show the menu on right clicks.
+    #[zbus(property)]
+    fn item_is_menu(&self) -> bool {
+        self.shared.state.lock().unwrap().menu_on_left_click
+    }
+
+    // Without a menu, hosts call ContextMenu on right clicks.
+    #[zbus(property)]
+    fn menu(&self) -> OwnedObjectPath {
+        if self.shared.state.lock().unwrap().suppress_menu {
+            OwnedObjectPath::try_from("/").unwrap()
+        } else {
+            OwnedObjectPath::try_from(MENU_PATH).unwrap()
+        }
+    }
+}
+
+struct DbusMenu {
+    shared: Arc<Shared>,
+}
+
+#[interface(name = "com.canonical.dbusmenu")]
+impl DbusMenu {
+    fn get_layout(
+        &self,
+        parent_id: i32,
+        _recursion_depth: i32,
+        _property_names: Vec<String>,
+    ) -> zbus::fdo::Result<(u32, Layout)> {
+        let state = self.shared.state.lock().unwrap();
+        if parent_id != 0 {
+            return state
+                .menu
+                .iter()
+                .find(|e| e.dbus_id() == parent_id)
+                .map(|e| (state.revision, e.layout(&state.menu)))
+                .ok_or_else(|| zbus::fdo::Error::InvalidArgs(format!("No item {}", parent_id)));
+        }
+        let mut props = HashMap::new();
+        props.insert("children-display".to_string(), owned("submenu"));
+        Ok((state.revision, (0, props, children(&state.menu, None))))
+    }
+
+    fn get_group_properties(
+        &self,
+        ids: Vec<i32>,
+        _property_names: Vec<String>,
+    ) -> Vec<(i32, HashMap<String, OwnedValue>)> {
+        let state = self.shared.state.lock().unwrap();
+        state
+            .menu
+            .iter()
+            .filter(|e| ids.is_empty() || ids.contains(&e.dbus_id()))
+            .map(|e| (e.dbus_id(), e.properties()))
+            .collect()
+    }
+
+    fn get_property(&self, id: i32, name: String) -> zbus::fdo::Result<OwnedValue> {
+        let state = self.shared.state.lock().unwrap();
+        state
+            .menu
+            .iter()
+            .find(|e| e.dbus_id() == id)
+            .and_then(|e| e.properties().remove(&name))
+            .ok_or_else(|| zbus::fdo::Error::InvalidArgs(format!("No property {} on {}", name, id)))
+    }
+
+    fn event(&self, id: i32, event_id: String, _data: OwnedValue, _timestamp: u32) {
+        match (id, event_id.as_str()) {
+            (0, "opened") => self.shared.send(SystrayEvent::MenuOpened),
+            (0, "closed") => self.shared.send(SystrayEvent::MenuClosed),
+            (id, "clicked") if id > 0 => {
+                self.shared
+                    .send(SystrayEvent::MenuItem(MenuItemId(id as u32 - 1)))
+            }
+            _ => {}
+        }
+    }
+
+    fn event_group(&self, events: Vec<(i32, String, OwnedValue, u32)>) -> Vec<i32> {
+        for (id, event_id, data, timestamp) in events {
+            self.event(id, event_id, data, timestamp);
+        }
+        Vec::new()
+    }
+
+    fn about_to_show(&self, _id: i32) -> bool {
+        false
+    }
+
+    fn about_to_show_group(&self, _ids: Vec<i32>) -> (Vec<i32>, Vec<i32>) {
+        (Vec::new(), Vec::new())
+    }
+
+    #[zbus(property)]
+    fn version(&self) -> u32 {
+        3
+    }
+
+    #[zbus(property)]
+    fn text_direction(&self) -> String {
+        "ltr".to_string()
+    }
+
+    #[zbus(property)]
+    fn status(&self) -> String {
+        "normal".to_string()
+    }
+
+    #[zbus(property)]
+    fn icon_theme_path(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+// The desktop portal's color-scheme: 1 is dark, 2 light and 0 no preference.
+// Read wraps the value in one variant more than SettingChanged does.
+fn portal_theme(value: &Value) -> Option<Theme> {
+    match value {
+        Value::Value(inner) => portal_theme(inner),
+        Value::U32(1) => Some(Theme::Dark),
+        Value::U32(_) => Some(Theme::Light),
+        _ => None,
+    }
+}
+
+fn read_portal_theme(conn: &Connection) -> Option<Theme> {
+    let reply = conn
+        .call_method(
+            Some(PORTAL),
+            PORTAL_PATH,
+            Some(PORTAL_SETTINGS),
+            "Read",
+            &("org.freedesktop.appearance", "color-scheme"),
+        )
+        .ok()?;
+    let value: OwnedValue = reply.body().deserialize().ok()?;
+    portal_theme(&value)
+}
+
+// Follows SettingChanged from the portal on its own thread, for as long as
+// the connection is up.
+fn watch_portal_theme(
+    conn: &Connection,
+    shared: Arc<Shared>,
+) -> zbus::Result<thread::JoinHandle<()>> {
+    let proxy = Proxy::new(conn, PORTAL, PORTAL_PATH, PORTAL_SETTINGS)?;
+    let signals = proxy.receive_signal("SettingChanged")?;
+    Ok(thread::spawn(move || {
+        for msg in signals {
+            let (namespace, key, value): (String, String, OwnedValue) =
+                match msg.body().deserialize() {
+                    Ok(args) => args,
+                    Err(_) => continue,
+                };
+            if namespace != "org.freedesktop.appearance" || key != "color-scheme" {
+                continue;
+            }
+            if let Some(theme) = portal_theme(&value) {
+                let old = shared.state.lock().unwrap().theme.replace(theme);
+                if old != Some(theme) {
+                    shared.send(SystrayEvent::ThemeChanged(theme));
+                }
+            }
+        }
+    }))
+}
+
+// Hosts hide Passive items.
+fn status(state: &State) -> &'static str {
+    if state.hidden {
+        "Passive"
+    } else if state.attention.is_some() {
+        "NeedsAttention"
+    } else {
+        "Active"
+    }
+}
+
+fn to_pixmap(buffer: &[u8], width: u32, height: u32) -> Pixmap {
+    let argb = buffer
+        .chunks_exact(4)
+        .flat_map(|p| [p[3], p[0], p[1], p[2]])
+        .collect();
+    (width as i32, height as i32, argb)
+}
+
+// Icon themes keep <theme>/<size>/<context>/<name>.<ext>, or size and context
+// the other way around, under each data dir's icons. Unthemed icons are in
+// pixmaps.
+fn theme_has_icon(name: &str) -> bool {
+    if name.is_empty() || name.contains('/') {
+        return false;
+    }
+    let home = env::var_os("HOME").map(PathBuf::from);
+    let data_home = env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| home.as_ref().map(|h| h.join(".local/share")));
+    let data_dirs = env::var("XDG_DATA_DIRS")
+        .ok()
+        .filter(|d| !d.is_empty())
+        .unwrap_or_else(|| "/usr/local/share:/usr/share".to_string());
+    let mut bases: Vec<PathBuf> = home.map(|h| h.join(".icons")).into_iter().collect();
+    bases.extend(
+        data_home
+            .into_iter()
+            .chain(data_dirs.split(':').map(PathBuf::from))
+            .map(|d| d.join("icons")),
+    );
+    let files = ["png", "svg", "xpm"].map(|ext| format!("{}.{}", name, ext));
+    let has_file = |dir: &Path| files.iter().any(|f| dir.join(f).is_file());
+    has_file(Path::new("/usr/share/pixmaps"))
+        || bases
+            .iter()
+            .flat_map(|base| subdirs(base))
+            .flat_map(|theme| subdirs(&theme))
+            .flat_map(|dir| subdirs(&dir))
+            .any(|dir| has_file(&dir))
+}
+
+fn subdirs(dir: &Path) -> impl Iterator<Item = PathBuf> {
+    fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|e| e.path())
+        .filter(|p| p.is_dir())
+}
+
+fn emit_new_icon(conn: &Connection) -> Result<(), Error> {
+    conn.emit_signal(None::<BusName>, ITEM_PATH, ITEM_INTERFACE, "NewIcon", &())
+        .map_err(dbus_error)
+}
+
+pub struct SniWindow {
+    conn: Connection,
+    name: String,
+    shared: Arc<Shared>,
+    theme_watch: RefCell<Option<thread::JoinHandle<()>>>,
+    // Dropping the sender stops the thread that steps through the frames.
+    animation: RefCell<Option<(Sender<()>, thread::JoinHandle<()>)>>,
+}
+
+impl SniWindow {
+    // `id` names the item for hosts that remember icons, a unique one is
+    // made up without it.
+    pub fn new(
+        event_tx: EventSender,
+        tooltip: ProviderSlot,
+        id: Option<String>,
+    ) -> Result<SniWindow, Error> {
+        let conn = Connection::session().map_err(dbus_error)?;
+        let watcher = BusName::try_from(WATCHER).map_err(dbus_error)?;
+        let dbus = DBusProxy::new(&conn).map_err(dbus_error)?;
+        if !dbus.name_has_owner(watcher).map_err(dbus_error)? {
+            return Err(Error::os("No StatusNotifierWatcher on the session bus"));
+        }
+
+        let n = ITEM_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let name = format!("org.kde.StatusNotifierItem-{}-{}", std::process::id(), n);
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::default()),
+            tx: Mutex::new(Some(event_tx)),
+            tooltip,
+        });
+        let item = StatusNotifierItem {
+            id: id.unwrap_or_else(|| format!("systray2-{}-{}", std::process::id(), n)),
+            shared: shared.clone(),
+        };
+        let menu = DbusMenu {
+            shared: shared.clone(),
+        };
+        conn.object_server()
+            .at(ITEM_PATH, item)
+            .map_err(dbus_error)?;
+        conn.object_server()
+            .at(MENU_PATH, menu)
+            .map_err(dbus_error)?;
+        conn.request_name(name.as_str()).map_err(dbus_error)?;
+        conn.call_method(
+            Some(WATCHER),
+            "/StatusNotifierWatcher",
+            Some(WATCHER),
+            "RegisterStatusNotifierItem",
+            &(name.as_str(),),
+        )
+        .map_err(dbus_error)?;
+
+        shared.state.lock().unwrap().theme = read_portal_theme(&conn);
+        let theme_watch = watch_portal_theme(&conn, shared.clone())
+            .map_err(|e| debug!("Not following theme changes: {}", e))
+            .ok();
+
+        Ok(SniWindow {
+            conn,
+            name,
+            shared,
+            theme_watch: RefCell::new(theme_watch),
+            animation: RefCell::new(None),
+        })
+    }
+
+    fn emit_item_signal(&self, signal: &str) -> Result<(), Error> {
+        self.conn
+            .emit_signal(None::<BusName>, ITEM_PATH, ITEM_INTERFACE, signal, &())
+            .map_err(dbus_error)
+    }
+
+    fn update_menu<F: FnOnce(&mut Vec<MenuEntry>)>(&self, f: F) -> Result<(), Error> {
+        let revision = {
+            let mut state = self.shared.state.lock().unwrap();
+            f(&mut state.menu);
+            state.revision += 1;
+            state.revision
+        };
+        self.conn
+            .emit_signal(
+                None::<BusName>,
+                MENU_PATH,
+                MENU_INTERFACE,
+                "LayoutUpdated",
+                &(revision, 0_i32),
+            )
+            .map_err(dbus_error)
+    }
+
+    fn push_entry(&self, entry: MenuEntry) -> Result<(), Error> {
+        self.update_menu(|menu| menu.push(entry))
+    }
+
+    pub fn add_menu_entry(
+        &self,
+        item_idx: u32,
+        item_name: &str,
+        parent: Option<u32>,
+    ) -> Result<(), Error> {
+        self.update_menu(|menu| match menu.iter_mut().find(|e| e.idx == item_idx) {
+            Some(e) => e.label = underscore_mnemonic(item_name),
+            None => menu.push(MenuEntry {
+                idx: item_idx,
+                parent,
+                label: underscore_mnemonic(item_name),
+                ..Default::default()
+            }),
+        })
+    }
+
+    pub fn add_check_menu_entry(
+        &self,
+        item_idx: u32,
+        item_name: &str,
+        checked: bool,
+        parent: Option<u32>,
+    ) -> Result<(), Error> {
+        self.push_entry(MenuEntry {
+            idx: item_idx,
+            parent,
+            label: underscore_mnemonic(item_name),
+            checked: Some(checked),
+            ..Default::default()
+        })
+    }
+
+    pub fn add_submenu(
+        &self,
+        item_idx: u32,
+        item_name: &str,
+        parent: Option<u32>,
+    ) -> Result<(), Error> {
+        self.push_entry(MenuEntry {
+            idx: item_idx,
+            parent,
+            label: underscore_mnemonic(item_name),
+            submenu: true,
+            ..Default::default()
+        })
+    }
+
+    // One LayoutUpdated for all of them.
+    pub(crate) fn add_menu_entries(&self, entries: &[BatchEntry]) -> Result<(), Error> {
+        self.update_menu(|menu| {
+            for entry in entries {
+                menu.push(match entry {
+                    BatchEntry::Item {
+                        idx,
+                        label,
+                        checked,
+                        parent,
+                    } => MenuEntry {
+                        idx: *idx,
+                        parent: *parent,
+                        label: underscore_mnemonic(label),
+                        checked: *checked,
+                        ..Default::default()
+                    },
+                    BatchEntry::Separator { idx, parent } => MenuEntry {
+                        idx: *idx,
+                        parent: *parent,
+                        separator: true,
+                        ..Default::default()
+                    },
+                    BatchEntry::Submenu { idx, label, parent } => MenuEntry {
+                        idx: *idx,
+                        parent: *parent,
+                        label: underscore_mnemonic(label),
+                        submenu: true,
+                        ..Default::default()
+                    },
+                });
+            }
+        })
+    }
+
+    pub fn add_menu_separator(&self, item_idx: u32, parent: Option<u32>) -> Result<(), Error> {
+        self.push_entry(MenuEntry {
+            idx: item_idx,
+            parent,
+            separator: true,
+            ..Default::default()
+        })
+    }
+
+    pub fn set_menu_item_checked(&self, item_idx: u32, checked: bool) -> Result<(), Error> {
+        self.update_menu(|menu| {
+            if let Some(e) = menu.iter_mut().find(|e| e.idx == item_idx) {
+                e.checked = e.checked.map(|_| checked);
+            }
+        })
+    }
+
+    pub fn set_menu_item_enabled(&self, item_idx: u32, enabled: bool) -> Result<(), Error> {
+        self.update_menu(|menu| {
+            if let Some(e) = menu.iter_mut().find(|e| e.idx == item_idx) {
+                e.disabled = !enabled;
+            }
+        })
+    }
+
+    pub fn set_menu_item_icon_rgba(
+        &self,
+        item_idx: u32,
+        rgba: &[u8],
+        width: u32,
+        height: u32,
+    ) -> Result<(), Error> {
+        use image::ImageEncoder;
+
+        let mut png = Vec::new();
+        image::codecs::png::PngEncoder::new(&mut png)
+            .write_image(rgba, width, height, image::ColorType::Rgba8)
+            .map_err(|e| Error::from_image("Failed to encode menu icon", &e))?;
+        self.update_menu(|menu| {
+            if let Some(e) = menu.iter_mut().find(|e| e.idx == item_idx) {
+                e.icon = Some(png);
+            }
+        })
+    }
+
+    // dbusmenu has no way to single out an item.
+    pub fn set_default_menu_item(&self, _item_idx: u32) -> Result<(), Error> {
+        Ok(())
+    }
+
+    // dbusmenu has no text styles.
+    pub fn set_menu_item_style(
+        &self,
+        _item_idx: u32,
+        _label: &str,
+        _style: MenuItemStyle,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+
+    // dbusmenu calls come in on zbus' threads, which can't keep the items'
+    // closures.
+    pub fn set_lazy_submenu(&self, _item_idx: u32, _builder: LazyBuilder) -> Result<(), Error> {
+        Err(Error::NotImplementedError)
+    }
+
+    pub fn remove_menu_item(&self, item_idx: u32) -> Result<(), Error> {
+        self.update_menu(|menu| {
+            let mut removed = vec![item_idx];
+            while let Some(idx) = removed.pop() {
+                menu.retain(|e| {
+                    if e.parent == Some(idx) {
+                        removed.push(e.idx);
+                    }
+                    e.idx != idx
+                });
+            }
+        })
+    }
+
+    // Hosts draw the icon their own way, and there's no property to ask for
+    // a recolored one.
+    pub fn set_icon_template(&self, _template: bool) -> Result<(), Error> {
+        Ok(())
+    }
+
+    // Hosts lay out the children of an item in the order they're sent, which
+    // is their order here.
+    pub fn move_menu_item(&self, item_idx: u32, position: u32) -> Result<(), Error> {
+        self.update_menu(|menu| {
+            let entry = match menu.iter().position(|e| e.idx == item_idx) {
+                Some(from) => menu.remove(from),
+                None => return,
+            };
+            let to = menu
+                .iter()
+                .enumerate()
+                .filter(|(_, e)| e.parent == entry.parent)
+                .nth(position as usize)
+                .map_or(menu.len(), |(i, _)| i);
+            menu.insert(to, entry);
+        })
+    }
+
+    pub fn set_icon_from_file(&self, file: &Path) -> Result<(), Error> {
+        let img = image::open(file)
+            .map_err(|e| Error::from_image(format!("Failed to load icon {}", file.display()), &e))?
+            .to_rgba8();
+        let (width, height) = img.dimensions();
+        self.set_icon_from_image_buffer(img.as_raw(), width, height)
+    }
+
+    pub fn set_icon_from_image_buffer(
+        &self,
+        buffer: &[u8],
+        width: u32,
+        height: u32,
+    ) -> Result<(), Error> {
+        if buffer.len() != (width as usize) * (height as usize) * 4 {
+            return Err(Error::os(format!(
+                "Icon buffer is {} bytes, expected {} for a {}x{} RGBA image",
+                buffer.len(),
+                (width as usize) * (height as usize) * 4,
+                width,
+                height
+            )));
+        }
+        self.end_animation();
+        let mut state = self.shared.state.lock().unwrap();
+        state.icon_name.clear();
+        state.icon = vec![to_pixmap(buffer, width, height)];
+        drop(state);
+        emit_new_icon(&self.conn)
+    }
+
+    // Pixmaps are ARGB, so the pixels are converted either way.
+    pub fn set_icon_from_rgba(&self, rgba: Vec<u8>, width: u32, height: u32) -> Result<(), Error> {
+        self.set_icon_from_image_buffer(&rgba, width, height)
+    }
+
+    // The host looks the name up itself, so only a name no installed theme
+    // has is turned down here.
+    pub fn set_icon_from_theme(&self, icon_name: &str) -> Result<(), Error> {
+        if !theme_has_icon(icon_name) {
+            return Err(Error::os(format!("No icon named {} in the icon theme", icon_name)));
+        }
+        self.end_animation();
+        let mut state = self.shared.state.lock().unwrap();
+        state.icon_name = icon_name.to_string();
+        state.icon.clear();
+        drop(state);
+        emit_new_icon(&self.conn)
+    }
+
+    pub fn clear_icon(&self) -> Result<(), Error> {
+        self.end_animation();
+        let mut state = self.shared.state.lock().unwrap();
+        state.icon_name = super::DEFAULT_ICON.to_string();
+        state.icon.clear();
+        drop(state);
+        emit_new_icon(&self.conn)
+    }
+
+    fn end_animation(&self) -> bool {
+        let (stop, ticker) = match self.animation.borrow_mut().take() {
+            Some(animation) => animation,
+            None => return false,
+        };
+        drop(stop);
+        crate::api::join_with_timeout(ticker, crate::api::SHUTDOWN_TIMEOUT);
+        let mut state = self.shared.state.lock().unwrap();
+        state.frame = None;
+        state.frames.clear();
+        true
+    }
+
+    pub fn start_icon_animation(
+        &self,
+        frames: &[IconFrame],
+        interval: Duration,
+    ) -> Result<(), Error> {
+        self.end_animation();
+        let count = frames.len();
+        self.shared.state.lock().unwrap().frames = frames
+            .iter()
+            .map(|f| to_pixmap(&f.rgba, f.width, f.height))
+            .collect();
+        let (stop_tx, stop_rx) = channel::<()>();
+        let shared = self.shared.clone();
+        let conn = self.conn.clone();
+        let ticker = thread::spawn(move || {
+            for i in (0..count).cycle() {
+                shared.state.lock().unwrap().frame = Some(i);
+                emit_new_icon(&conn).ok();
+                if stop_rx.recv_timeout(interval) != Err(RecvTimeoutError::Timeout) {
+                    break;
+                }
+            }
+        });
+        *self.animation.borrow_mut() = Some((stop_tx, ticker));
+        Ok(())
+    }
+
+    pub fn stop_icon_animation(&self) -> Result<(), Error> {
+        if self.end_animation() {
+            emit_new_icon(&self.conn)?;
+        }
+        Ok(())
+    }
+
+    pub fn scale_factor(&self) -> f64 {
+        1.0
+    }
+
+    pub fn icon_size(&self) -> u32 {
+        22
+    }
+
+    pub fn set_icon_from_resource(&self, resource: &str) -> Result<(), Error> {
+        let (rgba, width, height) = super::resource_rgba(resource)?;
+        self.set_icon_from_image_buffer(&rgba, width, height)
+    }
+
+    // Closing the connection takes the item off the bus and ends the theme
+    // watcher. Safe to call more than once.
+    pub fn shutdown(&self) -> Result<(), Error> {
+        if self.shared.tx.lock().unwrap().take().is_none() {
+            return Ok(());
+        }
+        self.end_animation();
+        self.conn.release_name(self.name.as_str()).ok();
+        self.conn.clone().close().ok();
+        if let Some(t) = self.theme_watch.borrow_mut().take() {
+            crate::api::join_with_timeout(t, crate::api::SHUTDOWN_TIMEOUT);
+        }
+        Ok(())
+    }
+
+    // The item's state is changed right here, and its signals sent before
+    // the calls return.
+    pub fn set_waiting(&self, _waiting: bool) -> bool {
+        true
+    }
+
+    pub fn event_sender(&self) -> Option<EventSender> {
+        self.shared.tx.lock().unwrap().clone()
+    }
+
+    pub fn set_tooltip(&self, tooltip: &str) -> Result<(), Error> {
+        self.shared.state.lock().unwrap().tooltip = tooltip.to_string();
+        self.emit_item_signal("NewToolTip")
+    }
+
+    pub fn system_theme(&self) -> Theme {
+        self.shared
+            .state
+            .lock()
+            .unwrap()
+            .theme
+            .unwrap_or(Theme::Light)
+    }
+
+    // Hosts never say where they put the item.
+    pub fn tray_icon_rect(&self) -> Result<Rect, Error> {
+        Err(Error::NotImplementedError)
+    }
+
+    pub fn set_icon_visible(&self, visible: bool) -> Result<(), Error> {
+        self.shared.state.lock().unwrap().hidden = !visible;
+        self.emit_new_status()
+    }
+
+    fn emit_new_status(&self) -> Result<(), Error> {
+        let status = status(&self.shared.state.lock().unwrap());
+        self.conn
+            .emit_signal(None::<BusName>, ITEM_PATH, ITEM_INTERFACE, "NewStatus", &(status,))
+            .map_err(dbus_error)
+    }
+
+    pub fn request_attention(&self, icon: Option<&IconFrame>) -> Result<(), Error> {
+        let pixmaps = icon
+            .map(|f| vec![to_pixmap(&f.rgba, f.width, f.height)])
+            .unwrap_or_default();
+        self.shared.state.lock().unwrap().attention = Some(pixmaps);
+        self.emit_item_signal("NewAttentionIcon")?;
+        self.emit_new_status()
+    }
+
+    pub fn clear_attention(&self) -> Result<(), Error> {
+        if self.shared.state.lock().unwrap().attention.take().is_none() {
+            return Ok(());
+        }
+        self.emit_new_status()
+    }
+
+    pub fn set_menu_on_left_click(&self, enabled: bool) {
+        self.shared.state.lock().unwrap().menu_on_left_click = enabled;
+        let changed = HashMap::from([("ItemIsMenu", Value::from(enabled))]);
+        self.conn
+            .emit_signal(
+                None::<BusName>,
+                ITEM_PATH,
+                "org.freedesktop.DBus.Properties",
+                "PropertiesChanged",
+                &(ITEM_INTERFACE, changed, Vec::<&str>::new()),
+            )
+            .map_err(|e| debug!("Failed to announce ItemIsMenu: {}", e))
+            .ok();
+    }
+
+    pub fn set_suppress_default_menu(&self, suppress: bool) {
+        self.shared.state.lock().unwrap().suppress_menu = suppress;
+        let path = ObjectPath::from_static_str_unchecked(if suppress { "/" } else { MENU_PATH });
+        let changed = HashMap::from([("Menu", Value::from(path))]);
+        self.conn
+            .emit_signal(
+                None::<BusName>,
+                ITEM_PATH,
+                "org.freedesktop.DBus.Properties",
+                "PropertiesChanged",
+                &(ITEM_INTERFACE, changed, Vec::<&str>::new()),
+            )
+            .map_err(|e| debug!("Failed to announce Menu: {}", e))
+            .ok();
+    }
+
+    // Dropping the sender in shutdown disconnects the event channel, which
+    // ends wait_for_message.
+    pub fn quit(&self) {
+        self.shutdown().ok();
+    }
+}
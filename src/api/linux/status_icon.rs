@@ -0,0 +1,158 @@
+// GtkStatusIcon, the XEmbed tray icon, for desktops without an AppIndicator
+// host. gtk-rs dropped its bindings because GTK deprecated it, so this talks
+// to gtk-sys directly.
+use crate::{Rect, SystrayEvent};
+use gtk::gdk::{Event, EventButton, EventType};
+use gtk::glib::{self, object::ObjectExt, translate::*};
+use gtk::prelude::*;
+use std::{cell::Cell, ffi::CString, rc::Rc};
+
+pub struct StatusIcon {
+    obj: glib::Object,
+    menu_on_left_click: Rc<Cell<bool>>,
+    suppress_menu: Rc<Cell<bool>>,
+}
+
+impl StatusIcon {
+    // "popup-menu" is the right click, "activate" the left one.
+    pub fn new(menu: &gtk::Menu) -> StatusIcon {
+        let obj: glib::Object = unsafe {
+            from_glib_full(gtk::ffi::gtk_status_icon_new() as *mut glib::gobject_ffi::GObject)
+        };
+        let menu_on_left_click = Rc::new(Cell::new(false));
+        let suppress_menu = Rc::new(Cell::new(false));
+        for signal in ["activate", "popup-menu"] {
+            let menu = menu.clone();
+            let left_click = menu_on_left_click.clone();
+            let suppress = suppress_menu.clone();
+            obj.connect_local(signal, false, move |_| {
+                let open = match signal {
+                    "popup-menu" => !suppress.get(),
+                    _ => left_click.get(),
+                };
+                if open {
+                    menu.popup_at_pointer(None);
+                }
+                None
+            });
+        }
+        StatusIcon {
+            obj,
+            menu_on_left_click,
+            suppress_menu,
+        }
+    }
+
+    pub fn set_menu_on_left_click(&self, enabled: bool) {
+        self.menu_on_left_click.set(enabled);
+    }
+
+    pub fn set_suppress_default_menu(&self, suppress: bool) {
+        self.suppress_menu.set(suppress);
+    }
+
+    // `f` gets a click event for every button press on the icon, at its
+    // screen position. The press still goes on to "activate" or "popup-menu".
+    pub fn connect_click<F: Fn(SystrayEvent) + 'static>(&self, f: F) {
+        self.obj.connect_local("button-press-event", false, move |values| {
+            let event = values[1].get::<Event>().ok();
+            if let Some(button) = event.as_ref().and_then(|e| e.downcast_ref::<EventButton>()) {
+                let (x, y) = button.root();
+                let (x, y) = (x.round() as i32, y.round() as i32);
+                let click = match (button.event_type(), button.button()) {
+                    (EventType::DoubleButtonPress, 1) => Some(SystrayEvent::DoubleClick { x, y }),
+                    (EventType::ButtonPress, 1) => Some(SystrayEvent::LeftClick { x, y }),
+                    (EventType::ButtonPress, 3) => Some(SystrayEvent::RightClick { x, y }),
+                    _ => None,
+                };
+                if let Some(click) = click {
+                    f(click);
+                }
+            }
+            Some(false.to_value())
+        });
+    }
+
+    // `f` returning None leaves the tooltip text set last in place.
+    pub fn connect_query_tooltip<F: Fn() -> Option<String> + 'static>(&self, f: F) {
+        self.obj.connect_local("query-tooltip", false, move |values| {
+            let tooltip = values[4].get::<gtk::Tooltip>().ok();
+            let shown = match (f(), tooltip) {
+                (Some(text), Some(tooltip)) => {
+                    tooltip.set_text(Some(&text));
+                    true
+                }
+                _ => false,
+            };
+            Some(shown.to_value())
+        });
+    }
+
+    fn as_ptr(&self) -> *mut gtk::ffi::GtkStatusIcon {
+        self.obj.as_ptr() as *mut gtk::ffi::GtkStatusIcon
+    }
+
+    // Lets the tray tell this icon from the app's others across runs.
+    pub fn set_name(&self, name: &str) {
+        if let Ok(name) = CString::new(name) {
+            unsafe { gtk::ffi::gtk_status_icon_set_name(self.as_ptr(), name.as_ptr()) }
+        }
+    }
+
+    pub fn set_from_file(&self, file: &str) {
+        // Paths can't hold interior NULs, so there's nothing to load then.
+        if let Ok(file) = CString::new(file) {
+            unsafe { gtk::ffi::gtk_status_icon_set_from_file(self.as_ptr(), file.as_ptr()) }
+        }
+    }
+
+    pub fn set_from_icon_name(&self, name: &str) {
+        if let Ok(name) = CString::new(name) {
+            unsafe { gtk::ffi::gtk_status_icon_set_from_icon_name(self.as_ptr(), name.as_ptr()) }
+        }
+    }
+
+    pub fn set_from_pixbuf(&self, pixbuf: &gtk::gdk_pixbuf::Pixbuf) {
+        unsafe {
+            gtk::ffi::gtk_status_icon_set_from_pixbuf(self.as_ptr(), pixbuf.to_glib_none().0)
+        }
+    }
+
+    pub fn set_tooltip_text(&self, text: &str) {
+        let text = CString::new(text.replace('\0', "")).unwrap();
+        unsafe { gtk::ffi::gtk_status_icon_set_tooltip_text(self.as_ptr(), text.as_ptr()) }
+    }
+
+    // The size the tray gives the icon, 0 while it isn't embedded in one.
+    pub fn size(&self) -> i32 {
+        unsafe { gtk::ffi::gtk_status_icon_get_size(self.as_ptr()) }
+    }
+
+    // None while the icon isn't embedded in a tray.
+    pub fn geometry(&self) -> Option<Rect> {
+        let mut area = gtk::gdk::ffi::GdkRectangle {
+            x: 0,
+            y: 0,
+            width: 0,
+            height: 0,
+        };
+        let found = unsafe {
+            gtk::ffi::gtk_status_icon_get_geometry(
+                self.as_ptr(),
+                std::ptr::null_mut(),
+                &mut area,
+                std::ptr::null_mut(),
+            )
+        };
+        (found != glib::ffi::GFALSE).then(|| Rect {
+            x: area.x,
+            y: area.y,
+            width: area.width.max(0) as u32,
+            height: area.height.max(0) as u32,
+        })
+    }
+
+    pub fn set_visible(&self, visible: bool) {
+        unsafe { gtk::ffi::gtk_status_icon_set_visible(self.as_ptr(), visible.into_glib()) }
+    }
+}
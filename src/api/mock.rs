@@ -0,0 +1,505 @@
+// In-process stand-in for the platform backends, for testing code that
+// drives an Application without a desktop. Nothing is shown; every call is
+// recorded instead.
+use crate::api::{BatchEntry, EventSender};
+use crate::lazy_menu::LazyBuilder;
+use crate::tooltip::ProviderSlot;
+use crate::{
+    Error, HotkeyModifiers, IconFrame, IconSource, InitOptions, MenuItemId, MenuItemStyle, Rect,
+    SystrayEvent, Theme,
+};
+use std::{
+    cell::RefCell,
+    path::Path,
+    sync::Mutex,
+    time::Duration,
+};
+
+/// A call made on a MockWindow, with what it was given.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MockCall {
+    AddMenuEntry {
+        id: MenuItemId,
+        label: String,
+        parent: Option<MenuItemId>,
+    },
+    AddCheckMenuEntry {
+        id: MenuItemId,
+        label: String,
+        checked: bool,
+        parent: Option<MenuItemId>,
+    },
+    AddSubmenu {
+        id: MenuItemId,
+        label: String,
+        parent: Option<MenuItemId>,
+    },
+    AddMenuSeparator {
+        id: MenuItemId,
+        parent: Option<MenuItemId>,
+    },
+    SetMenuItemChecked {
+        id: MenuItemId,
+        checked: bool,
+    },
+    SetMenuItemEnabled {
+        id: MenuItemId,
+        enabled: bool,
+    },
+    SetDefaultMenuItem(MenuItemId),
+    SetMenuItemStyle {
+        id: MenuItemId,
+        style: MenuItemStyle,
+    },
+    SetMenuItemIcon {
+        id: MenuItemId,
+        width: u32,
+        height: u32,
+    },
+    RemoveMenuItem(MenuItemId),
+    MoveMenuItem {
+        id: MenuItemId,
+        position: u32,
+    },
+    SetLazySubmenu(MenuItemId),
+    SetIconFromFile(String),
+    SetIconFromFileSized {
+        file: String,
+        size: u32,
+    },
+    SetIconFromResource(String),
+    SetIconFromResourceId(u16),
+    SetIconFromTheme(String),
+    ClearIcon,
+    SetIconTemplate(bool),
+    RequestAlwaysVisible,
+    SetClipboardText(String),
+    SetIconFromImageBuffer {
+        width: u32,
+        height: u32,
+    },
+    StartIconAnimation {
+        frames: usize,
+        interval: Duration,
+    },
+    StopIconAnimation,
+    RequestAttention {
+        // The attention icon's size, if it has one.
+        icon: Option<(u32, u32)>,
+    },
+    ClearAttention,
+    SetTooltip(String),
+    SetAppId(String),
+    ShowNotification {
+        summary: String,
+        body: String,
+        image: Option<String>,
+        // Action keys, in order.
+        actions: Vec<String>,
+    },
+    SetIconVisible(bool),
+    SetMenuOnLeftClick(bool),
+    SetSuppressDefaultMenu(bool),
+    RegisterHotkey {
+        id: u32,
+        modifiers: HotkeyModifiers,
+        key: u32,
+    },
+    UnregisterHotkey(u32),
+    Shutdown,
+}
+
+pub type Window = MockWindow;
+
+pub struct MockWindow {
+    calls: RefCell<Vec<MockCall>>,
+    // Dropped by shutdown, which ends wait_for_message once the events sent
+    // before it are handled.
+    event_tx: Mutex<Option<EventSender>>,
+}
+
+fn parent_id(parent: Option<u32>) -> Option<MenuItemId> {
+    parent.map(MenuItemId)
+}
+
+impl MockWindow {
+    pub fn new(
+        event_tx: EventSender,
+        _tooltip: ProviderSlot,
+        _options: &InitOptions,
+    ) -> Result<MockWindow, Error> {
+        Ok(MockWindow {
+            calls: RefCell::new(Vec::new()),
+            event_tx: Mutex::new(Some(event_tx)),
+        })
+    }
+
+    /// Everything called so far, oldest first.
+    pub fn recorded_calls(&self) -> Vec<MockCall> {
+        self.calls.borrow().clone()
+    }
+
+    pub fn clear_recorded_calls(&self) {
+        self.calls.borrow_mut().clear();
+    }
+
+    /// Queues an event for wait_for_message, as if the platform had sent it.
+    /// Ignored after shutdown.
+    pub fn send_event(&self, event: SystrayEvent) {
+        if let Some(tx) = self.event_tx.lock().unwrap().as_ref() {
+            tx.send(event).ok();
+        }
+    }
+
+    fn record(&self, call: MockCall) -> Result<(), Error> {
+        self.calls.borrow_mut().push(call);
+        Ok(())
+    }
+
+    pub fn add_menu_entry(
+        &self,
+        item_idx: u32,
+        item_name: &str,
+        parent: Option<u32>,
+    ) -> Result<(), Error> {
+        self.record(MockCall::AddMenuEntry {
+            id: MenuItemId(item_idx),
+            label: item_name.to_string(),
+            parent: parent_id(parent),
+        })
+    }
+
+    pub fn add_check_menu_entry(
+        &self,
+        item_idx: u32,
+        item_name: &str,
+        checked: bool,
+        parent: Option<u32>,
+    ) -> Result<(), Error> {
+        self.record(MockCall::AddCheckMenuEntry {
+            id: MenuItemId(item_idx),
+            label: item_name.to_string(),
+            checked,
+            parent: parent_id(parent),
+        })
+    }
+
+    pub fn add_submenu(
+        &self,
+        item_idx: u32,
+        item_name: &str,
+        parent: Option<u32>,
+    ) -> Result<(), Error> {
+        self.record(MockCall::AddSubmenu {
+            id: MenuItemId(item_idx),
+            label: item_name.to_string(),
+            parent: parent_id(parent),
+        })
+    }
+
+    // Recorded as the calls it stands for.
+    pub(crate) fn add_menu_entries(&self, entries: &[BatchEntry]) -> Result<(), Error> {
+        for entry in entries {
+            match entry {
+                BatchEntry::Item {
+                    idx,
+                    label,
+                    checked: None,
+                    parent,
+                } => self.add_menu_entry(*idx, label, *parent)?,
+                BatchEntry::Item {
+                    idx,
+                    label,
+                    checked: Some(checked),
+                    parent,
+                } => self.add_check_menu_entry(*idx, label, *checked, *parent)?,
+                BatchEntry::Separator { idx, parent } => self.add_menu_separator(*idx, *parent)?,
+                BatchEntry::Submenu { idx, label, parent } => {
+                    self.add_submenu(*idx, label, *parent)?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn add_menu_separator(&self, item_idx: u32, parent: Option<u32>) -> Result<(), Error> {
+        self.record(MockCall::AddMenuSeparator {
+            id: MenuItemId(item_idx),
+            parent: parent_id(parent),
+        })
+    }
+
+    pub fn set_menu_item_checked(&self, item_idx: u32, checked: bool) -> Result<(), Error> {
+        self.record(MockCall::SetMenuItemChecked {
+            id: MenuItemId(item_idx),
+            checked,
+        })
+    }
+
+    pub fn set_menu_item_enabled(&self, item_idx: u32, enabled: bool) -> Result<(), Error> {
+        self.record(MockCall::SetMenuItemEnabled {
+            id: MenuItemId(item_idx),
+            enabled,
+        })
+    }
+
+    pub fn set_menu_item_icon_rgba(
+        &self,
+        item_idx: u32,
+        _rgba: &[u8],
+        width: u32,
+        height: u32,
+    ) -> Result<(), Error> {
+        self.record(MockCall::SetMenuItemIcon {
+            id: MenuItemId(item_idx),
+            width,
+            height,
+        })
+    }
+
+    pub fn set_default_menu_item(&self, item_idx: u32) -> Result<(), Error> {
+        self.record(MockCall::SetDefaultMenuItem(MenuItemId(item_idx)))
+    }
+
+    pub fn set_menu_item_style(
+        &self,
+        item_idx: u32,
+        _label: &str,
+        style: MenuItemStyle,
+    ) -> Result<(), Error> {
+        self.record(MockCall::SetMenuItemStyle {
+            id: MenuItemId(item_idx),
+            style,
+        })
+    }
+
+    // The builder isn't called, as the mock menu never opens.
+    pub fn set_lazy_submenu(&self, item_idx: u32, _builder: LazyBuilder) -> Result<(), Error> {
+        self.record(MockCall::SetLazySubmenu(MenuItemId(item_idx)))
+    }
+
+    pub fn remove_menu_item(&self, item_idx: u32) -> Result<(), Error> {
+        self.record(MockCall::RemoveMenuItem(MenuItemId(item_idx)))
+    }
+
+    pub fn set_icon_template(&self, template: bool) -> Result<(), Error> {
+        self.record(MockCall::SetIconTemplate(template))
+    }
+
+    pub fn move_menu_item(&self, item_idx: u32, position: u32) -> Result<(), Error> {
+        self.record(MockCall::MoveMenuItem {
+            id: MenuItemId(item_idx),
+            position,
+        })
+    }
+
+    pub fn set_icon_from_file(&self, file: &Path) -> Result<(), Error> {
+        self.record(MockCall::SetIconFromFile(file.to_string_lossy().into_owned()))
+    }
+
+    // Windows' path for ICO files at a given size.
+    pub fn set_icon_from_file_sized(&self, file: &Path, size: u32) -> Result<(), Error> {
+        self.record(MockCall::SetIconFromFileSized {
+            file: file.to_string_lossy().into_owned(),
+            size,
+        })
+    }
+
+    pub fn set_icon_from_resource_id(&self, id: u16) -> Result<(), Error> {
+        self.record(MockCall::SetIconFromResourceId(id))
+    }
+
+    pub fn set_icon_from_theme(&self, icon_name: &str) -> Result<(), Error> {
+        self.record(MockCall::SetIconFromTheme(icon_name.to_string()))
+    }
+
+    pub fn clear_icon(&self) -> Result<(), Error> {
+        self.record(MockCall::ClearIcon)
+    }
+
+    pub fn set_icon_from_resource(&self, resource: &str) -> Result<(), Error> {
+        self.record(MockCall::SetIconFromResource(resource.to_string()))
+    }
+
+    pub fn set_icon_from_image_buffer(
+        &self,
+        buffer: &[u8],
+        width: u32,
+        height: u32,
+    ) -> Result<(), Error> {
+        if buffer.len() != (width as usize) * (height as usize) * 4 {
+            return Err(Error::os(format!(
+                "Icon buffer is {} bytes, expected {} for a {}x{} RGBA image",
+                buffer.len(),
+                (width as usize) * (height as usize) * 4,
+                width,
+                height
+            )));
+        }
+        self.record(MockCall::SetIconFromImageBuffer { width, height })
+    }
+
+    // Recorded as set_icon_from_image_buffer, which it stands for.
+    pub fn set_icon_from_rgba(&self, rgba: Vec<u8>, width: u32, height: u32) -> Result<(), Error> {
+        self.set_icon_from_image_buffer(&rgba, width, height)
+    }
+
+    // One source at a time, the way the matching set_icon_from_* does it,
+    // for the Application to show what it has no pixels of.
+    pub fn apply_icon(&self, icon: IconSource<'_>) -> Result<(), Error> {
+        match icon {
+            IconSource::Path(file) => self.set_icon_from_file(file),
+            IconSource::Resource(resource) => self.set_icon_from_resource(resource),
+            IconSource::ResourceId(id) => self.set_icon_from_resource_id(id),
+            IconSource::ThemeName(name) => self.set_icon_from_theme(name),
+            IconSource::Rgba {
+                data,
+                width,
+                height,
+            } => self.set_icon_from_rgba(data.into_owned(), width, height),
+            // The Application decodes these, with the image crate.
+            IconSource::Encoded(_) => Err(Error::NotImplementedError),
+        }
+    }
+
+    pub fn start_icon_animation(
+        &self,
+        frames: &[IconFrame],
+        interval: Duration,
+    ) -> Result<(), Error> {
+        self.record(MockCall::StartIconAnimation {
+            frames: frames.len(),
+            interval,
+        })
+    }
+
+    pub fn stop_icon_animation(&self) -> Result<(), Error> {
+        self.record(MockCall::StopIconAnimation)
+    }
+
+    pub fn request_attention(&self, icon: Option<&IconFrame>) -> Result<(), Error> {
+        self.record(MockCall::RequestAttention {
+            icon: icon.map(|f| (f.width, f.height)),
+        })
+    }
+
+    pub fn clear_attention(&self) -> Result<(), Error> {
+        self.record(MockCall::ClearAttention)
+    }
+
+    pub fn scale_factor(&self) -> f64 {
+        1.0
+    }
+
+    pub fn icon_size(&self) -> u32 {
+        16
+    }
+
+    // Safe to call more than once, like the real ones.
+    pub fn shutdown(&self) -> Result<(), Error> {
+        if self.event_tx.lock().unwrap().take().is_some() {
+            self.record(MockCall::Shutdown)?;
+        }
+        Ok(())
+    }
+
+    pub fn set_tooltip(&self, tooltip: &str) -> Result<(), Error> {
+        self.record(MockCall::SetTooltip(tooltip.to_string()))
+    }
+
+    pub fn system_theme(&self) -> Theme {
+        Theme::Light
+    }
+
+    pub fn set_app_id(&self, app_id: &str) -> Result<(), Error> {
+        self.record(MockCall::SetAppId(app_id.to_string()))
+    }
+
+    pub fn set_waiting(&self, _waiting: bool) -> bool {
+        true
+    }
+
+    pub fn event_sender(&self) -> Option<EventSender> {
+        self.event_tx.lock().unwrap().clone()
+    }
+
+    pub fn is_elevated(&self) -> Result<bool, Error> {
+        Ok(false)
+    }
+
+    pub fn request_always_visible(&self) -> Result<(), Error> {
+        self.record(MockCall::RequestAlwaysVisible)
+    }
+
+    pub fn set_clipboard_text(&self, text: &str) -> Result<(), Error> {
+        self.record(MockCall::SetClipboardText(text.to_string()))
+    }
+
+    pub fn remove_icon_on_panic(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    pub fn show_notification(
+        &self,
+        summary: &str,
+        body: &str,
+        image: Option<&str>,
+        actions: &[(String, String)],
+    ) -> Result<(), Error> {
+        self.record(MockCall::ShowNotification {
+            summary: summary.to_string(),
+            body: body.to_string(),
+            image: image.map(str::to_string),
+            actions: actions.iter().map(|(key, _)| key.clone()).collect(),
+        })
+    }
+
+    #[cfg(feature = "rwh")]
+    pub fn raw_window_handle(&self) -> Result<raw_window_handle::RawWindowHandle, Error> {
+        Err(Error::NotImplementedError)
+    }
+
+    // An icon-sized square in the corner of the screen.
+    pub fn tray_icon_rect(&self) -> Result<Rect, Error> {
+        Ok(Rect {
+            x: 0,
+            y: 0,
+            width: self.icon_size(),
+            height: self.icon_size(),
+        })
+    }
+
+    pub fn set_icon_visible(&self, visible: bool) -> Result<(), Error> {
+        self.record(MockCall::SetIconVisible(visible))
+    }
+
+    pub fn set_menu_on_left_click(&self, enabled: bool) {
+        self.record(MockCall::SetMenuOnLeftClick(enabled)).ok();
+    }
+
+    pub fn set_suppress_default_menu(&self, suppress: bool) {
+        self.record(MockCall::SetSuppressDefaultMenu(suppress)).ok();
+    }
+
+    pub fn register_hotkey(
+        &self,
+        id: u32,
+        modifiers: HotkeyModifiers,
+        key: u32,
+    ) -> Result<(), Error> {
+        self.record(MockCall::RegisterHotkey { id, modifiers, key })
+    }
+
+    pub fn unregister_hotkey(&self, id: u32) -> Result<(), Error> {
+        self.record(MockCall::UnregisterHotkey(id))
+    }
+
+    // Stands in for the AppKit run loop on macOS.
+    pub fn pump_events(&self) {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    pub fn quit(&self) {
+        self.shutdown().ok();
+    }
+}
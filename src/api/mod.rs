@@ -1,11 +1,153 @@
-#[cfg(target_os = "windows")]
+#[cfg(all(target_os = "windows", not(feature = "test-utils")))]
 #[path = "win32/mod.rs"]
 pub mod platform;
 
-#[cfg(target_os = "linux")]
+#[cfg(all(target_os = "linux", not(feature = "test-utils")))]
 #[path = "linux/mod.rs"]
 pub mod platform;
 
-#[cfg(target_os = "macos")]
+#[cfg(all(target_os = "macos", not(feature = "test-utils")))]
 #[path = "cocoa/mod.rs"]
 pub mod platform;
+
+#[cfg(feature = "test-utils")]
+#[path = "mock.rs"]
+pub mod platform;
+
+#[cfg(all(
+    feature = "clipboard",
+    any(target_os = "linux", target_os = "macos"),
+    not(feature = "test-utils")
+))]
+pub(crate) mod clipboard;
+mod events;
+pub(crate) use events::{
+    event_channel, EventReceiver, EventSender, RecvError, RecvTimeoutError, TryRecvError,
+};
+
+// A menu entry for build_menu, which hands the platform all of them at once.
+// Labels are in the mnemonic syntax below.
+#[derive(Clone, Debug)]
+pub(crate) enum BatchEntry {
+    Item {
+        idx: u32,
+        label: String,
+        // Some for check items.
+        checked: Option<bool>,
+        parent: Option<u32>,
+    },
+    Separator {
+        idx: u32,
+        parent: Option<u32>,
+    },
+    Submenu {
+        idx: u32,
+        label: String,
+        parent: Option<u32>,
+    },
+}
+
+// macOS runs on the caller's thread, the others have a loop thread to stop.
+#[cfg(not(any(target_os = "macos", feature = "test-utils")))]
+use std::time::Duration;
+#[cfg(all(
+    not(feature = "test-utils"),
+    any(target_os = "windows", all(target_os = "linux", feature = "sni"))
+))]
+use std::{
+    thread::{self, JoinHandle},
+    time::Instant,
+};
+
+// How long shutdown waits for a platform thread before leaving it behind.
+#[cfg(not(any(target_os = "macos", feature = "test-utils")))]
+pub(crate) const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+
+// JoinHandle::join can't time out, so poll until the thread is done.
+#[cfg(all(
+    not(feature = "test-utils"),
+    any(target_os = "windows", all(target_os = "linux", feature = "sni"))
+))]
+pub(crate) fn join_with_timeout(handle: JoinHandle<()>, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while !handle.is_finished() {
+        if Instant::now() >= deadline {
+            warn!("Platform thread did not stop within {:?}", timeout);
+            return false;
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+    handle.join().is_ok()
+}
+
+// Labels reach the platforms in Windows' syntax, where `&` underlines the
+// character after it and `&&` stands for an ampersand.
+pub(crate) fn escape_mnemonic(label: &str) -> String {
+    label.replace('&', "&&")
+}
+
+// GTK and dbusmenu underline after `_` instead, and take `__` for `_`.
+#[cfg(any(test, all(target_os = "linux", not(feature = "test-utils"))))]
+pub(crate) fn underscore_mnemonic(label: &str) -> String {
+    let mut out = String::with_capacity(label.len());
+    let mut chars = label.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '&' if chars.peek() == Some(&'&') => {
+                chars.next();
+                out.push('&');
+            }
+            '&' => out.push('_'),
+            '_' => out.push_str("__"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+// macOS menus have no mnemonics.
+#[cfg(any(test, all(target_os = "macos", not(feature = "test-utils"))))]
+pub(crate) fn strip_mnemonic(label: &str) -> String {
+    let mut out = String::with_capacity(label.len());
+    let mut chars = label.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '&' if chars.peek() == Some(&'&') => {
+                chars.next();
+                out.push('&');
+            }
+            '&' => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_labels_get_their_ampersands_doubled() {
+        assert_eq!(escape_mnemonic("Save & Quit"), "Save && Quit");
+        assert_eq!(escape_mnemonic("&&"), "&&&&");
+        assert_eq!(escape_mnemonic("Quit"), "Quit");
+    }
+
+    #[test]
+    fn mnemonics_become_underscores_for_gtk() {
+        assert_eq!(underscore_mnemonic("&Quit"), "_Quit");
+        assert_eq!(underscore_mnemonic("Save && Quit"), "Save & Quit");
+        assert_eq!(underscore_mnemonic("snake_case &file"), "snake__case _file");
+        assert_eq!(underscore_mnemonic(&escape_mnemonic("R&D_1")), "R&D__1");
+        assert_eq!(underscore_mnemonic("trailing &"), "trailing _");
+    }
+
+    #[test]
+    fn mnemonics_are_dropped_for_macos() {
+        assert_eq!(strip_mnemonic("&Quit"), "Quit");
+        assert_eq!(strip_mnemonic("Save && Quit"), "Save & Quit");
+        assert_eq!(strip_mnemonic(&escape_mnemonic("R&D")), "R&D");
+        assert_eq!(strip_mnemonic("&&&x"), "&x");
+    }
+}
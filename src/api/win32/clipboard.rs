@@ -0,0 +1,46 @@
+// The clipboard by hand, as Windows has it in user32. Text goes up as
+// CF_UNICODETEXT, for which Windows makes the ANSI formats itself.
+use super::{get_win_os_error, to_wstring};
+use crate::Error;
+use winapi::shared::windef::HWND;
+use winapi::um::{winbase, winuser};
+
+// The clipboard is owned by `hwnd` afterwards. Without an owner window,
+// EmptyClipboard leaves none and SetClipboardData fails.
+pub(crate) fn set_text(hwnd: HWND, text: &str) -> Result<(), Error> {
+    let text = to_wstring(text);
+    unsafe {
+        if winuser::OpenClipboard(hwnd) == 0 {
+            return Err(get_win_os_error("Error opening the clipboard"));
+        }
+        let res = put_text(&text);
+        winuser::CloseClipboard();
+        res
+    }
+}
+
+// Runs with the clipboard open. `text` is NUL terminated.
+unsafe fn put_text(text: &[u16]) -> Result<(), Error> {
+    if winuser::EmptyClipboard() == 0 {
+        return Err(get_win_os_error("Error emptying the clipboard"));
+    }
+    let mem = winbase::GlobalAlloc(winbase::GMEM_MOVEABLE, std::mem::size_of_val(text));
+    if mem.is_null() {
+        return Err(get_win_os_error("Error allocating the clipboard text"));
+    }
+    let data = winbase::GlobalLock(mem) as *mut u16;
+    if data.is_null() {
+        let e = get_win_os_error("Error locking the clipboard text");
+        winbase::GlobalFree(mem);
+        return Err(e);
+    }
+    std::ptr::copy_nonoverlapping(text.as_ptr(), data, text.len());
+    winbase::GlobalUnlock(mem);
+    // The memory is the clipboard's once it took it, and still ours if not.
+    if winuser::SetClipboardData(winuser::CF_UNICODETEXT, mem).is_null() {
+        let e = get_win_os_error("Error setting the clipboard text");
+        winbase::GlobalFree(mem);
+        return Err(e);
+    }
+    Ok(())
+}
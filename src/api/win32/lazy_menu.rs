@@ -0,0 +1,74 @@
+// Submenus from add_lazy_submenu, filled in on WM_INITMENUPOPUP and emptied
+// on WM_UNINITMENUPOPUP. The items' closures aren't Send, so they stay on
+// the window's thread and run there.
+use crate::lazy_menu::{self, LazyBuilder};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use winapi::{
+    shared::{minwindef::UINT, windef::HMENU},
+    um::winuser,
+};
+
+// Builders by submenu handle.
+pub type LazyMenus = Arc<Mutex<HashMap<usize, LazyBuilder>>>;
+
+type Items = Vec<Option<Box<dyn FnMut()>>>;
+
+thread_local! {
+    // What each submenu showed last, by position. WM_MENUCOMMAND comes
+    // after the submenu closed and was emptied, so these stay until it
+    // opens again.
+    static ITEMS: RefCell<HashMap<usize, Items>> = RefCell::new(HashMap::new());
+}
+
+pub unsafe fn fill(menus: &LazyMenus, hmenu: HMENU) {
+    let builder = match menus.lock().unwrap().get(&(hmenu as usize)) {
+        Some(builder) => builder.clone(),
+        None => return,
+    };
+    clear(hmenu);
+    let mut items = Vec::new();
+    for (label, f) in lazy_menu::build(&builder) {
+        let label = super::to_wstring(&crate::api::escape_mnemonic(&label));
+        winuser::AppendMenuW(hmenu, winuser::MF_STRING, 0, label.as_ptr());
+        items.push(Some(f));
+    }
+    ITEMS.with(|i| i.borrow_mut().insert(hmenu as usize, items));
+}
+
+pub unsafe fn empty(menus: &LazyMenus, hmenu: HMENU) {
+    if menus.lock().unwrap().contains_key(&(hmenu as usize)) {
+        clear(hmenu);
+    }
+}
+
+unsafe fn clear(hmenu: HMENU) {
+    for pos in (0..winuser::GetMenuItemCount(hmenu)).rev() {
+        winuser::DeleteMenu(hmenu, pos as UINT, winuser::MF_BYPOSITION);
+    }
+}
+
+// Answers WM_MENUCOMMAND, false for menus that aren't lazy.
+pub fn activate(menus: &LazyMenus, hmenu: HMENU, pos: usize) -> bool {
+    let key = hmenu as usize;
+    if !menus.lock().unwrap().contains_key(&key) {
+        return false;
+    }
+    let f = ITEMS.with(|i| {
+        i.borrow_mut()
+            .get_mut(&key)
+            .and_then(|items| items.get_mut(pos))
+            .and_then(Option::take)
+    });
+    if let Some(mut f) = f {
+        lazy_menu::activate(&mut f);
+        // Put it back unless the submenu was filled again meanwhile.
+        ITEMS.with(|i| {
+            if let Some(slot @ None) = i.borrow_mut().get_mut(&key).and_then(|i| i.get_mut(pos)) {
+                *slot = Some(f);
+            }
+        });
+    }
+    true
+}
@@ -1,33 +1,67 @@
-use crate::{Error, SystrayEvent};
+use crate::api::{BatchEntry, EventSender};
+mod clipboard;
+mod lazy_menu;
+mod owner_draw;
+mod promotion;
+#[cfg(feature = "windows-toast")]
+mod toast;
+
+use crate::lazy_menu::LazyBuilder;
+use crate::tooltip::{self, ProviderSlot};
+use crate::ComApartment;
+use crate::{
+    Error, HotkeyModifiers, IconFrame, IconSource, InitOptions, MenuItemId, MenuItemStyle, Rect,
+    SystrayEvent, Theme,
+};
 use std;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::os::windows::ffi::OsStrExt;
-use std::sync::mpsc::{channel, Sender};
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    mpsc::channel,
+    Arc, Mutex,
+};
 use std::thread;
+use std::time::Duration;
 use winapi::{
     ctypes::{c_ulong, c_ushort},
     shared::{
         basetsd::ULONG_PTR,
         guiddef::GUID,
-        minwindef::{DWORD, HINSTANCE, LPARAM, LRESULT, PBYTE, TRUE, UINT, WPARAM},
+        minwindef::{DWORD, FARPROC, HINSTANCE, LPARAM, LRESULT, PBYTE, TRUE, UINT, WPARAM},
         ntdef::LPCWSTR,
-        windef::{HBITMAP, HBRUSH, HICON, HMENU, HWND, POINT},
+        windef::{HBITMAP, HBRUSH, HICON, HMENU, HWND, POINT, RECT},
+        winerror::{ERROR_CLASS_ALREADY_EXISTS, S_OK},
     },
     um::{
-        errhandlingapi, libloaderapi,
+        combaseapi, errhandlingapi, handleapi, libloaderapi, objbase, processthreadsapi,
+        securitybaseapi,
         shellapi::{
-            self, NIF_ICON, NIF_MESSAGE, NIF_TIP, NIM_ADD, NIM_DELETE, NIM_MODIFY, NOTIFYICONDATAW,
+            self, NIF_ICON, NIF_INFO, NIF_MESSAGE, NIF_TIP, NIIF_INFO, NIM_ADD, NIM_DELETE,
+            NIM_MODIFY, NIN_BALLOONUSERCLICK, NOTIFYICONDATAW, NOTIFYICONIDENTIFIER,
         },
+        wingdi,
+        winnt::{TokenElevation, TOKEN_ELEVATION, TOKEN_QUERY},
+        winreg,
         winuser::{
             self, CW_USEDEFAULT, IMAGE_ICON, LR_DEFAULTCOLOR, LR_LOADFROMFILE, MENUINFO,
-            MENUITEMINFOW, MFT_SEPARATOR, MFT_STRING, MIIM_FTYPE, MIIM_ID, MIIM_STATE, MIIM_STRING,
-            MIM_APPLYTOSUBMENUS, MIM_STYLE, MNS_NOTIFYBYPOS, WM_DESTROY, WM_USER, WNDCLASSW,
+            MENUITEMINFOW, MFS_CHECKED, MIIM_BITMAP, MFT_OWNERDRAW, MFT_SEPARATOR, MFT_STRING,
+            MF_BYCOMMAND, MF_BYPOSITION, MF_CHECKED, MF_ENABLED, MF_GRAYED, MF_UNCHECKED,
+            MIIM_FTYPE, MIIM_ID, MIIM_STATE, MIIM_STRING, MIIM_SUBMENU, MIM_APPLYTOSUBMENUS,
+            MIM_STYLE, MNS_NOTIFYBYPOS, MOD_NOREPEAT, WM_CLOSE, WM_USER, WNDCLASSW,
             WS_OVERLAPPEDWINDOW,
         },
     },
 };
 
+#[link(name = "shell32")]
+extern "system" {
+    fn SetCurrentProcessExplicitAppUserModelID(app_id: LPCWSTR) -> i32;
+}
+
 // Got this idea from glutin. Yay open source! Boo stupid winproc! Even more boo
 // doing SetLongPtr tho.
 thread_local!(static WININFO_STASH: RefCell<Option<WindowsLoopData>> = const { RefCell::new(None) });
@@ -39,11 +73,133 @@ fn to_wstring(str: &str) -> Vec<u16> {
         .collect::<Vec<_>>()
 }
 
+// What has been put into the notification area, so it can be put back after
+// Explorer restarts.
+struct IconState {
+    icon: HICON,
+    // Whether `icon` is ours to destroy once it's replaced. Handles from
+    // set_icon_from_hicon are left to the app.
+    owned: bool,
+    tip: [u16; 128],
+    hidden: bool,
+    removed: bool,
+    // What `icon` was loaded from, if it was loaded here at the tray's size.
+    origin: Option<IconOrigin>,
+    // Shown in turn instead of `icon` while it's set.
+    animation: Option<Animation>,
+    attention: Option<Attention>,
+}
+
+// Where an icon loaded at the tray's icon size came from, so it can be
+// loaded again at the new size after a DPI change. Icons from pixels or
+// handles have none; the Application refits those it has the pixels of.
+#[derive(Clone)]
+enum IconOrigin {
+    File(PathBuf),
+    // The name NUL terminated.
+    Resource(HINSTANCE, Vec<u16>),
+    // The module stays loaded, as set_icon_from_module_resource_id asks.
+    ResourceId(HINSTANCE, u16),
+}
+
+impl IconOrigin {
+    unsafe fn load(&self, size: u32) -> Result<HICON, Error> {
+        let (icon, what) = match self {
+            IconOrigin::File(file) => return load_icon_file(file, size),
+            IconOrigin::Resource(module, name) => (
+                load_resource_icon(*module, name.as_ptr(), size),
+                "Error setting icon from resource".to_string(),
+            ),
+            IconOrigin::ResourceId(module, id) => (
+                load_resource_icon(*module, winuser::MAKEINTRESOURCEW(*id), size),
+                format!("Error loading icon resource {}", id),
+            ),
+        };
+        if icon.is_null() {
+            return Err(get_win_os_error(&what));
+        }
+        Ok(icon)
+    }
+}
+
+impl IconState {
+    // Takes `icon` as the static icon, handing back the previous one if it
+    // was ours, to destroy once the shell shows something else.
+    fn replace_icon(&mut self, icon: HICON, owned: bool) -> Option<HICON> {
+        let old = std::mem::replace(&mut self.icon, icon);
+        std::mem::replace(&mut self.owned, owned).then_some(old)
+    }
+
+    fn destroy_icon(&mut self) {
+        self.origin = None;
+        if let Some(old) = self.replace_icon(0 as HICON, false) {
+            unsafe {
+                winuser::DestroyIcon(old);
+            }
+        }
+    }
+}
+
+impl Drop for IconState {
+    fn drop(&mut self) {
+        self.destroy_icon();
+    }
+}
+
+struct Animation {
+    frames: Vec<HICON>,
+    next: usize,
+}
+
+impl Drop for Animation {
+    fn drop(&mut self) {
+        for icon in self.frames.drain(..) {
+            unsafe {
+                winuser::DestroyIcon(icon);
+            }
+        }
+    }
+}
+
+// Swapped in for every other tick of the attention timer.
+struct Attention {
+    icon: HICON,
+    showing: bool,
+}
+
+impl Drop for Attention {
+    fn drop(&mut self) {
+        unsafe {
+            winuser::DestroyIcon(self.icon);
+        }
+    }
+}
+
+// WM_USER + 1 is what the icon reports clicks and moves with.
+const WM_START_ANIMATION: UINT = WM_USER + 2;
+const WM_STOP_ANIMATION: UINT = WM_USER + 3;
+const WM_REGISTER_HOTKEY: UINT = WM_USER + 4;
+const WM_UNREGISTER_HOTKEY: UINT = WM_USER + 5;
+const WM_START_ATTENTION: UINT = WM_USER + 6;
+const WM_STOP_ATTENTION: UINT = WM_USER + 7;
+const ANIMATION_TIMER: usize = 1;
+const ATTENTION_TIMER: usize = 2;
+const ATTENTION_BLINK_MS: UINT = 500;
+
+unsafe impl Send for IconState {}
+
 #[derive(Clone)]
 struct WindowInfo {
     pub hwnd: HWND,
     pub hinstance: HINSTANCE,
     pub hmenu: HMENU,
+    pub taskbar_created: UINT,
+    pub tray_id: UINT,
+    pub icon_state: Arc<Mutex<IconState>>,
+    pub menu_on_left_click: Arc<AtomicBool>,
+    pub suppress_menu: Arc<AtomicBool>,
+    pub styled_items: owner_draw::StyledItems,
+    pub lazy_menus: lazy_menu::LazyMenus,
 }
 
 unsafe impl Send for WindowInfo {}
@@ -52,11 +208,66 @@ unsafe impl Sync for WindowInfo {}
 #[derive(Clone)]
 struct WindowsLoopData {
     pub info: WindowInfo,
-    pub tx: Sender<SystrayEvent>,
+    pub tx: EventSender,
+    pub menu_open: Cell<bool>,
+    pub scale: Cell<f64>,
+    pub theme: Cell<Theme>,
+    pub tooltip: ProviderSlot,
 }
 
 unsafe fn get_win_os_error(msg: &str) -> Error {
-    Error::OsError(format!("{}: {}", &msg, errhandlingapi::GetLastError()))
+    Error::OsError {
+        code: errhandlingapi::GetLastError() as i32,
+        message: msg.to_string(),
+    }
+}
+
+// For failed notify_icon calls. UIPI keeps an elevated process from talking
+// to the shell in some setups, which the code alone doesn't tell.
+unsafe fn get_shell_error(msg: &str) -> Error {
+    let code = errhandlingapi::GetLastError() as i32;
+    let message = match is_elevated() {
+        Ok(true) => format!("{} (the process is elevated)", msg),
+        _ => msg.to_string(),
+    };
+    Error::OsError { code, message }
+}
+
+// Shell_NotifyIcon doesn't set the last error on every failure, so it's
+// cleared first, rather than left to report some earlier call's code.
+unsafe fn notify_icon(message: DWORD, nid: &mut NOTIFYICONDATAW) -> bool {
+    errhandlingapi::SetLastError(0);
+    shellapi::Shell_NotifyIconW(message, nid) != 0
+}
+
+fn is_elevated() -> Result<bool, Error> {
+    unsafe {
+        let mut token = std::ptr::null_mut();
+        if processthreadsapi::OpenProcessToken(
+            processthreadsapi::GetCurrentProcess(),
+            TOKEN_QUERY,
+            &mut token,
+        ) == 0
+        {
+            return Err(get_win_os_error("Error opening the process token"));
+        }
+        let mut elevation: TOKEN_ELEVATION = std::mem::zeroed();
+        let mut len = 0;
+        let ok = securitybaseapi::GetTokenInformation(
+            token,
+            TokenElevation,
+            &mut elevation as *mut TOKEN_ELEVATION as *mut _,
+            std::mem::size_of::<TOKEN_ELEVATION>() as DWORD,
+            &mut len,
+        );
+        let res = if ok == 0 {
+            Err(get_win_os_error("Error querying the process elevation"))
+        } else {
+            Ok(elevation.TokenIsElevated != 0)
+        };
+        handleapi::CloseHandle(token);
+        res
+    }
 }
 
 unsafe extern "system" fn window_proc(
@@ -70,53 +281,252 @@ unsafe extern "system" fn window_proc(
             let stash = stash.borrow();
             let stash = stash.as_ref();
             if let Some(stash) = stash {
-                let menu_id = winuser::GetMenuItemID(stash.info.hmenu, w_param as i32) as i32;
+                let lazy_menus = &stash.info.lazy_menus;
+                if lazy_menu::activate(lazy_menus, l_param as HMENU, w_param) {
+                    return;
+                }
+                // l_param is the menu the item is in, which may be a submenu.
+                let menu_id = winuser::GetMenuItemID(l_param as HMENU, w_param as i32) as i32;
                 if menu_id != -1 {
                     stash
                         .tx
-                        .send(SystrayEvent {
-                            menu_index: menu_id as u32,
-                        })
+                        .send(SystrayEvent::MenuItem(MenuItemId(menu_id as u32)))
                         .ok();
                 }
             }
         });
     }
 
-    if msg == WM_USER + 1
-        && (l_param as UINT == winuser::WM_LBUTTONUP || l_param as UINT == winuser::WM_RBUTTONUP) {
-            let mut p = POINT { x: 0, y: 0 };
-            if winuser::GetCursorPos(&mut p as *mut POINT) == 0 {
-                return 1;
-            }
-            winuser::SetForegroundWindow(h_wnd);
-            WININFO_STASH.with(|stash| {
-                let stash = stash.borrow();
-                let stash = stash.as_ref();
-                if let Some(stash) = stash {
-                    winuser::TrackPopupMenu(
-                        stash.info.hmenu,
-                        0,
-                        p.x,
-                        p.y,
-                        (winuser::TPM_BOTTOMALIGN | winuser::TPM_LEFTALIGN) as i32,
-                        h_wnd,
-                        std::ptr::null_mut(),
-                    );
+    // Only the root popup counts, submenus get these messages too. Escape
+    // can end the menu loop without an uninit, so the loop exit closes
+    // whatever is still open.
+    if msg == winuser::WM_INITMENUPOPUP
+        || msg == winuser::WM_UNINITMENUPOPUP
+        || msg == winuser::WM_EXITMENULOOP
+    {
+        WININFO_STASH.with(|stash| {
+            let stash = stash.borrow();
+            let stash = stash.as_ref();
+            if let Some(stash) = stash {
+                let opening = msg == winuser::WM_INITMENUPOPUP;
+                if msg == winuser::WM_INITMENUPOPUP {
+                    lazy_menu::fill(&stash.info.lazy_menus, w_param as HMENU);
+                } else if msg == winuser::WM_UNINITMENUPOPUP {
+                    lazy_menu::empty(&stash.info.lazy_menus, w_param as HMENU);
                 }
-            });
+                if msg != winuser::WM_EXITMENULOOP && w_param as HMENU != stash.info.hmenu {
+                    return;
+                }
+                if stash.menu_open.get() != opening {
+                    stash.menu_open.set(opening);
+                    let event = if opening {
+                        SystrayEvent::MenuOpened
+                    } else {
+                        SystrayEvent::MenuClosed
+                    };
+                    stash.tx.send(event).ok();
+                }
+            }
+        });
+    }
+
+    if msg == winuser::WM_MEASUREITEM || msg == winuser::WM_DRAWITEM {
+        let handled = WININFO_STASH.with(|stash| match stash.borrow().as_ref() {
+            Some(stash) if msg == winuser::WM_MEASUREITEM => owner_draw::measure_item(
+                h_wnd,
+                &stash.info.styled_items,
+                &mut *(l_param as *mut winuser::MEASUREITEMSTRUCT),
+            ),
+            Some(stash) => owner_draw::draw_item(
+                &stash.info.styled_items,
+                &*(l_param as *const winuser::DRAWITEMSTRUCT),
+            ),
+            None => false,
+        });
+        if handled {
+            return TRUE as LRESULT;
+        }
+    }
+
+    // Theme switches come as a broadcast WM_SETTINGCHANGE, along with plenty
+    // of unrelated ones, so just look again each time.
+    if msg == winuser::WM_SETTINGCHANGE {
+        WININFO_STASH.with(|stash| {
+            let stash = stash.borrow();
+            let stash = stash.as_ref();
+            if let Some(stash) = stash {
+                let theme = system_theme();
+                if stash.theme.replace(theme) != theme {
+                    stash.tx.send(SystrayEvent::ThemeChanged(theme)).ok();
+                }
+            }
+        });
+    }
+
+    // The hidden window only gets WM_DPICHANGED with per-monitor DPI
+    // awareness, so display and settings changes are checked as well.
+    if msg == winuser::WM_DPICHANGED
+        || msg == winuser::WM_DISPLAYCHANGE
+        || msg == winuser::WM_SETTINGCHANGE
+    {
+        WININFO_STASH.with(|stash| {
+            let stash = stash.borrow();
+            let stash = stash.as_ref();
+            if let Some(stash) = stash {
+                let scale = scale_factor();
+                if stash.scale.replace(scale) != scale {
+                    reshow_icon(&stash.info);
+                    stash.tx.send(SystrayEvent::ScaleFactorChanged).ok();
+                }
+            }
+        });
+    }
+
+    // Timers belong to the thread of the window they're set on, so this one
+    // starts and stops the animation for the others.
+    if msg == WM_START_ANIMATION {
+        winuser::SetTimer(h_wnd, ANIMATION_TIMER, w_param as UINT, None);
+    }
+    if msg == WM_STOP_ANIMATION {
+        winuser::KillTimer(h_wnd, ANIMATION_TIMER);
+    }
+    if msg == WM_START_ATTENTION {
+        winuser::SetTimer(h_wnd, ATTENTION_TIMER, ATTENTION_BLINK_MS, None);
+    }
+    if msg == WM_STOP_ATTENTION {
+        winuser::KillTimer(h_wnd, ATTENTION_TIMER);
+    }
+    // Hotkeys belong to the window's thread as well. These are sent, not
+    // posted, and answer with the error code, 0 when it went through.
+    if msg == WM_REGISTER_HOTKEY {
+        let modifiers = (l_param as UINT & 0xffff) | MOD_NOREPEAT as UINT;
+        if winuser::RegisterHotKey(h_wnd, w_param as i32, modifiers, l_param as UINT >> 16) == 0 {
+            return errhandlingapi::GetLastError() as LRESULT;
+        }
+        return 0;
+    }
+    if msg == WM_UNREGISTER_HOTKEY {
+        if winuser::UnregisterHotKey(h_wnd, w_param as i32) == 0 {
+            return errhandlingapi::GetLastError() as LRESULT;
+        }
+        return 0;
+    }
+    if msg == winuser::WM_HOTKEY {
+        WININFO_STASH.with(|stash| {
+            if let Some(stash) = stash.borrow().as_ref() {
+                stash.tx.send(SystrayEvent::HotkeyPressed(w_param as u32)).ok();
+            }
+        });
+    }
+    if msg == winuser::WM_TIMER && w_param == ANIMATION_TIMER {
+        WININFO_STASH.with(|stash| {
+            if let Some(stash) = stash.borrow().as_ref() {
+                show_next_frame(&stash.info);
+            }
+        });
+    }
+    if msg == winuser::WM_TIMER && w_param == ATTENTION_TIMER {
+        WININFO_STASH.with(|stash| {
+            if let Some(stash) = stash.borrow().as_ref() {
+                blink_attention(&stash.info);
+            }
+        });
+    }
+
+    WININFO_STASH.with(|stash| {
+        let stash = stash.borrow();
+        let stash = stash.as_ref();
+        if let Some(stash) = stash {
+            if msg == stash.info.taskbar_created && add_icon(&stash.info) {
+                stash.tx.send(SystrayEvent::IconRecreated).ok();
+            }
         }
+    });
+
+    // Moves over the icon are what comes closest to a hover.
+    if msg == WM_USER + 1 && l_param as UINT == winuser::WM_MOUSEMOVE {
+        WININFO_STASH.with(|stash| {
+            let stash = stash.borrow();
+            let stash = stash.as_ref();
+            if let Some(stash) = stash {
+                if let Some(tooltip) = tooltip::provide(&stash.tooltip) {
+                    let tip = to_tip(&tooltip);
+                    if stash.info.icon_state.lock().unwrap().tip != tip {
+                        set_tip(&stash.info, tip);
+                    }
+                }
+            }
+        });
+    }
+
+    if msg == WM_USER + 1 && l_param as UINT == NIN_BALLOONUSERCLICK {
+        WININFO_STASH.with(|stash| {
+            if let Some(stash) = stash.borrow().as_ref() {
+                let action_key = "default".to_string();
+                stash.tx.send(SystrayEvent::NotificationAction { action_key }).ok();
+            }
+        });
+    }
+
+    // Without NOTIFYICON_VERSION_4 the callback doesn't carry the click
+    // position, but the cursor is still where the click was.
+    if msg == WM_USER + 1
+        && (l_param as UINT == winuser::WM_LBUTTONUP
+            || l_param as UINT == winuser::WM_RBUTTONUP
+            || l_param as UINT == winuser::WM_LBUTTONDBLCLK)
+    {
+        WININFO_STASH.with(|stash| {
+            let stash = stash.borrow();
+            let stash = stash.as_ref();
+            if let Some(stash) = stash {
+                let mut p = POINT { x: 0, y: 0 };
+                if winuser::GetCursorPos(&mut p as *mut POINT) == 0 {
+                    return;
+                }
+                let (x, y) = (p.x, p.y);
+                let (event, open_menu) = match l_param as UINT {
+                    winuser::WM_LBUTTONUP => (
+                        SystrayEvent::LeftClick { x, y },
+                        stash.info.menu_on_left_click.load(Ordering::Relaxed),
+                    ),
+                    winuser::WM_RBUTTONUP => (
+                        SystrayEvent::RightClick { x, y },
+                        !stash.info.suppress_menu.load(Ordering::Relaxed),
+                    ),
+                    _ => (SystrayEvent::DoubleClick { x, y }, false),
+                };
+                stash.tx.send(event).ok();
+                if !open_menu {
+                    return;
+                }
+                winuser::SetForegroundWindow(h_wnd);
+                winuser::TrackPopupMenu(
+                    stash.info.hmenu,
+                    0,
+                    p.x,
+                    p.y,
+                    (winuser::TPM_BOTTOMALIGN | winuser::TPM_LEFTALIGN) as i32,
+                    h_wnd,
+                    std::ptr::null_mut(),
+                );
+            }
+        });
+    }
     if msg == winuser::WM_DESTROY {
         winuser::PostQuitMessage(0);
     }
     winuser::DefWindowProcW(h_wnd, msg, w_param, l_param)
 }
 
-fn get_nid_struct(hwnd: &HWND) -> NOTIFYICONDATAW {
+fn get_nid_struct(info: &WindowInfo) -> NOTIFYICONDATAW {
+    nid_struct(info.hwnd, info.tray_id)
+}
+
+fn nid_struct(hwnd: HWND, tray_id: UINT) -> NOTIFYICONDATAW {
     NOTIFYICONDATAW {
         cbSize: std::mem::size_of::<NOTIFYICONDATAW>() as DWORD,
-        hWnd: *hwnd,
-        uID: 0x1 as UINT,
+        hWnd: hwnd,
+        uID: tray_id,
         uFlags: 0 as UINT,
         uCallbackMessage: 0 as UINT,
         hIcon: 0 as HICON,
@@ -154,49 +564,9 @@ fn get_menu_item_struct() -> MENUITEMINFOW {
     }
 }
 
-unsafe fn init_window() -> Result<WindowInfo, Error> {
-    let class_name = to_wstring("my_window");
-    let hinstance: HINSTANCE = libloaderapi::GetModuleHandleA(std::ptr::null_mut());
-    let wnd = WNDCLASSW {
-        style: 0,
-        lpfnWndProc: Some(window_proc),
-        cbClsExtra: 0,
-        cbWndExtra: 0,
-        hInstance: 0 as HINSTANCE,
-        hIcon: winuser::LoadIconW(0 as HINSTANCE, winuser::IDI_APPLICATION),
-        hCursor: winuser::LoadCursorW(0 as HINSTANCE, winuser::IDI_APPLICATION),
-        hbrBackground: 16 as HBRUSH,
-        lpszMenuName: 0 as LPCWSTR,
-        lpszClassName: class_name.as_ptr(),
-    };
-    if winuser::RegisterClassW(&wnd) == 0 {
-        return Err(get_win_os_error("Error creating window class"));
-    }
-    let hwnd = winuser::CreateWindowExW(
-        0,
-        class_name.as_ptr(),
-        to_wstring("rust_systray_window").as_ptr(),
-        WS_OVERLAPPEDWINDOW,
-        CW_USEDEFAULT,
-        0,
-        CW_USEDEFAULT,
-        0,
-        0 as HWND,
-        0 as HMENU,
-        0 as HINSTANCE,
-        std::ptr::null_mut(),
-    );
-    if hwnd.is_null() {
-        return Err(get_win_os_error("Error creating window"));
-    }
-    let mut nid = get_nid_struct(&hwnd);
-    nid.uID = 0x1;
-    nid.uFlags = NIF_MESSAGE;
-    nid.uCallbackMessage = WM_USER + 1;
-    if shellapi::Shell_NotifyIconW(NIM_ADD, &mut nid as *mut NOTIFYICONDATAW) == 0 {
-        return Err(get_win_os_error("Error adding menu icon"));
-    }
-    // Setup menu
+// MIM_APPLYTOSUBMENUS only reaches submenus that already exist, so every popup
+// gets MNS_NOTIFYBYPOS set on its own.
+unsafe fn create_popup_menu() -> Result<HMENU, Error> {
     let hmenu = winuser::CreatePopupMenu();
     let m = MENUINFO {
         cbSize: std::mem::size_of::<MENUINFO>() as DWORD,
@@ -210,244 +580,1740 @@ unsafe fn init_window() -> Result<WindowInfo, Error> {
     if winuser::SetMenuInfo(hmenu, &m as *const MENUINFO) == 0 {
         return Err(get_win_os_error("Error setting up menu"));
     }
+    Ok(hmenu)
+}
 
-    Ok(WindowInfo {
-        hwnd,
-        hmenu,
-        hinstance,
+// Position of the item with the given id, found through MIIM_ID since
+// GetMenuItemID doesn't report ids of items that open a submenu.
+unsafe fn find_menu_position(hmenu: HMENU, item_idx: u32) -> Option<u32> {
+    (0..winuser::GetMenuItemCount(hmenu).max(0) as u32).find(|&pos| {
+        let mut item = get_menu_item_struct();
+        item.fMask = MIIM_ID;
+        winuser::GetMenuItemInfoW(hmenu, pos, TRUE, &mut item as *mut MENUITEMINFOW) != 0
+            && item.wID == item_idx
     })
 }
 
-unsafe fn run_loop() {
-    log::debug!("Running windows loop");
-    // Run message loop
-    let mut msg = winuser::MSG {
-        hwnd: 0 as HWND,
-        message: 0 as UINT,
-        wParam: 0 as WPARAM,
-        lParam: 0 as LPARAM,
-        time: 0 as DWORD,
-        pt: POINT { x: 0, y: 0 },
-    };
-    loop {
-        winuser::GetMessageW(&mut msg, 0 as HWND, 0, 0);
-        if msg.message == winuser::WM_QUIT {
-            break;
-        }
-        winuser::TranslateMessage(&msg);
-        winuser::DispatchMessageW(&msg);
+// Windows 10 1607 and later tell the DPI of each window, the taskbar's
+// included, which is that of the monitor the tray is on. Looked up at run
+// time so older versions can still load the crate.
+unsafe fn user32_fn(name: &[u8]) -> Option<FARPROC> {
+    let user32 = libloaderapi::GetModuleHandleW(to_wstring("user32.dll").as_ptr());
+    if user32.is_null() {
+        return None;
     }
-    log::debug!("Leaving windows run loop");
+    let f = libloaderapi::GetProcAddress(user32, name.as_ptr() as *const i8);
+    (!f.is_null()).then_some(f)
 }
 
-pub struct Window {
-    info: WindowInfo,
-    windows_loop: Option<thread::JoinHandle<()>>,
+fn taskbar_dpi() -> Option<UINT> {
+    type GetDpiForWindow = unsafe extern "system" fn(HWND) -> UINT;
+    unsafe {
+        let f: GetDpiForWindow = std::mem::transmute(user32_fn(b"GetDpiForWindow\0")?);
+        let taskbar = winuser::FindWindowW(to_wstring("Shell_TrayWnd").as_ptr(), std::ptr::null());
+        if taskbar.is_null() {
+            return None;
+        }
+        Some(f(taskbar)).filter(|&dpi| dpi != 0)
+    }
 }
 
-impl Window {
-    pub fn new(event_tx: Sender<SystrayEvent>) -> Result<Window, Error> {
-        let (tx, rx) = channel();
-        let windows_loop = thread::spawn(move || {
-            unsafe {
-                let k = match init_window() {
-                    Ok(j) => {
-                        tx.send(Ok(j.clone())).ok();
-                        j
-                    }
-                    Err(e) => {
-                        // If creation didn't work, return out of the thread.
-                        tx.send(Err(e)).ok();
-                        return;
-                    }
-                };
-                WININFO_STASH.with(|stash| {
-                    let data = WindowsLoopData {
-                        info: k,
-                        tx: event_tx,
-                    };
-                    (*stash.borrow_mut()) = Some(data);
-                });
-                run_loop();
-            }
-        });
-        let info = match rx.recv().unwrap() {
-            Ok(i) => i,
-            Err(e) => {
-                return Err(e);
-            }
-        };
-        let w = Window {
-            info,
-            windows_loop: Some(windows_loop),
-        };
-        Ok(w)
+// The taskbar's DPI where Windows tells it, else the DPI the process is
+// aware of. The tray's icon size follows it.
+fn scale_factor() -> f64 {
+    if let Some(dpi) = taskbar_dpi() {
+        return dpi as f64 / 96.0;
     }
-
-    pub fn quit(&mut self) {
-        unsafe {
-            winuser::PostMessageW(self.info.hwnd, WM_DESTROY, 0 as WPARAM, 0 as LPARAM);
-        }
-        if let Some(t) = self.windows_loop.take() {
-            t.join().ok();
+    unsafe {
+        let hdc = winuser::GetDC(std::ptr::null_mut());
+        if hdc.is_null() {
+            return 1.0;
         }
+        let dpi = wingdi::GetDeviceCaps(hdc, wingdi::LOGPIXELSX);
+        winuser::ReleaseDC(std::ptr::null_mut(), hdc);
+        dpi as f64 / 96.0
     }
+}
 
-    pub fn set_tooltip(&self, tooltip: &str) -> Result<(), Error> {
-        // Add Tooltip
-        log::debug!("Setting tooltip to {}", tooltip);
-        // Gross way to convert String to [i8; 128]
-        // TODO: Clean up conversion, test for length so we don't panic at runtime
-        let tt = tooltip.as_bytes();
-        let mut nid = get_nid_struct(&self.info.hwnd);
-        for (i, &byte) in tt.iter().enumerate() {
-            nid.szTip[i] = byte as u16;
-        }
-        nid.uFlags = NIF_TIP;
-        unsafe {
-            if shellapi::Shell_NotifyIconW(NIM_MODIFY, &mut nid as *mut NOTIFYICONDATAW) == 0 {
-                return Err(get_win_os_error("Error setting tooltip"));
-            }
+// The taskbar follows SystemUsesLightTheme; AppsUseLightTheme is the older,
+// app-only setting. Neither exists before Windows 10.
+fn system_theme() -> Theme {
+    let key = to_wstring("Software\\Microsoft\\Windows\\CurrentVersion\\Themes\\Personalize");
+    for value in ["SystemUsesLightTheme", "AppsUseLightTheme"] {
+        let name = to_wstring(value);
+        let mut data: DWORD = 0;
+        let mut size = std::mem::size_of::<DWORD>() as DWORD;
+        let status = unsafe {
+            winreg::RegGetValueW(
+                winreg::HKEY_CURRENT_USER,
+                key.as_ptr(),
+                name.as_ptr(),
+                winreg::RRF_RT_REG_DWORD,
+                std::ptr::null_mut(),
+                &mut data as *mut DWORD as *mut _,
+                &mut size,
+            )
+        };
+        if status == 0 {
+            return if data == 0 { Theme::Dark } else { Theme::Light };
         }
-        Ok(())
     }
+    Theme::Light
+}
 
-    pub fn add_menu_entry(&self, item_idx: u32, item_name: &str) -> Result<(), Error> {
-        let mut st = to_wstring(item_name);
-        let mut item = get_menu_item_struct();
+// Tooltips break lines on \r\n. Whatever doesn't fit next to the NUL in
+// szTip is dropped.
+fn to_tip(tooltip: &str) -> [u16; 128] {
+    let tt: Vec<u16> = tooltip
+        .replace("\r\n", "\n")
+        .replace('\n', "\r\n")
+        .encode_utf16()
+        .collect();
+    let mut tip = [0_u16; 128];
+    let len = tt.len().min(tip.len() - 1);
+    tip[..len].copy_from_slice(&tt[..len]);
+    tip
+}
+
+// Fills a fixed NUL terminated field, dropping whatever doesn't fit.
+fn to_wide_field<const N: usize>(text: &str) -> [u16; N] {
+    let mut field = [0_u16; N];
+    for (dst, src) in field[..N - 1].iter_mut().zip(text.encode_utf16()) {
+        *dst = src;
+    }
+    field
+}
+
+// A hidden icon only has the tooltip stored, for when it's added back.
+unsafe fn set_tip(info: &WindowInfo, tip: [u16; 128]) -> bool {
+    let mut nid = get_nid_struct(info);
+    nid.szTip = tip;
+    nid.uFlags = NIF_TIP;
+    {
+        let mut state = info.icon_state.lock().unwrap();
+        state.tip = tip;
+        if state.hidden {
+            return true;
+        }
+    }
+    notify_icon(NIM_MODIFY, &mut nid)
+}
+
+// Picks the frame of an ICO file that best fits a `size` px icon: that exact
+// size, else the smallest one above it, else the largest. Frames may be BMP
+// or PNG data, CreateIconFromResourceEx takes both.
+fn pick_ico_frame(data: &[u8], size: u32) -> Option<&[u8]> {
+    // ICONDIR is reserved, type (1 for icons) and count, then 16 byte entries.
+    if data.len() < 6 || u16::from_le_bytes([data[2], data[3]]) != 1 {
+        return None;
+    }
+    let count = u16::from_le_bytes([data[4], data[5]]) as usize;
+    let mut best: Option<(u32, &[u8])> = None;
+    for i in 0..count {
+        let entry = data.get(6 + i * 16..6 + (i + 1) * 16)?;
+        // A width of 0 stands for 256.
+        let width = if entry[0] == 0 { 256 } else { entry[0] as u32 };
+        let len = u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]) as usize;
+        let offset = u32::from_le_bytes([entry[12], entry[13], entry[14], entry[15]]) as usize;
+        let frame = data.get(offset..offset.checked_add(len)?)?;
+        let better = match best {
+            None => true,
+            Some((w, _)) if w >= size => width >= size && width < w,
+            Some((w, _)) => width > w,
+        };
+        if better {
+            best = Some((width, frame));
+        }
+    }
+    best.map(|(_, frame)| frame)
+}
+
+// Every RGBA pixel from the crate goes through here on its way into a DIB.
+fn to_bgra(px: &[u8]) -> [u8; 4] {
+    [px[2], px[1], px[0], px[3]]
+}
+
+// A 32bpp top-down DIB section, deleted when dropped unless taken over
+// with into_raw. Its pixels, in BGRA, are GDI's memory, so they're only
+// lent out for as long as the Dib is.
+struct Dib {
+    bmp: HBITMAP,
+    bits: *mut u8,
+    len: usize,
+}
+
+impl Dib {
+    fn new(width: u32, height: u32) -> Option<Dib> {
+        let (bmp, bits) = unsafe { create_dib_section(width, height)? };
+        // At 32 bits a pixel every row is DWORD aligned already, so rows
+        // follow each other without padding, as in RGBA buffers.
+        let len = width as usize * height as usize * 4;
+        Some(Dib { bmp, bits, len })
+    }
+
+    fn handle(&self) -> HBITMAP {
+        self.bmp
+    }
+
+    fn pixels(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.bits, self.len) }
+    }
+
+    // Hands the bitmap over to whoever deletes it from now on.
+    fn into_raw(self) -> HBITMAP {
+        let bmp = self.bmp;
+        std::mem::forget(self);
+        bmp
+    }
+}
+
+impl Drop for Dib {
+    fn drop(&mut self) {
+        unsafe {
+            wingdi::DeleteObject(self.bmp as _);
+        }
+    }
+}
+
+unsafe fn create_dib_section(width: u32, height: u32) -> Option<(HBITMAP, *mut u8)> {
+    let mut bmi: wingdi::BITMAPINFO = std::mem::zeroed();
+    bmi.bmiHeader.biSize = std::mem::size_of::<wingdi::BITMAPINFOHEADER>() as DWORD;
+    bmi.bmiHeader.biWidth = width as i32;
+    // Negative height for top-down rows, like RGBA buffers.
+    bmi.bmiHeader.biHeight = -(height as i32);
+    bmi.bmiHeader.biPlanes = 1;
+    bmi.bmiHeader.biBitCount = 32;
+    bmi.bmiHeader.biCompression = wingdi::BI_RGB;
+    let mut bits = std::ptr::null_mut();
+    let bmp = wingdi::CreateDIBSection(
+        std::ptr::null_mut(),
+        &bmi,
+        wingdi::DIB_RGB_COLORS,
+        &mut bits,
+        std::ptr::null_mut(),
+        0,
+    );
+    if bmp.is_null() {
+        return None;
+    }
+    Some((bmp, bits as *mut u8))
+}
+
+// A 32bpp DIB section gets its alpha used as is, where the bitmap
+// CreateIcon makes may not. Icons take straight alpha, which the shell
+// blends with the taskbar itself. The monochrome mask, with WORD-aligned
+// rows and a set bit for each transparent pixel, is only for whatever
+// draws without alpha. Those XOR the color over the background, so fully
+// transparent pixels must be black or they show as a halo.
+fn create_icon(buffer: &[u8], width: u32, height: u32) -> Result<HICON, Error> {
+    let mask_stride = (width as usize).div_ceil(16) * 2;
+    let mut and_mask = vec![0_u8; mask_stride * height as usize];
+    unsafe {
+        let mut color = match Dib::new(width, height) {
+            Some(dib) => dib,
+            None => return Err(get_win_os_error("Cannot create the icon's bitmap")),
+        };
+        let pixels = color.pixels().chunks_exact_mut(4).zip(buffer.chunks_exact(4));
+        for (i, (d, px)) in pixels.enumerate() {
+            if px[3] == 0 {
+                d.fill(0);
+                let (x, y) = (i % width as usize, i / width as usize);
+                and_mask[y * mask_stride + x / 8] |= 0x80 >> (x % 8);
+            } else {
+                d.copy_from_slice(&to_bgra(px));
+            }
+        }
+        let mask = wingdi::CreateBitmap(
+            width as i32,
+            height as i32,
+            1,
+            1,
+            and_mask.as_ptr() as _,
+        );
+        let mut info = winuser::ICONINFO {
+            fIcon: TRUE,
+            xHotspot: 0,
+            yHotspot: 0,
+            hbmMask: mask,
+            hbmColor: color.handle(),
+        };
+        // The icon gets copies of both bitmaps.
+        let hicon = winuser::CreateIconIndirect(&mut info);
+        let res = if hicon.is_null() {
+            Err(get_win_os_error("Cannot create icon from the image buffer"))
+        } else {
+            Ok(hicon)
+        };
+        if !mask.is_null() {
+            wingdi::DeleteObject(mask as _);
+        }
+        res
+    }
+}
+
+unsafe fn show_icon(info: &WindowInfo, icon: HICON) -> bool {
+    let mut nid = get_nid_struct(info);
+    nid.uFlags = NIF_ICON;
+    nid.hIcon = icon;
+    notify_icon(NIM_MODIFY, &mut nid)
+}
+
+// Hands the static icon to the shell again after a DPI change, loaded anew
+// at the new size if it came from a file or resource, and otherwise so it's
+// scaled to the new size once, from the icon, rather than from the shell's
+// copy at the old size. The Application then refits icons it has the pixels
+// of. Animations and attention hand over their next frame soon enough.
+unsafe fn reshow_icon(info: &WindowInfo) {
+    let mut state = info.icon_state.lock().unwrap();
+    let mut old = None;
+    if let Some(origin) = state.origin.clone() {
+        match origin.load(icon_size()) {
+            Ok(icon) => old = state.replace_icon(icon, true),
+            Err(e) => warn!("Failed to load the icon at the new size: {}", e),
+        }
+    }
+    let busy = state.animation.is_some() || state.attention.is_some();
+    if !state.hidden && !state.removed && !busy && !state.icon.is_null() {
+        show_icon(info, state.icon);
+    }
+    if let Some(old) = old {
+        winuser::DestroyIcon(old);
+    }
+}
+
+// The frames were made up front, so a tick only hands one to the shell, which
+// keeps its own copy.
+unsafe fn show_next_frame(info: &WindowInfo) {
+    let mut state = info.icon_state.lock().unwrap();
+    // The attention icon stays up for its whole tick.
+    let covered = state.hidden || state.attention.as_ref().is_some_and(|a| a.showing);
+    if let Some(animation) = state.animation.as_mut() {
+        let icon = animation.frames[animation.next];
+        animation.next = (animation.next + 1) % animation.frames.len();
+        if !covered {
+            show_icon(info, icon);
+        }
+    }
+}
+
+// The icon as it would be without attention: the animation's last frame
+// while there is one, else the static icon.
+fn base_icon(state: &IconState) -> HICON {
+    match &state.animation {
+        Some(animation) => {
+            let len = animation.frames.len();
+            animation.frames[(animation.next + len - 1) % len]
+        }
+        None => state.icon,
+    }
+}
+
+unsafe fn blink_attention(info: &WindowInfo) {
+    let mut state = info.icon_state.lock().unwrap();
+    let (base, hidden) = (base_icon(&state), state.hidden);
+    if let Some(attention) = state.attention.as_mut() {
+        attention.showing = !attention.showing;
+        let icon = if attention.showing { attention.icon } else { base };
+        if !hidden {
+            show_icon(info, icon);
+        }
+    }
+}
+
+// Adds the icon with everything it had before, unless it was shut down or
+// is hidden.
+unsafe fn add_icon(info: &WindowInfo) -> bool {
+    let state = info.icon_state.lock().unwrap();
+    if state.removed || state.hidden {
+        return false;
+    }
+    let mut nid = get_nid_struct(info);
+    nid.uFlags = NIF_MESSAGE | NIF_ICON | NIF_TIP;
+    nid.uCallbackMessage = WM_USER + 1;
+    nid.hIcon = state.icon;
+    nid.szTip = state.tip;
+    notify_icon(NIM_ADD, &mut nid)
+}
+
+// `name` is a resource name or a MAKEINTRESOURCE id, loaded from the image
+// closest to `size`. Null on failure.
+unsafe fn load_resource_icon(module: HINSTANCE, name: LPCWSTR, size: u32) -> HICON {
+    winuser::LoadImageW(module, name, IMAGE_ICON, size as i32, size as i32, 0) as HICON
+}
+
+// ICO files get the frame closest to `size`, scaled to it if need be.
+// Anything else is left to LoadImage.
+fn load_icon_file(icon_file: &Path, size: u32) -> Result<HICON, Error> {
+    let data = std::fs::read(icon_file)
+        .map_err(|e| Error::from_io(format!("Failed to read icon {}", icon_file.display()), &e))?;
+    let wide: Vec<u16> = icon_file.as_os_str().encode_wide().chain(Some(0)).collect();
+    let hicon = match pick_ico_frame(&data, size) {
+        Some(frame) => unsafe {
+            winuser::CreateIconFromResourceEx(
+                frame.as_ptr() as PBYTE,
+                frame.len() as DWORD,
+                TRUE,
+                0x30000,
+                size as i32,
+                size as i32,
+                LR_DEFAULTCOLOR,
+            )
+        },
+        None => unsafe {
+            winuser::LoadImageW(
+                std::ptr::null_mut() as HINSTANCE,
+                wide.as_ptr(),
+                IMAGE_ICON,
+                size as i32,
+                size as i32,
+                LR_LOADFROMFILE,
+            ) as HICON
+        },
+    };
+    if hicon.is_null() {
+        return Err(unsafe { get_win_os_error("Error setting icon from file") });
+    }
+    Ok(hicon)
+}
+
+// For the taskbar's DPI where Windows tells it, and otherwise already
+// scaled to the DPI the process is aware of.
+fn icon_size() -> u32 {
+    type GetSystemMetricsForDpi = unsafe extern "system" fn(i32, UINT) -> i32;
+    unsafe {
+        if let (Some(f), Some(dpi)) = (user32_fn(b"GetSystemMetricsForDpi\0"), taskbar_dpi()) {
+            let f: GetSystemMetricsForDpi = std::mem::transmute(f);
+            return f(winuser::SM_CXSMICON, dpi) as u32;
+        }
+        winuser::GetSystemMetrics(winuser::SM_CXSMICON) as u32
+    }
+}
+
+// For the tray's own thread only, which is why any apartment will do.
+unsafe fn init_com(apartment: ComApartment) -> Result<(), Error> {
+    let model = match apartment {
+        ComApartment::SingleThreaded => objbase::COINIT_APARTMENTTHREADED,
+        ComApartment::MultiThreaded => objbase::COINIT_MULTITHREADED,
+    };
+    let hr = combaseapi::CoInitializeEx(std::ptr::null_mut(), model);
+    if hr < 0 {
+        return Err(Error::OsError {
+            code: hr,
+            message: "Error initializing COM".to_string(),
+        });
+    }
+    Ok(())
+}
+
+unsafe fn init_window(tray_id: UINT) -> Result<WindowInfo, Error> {
+    let class_name = to_wstring("my_window");
+    let hinstance: HINSTANCE = libloaderapi::GetModuleHandleA(std::ptr::null_mut());
+    let wnd = WNDCLASSW {
+        style: 0,
+        lpfnWndProc: Some(window_proc),
+        cbClsExtra: 0,
+        cbWndExtra: 0,
+        hInstance: 0 as HINSTANCE,
+        hIcon: winuser::LoadIconW(0 as HINSTANCE, winuser::IDI_APPLICATION),
+        hCursor: winuser::LoadCursorW(0 as HINSTANCE, winuser::IDI_APPLICATION),
+        hbrBackground: 16 as HBRUSH,
+        lpszMenuName: 0 as LPCWSTR,
+        lpszClassName: class_name.as_ptr(),
+    };
+    // Every Application makes its own window of the class, and only the
+    // first registers it.
+    if winuser::RegisterClassW(&wnd) == 0
+        && errhandlingapi::GetLastError() != ERROR_CLASS_ALREADY_EXISTS
+    {
+        return Err(get_win_os_error("Error creating window class"));
+    }
+    let hwnd = winuser::CreateWindowExW(
+        0,
+        class_name.as_ptr(),
+        to_wstring("rust_systray_window").as_ptr(),
+        WS_OVERLAPPEDWINDOW,
+        CW_USEDEFAULT,
+        0,
+        CW_USEDEFAULT,
+        0,
+        0 as HWND,
+        0 as HMENU,
+        0 as HINSTANCE,
+        std::ptr::null_mut(),
+    );
+    if hwnd.is_null() {
+        return Err(get_win_os_error("Error creating window"));
+    }
+    // Explorer broadcasts this after it restarts, with an empty tray.
+    let taskbar_created = winuser::RegisterWindowMessageW(to_wstring("TaskbarCreated").as_ptr());
+    // Setup menu
+    let hmenu = create_popup_menu()?;
+    let info = WindowInfo {
+        hwnd,
+        hmenu,
+        hinstance,
+        taskbar_created,
+        tray_id,
+        icon_state: Arc::new(Mutex::new(IconState {
+            icon: 0 as HICON,
+            owned: false,
+            tip: [0_u16; 128],
+            hidden: false,
+            removed: false,
+            origin: None,
+            animation: None,
+            attention: None,
+        })),
+        menu_on_left_click: Arc::new(AtomicBool::new(false)),
+        suppress_menu: Arc::new(AtomicBool::new(false)),
+        styled_items: Arc::new(Mutex::new(HashMap::new())),
+        lazy_menus: Arc::new(Mutex::new(HashMap::new())),
+    };
+    if !add_icon(&info) {
+        return Err(get_shell_error("Error adding menu icon"));
+    }
+
+    Ok(info)
+}
+
+unsafe fn run_loop() {
+    debug!("Running windows loop");
+    // Run message loop
+    let mut msg = winuser::MSG {
+        hwnd: 0 as HWND,
+        message: 0 as UINT,
+        wParam: 0 as WPARAM,
+        lParam: 0 as LPARAM,
+        time: 0 as DWORD,
+        pt: POINT { x: 0, y: 0 },
+    };
+    loop {
+        winuser::GetMessageW(&mut msg, 0 as HWND, 0, 0);
+        if msg.message == winuser::WM_QUIT {
+            break;
+        }
+        winuser::TranslateMessage(&msg);
+        winuser::DispatchMessageW(&msg);
+    }
+    debug!("Leaving windows run loop");
+}
+
+pub struct Window {
+    info: WindowInfo,
+    windows_loop: RefCell<Option<thread::JoinHandle<()>>>,
+    // The menu each item was inserted into, and the popups behind submenus.
+    parents: RefCell<HashMap<u32, HMENU>>,
+    submenus: RefCell<HashMap<u32, HMENU>>,
+    // Menus don't own their item bitmaps, so they're freed here.
+    bitmaps: RefCell<HashMap<u32, HBITMAP>>,
+    // Set once remove_icon_on_panic hooked in.
+    panic_hook: Cell<bool>,
+    #[cfg_attr(not(feature = "windows-toast"), allow(dead_code))]
+    event_tx: EventSender,
+}
+
+impl Window {
+    pub fn new(
+        event_tx: EventSender,
+        tooltip: ProviderSlot,
+        options: &InitOptions,
+    ) -> Result<Window, Error> {
+        let (tx, rx) = channel();
+        let loop_tx = event_tx.clone();
+        let apartment = options.com_apartment;
+        let tray_id = options.tray_id.unwrap_or(1);
+        let windows_loop = thread::spawn(move || {
+            unsafe {
+                let com = match apartment.map(|a| init_com(a)).transpose() {
+                    Ok(com) => com,
+                    Err(e) => {
+                        tx.send(Err(e)).ok();
+                        return;
+                    }
+                };
+                let k = match init_window(tray_id) {
+                    Ok(j) => {
+                        tx.send(Ok(j.clone())).ok();
+                        j
+                    }
+                    Err(e) => {
+                        // If creation didn't work, return out of the thread.
+                        tx.send(Err(e)).ok();
+                        if com.is_some() {
+                            combaseapi::CoUninitialize();
+                        }
+                        return;
+                    }
+                };
+                WININFO_STASH.with(|stash| {
+                    let data = WindowsLoopData {
+                        info: k,
+                        tx: loop_tx,
+                        menu_open: Cell::new(false),
+                        theme: Cell::new(system_theme()),
+                        scale: Cell::new(scale_factor()),
+                        tooltip,
+                    };
+                    (*stash.borrow_mut()) = Some(data);
+                });
+                run_loop();
+                if com.is_some() {
+                    combaseapi::CoUninitialize();
+                }
+            }
+        });
+        let info = match rx.recv().unwrap() {
+            Ok(i) => i,
+            Err(e) => {
+                return Err(e);
+            }
+        };
+        let w = Window {
+            info,
+            windows_loop: RefCell::new(Some(windows_loop)),
+            parents: RefCell::new(HashMap::new()),
+            submenus: RefCell::new(HashMap::new()),
+            bitmaps: RefCell::new(HashMap::new()),
+            panic_hook: Cell::new(false),
+            event_tx,
+        };
+        Ok(w)
+    }
+
+    pub fn quit(&self) {
+        if let Err(e) = self.shutdown() {
+            warn!("Error shutting down: {}", e);
+        }
+    }
+
+    pub fn set_tooltip(&self, tooltip: &str) -> Result<(), Error> {
+        // Add Tooltip
+        debug!("Setting tooltip to {}", tooltip);
+        unsafe {
+            if !set_tip(&self.info, to_tip(tooltip)) {
+                return Err(get_shell_error("Error setting tooltip"));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn system_theme(&self) -> Theme {
+        system_theme()
+    }
+
+    // Shell_NotifyIconW and the menu calls run on the caller's thread, so
+    // they're done by the time they return.
+    pub fn set_waiting(&self, _waiting: bool) -> bool {
+        true
+    }
+
+    pub fn event_sender(&self) -> Option<EventSender> {
+        Some(self.event_tx.clone())
+    }
+
+    pub fn is_elevated(&self) -> Result<bool, Error> {
+        is_elevated()
+    }
+
+    pub fn set_clipboard_text(&self, text: &str) -> Result<(), Error> {
+        clipboard::set_text(self.info.hwnd, text)
+    }
+
+    pub fn request_always_visible(&self) -> Result<(), Error> {
+        if promotion::promote()? {
+            Ok(())
+        } else {
+            Err(Error::os("Windows has no settings for the icon yet"))
+        }
+    }
+
+    pub fn set_app_id(&self, app_id: &str) -> Result<(), Error> {
+        let app_id = to_wstring(app_id);
+        let hr = unsafe { SetCurrentProcessExplicitAppUserModelID(app_id.as_ptr()) };
+        if hr < 0 {
+            return Err(Error::OsError {
+                code: hr,
+                message: "Error setting the AppUserModelID".to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    // Toasts where there are any, else the notification area's balloon,
+    // which has no image or buttons. Clicking it counts as "default".
+    pub fn show_notification(
+        &self,
+        summary: &str,
+        body: &str,
+        image: Option<&str>,
+        actions: &[(String, String)],
+    ) -> Result<(), Error> {
+        #[cfg(feature = "windows-toast")]
+        if toast::available() {
+            return toast::show(summary, body, image, actions, self.event_tx.clone());
+        }
+        // The balloon has no room for either.
+        let _ = (image, actions);
+        let mut nid = get_nid_struct(&self.info);
+        nid.uFlags = NIF_INFO;
+        nid.szInfoTitle = to_wide_field(summary);
+        nid.szInfo = to_wide_field(body);
+        nid.dwInfoFlags = NIIF_INFO;
+        unsafe {
+            if !notify_icon(NIM_MODIFY, &mut nid) {
+                return Err(get_shell_error("Error showing notification"));
+            }
+        }
+        Ok(())
+    }
+
+    // The hidden window tray messages go to.
+    #[cfg(feature = "rwh")]
+    pub fn raw_window_handle(&self) -> Result<raw_window_handle::RawWindowHandle, Error> {
+        let hwnd = std::num::NonZeroIsize::new(self.info.hwnd as isize)
+            .ok_or_else(|| Error::os("The tray has no window"))?;
+        let mut handle = raw_window_handle::Win32WindowHandle::new(hwnd);
+        handle.hinstance = std::num::NonZeroIsize::new(self.info.hinstance as isize);
+        Ok(handle.into())
+    }
+
+    pub fn tray_icon_rect(&self) -> Result<Rect, Error> {
+        let nid = get_nid_struct(&self.info);
+        let id = NOTIFYICONIDENTIFIER {
+            cbSize: std::mem::size_of::<NOTIFYICONIDENTIFIER>() as DWORD,
+            hWnd: nid.hWnd,
+            uID: nid.uID,
+            guidItem: nid.guidItem,
+        };
+        let mut rect: RECT = unsafe { std::mem::zeroed() };
+        let hr = unsafe { shellapi::Shell_NotifyIconGetRect(&id, &mut rect) };
+        if hr != S_OK {
+            return Err(Error::OsError {
+                code: hr,
+                message: "Error getting tray icon position".to_string(),
+            });
+        }
+        Ok(Rect {
+            x: rect.left,
+            y: rect.top,
+            width: (rect.right - rect.left).max(0) as u32,
+            height: (rect.bottom - rect.top).max(0) as u32,
+        })
+    }
+
+    // NIS_HIDDEN leaves a gap in some taskbars, so a hidden icon is deleted
+    // outright and added back under the same id, with the icon and tooltip
+    // it has by then. Explorer restarts skip it while it's hidden.
+    pub fn set_icon_visible(&self, visible: bool) -> Result<(), Error> {
+        {
+            let mut state = self.info.icon_state.lock().unwrap();
+            if state.hidden != visible {
+                return Ok(());
+            }
+            state.hidden = !visible;
+        }
+        unsafe {
+            if visible {
+                if !add_icon(&self.info) {
+                    let e = get_shell_error("Error adding icon back");
+                    self.info.icon_state.lock().unwrap().hidden = true;
+                    return Err(e);
+                }
+                return Ok(());
+            }
+            let mut nid = get_nid_struct(&self.info);
+            if !notify_icon(NIM_DELETE, &mut nid) {
+                let e = get_shell_error("Error hiding icon");
+                self.info.icon_state.lock().unwrap().hidden = false;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_menu_on_left_click(&self, enabled: bool) {
+        self.info.menu_on_left_click.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn set_suppress_default_menu(&self, suppress: bool) {
+        self.info.suppress_menu.store(suppress, Ordering::Relaxed);
+    }
+
+    pub fn register_hotkey(
+        &self,
+        id: u32,
+        modifiers: HotkeyModifiers,
+        key: u32,
+    ) -> Result<(), Error> {
+        // Virtual-key codes fit in the high word.
+        let packed = (modifiers.bits() & 0xffff) | (key << 16);
+        let code = unsafe {
+            winuser::SendMessageW(
+                self.info.hwnd,
+                WM_REGISTER_HOTKEY,
+                id as WPARAM,
+                packed as LPARAM,
+            )
+        };
+        if code != 0 {
+            return Err(Error::OsError {
+                code: code as i32,
+                message: format!("Error registering hotkey {}", id),
+            });
+        }
+        Ok(())
+    }
+
+    pub fn unregister_hotkey(&self, id: u32) -> Result<(), Error> {
+        let code = unsafe {
+            winuser::SendMessageW(self.info.hwnd, WM_UNREGISTER_HOTKEY, id as WPARAM, 0 as LPARAM)
+        };
+        if code != 0 {
+            return Err(Error::OsError {
+                code: code as i32,
+                message: format!("Error unregistering hotkey {}", id),
+            });
+        }
+        Ok(())
+    }
+
+    fn container(&self, parent: Option<u32>) -> HMENU {
+        parent
+            .and_then(|p| self.submenus.borrow().get(&p).copied())
+            .unwrap_or(self.info.hmenu)
+    }
+
+    fn insert_item(
+        &self,
+        item_idx: u32,
+        item: &MENUITEMINFOW,
+        parent: Option<u32>,
+        what: &str,
+    ) -> Result<(), Error> {
+        let hmenu = self.container(parent);
+        unsafe {
+            let pos = winuser::GetMenuItemCount(hmenu).max(0) as u32;
+            if winuser::InsertMenuItemW(hmenu, pos, TRUE, item as *const MENUITEMINFOW) == 0 {
+                return Err(get_win_os_error(&format!("Error inserting {}", what)));
+            }
+        }
+        self.parents.borrow_mut().insert(item_idx, hmenu);
+        Ok(())
+    }
+
+    pub fn add_menu_entry(
+        &self,
+        item_idx: u32,
+        item_name: &str,
+        parent: Option<u32>,
+    ) -> Result<(), Error> {
+        let mut st = to_wstring(item_name);
+        let mut item = get_menu_item_struct();
         item.fMask = MIIM_FTYPE | MIIM_STRING | MIIM_ID | MIIM_STATE;
         item.fType = MFT_STRING;
         item.wID = item_idx;
         item.dwTypeData = st.as_mut_ptr();
         item.cch = (item_name.len() * 2) as u32;
-        unsafe {
-            if winuser::InsertMenuItemW(self.info.hmenu, item_idx, 1, &item as *const MENUITEMINFOW)
-                == 0
-            {
-                return Err(get_win_os_error("Error inserting menu item"));
+        self.insert_item(item_idx, &item, parent, "menu item")
+    }
+
+    pub fn add_check_menu_entry(
+        &self,
+        item_idx: u32,
+        item_name: &str,
+        checked: bool,
+        parent: Option<u32>,
+    ) -> Result<(), Error> {
+        let mut st = to_wstring(item_name);
+        let mut item = get_menu_item_struct();
+        item.fMask = MIIM_FTYPE | MIIM_STRING | MIIM_ID | MIIM_STATE;
+        item.fType = MFT_STRING;
+        item.fState = if checked { MFS_CHECKED } else { 0 };
+        item.wID = item_idx;
+        item.dwTypeData = st.as_mut_ptr();
+        item.cch = (item_name.len() * 2) as u32;
+        self.insert_item(item_idx, &item, parent, "menu item")
+    }
+
+    pub fn add_submenu(
+        &self,
+        item_idx: u32,
+        item_name: &str,
+        parent: Option<u32>,
+    ) -> Result<(), Error> {
+        let submenu = unsafe { create_popup_menu()? };
+        let mut st = to_wstring(item_name);
+        let mut item = get_menu_item_struct();
+        item.fMask = MIIM_FTYPE | MIIM_STRING | MIIM_ID | MIIM_SUBMENU;
+        item.fType = MFT_STRING;
+        item.wID = item_idx;
+        item.hSubMenu = submenu;
+        item.dwTypeData = st.as_mut_ptr();
+        item.cch = (item_name.len() * 2) as u32;
+        if let Err(e) = self.insert_item(item_idx, &item, parent, "submenu") {
+            unsafe {
+                winuser::DestroyMenu(submenu);
             }
+            return Err(e);
         }
+        self.submenus.borrow_mut().insert(item_idx, submenu);
         Ok(())
     }
 
-    pub fn add_menu_separator(&self, item_idx: u32) -> Result<(), Error> {
+    // One pass over the entries with the menu maps borrowed once, counting
+    // each menu's items only the first time something goes into it. When an
+    // item can't be added, those added before it are taken out again, so
+    // the menu is as it was.
+    pub(crate) fn add_menu_entries(&self, entries: &[BatchEntry]) -> Result<(), Error> {
+        let mut parents = self.parents.borrow_mut();
+        let mut submenus = self.submenus.borrow_mut();
+        let mut ends: HashMap<HMENU, u32> = HashMap::new();
+        let mut added = Vec::with_capacity(entries.len());
+        let res = entries.iter().try_for_each(|entry| {
+            let (idx, parent, label) = match entry {
+                BatchEntry::Item {
+                    idx, label, parent, ..
+                }
+                | BatchEntry::Submenu { idx, label, parent } => (*idx, *parent, Some(label)),
+                BatchEntry::Separator { idx, parent } => (*idx, *parent, None),
+            };
+            let hmenu = parent
+                .and_then(|p| submenus.get(&p).copied())
+                .unwrap_or(self.info.hmenu);
+            let mut st = label.map(|label| to_wstring(label)).unwrap_or_default();
+            let mut item = get_menu_item_struct();
+            item.wID = idx;
+            item.fMask = MIIM_FTYPE | MIIM_ID;
+            if let Some(label) = label {
+                item.fMask |= MIIM_STRING;
+                item.fType = MFT_STRING;
+                item.dwTypeData = st.as_mut_ptr();
+                item.cch = (label.len() * 2) as u32;
+            }
+            let mut submenu = None;
+            match entry {
+                BatchEntry::Item { checked, .. } => {
+                    item.fMask |= MIIM_STATE;
+                    item.fState = if *checked == Some(true) { MFS_CHECKED } else { 0 };
+                }
+                BatchEntry::Separator { .. } => item.fType = MFT_SEPARATOR,
+                BatchEntry::Submenu { .. } => {
+                    let menu = unsafe { create_popup_menu()? };
+                    item.fMask |= MIIM_SUBMENU;
+                    item.hSubMenu = menu;
+                    submenu = Some(menu);
+                }
+            }
+            let end = ends
+                .entry(hmenu)
+                .or_insert_with(|| unsafe { winuser::GetMenuItemCount(hmenu).max(0) as u32 });
+            unsafe {
+                if winuser::InsertMenuItemW(hmenu, *end, TRUE, &item) == 0 {
+                    let e = get_win_os_error("Error inserting menu item");
+                    if let Some(menu) = submenu {
+                        winuser::DestroyMenu(menu);
+                    }
+                    return Err(e);
+                }
+            }
+            *end += 1;
+            parents.insert(idx, hmenu);
+            if let Some(menu) = submenu {
+                submenus.insert(idx, menu);
+            }
+            added.push((idx, hmenu));
+            Ok(())
+        });
+        if res.is_err() {
+            // Backwards, so submenus go after what's in them. DeleteMenu
+            // destroys a submenu along with its item.
+            for (idx, hmenu) in added.into_iter().rev() {
+                unsafe {
+                    winuser::DeleteMenu(hmenu, idx, MF_BYCOMMAND);
+                }
+                parents.remove(&idx);
+                submenus.remove(&idx);
+            }
+        }
+        res
+    }
+
+    pub fn add_menu_separator(&self, item_idx: u32, parent: Option<u32>) -> Result<(), Error> {
         let mut item = get_menu_item_struct();
-        item.fMask = MIIM_FTYPE;
+        item.fMask = MIIM_FTYPE | MIIM_ID;
         item.fType = MFT_SEPARATOR;
         item.wID = item_idx;
+        self.insert_item(item_idx, &item, parent, "separator")
+    }
+
+    pub fn set_menu_item_checked(&self, item_idx: u32, checked: bool) -> Result<(), Error> {
+        let flag = if checked { MF_CHECKED } else { MF_UNCHECKED };
         unsafe {
-            if winuser::InsertMenuItemW(self.info.hmenu, item_idx, 1, &item as *const MENUITEMINFOW)
-                == 0
-            {
-                return Err(get_win_os_error("Error inserting separator"));
+            let menu = self.container(None);
+            if winuser::CheckMenuItem(menu, item_idx, MF_BYCOMMAND | flag) == u32::MAX {
+                return Err(get_win_os_error("Error checking menu item"));
             }
         }
         Ok(())
     }
 
-    fn set_icon(&self, icon: HICON) -> Result<(), Error> {
+    pub fn set_menu_item_enabled(&self, item_idx: u32, enabled: bool) -> Result<(), Error> {
+        let flag = if enabled { MF_ENABLED } else { MF_GRAYED };
         unsafe {
-            let mut nid = get_nid_struct(&self.info.hwnd);
-            nid.uFlags = NIF_ICON;
-            nid.hIcon = icon;
-            if shellapi::Shell_NotifyIconW(NIM_MODIFY, &mut nid as *mut NOTIFYICONDATAW) == 0 {
-                return Err(get_win_os_error("Error setting icon"));
+            if winuser::EnableMenuItem(self.container(None), item_idx, MF_BYCOMMAND | flag) == -1 {
+                return Err(get_win_os_error("Error enabling menu item"));
             }
         }
         Ok(())
     }
 
-    pub fn set_icon_from_resource(&self, resource_name: &str) -> Result<(), Error> {
-        let icon;
+    // Windows keeps a default per menu, so the ones in submenus go too. The
+    // item is given by command id, not position.
+    pub fn set_default_menu_item(&self, item_idx: u32) -> Result<(), Error> {
+        let hmenu = match self.parents.borrow().get(&item_idx) {
+            Some(hmenu) => *hmenu,
+            None => return Err(Error::os(format!("No menu item {}", item_idx))),
+        };
         unsafe {
-            icon = winuser::LoadImageW(
-                self.info.hinstance,
-                to_wstring(resource_name).as_ptr(),
-                IMAGE_ICON,
-                64,
-                64,
-                0,
-            ) as HICON;
-            if std::ptr::eq(icon, std::ptr::null_mut()) {
-                return Err(get_win_os_error("Error setting icon from resource"));
+            for menu in self.submenus.borrow().values().chain([&self.info.hmenu]) {
+                winuser::SetMenuDefaultItem(*menu, !0, 0);
+            }
+            if winuser::SetMenuDefaultItem(hmenu, item_idx, 0) == 0 {
+                return Err(get_win_os_error("Error setting default menu item"));
             }
         }
-        self.set_icon(icon)
+        Ok(())
     }
 
-    pub fn set_icon_from_file(&self, icon_file: &str) -> Result<(), Error> {
-        let wstr_icon_file = to_wstring(icon_file);
-        let hicon;
+    // Owner drawn, as menus have no text styles of their own.
+    pub fn set_menu_item_style(
+        &self,
+        item_idx: u32,
+        label: &str,
+        style: MenuItemStyle,
+    ) -> Result<(), Error> {
+        let hmenu = match self.parents.borrow().get(&item_idx) {
+            Some(hmenu) => *hmenu,
+            None => return Err(Error::os(format!("No menu item {}", item_idx))),
+        };
+        self.info.styled_items.lock().unwrap().insert(
+            item_idx,
+            owner_draw::StyledItem {
+                label: to_wstring(label),
+                style,
+            },
+        );
+        let mut item = get_menu_item_struct();
+        item.fMask = MIIM_FTYPE;
+        item.fType = MFT_OWNERDRAW;
         unsafe {
-            hicon = winuser::LoadImageW(
-                std::ptr::null_mut() as HINSTANCE,
-                wstr_icon_file.as_ptr(),
-                IMAGE_ICON,
-                64,
-                64,
-                LR_LOADFROMFILE,
-            ) as HICON;
-            if std::ptr::eq(hicon, std::ptr::null_mut()) {
-                return Err(get_win_os_error("Error setting icon from file"));
+            let set = find_menu_position(hmenu, item_idx)
+                .map(|pos| winuser::SetMenuItemInfoW(hmenu, pos, TRUE, &item) != 0);
+            if set != Some(true) {
+                self.info.styled_items.lock().unwrap().remove(&item_idx);
+                return Err(get_win_os_error("Error styling menu item"));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_lazy_submenu(&self, item_idx: u32, builder: LazyBuilder) -> Result<(), Error> {
+        let submenu = self
+            .submenus
+            .borrow()
+            .get(&item_idx)
+            .copied()
+            .ok_or_else(|| Error::os(format!("Menu item {} is no submenu", item_idx)))?;
+        self.info
+            .lazy_menus
+            .lock()
+            .unwrap()
+            .insert(submenu as usize, builder);
+        Ok(())
+    }
+
+    pub fn remove_menu_item(&self, item_idx: u32) -> Result<(), Error> {
+        let hmenu = match self.parents.borrow_mut().remove(&item_idx) {
+            Some(hmenu) => hmenu,
+            None => return Ok(()),
+        };
+        // DeleteMenu destroys a submenu's popup along with the item.
+        unsafe {
+            if let Some(pos) = find_menu_position(hmenu, item_idx) {
+                if winuser::DeleteMenu(hmenu, pos, MF_BYPOSITION) == 0 {
+                    return Err(get_win_os_error("Error removing menu item"));
+                }
+            }
+        }
+        self.free_bitmap(item_idx);
+        self.info.styled_items.lock().unwrap().remove(&item_idx);
+        self.forget_submenu(item_idx);
+        Ok(())
+    }
+
+    // The taskbar shows icons as they are.
+    pub fn set_icon_template(&self, _template: bool) -> Result<(), Error> {
+        Ok(())
+    }
+
+    // Windows can't move an item, so it's taken out, with RemoveMenu as
+    // DeleteMenu would destroy its submenu, and put back where it goes. If
+    // it can't go there, it's put back where it was.
+    pub fn move_menu_item(&self, item_idx: u32, position: u32) -> Result<(), Error> {
+        let hmenu = match self.parents.borrow().get(&item_idx) {
+            Some(hmenu) => *hmenu,
+            None => return Err(Error::os(format!("No menu item {}", item_idx))),
+        };
+        unsafe {
+            let pos = match find_menu_position(hmenu, item_idx) {
+                Some(pos) => pos,
+                None => return Err(Error::os(format!("No menu item {}", item_idx))),
+            };
+            let mut item = get_menu_item_struct();
+            item.fMask = MIIM_STRING;
+            if winuser::GetMenuItemInfoW(hmenu, pos, TRUE, &mut item as *mut MENUITEMINFOW) == 0 {
+                return Err(get_win_os_error("Error reading menu item"));
+            }
+            let mut text = vec![0_u16; item.cch as usize + 1];
+            let mut item = get_menu_item_struct();
+            item.fMask = MIIM_BITMAP
+                | MIIM_FTYPE
+                | MIIM_ID
+                | MIIM_STATE
+                | MIIM_STRING
+                | MIIM_SUBMENU
+                | winuser::MIIM_DATA;
+            item.dwTypeData = text.as_mut_ptr();
+            item.cch = text.len() as u32;
+            if winuser::GetMenuItemInfoW(hmenu, pos, TRUE, &mut item as *mut MENUITEMINFOW) == 0 {
+                return Err(get_win_os_error("Error reading menu item"));
+            }
+            if winuser::RemoveMenu(hmenu, pos, MF_BYPOSITION) == 0 {
+                return Err(get_win_os_error("Error moving menu item"));
+            }
+            let count = winuser::GetMenuItemCount(hmenu).max(0) as u32;
+            let to = position.min(count);
+            if winuser::InsertMenuItemW(hmenu, to, TRUE, &item as *const MENUITEMINFOW) == 0 {
+                let e = get_win_os_error("Error moving menu item");
+                if winuser::InsertMenuItemW(hmenu, pos, TRUE, &item as *const MENUITEMINFOW) == 0 {
+                    // It's in no menu now, so nothing is kept for it either.
+                    if !item.hSubMenu.is_null() {
+                        winuser::DestroyMenu(item.hSubMenu);
+                    }
+                    self.parents.borrow_mut().remove(&item_idx);
+                    self.free_bitmap(item_idx);
+                    self.info.styled_items.lock().unwrap().remove(&item_idx);
+                    self.forget_submenu(item_idx);
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn free_bitmap(&self, item_idx: u32) {
+        if let Some(bmp) = self.bitmaps.borrow_mut().remove(&item_idx) {
+            unsafe {
+                wingdi::DeleteObject(bmp as _);
             }
         }
-        self.set_icon(hicon)
     }
 
-    pub fn set_icon_from_buffer(
+    // Menus draw 32bpp item bitmaps with premultiplied alpha.
+    pub fn set_menu_item_icon_rgba(
         &self,
-        buffer: &[u8],
+        item_idx: u32,
+        rgba: &[u8],
         width: u32,
         height: u32,
     ) -> Result<(), Error> {
-        let offset = unsafe {
-            winuser::LookupIconIdFromDirectoryEx(
-                buffer.as_ptr() as PBYTE,
-                TRUE,
-                width as i32,
-                height as i32,
-                LR_DEFAULTCOLOR,
-            )
+        let hmenu = match self.parents.borrow().get(&item_idx) {
+            Some(hmenu) => *hmenu,
+            None => return Err(Error::os(format!("No menu item {}", item_idx))),
         };
+        unsafe {
+            let mut dib = match Dib::new(width, height) {
+                Some(dib) => dib,
+                None => return Err(get_win_os_error("Error creating menu item bitmap")),
+            };
+            for (d, px) in dib.pixels().chunks_exact_mut(4).zip(rgba.chunks_exact(4)) {
+                let bgra = to_bgra(px);
+                let a = bgra[3] as u32;
+                for (d, c) in d.iter_mut().zip(&bgra[..3]) {
+                    *d = (*c as u32 * a / 255) as u8;
+                }
+                d[3] = bgra[3];
+            }
+            let mut item = get_menu_item_struct();
+            item.fMask = MIIM_BITMAP;
+            item.hbmpItem = dib.handle();
+            let set = find_menu_position(hmenu, item_idx)
+                .map(|pos| winuser::SetMenuItemInfoW(hmenu, pos, TRUE, &item) != 0);
+            if set != Some(true) {
+                return Err(get_win_os_error("Error setting menu item bitmap"));
+            }
+            if let Some(old) = self.bitmaps.borrow_mut().insert(item_idx, dib.into_raw()) {
+                wingdi::DeleteObject(old as _);
+            }
+        }
+        Ok(())
+    }
 
-        if offset != 0 {
-            let icon_data = &buffer[offset as usize..];
-            let hicon = unsafe {
-                winuser::CreateIconFromResourceEx(
-                    icon_data.as_ptr() as PBYTE,
-                    0,
-                    TRUE,
-                    0x30000,
-                    width as i32,
-                    height as i32,
-                    LR_DEFAULTCOLOR,
-                )
+    // Drops the bookkeeping for everything inside a deleted submenu.
+    fn forget_submenu(&self, item_idx: u32) {
+        let submenu = match self.submenus.borrow_mut().remove(&item_idx) {
+            Some(submenu) => submenu,
+            None => return,
+        };
+        self.info.lazy_menus.lock().unwrap().remove(&(submenu as usize));
+        let children: Vec<u32> = self
+            .parents
+            .borrow()
+            .iter()
+            .filter(|(_, m)| **m == submenu)
+            .map(|(c, _)| *c)
+            .collect();
+        for c in children {
+            self.parents.borrow_mut().remove(&c);
+            self.free_bitmap(c);
+            self.info.styled_items.lock().unwrap().remove(&c);
+            self.forget_submenu(c);
+        }
+    }
+
+    // A static icon ends any animation. Icons made here are `owned`.
+    fn set_icon(&self, icon: HICON, owned: bool) -> Result<(), Error> {
+        self.set_icon_from(icon, owned, None)
+    }
+
+    // Loads the icon at the tray's size, and again whenever that changes.
+    fn set_icon_from_origin(&self, origin: IconOrigin) -> Result<(), Error> {
+        let icon = unsafe { origin.load(self.icon_size())? };
+        self.set_icon_from(icon, true, Some(origin))
+    }
+
+    fn set_icon_from(
+        &self,
+        icon: HICON,
+        owned: bool,
+        origin: Option<IconOrigin>,
+    ) -> Result<(), Error> {
+        let (old, hidden) = {
+            let mut state = self.info.icon_state.lock().unwrap();
+            let old = state.replace_icon(icon, owned);
+            state.origin = origin;
+            if state.animation.take().is_some() {
+                self.kill_animation_timer();
+            }
+            (old, state.hidden)
+        };
+        unsafe {
+            let res = if hidden || show_icon(&self.info, icon) {
+                Ok(())
+            } else {
+                Err(get_shell_error("Error setting icon"))
             };
+            // The shell has its own copy of the new icon by now, or failed
+            // to take it and keeps showing its copy of the old one.
+            if let Some(old) = old {
+                winuser::DestroyIcon(old);
+            }
+            res
+        }
+    }
+
+    fn kill_animation_timer(&self) {
+        unsafe {
+            winuser::PostMessageW(self.info.hwnd, WM_STOP_ANIMATION, 0 as WPARAM, 0 as LPARAM);
+        }
+    }
+
+    pub fn start_icon_animation(
+        &self,
+        frames: &[IconFrame],
+        interval: Duration,
+    ) -> Result<(), Error> {
+        let mut animation = Animation {
+            frames: Vec::with_capacity(frames.len()),
+            next: 0,
+        };
+        for frame in frames {
+            // Dropping the animation frees the frames made so far.
+            animation
+                .frames
+                .push(create_icon(&frame.rgba, frame.width, frame.height)?);
+        }
+        // Frames of an earlier animation are freed here; the shell holds its
+        // own copy of whatever it shows.
+        self.info.icon_state.lock().unwrap().animation = Some(animation);
+        let ms = interval
+            .as_millis()
+            .clamp(winuser::USER_TIMER_MINIMUM as u128, u32::MAX as u128);
+        unsafe {
+            show_next_frame(&self.info);
+            winuser::PostMessageW(
+                self.info.hwnd,
+                WM_START_ANIMATION,
+                ms as WPARAM,
+                0 as LPARAM,
+            );
+        }
+        Ok(())
+    }
 
-            if std::ptr::eq(hicon, std::ptr::null_mut()) {
-                return Err(unsafe { get_win_os_error("Cannot load icon from the buffer") });
+    // Without an icon of its own the attention state blinks a blank one.
+    pub fn request_attention(&self, icon: Option<&IconFrame>) -> Result<(), Error> {
+        let icon = match icon {
+            Some(f) => create_icon(&f.rgba, f.width, f.height)?,
+            None => {
+                let size = self.icon_size();
+                create_icon(&vec![0; (size * size * 4) as usize], size, size)?
             }
+        };
+        // A replaced attention icon is freed here.
+        self.info.icon_state.lock().unwrap().attention = Some(Attention {
+            icon,
+            showing: false,
+        });
+        unsafe {
+            blink_attention(&self.info);
+            winuser::PostMessageW(self.info.hwnd, WM_START_ATTENTION, 0, 0);
+        }
+        Ok(())
+    }
 
-            self.set_icon(hicon)
-        } else {
-            Err(unsafe { get_win_os_error("Error setting icon from buffer") })
+    pub fn clear_attention(&self) -> Result<(), Error> {
+        let icon = {
+            let mut state = self.info.icon_state.lock().unwrap();
+            if state.attention.take().is_none() || state.hidden {
+                return Ok(());
+            }
+            base_icon(&state)
+        };
+        unsafe {
+            winuser::PostMessageW(self.info.hwnd, WM_STOP_ATTENTION, 0, 0);
+            if !show_icon(&self.info, icon) {
+                return Err(get_shell_error("Error restoring icon"));
+            }
+        }
+        Ok(())
+    }
+
+    // Puts the static icon back, if an animation was running.
+    pub fn stop_icon_animation(&self) -> Result<(), Error> {
+        let icon = {
+            let mut state = self.info.icon_state.lock().unwrap();
+            if state.animation.take().is_none() || state.hidden {
+                return Ok(());
+            }
+            state.icon
+        };
+        self.kill_animation_timer();
+        unsafe {
+            if !show_icon(&self.info, icon) {
+                return Err(get_shell_error("Error restoring icon"));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_icon_from_theme(&self, _icon_name: &str) -> Result<(), Error> {
+        Err(Error::NotImplementedError)
+    }
+
+    // The stock application icon is shared, so it's never destroyed.
+    pub fn clear_icon(&self) -> Result<(), Error> {
+        let icon = unsafe { winuser::LoadIconW(0 as HINSTANCE, winuser::IDI_APPLICATION) };
+        if icon.is_null() {
+            return Err(unsafe { get_win_os_error("Error loading the default icon") });
+        }
+        self.set_icon(icon, false)
+    }
+
+    pub fn set_icon_from_resource(&self, resource_name: &str) -> Result<(), Error> {
+        let name = to_wstring(resource_name);
+        self.set_icon_from_origin(IconOrigin::Resource(self.info.hinstance, name))
+    }
+
+    pub fn set_icon_from_resource_id(&self, id: u16) -> Result<(), Error> {
+        unsafe { self.set_icon_from_module_resource_id(self.info.hinstance, id) }
+    }
+
+    /// # Safety
+    ///
+    /// `module` has to be a loaded module, which stays the caller's. It has
+    /// to stay loaded while the icon is from it, which is loaded from it
+    /// again after DPI changes.
+    pub unsafe fn set_icon_from_module_resource_id(
+        &self,
+        module: HINSTANCE,
+        id: u16,
+    ) -> Result<(), Error> {
+        self.set_icon_from_origin(IconOrigin::ResourceId(module, id))
+    }
+
+    // With `owned` the handle is destroyed once it's replaced, like the
+    // icons made here, otherwise it stays the caller's.
+    pub fn set_icon_from_hicon(&self, hicon: HICON, owned: bool) -> Result<(), Error> {
+        if hicon.is_null() {
+            return Err(Error::os("Null HICON"));
+        }
+        self.set_icon(hicon, owned)
+    }
+
+    pub fn set_icon_from_file(&self, icon_file: &Path) -> Result<(), Error> {
+        self.set_icon_from_origin(IconOrigin::File(icon_file.to_path_buf()))
+    }
+
+    // Stays at `size` through DPI changes.
+    pub fn set_icon_from_file_sized(&self, icon_file: &Path, size: u32) -> Result<(), Error> {
+        self.set_icon(load_icon_file(icon_file, size)?, true)
+    }
+
+    pub fn set_icon_from_image_buffer(
+        &self,
+        buffer: &[u8],
+        width: u32,
+        height: u32,
+    ) -> Result<(), Error> {
+        if buffer.len() != (width as usize) * (height as usize) * 4 {
+            return Err(Error::os(format!(
+                "Icon buffer is {} bytes, expected {} for a {}x{} RGBA image",
+                buffer.len(),
+                (width as usize) * (height as usize) * 4,
+                width,
+                height
+            )));
+        }
+        let hicon = create_icon(buffer, width, height)?;
+        self.set_icon(hicon, true)
+    }
+
+    // The pixels are copied into the icon's DIB section either way.
+    pub fn set_icon_from_rgba(&self, rgba: Vec<u8>, width: u32, height: u32) -> Result<(), Error> {
+        self.set_icon_from_image_buffer(&rgba, width, height)
+    }
+
+    // One source at a time, the way the matching set_icon_from_* does it,
+    // for the Application to show what it has no pixels of.
+    pub fn apply_icon(&self, icon: IconSource<'_>) -> Result<(), Error> {
+        match icon {
+            IconSource::Path(file) => self.set_icon_from_file(file),
+            IconSource::Resource(resource) => self.set_icon_from_resource(resource),
+            IconSource::ResourceId(id) => self.set_icon_from_resource_id(id),
+            IconSource::ThemeName(name) => self.set_icon_from_theme(name),
+            IconSource::Rgba {
+                data,
+                width,
+                height,
+            } => self.set_icon_from_rgba(data.into_owned(), width, height),
+            // The Application decodes these, with the image crate.
+            IconSource::Encoded(_) => Err(Error::NotImplementedError),
+        }
+    }
+
+    pub fn scale_factor(&self) -> f64 {
+        scale_factor()
+    }
+
+    pub fn icon_size(&self) -> u32 {
+        icon_size()
+    }
+
+    // The hook may run on a thread holding the icon state's lock, so it
+    // doesn't wait for it: the icon goes unless the lock shows it's gone
+    // already. It only holds on to the state weakly, and does nothing but
+    // call the previous hook once the Window is gone.
+    pub fn remove_icon_on_panic(&self) -> Result<(), Error> {
+        if self.panic_hook.replace(true) {
+            return Ok(());
         }
+        let icon_state = Arc::downgrade(&self.info.icon_state);
+        // Window handles aren't Send, though they're good on any thread.
+        let hwnd = self.info.hwnd as usize;
+        let tray_id = self.info.tray_id;
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |panic| {
+            // The app goes on after panics in callbacks, icon and all.
+            let state = icon_state.upgrade().filter(|_| !crate::panic_is_caught());
+            if let Some(state) = state {
+                let gone = match state.try_lock() {
+                    Ok(mut state) => {
+                        let gone = state.removed || state.hidden;
+                        state.removed = true;
+                        gone
+                    }
+                    Err(_) => false,
+                };
+                if !gone {
+                    let mut nid = nid_struct(hwnd as HWND, tray_id);
+                    unsafe {
+                        notify_icon(NIM_DELETE, &mut nid);
+                    }
+                }
+            }
+            previous(panic);
+        }));
+        Ok(())
     }
 
+    // Removes the icon, then closes the window so the loop thread ends, which
+    // also disconnects the event channel. Safe to call more than once.
     pub fn shutdown(&self) -> Result<(), Error> {
+        let loop_thread = match self.windows_loop.borrow_mut().take() {
+            Some(t) => t,
+            None => return Ok(()),
+        };
+        let hidden = {
+            let mut state = self.info.icon_state.lock().unwrap();
+            state.removed = true;
+            // Nothing puts the icon up again, so it can go now.
+            state.destroy_icon();
+            state.hidden
+        };
+        let mut result = Ok(());
         unsafe {
-            let mut nid = get_nid_struct(&self.info.hwnd);
+            let mut nid = get_nid_struct(&self.info);
             nid.uFlags = NIF_ICON;
-            if shellapi::Shell_NotifyIconW(NIM_DELETE, &mut nid as *mut NOTIFYICONDATAW) == 0 {
-                return Err(get_win_os_error("Error deleting icon from menu"));
+            if !hidden && !notify_icon(NIM_DELETE, &mut nid) {
+                result = Err(get_shell_error("Error deleting icon from menu"));
             }
+            winuser::PostMessageW(self.info.hwnd, WM_CLOSE, 0 as WPARAM, 0 as LPARAM);
         }
-        Ok(())
+        crate::api::join_with_timeout(loop_thread, crate::api::SHUTDOWN_TIMEOUT);
+        result
     }
 }
 
 impl Drop for Window {
     fn drop(&mut self) {
         self.shutdown().ok();
+        for (_, bmp) in self.bitmaps.borrow_mut().drain() {
+            unsafe {
+                wingdi::DeleteObject(bmp as _);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An ICO file of `frames`, each a width (0 for 256) and its image data,
+    // laid out after the directory in order.
+    fn ico(frames: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut data = vec![0, 0, 1, 0];
+        data.extend_from_slice(&(frames.len() as u16).to_le_bytes());
+        let mut offset = 6 + frames.len() * 16;
+        for (width, frame) in frames {
+            data.extend_from_slice(&[*width, *width, 0, 0, 1, 0, 32, 0]);
+            data.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            data.extend_from_slice(&(offset as u32).to_le_bytes());
+            offset += frame.len();
+        }
+        for (_, frame) in frames {
+            data.extend_from_slice(frame);
+        }
+        data
+    }
+
+    #[test]
+    fn ico_frames_of_the_exact_size_are_picked() {
+        let data = ico(&[(16, b"16"), (32, b"32"), (48, b"48")]);
+        assert_eq!(pick_ico_frame(&data, 32), Some(&b"32"[..]));
+        assert_eq!(pick_ico_frame(&data, 16), Some(&b"16"[..]));
+    }
+
+    #[test]
+    fn ico_frames_are_scaled_down_from_the_next_larger_one() {
+        let data = ico(&[(0, b"256"), (16, b"16"), (48, b"48"), (64, b"64")]);
+        assert_eq!(pick_ico_frame(&data, 20), Some(&b"48"[..]));
+        assert_eq!(pick_ico_frame(&data, 100), Some(&b"256"[..]));
+    }
+
+    #[test]
+    fn ico_frames_are_scaled_up_from_the_largest_without_a_larger_one() {
+        let data = ico(&[(16, b"16"), (32, b"32")]);
+        assert_eq!(pick_ico_frame(&data, 64), Some(&b"32"[..]));
+    }
+
+    #[test]
+    fn single_frame_icos_give_their_frame() {
+        let data = ico(&[(32, b"32")]);
+        assert_eq!(pick_ico_frame(&data, 16), Some(&b"32"[..]));
+        assert_eq!(pick_ico_frame(&data, 64), Some(&b"32"[..]));
+    }
+
+    #[test]
+    fn png_frames_are_handed_over_as_they_are() {
+        let png = b"\x89PNG\r\n\x1a\n....";
+        let data = ico(&[(16, b"16"), (0, png)]);
+        assert_eq!(pick_ico_frame(&data, 256), Some(&png[..]));
+    }
+
+    #[test]
+    fn broken_ico_directories_give_no_frame() {
+        let data = ico(&[(16, b"16"), (32, b"32")]);
+        // Cut off in the second directory entry.
+        assert_eq!(pick_ico_frame(&data[..6 + 20], 16), None);
+        // Cut off in the last frame.
+        assert_eq!(pick_ico_frame(&data[..data.len() - 1], 16), None);
+        // A cursor rather than an icon.
+        let mut cursor = data.clone();
+        cursor[2] = 2;
+        assert_eq!(pick_ico_frame(&cursor, 16), None);
+        assert_eq!(pick_ico_frame(&data[..4], 16), None);
+        assert_eq!(pick_ico_frame(b"\x89PNG\r\n\x1a\n", 16), None);
+        // A frame that would end past the end of memory.
+        let mut huge = data;
+        huge[6 + 8..6 + 12].copy_from_slice(&u32::MAX.to_le_bytes());
+        huge[6 + 12..6 + 16].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(pick_ico_frame(&huge, 16), None);
+    }
+
+    // Reads the color bitmap of `icon` back as top-down BGRA rows.
+    unsafe fn icon_bgra(icon: HICON, width: u32, height: u32) -> Vec<u8> {
+        let mut info: winuser::ICONINFO = std::mem::zeroed();
+        assert_ne!(winuser::GetIconInfo(icon, &mut info), 0);
+        let mut bmi: wingdi::BITMAPINFO = std::mem::zeroed();
+        bmi.bmiHeader.biSize = std::mem::size_of::<wingdi::BITMAPINFOHEADER>() as DWORD;
+        bmi.bmiHeader.biWidth = width as i32;
+        bmi.bmiHeader.biHeight = -(height as i32);
+        bmi.bmiHeader.biPlanes = 1;
+        bmi.bmiHeader.biBitCount = 32;
+        bmi.bmiHeader.biCompression = wingdi::BI_RGB;
+        let mut bgra = vec![0_u8; width as usize * height as usize * 4];
+        let dc = winuser::GetDC(std::ptr::null_mut());
+        let rows = wingdi::GetDIBits(
+            dc,
+            info.hbmColor,
+            0,
+            height,
+            bgra.as_mut_ptr() as _,
+            &mut bmi,
+            wingdi::DIB_RGB_COLORS,
+        );
+        winuser::ReleaseDC(std::ptr::null_mut(), dc);
+        wingdi::DeleteObject(info.hbmColor as _);
+        wingdi::DeleteObject(info.hbmMask as _);
+        assert_eq!(rows, height as i32);
+        bgra
+    }
+
+    // Every pixel tells where it is, so a flipped or sheared row shows.
+    fn positions(width: u32, height: u32) -> Vec<u8> {
+        (0..height)
+            .flat_map(|y| (0..width).flat_map(move |x| [x as u8, y as u8, (x ^ y) as u8, 255]))
+            .collect()
+    }
+
+    #[test]
+    fn buffer_icons_keep_their_rows_top_down_and_unsheared() {
+        for (width, height) in [(15, 15), (16, 16), (17, 17), (17, 5), (5, 17), (16, 3), (1, 9)] {
+            let rgba = positions(width, height);
+            let icon = create_icon(&rgba, width, height).unwrap();
+            let bgra = unsafe { icon_bgra(icon, width, height) };
+            unsafe {
+                winuser::DestroyIcon(icon);
+            }
+            let expected: Vec<u8> = rgba.chunks_exact(4).flat_map(to_bgra).collect();
+            assert!(bgra == expected, "{}x{} icon came back changed", width, height);
+        }
+    }
+
+    // 16x16: an opaque square with a shadow fading out below and right of
+    // it, a band at 50% alpha, and white where it's fully transparent, which
+    // would show as a halo unless it's blacked out.
+    fn soft_edged_icon() -> Vec<u8> {
+        let mut rgba = Vec::new();
+        for y in 0..16_u32 {
+            for x in 0..16_u32 {
+                let px = if (4..10).contains(&x) && (4..10).contains(&y) {
+                    [200, 30, 30, 255]
+                } else if (5..13).contains(&x) && (5..13).contains(&y) {
+                    [0, 0, 0, 160 >> (x.max(y) - 10)]
+                } else if y < 2 {
+                    [30, 60, 220, 128]
+                } else {
+                    [255, 255, 255, 0]
+                };
+                rgba.extend_from_slice(&px);
+            }
+        }
+        rgba
+    }
+
+    // Missing from winapi's winuser.
+    const DI_NORMAL: UINT = 0x0003;
+
+    // Draws `icon` over a taskbar colored `background` and reads the result
+    // back as BGRA.
+    unsafe fn draw_over(icon: HICON, background: [u8; 3]) -> Vec<u8> {
+        let mut dib = Dib::new(16, 16).unwrap();
+        for px in dib.pixels().chunks_exact_mut(4) {
+            px.copy_from_slice(&[background[2], background[1], background[0], 255]);
+        }
+        let dc = wingdi::CreateCompatibleDC(std::ptr::null_mut());
+        let old = wingdi::SelectObject(dc, dib.handle() as _);
+        let drawn = winuser::DrawIconEx(
+            dc,
+            0,
+            0,
+            icon,
+            16,
+            16,
+            0,
+            std::ptr::null_mut(),
+            DI_NORMAL,
+        );
+        wingdi::GdiFlush();
+        let bgra = dib.pixels().to_vec();
+        wingdi::SelectObject(dc, old);
+        wingdi::DeleteDC(dc);
+        assert_ne!(drawn, 0);
+        bgra
+    }
+
+    #[test]
+    fn soft_edges_blend_with_light_and_dark_taskbars() {
+        let rgba = soft_edged_icon();
+        let icon = create_icon(&rgba, 16, 16).unwrap();
+        for background in [[0xf3, 0xf3, 0xf3], [0x20, 0x20, 0x20]] {
+            let drawn = unsafe { draw_over(icon, background) };
+            for (i, (px, out)) in rgba.chunks_exact(4).zip(drawn.chunks_exact(4)).enumerate() {
+                let alpha = px[3] as i32;
+                for c in 0..3 {
+                    let over = background[c] as i32 * (255 - alpha);
+                    let expected = (px[c] as i32 * alpha + over) / 255;
+                    let got = out[2 - c] as i32;
+                    assert!(
+                        (got - expected).abs() <= 3,
+                        "pixel {} channel {} over {:?} is {} instead of {}",
+                        i,
+                        c,
+                        background,
+                        got,
+                        expected
+                    );
+                }
+            }
+        }
+        unsafe {
+            winuser::DestroyIcon(icon);
+        }
+    }
+
+    #[test]
+    fn transparent_buffer_pixels_come_back_black() {
+        let mut rgba = positions(17, 3);
+        rgba[4..8].copy_from_slice(&[255, 255, 255, 0]);
+        let icon = create_icon(&rgba, 17, 3).unwrap();
+        let bgra = unsafe { icon_bgra(icon, 17, 3) };
+        unsafe {
+            winuser::DestroyIcon(icon);
+        }
+        assert_eq!(bgra[4..8], [0, 0, 0, 0]);
+        assert_eq!(bgra[8..12], to_bgra(&rgba[8..12]));
     }
 }
@@ -0,0 +1,137 @@
+// Menu items from add_menu_item_styled, which Windows leaves to the window to
+// measure and draw. The label has to be kept here, as owner drawn items
+// don't hold one.
+use crate::MenuItemStyle;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use winapi::{
+    ctypes::c_void,
+    shared::{
+        minwindef::{TRUE, UINT},
+        windef::{HFONT, HWND, RECT},
+    },
+    um::{wingdi, winuser},
+};
+
+// Room left and right of the label, on top of the check mark column
+// Windows adds by itself.
+const PADDING: i32 = 8;
+const CHECK_MARK: [u16; 2] = [0x2713, 0];
+
+pub struct StyledItem {
+    // Nul terminated, in the mnemonic syntax of add_menu_item_with_mnemonic.
+    pub label: Vec<u16>,
+    pub style: MenuItemStyle,
+}
+
+pub type StyledItems = Arc<Mutex<HashMap<u32, StyledItem>>>;
+
+// The system menu font, made bold or italic as the style and default-ness
+// of the item want.
+unsafe fn menu_font(style: &MenuItemStyle, default: bool) -> HFONT {
+    let mut metrics: winuser::NONCLIENTMETRICSW = std::mem::zeroed();
+    metrics.cbSize = std::mem::size_of::<winuser::NONCLIENTMETRICSW>() as UINT;
+    winuser::SystemParametersInfoW(
+        winuser::SPI_GETNONCLIENTMETRICS,
+        metrics.cbSize,
+        &mut metrics as *mut _ as *mut c_void,
+        0,
+    );
+    let mut font = metrics.lfMenuFont;
+    if style.bold || default {
+        font.lfWeight = wingdi::FW_BOLD;
+    }
+    if style.italic {
+        font.lfItalic = TRUE as u8;
+    }
+    wingdi::CreateFontIndirectW(&font)
+}
+
+// Answers WM_MEASUREITEM, false for items that aren't ours.
+pub unsafe fn measure_item(
+    hwnd: HWND,
+    items: &StyledItems,
+    measure: &mut winuser::MEASUREITEMSTRUCT,
+) -> bool {
+    if measure.CtlType != winuser::ODT_MENU {
+        return false;
+    }
+    let items = items.lock().unwrap();
+    let item = match items.get(&measure.itemID) {
+        Some(item) => item,
+        None => return false,
+    };
+    let hdc = winuser::GetDC(hwnd);
+    // Bold for the default item too, so it fits either way.
+    let font = menu_font(&item.style, true);
+    let old_font = wingdi::SelectObject(hdc, font as _);
+    let mut rect: RECT = std::mem::zeroed();
+    winuser::DrawTextW(
+        hdc,
+        item.label.as_ptr(),
+        -1,
+        &mut rect,
+        winuser::DT_SINGLELINE | winuser::DT_CALCRECT,
+    );
+    wingdi::SelectObject(hdc, old_font);
+    wingdi::DeleteObject(font as _);
+    winuser::ReleaseDC(hwnd, hdc);
+    measure.itemWidth = (rect.right - rect.left + 2 * PADDING) as UINT;
+    measure.itemHeight = (rect.bottom - rect.top + PADDING / 2)
+        .max(winuser::GetSystemMetrics(winuser::SM_CYMENU)) as UINT;
+    true
+}
+
+// Answers WM_DRAWITEM, false for items that aren't ours. Highlighted and
+// disabled items take the system colors, so they read like the others.
+pub unsafe fn draw_item(items: &StyledItems, draw: &winuser::DRAWITEMSTRUCT) -> bool {
+    if draw.CtlType != winuser::ODT_MENU {
+        return false;
+    }
+    let items = items.lock().unwrap();
+    let item = match items.get(&draw.itemID) {
+        Some(item) => item,
+        None => return false,
+    };
+    let state = draw.itemState;
+    let selected = state & winuser::ODS_SELECTED != 0;
+    let grayed = state & (winuser::ODS_GRAYED | winuser::ODS_DISABLED) != 0;
+    let background = if selected {
+        winuser::COLOR_HIGHLIGHT
+    } else {
+        winuser::COLOR_MENU
+    };
+    winuser::FillRect(draw.hDC, &draw.rcItem, winuser::GetSysColorBrush(background));
+    let color = match item.style.color {
+        _ if grayed => winuser::GetSysColor(winuser::COLOR_GRAYTEXT),
+        _ if selected => winuser::GetSysColor(winuser::COLOR_HIGHLIGHTTEXT),
+        Some([r, g, b]) => wingdi::RGB(r, g, b),
+        None => winuser::GetSysColor(winuser::COLOR_MENUTEXT),
+    };
+    let font = menu_font(&item.style, state & winuser::ODS_DEFAULT != 0);
+    let old_font = wingdi::SelectObject(draw.hDC, font as _);
+    wingdi::SetTextColor(draw.hDC, color);
+    wingdi::SetBkMode(draw.hDC, wingdi::TRANSPARENT as i32);
+    let check_width = winuser::GetSystemMetrics(winuser::SM_CXMENUCHECK);
+    let mut flags = winuser::DT_SINGLELINE | winuser::DT_VCENTER;
+    if state & winuser::ODS_NOACCEL != 0 {
+        flags |= winuser::DT_HIDEPREFIX;
+    }
+    if state & winuser::ODS_CHECKED != 0 {
+        let mut rect = draw.rcItem;
+        rect.right = rect.left + check_width + PADDING;
+        winuser::DrawTextW(
+            draw.hDC,
+            CHECK_MARK.as_ptr(),
+            -1,
+            &mut rect,
+            flags | winuser::DT_CENTER,
+        );
+    }
+    let mut rect = draw.rcItem;
+    rect.left += check_width + PADDING;
+    winuser::DrawTextW(draw.hDC, item.label.as_ptr(), -1, &mut rect, flags);
+    wingdi::SelectObject(draw.hDC, old_font);
+    wingdi::DeleteObject(font as _);
+    true
+}
@@ -0,0 +1,113 @@
+// Windows 11 moves new tray icons into the overflow flyout. It keeps a key
+// per icon it has seen under NotifyIconSettings, with the user's choice in
+// IsPromoted, which is the switch the taskbar settings flip. There's no API
+// for it, and Windows 10 has no such keys at all.
+use super::to_wstring;
+use crate::Error;
+use std::ffi::OsString;
+use std::os::windows::ffi::OsStringExt;
+use std::path::Path;
+use winapi::shared::minwindef::{DWORD, HKEY};
+use winapi::shared::winerror::ERROR_SUCCESS;
+use winapi::um::winnt::{KEY_READ, KEY_SET_VALUE, REG_DWORD};
+use winapi::um::winreg;
+
+const SETTINGS: &str = "Control Panel\\NotifyIconSettings";
+
+// Promotes every icon Explorer has settings for from this executable. False
+// if it has none yet, as before the icon was first shown.
+pub(crate) fn promote() -> Result<bool, Error> {
+    let exe = std::env::current_exe()
+        .map_err(|e| Error::from_io("Failed to find the executable", &e))?;
+    let mut key: HKEY = std::ptr::null_mut();
+    let status = unsafe {
+        winreg::RegOpenKeyExW(
+            winreg::HKEY_CURRENT_USER,
+            to_wstring(SETTINGS).as_ptr(),
+            0,
+            KEY_READ | KEY_SET_VALUE,
+            &mut key,
+        )
+    };
+    if status != ERROR_SUCCESS as i32 {
+        warn!("No {}, so Windows doesn't overflow tray icons", SETTINGS);
+        return Err(Error::NotImplementedError);
+    }
+    let mut promoted = false;
+    for index in 0.. {
+        let mut name = [0_u16; 256];
+        let mut len = name.len() as DWORD;
+        let status = unsafe {
+            winreg::RegEnumKeyExW(
+                key,
+                index,
+                name.as_mut_ptr(),
+                &mut len,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+            )
+        };
+        if status != ERROR_SUCCESS as i32 {
+            break;
+        }
+        let ours = executable_path(key, &name).is_some_and(|path| same_executable(&path, &exe));
+        if !ours {
+            continue;
+        }
+        let one: DWORD = 1;
+        let status = unsafe {
+            winreg::RegSetKeyValueW(
+                key,
+                name.as_ptr(),
+                to_wstring("IsPromoted").as_ptr(),
+                REG_DWORD,
+                &one as *const DWORD as *const _,
+                std::mem::size_of::<DWORD>() as DWORD,
+            )
+        };
+        if status == ERROR_SUCCESS as i32 {
+            promoted = true;
+        } else {
+            warn!("Failed to promote the tray icon: error {}", status);
+        }
+    }
+    unsafe {
+        winreg::RegCloseKey(key);
+    }
+    Ok(promoted)
+}
+
+// `name` is NUL terminated, as RegEnumKeyExW leaves it.
+fn executable_path(key: HKEY, name: &[u16]) -> Option<String> {
+    let mut data = [0_u16; 1024];
+    let mut size = std::mem::size_of_val(&data) as DWORD;
+    let status = unsafe {
+        winreg::RegGetValueW(
+            key,
+            name.as_ptr(),
+            to_wstring("ExecutablePath").as_ptr(),
+            winreg::RRF_RT_REG_SZ,
+            std::ptr::null_mut(),
+            data.as_mut_ptr() as *mut _,
+            &mut size,
+        )
+    };
+    if status != ERROR_SUCCESS as i32 {
+        return None;
+    }
+    let len = data.iter().position(|&c| c == 0).unwrap_or(data.len());
+    OsString::from_wide(&data[..len]).into_string().ok()
+}
+
+// Paths under known folders start with the folder's GUID instead, as in
+// "{6D809377-6AF0-444B-8957-A3773F02200E}\App\app.exe" for Program Files.
+fn same_executable(path: &str, exe: &Path) -> bool {
+    let exe = exe.to_string_lossy().to_lowercase();
+    let path = path.to_lowercase();
+    match path.strip_prefix('{').and_then(|rest| rest.split_once('}')) {
+        Some((_, relative)) => exe.ends_with(relative),
+        None => exe == path,
+    }
+}
@@ -0,0 +1,133 @@
+// Toast notifications through WinRT, for Windows 10 and later. A click on the
+// toast or one of its buttons comes back through Activated, with the action's
+// key as the arguments.
+use crate::api::EventSender;
+use crate::{Error, SystrayEvent};
+use winapi::{
+    shared::{minwindef::DWORD, ntdef::PWSTR},
+    um::{combaseapi::CoTaskMemFree, winnt::OSVERSIONINFOW},
+};
+use windows::{
+    core::{IInspectable, Interface, HSTRING},
+    Data::Xml::Dom::XmlDocument,
+    Foundation::TypedEventHandler,
+    UI::Notifications::{ToastActivatedEventArgs, ToastNotification, ToastNotificationManager},
+};
+
+// Toasts only show for an AppUserModelID the Start menu knows, which
+// unpackaged apps don't have unless their shortcut carries one. Without an
+// id from set_app_id they go out as PowerShell's, which is always there.
+const FALLBACK_APP_ID: &str =
+    "{1AC14E77-02E7-4E5D-B744-2EB1AE5198B7}\\WindowsPowerShell\\v1.0\\powershell.exe";
+
+#[link(name = "ntdll")]
+extern "system" {
+    fn RtlGetVersion(info: *mut OSVERSIONINFOW) -> i32;
+}
+
+#[link(name = "shell32")]
+extern "system" {
+    fn GetCurrentProcessExplicitAppUserModelID(app_id: *mut PWSTR) -> i32;
+}
+
+// The process's id, from set_app_id or the app's own call.
+fn app_id() -> Option<String> {
+    unsafe {
+        let mut id: PWSTR = std::ptr::null_mut();
+        if GetCurrentProcessExplicitAppUserModelID(&mut id) < 0 || id.is_null() {
+            return None;
+        }
+        let len = (0..).take_while(|&i| *id.add(i) != 0).count();
+        let app_id = String::from_utf16_lossy(std::slice::from_raw_parts(id, len));
+        CoTaskMemFree(id as _);
+        Some(app_id)
+    }
+}
+
+// GetVersionEx reports 8 to processes without a manifest saying otherwise,
+// RtlGetVersion tells the truth.
+pub fn available() -> bool {
+    unsafe {
+        let mut info: OSVERSIONINFOW = std::mem::zeroed();
+        info.dwOSVersionInfoSize = std::mem::size_of::<OSVERSIONINFOW>() as DWORD;
+        RtlGetVersion(&mut info) == 0 && info.dwMajorVersion >= 10
+    }
+}
+
+fn toast_error(e: windows::core::Error) -> Error {
+    Error::OsError {
+        code: e.code().0,
+        message: format!("Toast error: {}", e.message()),
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn toast_xml(
+    summary: &str,
+    body: &str,
+    image: Option<&str>,
+    actions: &[(String, String)],
+) -> String {
+    let mut xml = format!(
+        "<toast launch=\"default\"><visual><binding template=\"ToastGeneric\">\
+         <text>{}</text><text>{}</text>",
+        escape(summary),
+        escape(body)
+    );
+    if let Some(image) = image {
+        xml += &format!(
+            "<image placement=\"appLogoOverride\" src=\"file:///{}\"/>",
+            escape(&image.replace('\\', "/"))
+        );
+    }
+    xml += "</binding></visual>";
+    if !actions.is_empty() {
+        xml += "<actions>";
+        for (key, label) in actions {
+            xml += &format!(
+                "<action content=\"{}\" arguments=\"{}\"/>",
+                escape(label),
+                escape(key)
+            );
+        }
+        xml += "</actions>";
+    }
+    xml + "</toast>"
+}
+
+pub fn show(
+    summary: &str,
+    body: &str,
+    image: Option<&str>,
+    actions: &[(String, String)],
+    event_tx: EventSender,
+) -> Result<(), Error> {
+    let doc = XmlDocument::new().map_err(toast_error)?;
+    doc.LoadXml(&HSTRING::from(toast_xml(summary, body, image, actions)))
+        .map_err(toast_error)?;
+    let toast = ToastNotification::CreateToastNotification(&doc).map_err(toast_error)?;
+    toast
+        .Activated(&TypedEventHandler::new(
+            move |_: &Option<ToastNotification>, args: &Option<IInspectable>| {
+                if let Some(args) = args {
+                    let args: ToastActivatedEventArgs = args.cast()?;
+                    let action_key = args.Arguments()?.to_string_lossy();
+                    event_tx
+                        .send(SystrayEvent::NotificationAction { action_key })
+                        .ok();
+                }
+                Ok(())
+            },
+        ))
+        .map_err(toast_error)?;
+    let app_id = app_id().unwrap_or_else(|| FALLBACK_APP_ID.to_string());
+    ToastNotificationManager::CreateToastNotifierWithId(&HSTRING::from(app_id))
+        .and_then(|notifier| notifier.Show(&toast))
+        .map_err(toast_error)
+}
@@ -0,0 +1,77 @@
+// Getting the user's attention, e.g. when a job finished while they were away.
+use crate::{Application, Error, IconFrame, IconSource, Result};
+
+impl Application {
+    /// Makes the icon ask for attention until clear_attention. On Windows
+    /// and with GtkStatusIcon the icon blinks between itself and
+    /// `attention_icon`, or nothing when that's None. AppIndicator and
+    /// StatusNotifierItem switch to the NeedsAttention status, which the
+    /// desktop shows with `attention_icon` or its own way. macOS returns
+    /// NotImplementedError.
+    ///
+    /// Clicks on the icon and opening its menu clear the attention again,
+    /// unless set_clear_attention_on_click(false) turned that off.
+    /// Resource and theme icons can't be used as the attention icon.
+    pub fn request_attention(&self, attention_icon: Option<IconSource<'_>>) -> Result<()> {
+        let frame = attention_icon.map(attention_frame).transpose()?;
+        self.window.request_attention(frame.as_ref())?;
+        self.attention.set(true);
+        Ok(())
+    }
+
+    /// Puts the icon back the way it was before request_attention.
+    pub fn clear_attention(&self) -> Result<()> {
+        if !self.attention.replace(false) {
+            return Ok(());
+        }
+        self.window.clear_attention()
+    }
+
+    /// Whether clicking the icon or opening its menu clears the attention.
+    /// On by default.
+    pub fn set_clear_attention_on_click(&self, enabled: bool) {
+        self.clear_attention_on_click.set(enabled);
+    }
+
+    pub(crate) fn attention_acknowledged(&self) {
+        if self.attention.get() && self.clear_attention_on_click.get() {
+            self.clear_attention()
+                .map_err(|e| warn!("Failed to clear attention: {}", e))
+                .ok();
+        }
+    }
+}
+
+fn attention_frame(icon: IconSource<'_>) -> Result<IconFrame> {
+    match icon {
+        IconSource::Rgba {
+            data,
+            width,
+            height,
+        } => IconFrame::from_rgba(data.into_owned(), width, height),
+        #[cfg(feature = "image-icons")]
+        IconSource::Path(file) => image::open(file)
+            .map(|image| IconFrame::from_image(&image))
+            .map_err(|e| {
+                Error::from_image(format!("Failed to load icon {}", file.display()), &e)
+            }),
+        #[cfg(feature = "image-icons")]
+        IconSource::Encoded(data) => image::load_from_memory(data)
+            .map(|image| IconFrame::from_image(&image))
+            .map_err(|e| Error::from_image("Failed to decode icon", &e)),
+        #[cfg(not(feature = "image-icons"))]
+        IconSource::Path(_) | IconSource::Encoded(_) => Err(Error::NotImplementedError),
+        IconSource::Resource(resource) => Err(Error::os(format!(
+            "Icon resource {} can't be an attention icon",
+            resource
+        ))),
+        IconSource::ResourceId(id) => Err(Error::os(format!(
+            "Icon resource {} can't be an attention icon",
+            id
+        ))),
+        IconSource::ThemeName(name) => Err(Error::os(format!(
+            "Theme icon {} can't be an attention icon",
+            name
+        ))),
+    }
+}
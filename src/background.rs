@@ -0,0 +1,263 @@
+// An Application on a thread of its own, for apps whose main thread has
+// other work than waiting for tray events. The Application can't leave the
+// thread it was made on, so everything reaches it as a closure.
+use crate::{
+    api, themed_icon::OwnedIconSource, Application, Error, IconSource, InitOptions, MenuItemId,
+    Result, SystrayEvent, TruncationBehavior,
+};
+use std::error;
+use std::sync::mpsc::{channel, Sender};
+use std::thread::{self, JoinHandle};
+
+type Command = Box<dyn FnOnce(&mut Application) + Send + 'static>;
+
+// What the tray thread waits for: events from the platform, and commands
+// from the handles.
+enum Input {
+    Event(SystrayEvent),
+    // The platform's event channel closed.
+    Closed,
+    Command(Command),
+}
+
+/// Drives an Application on its background thread, see new_in_background.
+/// Clones drive the same one. The setters are the Application's own, and
+/// anything else it does is reached through run.
+///
+/// Every call waits for the tray thread to get to it, so calling these from
+/// the Application's own callbacks, which run on that thread, never returns.
+#[derive(Clone)]
+pub struct TrayHandle {
+    tx: Sender<Input>,
+}
+
+impl TrayHandle {
+    /// Runs `f` with the Application on its thread and hands back what it
+    /// returns.
+    pub fn run<F, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Application) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = channel();
+        let command: Command = Box::new(move |app: &mut Application| {
+            tx.send(f(app)).ok();
+        });
+        self.tx
+            .send(Input::Command(command))
+            .map_err(|_| Error::os("The tray thread is gone"))?;
+        rx.recv().map_err(|_| Error::os("The tray thread is gone"))
+    }
+
+    pub fn add_menu_item<F, E>(&self, item_name: &str, f: F) -> Result<MenuItemId>
+    where
+        F: FnMut(&mut Application) -> std::result::Result<(), E> + Send + Sync + 'static,
+        E: error::Error + Send + Sync + 'static,
+    {
+        let item_name = item_name.to_string();
+        self.run(move |app: &mut Application| app.add_menu_item(&item_name, f))?
+    }
+
+    pub fn add_check_menu_item<F, E>(
+        &self,
+        key: &str,
+        item_name: &str,
+        checked: bool,
+        f: F,
+    ) -> Result<MenuItemId>
+    where
+        F: FnMut(&mut Application) -> std::result::Result<(), E> + Send + Sync + 'static,
+        E: error::Error + Send + Sync + 'static,
+    {
+        let key = key.to_string();
+        let item_name = item_name.to_string();
+        self.run(move |app: &mut Application| {
+            app.add_check_menu_item(&key, &item_name, checked, f)
+        })?
+    }
+
+    pub fn add_menu_separator(&self) -> Result<MenuItemId> {
+        self.run(|app: &mut Application| app.add_menu_separator())?
+    }
+
+    pub fn add_menu_label(&self, text: &str) -> Result<MenuItemId> {
+        let text = text.to_string();
+        self.run(move |app: &mut Application| app.add_menu_label(&text))?
+    }
+
+    pub fn add_menu_section_header(&self, label: &str) -> Result<MenuItemId> {
+        let label = label.to_string();
+        self.run(move |app: &mut Application| app.add_menu_section_header(&label))?
+    }
+
+    pub fn move_menu_item(&self, id: MenuItemId, position: u32) -> Result<()> {
+        self.run(move |app: &mut Application| app.move_menu_item(id, position))?
+    }
+
+    pub fn remove_menu_item(&self, id: MenuItemId) -> Result<()> {
+        self.run(move |app: &mut Application| app.remove_menu_item(id))?
+    }
+
+    pub fn set_menu_item_checked(&self, id: MenuItemId, checked: bool) -> Result<()> {
+        self.run(move |app: &mut Application| app.set_menu_item_checked(id, checked))?
+    }
+
+    pub fn set_menu_item_enabled(&self, id: MenuItemId, enabled: bool) -> Result<()> {
+        self.run(move |app: &mut Application| app.set_menu_item_enabled(id, enabled))?
+    }
+
+    pub fn set_default_menu_item(&self, id: MenuItemId) -> Result<()> {
+        self.run(move |app: &mut Application| app.set_default_menu_item(id))?
+    }
+
+    pub fn set_menu_item_icon_rgba(
+        &self,
+        id: MenuItemId,
+        rgba: &[u8],
+        width: u32,
+        height: u32,
+    ) -> Result<()> {
+        let rgba = rgba.to_vec();
+        self.run(move |app: &mut Application| {
+            app.set_menu_item_icon_rgba(id, &rgba, width, height)
+        })?
+    }
+
+    /// The icon goes to the tray thread as a copy.
+    pub fn set_icon(&self, icon: IconSource<'_>) -> Result<()> {
+        let icon = OwnedIconSource::from(icon);
+        self.run(move |app: &mut Application| app.set_icon(icon.source()))?
+    }
+
+    pub fn clear_icon(&self) -> Result<()> {
+        self.run(|app: &mut Application| app.clear_icon())?
+    }
+
+    #[deprecated(note = "use set_icon with IconSource::Path")]
+    pub fn set_icon_from_file(&self, file: &str) -> Result<()> {
+        self.set_icon(IconSource::Path(std::path::Path::new(file)))
+    }
+
+    pub fn set_tooltip(&self, tooltip: &str) -> Result<()> {
+        let tooltip = tooltip.to_string();
+        self.run(move |app: &mut Application| app.set_tooltip(&tooltip))?
+    }
+
+    pub fn set_tooltip_ex(&self, tooltip: &str, behavior: TruncationBehavior) -> Result<()> {
+        let tooltip = tooltip.to_string();
+        self.run(move |app: &mut Application| app.set_tooltip_ex(&tooltip, behavior))?
+    }
+
+    pub fn set_icon_visible(&self, visible: bool) -> Result<()> {
+        self.run(move |app: &mut Application| app.set_icon_visible(visible))?
+    }
+
+    pub fn show_icon(&self) -> Result<()> {
+        self.set_icon_visible(true)
+    }
+
+    pub fn hide_icon(&self) -> Result<()> {
+        self.set_icon_visible(false)
+    }
+
+    pub fn set_menu_on_left_click(&self, enabled: bool) -> Result<()> {
+        self.run(move |app: &mut Application| app.set_menu_on_left_click(enabled))
+    }
+
+    pub fn set_suppress_default_menu(&self, suppress: bool) -> Result<()> {
+        self.run(move |app: &mut Application| app.set_suppress_default_menu(suppress))
+    }
+
+    /// Ends the tray thread, like Application::quit.
+    pub fn quit(&self) -> Result<()> {
+        self.run(|app: &mut Application| app.quit())
+    }
+}
+
+impl Application {
+    /// Makes an Application on a new thread, which then runs its callbacks
+    /// as wait_for_message would, until the Application quits or a callback
+    /// fails. The thread's result is what wait_for_message would return.
+    ///
+    /// macOS returns NotImplementedError, as AppKit only runs on the main
+    /// thread.
+    pub fn new_in_background(options: InitOptions) -> Result<(TrayHandle, JoinHandle<Result<()>>)> {
+        Self::into_background_thread(options, |_: &mut Application| Ok(()))
+    }
+
+    /// Like new_in_background, with the Application set up by `setup`, on
+    /// the tray thread, before it takes any event. If `setup` fails, the
+    /// Application is dropped and its error returned here.
+    ///
+    /// An Application can't be made first and moved to the thread, as it
+    /// isn't Send: on Windows its window and menus belong to the thread that
+    /// made them, the only one their messages go to. So whatever would have
+    /// been set up on it beforehand, its menu, icon and tooltip, goes into
+    /// `setup` instead.
+    pub fn into_background_thread<F>(
+        options: InitOptions,
+        setup: F,
+    ) -> Result<(TrayHandle, JoinHandle<Result<()>>)>
+    where
+        F: FnOnce(&mut Application) -> Result<()> + Send + 'static,
+    {
+        if cfg!(target_os = "macos") {
+            return Err(Error::NotImplementedError);
+        }
+        let (tx, rx) = channel();
+        let (ready_tx, ready_rx) = channel();
+        let events_tx = tx.clone();
+        let tray_thread = thread::spawn(move || {
+            let mut app = match Application::new_with_options(options) {
+                Ok(app) => app,
+                Err(e) => {
+                    ready_tx.send(Err(e)).ok();
+                    return Ok(());
+                }
+            };
+            if let Err(e) = setup(&mut app) {
+                ready_tx.send(Err(e)).ok();
+                return Ok(());
+            }
+            app.forward_events(events_tx);
+            ready_tx.send(Ok(())).ok();
+            for input in rx {
+                match input {
+                    Input::Event(event) => {
+                        if let Some(event) = app.accept_event(event) {
+                            app.dispatch_event(&event)?;
+                        }
+                    }
+                    Input::Command(command) => command(&mut app),
+                    Input::Closed => {
+                        app.event_channel_closed();
+                        break;
+                    }
+                }
+            }
+            Ok(())
+        });
+        match ready_rx.recv() {
+            Ok(Ok(())) => Ok((TrayHandle { tx }, tray_thread)),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(Error::os("The tray thread is gone")),
+        }
+    }
+
+    // Hands the platform's events to the tray thread's loop, which also
+    // takes commands. The Application keeps a channel that closes along
+    // with the platform's.
+    fn forward_events(&mut self, tx: Sender<Input>) {
+        let (events_tx, events_rx) = api::event_channel(None);
+        let events = std::mem::replace(&mut self.rx, events_rx);
+        thread::spawn(move || {
+            let _events_tx = events_tx;
+            for event in events {
+                if tx.send(Input::Event(event)).is_err() {
+                    return;
+                }
+            }
+            tx.send(Input::Closed).ok();
+        });
+    }
+}
@@ -0,0 +1,199 @@
+// Unread counts and status dots drawn over the icon's top right corner. The
+// icon underneath is kept, so the badge can change or go without it.
+use crate::{themed_icon::OwnedIconSource, Application, Error, IconSource, Result};
+use image::{imageops::FilterType, GenericImageView, Rgba, RgbaImage};
+use imageproc::{
+    drawing::{draw_filled_circle_mut, draw_filled_rect_mut, Blend},
+    rect::Rect,
+};
+
+const COUNT_BACKGROUND: Rgba<u8> = Rgba([0xe5, 0x39, 0x35, 0xff]);
+const COUNT_TEXT: Rgba<u8> = Rgba([0xff, 0xff, 0xff, 0xff]);
+
+/// What set_icon_badge draws over the icon.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Badge {
+    /// White digits on red, with anything over 99 shown as "99+".
+    Count(u32),
+    /// A plain dot in the given color, e.g. for an online status.
+    Dot(Rgba<u8>),
+}
+
+// 3x5 digits, then '+', one byte per row with the leftmost pixel in bit 2.
+const GLYPHS: [[u8; 5]; 11] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b010, 0b010, 0b010],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+    [0b000, 0b010, 0b111, 0b010, 0b000],
+];
+const PLUS: usize = 10;
+
+fn glyphs(count: u32) -> Vec<usize> {
+    if count > 99 {
+        return vec![9, 9, PLUS];
+    }
+    count
+        .to_string()
+        .bytes()
+        .map(|b| (b - b'0') as usize)
+        .collect()
+}
+
+// Draws over `icon` blending by alpha, so the icon shows through
+// translucent badge colors and stays as it was around the badge.
+fn draw(icon: RgbaImage, badge: Badge) -> RgbaImage {
+    let (width, height) = icon.dimensions();
+    let size = width.min(height) as i32;
+    let mut canvas = Blend(icon);
+    match badge {
+        Badge::Dot(color) => {
+            let radius = (size * 3 / 16).max(2);
+            let center = (width as i32 - 1 - radius, radius);
+            draw_filled_circle_mut(&mut canvas, center, radius, color);
+        }
+        Badge::Count(count) => {
+            let glyphs = glyphs(count);
+            // A pill of odd height, so the text sits in the middle.
+            let radius = (size * 9 / 32).max(3);
+            let pill_height = 2 * radius + 1;
+            let scale = (pill_height / 8).max(1);
+            let text_width = glyphs.len() as i32 * 4 * scale - scale;
+            let padding = (pill_height - 5 * scale) / 2;
+            let pill_width = (text_width + 2 * padding)
+                .max(pill_height)
+                .min(width as i32);
+            let left = width as i32 - pill_width;
+            draw_filled_circle_mut(&mut canvas, (left + radius, radius), radius, COUNT_BACKGROUND);
+            draw_filled_circle_mut(
+                &mut canvas,
+                (left + pill_width - 1 - radius, radius),
+                radius,
+                COUNT_BACKGROUND,
+            );
+            if pill_width > pill_height {
+                let rect = Rect::at(left + radius, 0)
+                    .of_size((pill_width - 2 * radius) as u32, pill_height as u32);
+                draw_filled_rect_mut(&mut canvas, rect, COUNT_BACKGROUND);
+            }
+            let mut x = left + (pill_width - text_width) / 2;
+            let y = (pill_height - 5 * scale) / 2;
+            for glyph in glyphs {
+                for (row, bits) in GLYPHS[glyph].iter().enumerate() {
+                    for col in 0..3 {
+                        if bits & (0b100 >> col) != 0 {
+                            let rect = Rect::at(x + col * scale, y + row as i32 * scale)
+                                .of_size(scale as u32, scale as u32);
+                            draw_filled_rect_mut(&mut canvas, rect, COUNT_TEXT);
+                        }
+                    }
+                }
+                x += 4 * scale;
+            }
+        }
+    }
+    canvas.0
+}
+
+impl Application {
+    /// Draws `badge` over the top right corner of the icon, or takes it off
+    /// again with None. The badge stays through later icon changes, themed
+    /// icons included, until it's replaced.
+    ///
+    /// Icons from resources can't be badged and are shown without it.
+    pub fn set_icon_badge(&self, badge: Option<Badge>) -> Result<()> {
+        self.icon_badge.set(badge);
+        // Put back by show_base_icon.
+        let base = self.base_icon.take();
+        match base {
+            Some(base) => self.show_base_icon(base, |base| self.show_unbadged(base)),
+            None => Ok(()),
+        }
+    }
+
+    // Every icon setter ends up here with what the icon is made from, and
+    // `show` to put it up as it is. With a badge it's composited instead.
+    pub(crate) fn show_base_icon<F>(&self, base: OwnedIconSource, show: F) -> Result<()>
+    where
+        F: FnOnce(IconSource<'_>) -> Result<()>,
+    {
+        let res = match self.icon_badge.get() {
+            Some(badge) => match self.icon_pixels(base.source()) {
+                Ok(icon) => {
+                    let icon = self.fit_icon(icon);
+                    let icon = draw(icon, badge);
+                    let (width, height) = icon.dimensions();
+                    self.window.set_icon_from_rgba(icon.into_raw(), width, height)
+                }
+                Err(e) => {
+                    warn!("Showing the icon without its badge: {}", e);
+                    show(base.source())
+                }
+            },
+            None => show(base.source()),
+        };
+        self.base_icon.replace(Some(base));
+        res
+    }
+
+    pub(crate) fn icon_pixels(&self, base: IconSource<'_>) -> Result<RgbaImage> {
+        Ok(match base {
+            IconSource::Path(file) => image::open(file)
+                .map_err(|e| {
+                    Error::from_image(format!("Failed to load icon {}", file.display()), &e)
+                })?
+                .to_rgba8(),
+            IconSource::Encoded(data) => image::load_from_memory(data)
+                .map_err(|e| Error::from_image("Failed to decode icon", &e))?
+                .to_rgba8(),
+            IconSource::Rgba {
+                data,
+                width,
+                height,
+            } => RgbaImage::from_raw(width, height, data.into_owned())
+                .ok_or_else(|| Error::os(format!("Bad {}x{} RGBA icon", width, height)))?,
+            IconSource::Resource(resource) => {
+                return Err(Error::os(format!("Icon resource {} has no pixels to badge", resource)))
+            }
+            IconSource::ResourceId(id) => {
+                return Err(Error::os(format!("Icon resource {} has no pixels to badge", id)))
+            }
+            IconSource::ThemeName(name) => {
+                return Err(Error::os(format!("Theme icon {} has no pixels to badge", name)))
+            }
+        })
+    }
+
+    // Brings big icons down to the tray's icon size first, so the badge
+    // isn't scaled into a blur with them.
+    pub(crate) fn fit_icon(&self, icon: RgbaImage) -> RgbaImage {
+        self.fitted_icon(&icon).unwrap_or(icon)
+    }
+
+    // A copy of `icon` scaled down to the tray's icon size, keeping its
+    // aspect ratio, or None if it fits already.
+    pub(crate) fn fitted_icon<I>(&self, icon: &I) -> Option<RgbaImage>
+    where
+        I: GenericImageView<Pixel = Rgba<u8>>,
+    {
+        let size = self.icon_size();
+        let (width, height) = icon.dimensions();
+        if width <= size && height <= size {
+            return None;
+        }
+        let ratio = size as f64 / width.max(height) as f64;
+        let scaled = |n: u32| ((n as f64 * ratio).round() as u32).max(1);
+        Some(image::imageops::resize(
+            icon,
+            scaled(width),
+            scaled(height),
+            FilterType::Lanczos3,
+        ))
+    }
+}
@@ -0,0 +1,50 @@
+// What the platform can do, for apps that would rather leave out a menu item
+// than have it fail with NotImplementedError.
+
+/// Something only some platforms have, see supports.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Capability {
+    /// Application::show_notification.
+    Notifications,
+    /// Application::start_icon_animation.
+    AnimatedIcon,
+    /// SystrayEvent::LeftClick, e.g. for a callback from set_event_callback.
+    LeftClickCallback,
+    /// Application::tray_icon_rect.
+    TrayIconRect,
+    /// Application::register_hotkey.
+    GlobalHotkey,
+}
+
+/// Whether `capability` is there on this platform, with the features the
+/// crate was built with, so it's known before there's an Application.
+///
+/// On Linux the tray is picked when the Application starts, from what the
+/// desktop has, so this only says yes where every tray the build may pick
+/// has it. Hotkeys on Linux still need libkeybinder-3.0 and an X11 session.
+pub const fn supports(capability: Capability) -> bool {
+    match capability {
+        Capability::Notifications => {
+            cfg!(target_os = "windows")
+                || cfg!(all(target_os = "linux", feature = "dbus-notifications"))
+        }
+        Capability::AnimatedIcon => true,
+        // AppIndicator doesn't report clicks, and macOS opens the menu.
+        Capability::LeftClickCallback => {
+            cfg!(target_os = "windows")
+                || cfg!(all(target_os = "linux", not(feature = "appindicator")))
+        }
+        // Only GtkStatusIcon knows where it is on Linux.
+        Capability::TrayIconRect => {
+            cfg!(target_os = "windows")
+                || cfg!(all(
+                    target_os = "linux",
+                    not(feature = "appindicator"),
+                    not(feature = "sni")
+                ))
+        }
+        Capability::GlobalHotkey => {
+            cfg!(target_os = "windows") || cfg!(all(target_os = "linux", not(feature = "sni")))
+        }
+    }
+}
@@ -0,0 +1,117 @@
+// A small window that pops up by the icon, drawn with egui, e.g. for a
+// status panel on a left click.
+use crate::{Application, Error, Result};
+use eframe::egui::{self, ViewportBuilder, ViewportCommand};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+
+// In logical points, as egui measures.
+const PANEL_SIZE: [f32; 2] = [320.0, 240.0];
+
+struct Panel {
+    ui_fn: Box<dyn Fn(&egui::Context) + Send + 'static>,
+    position: Option<egui::Pos2>,
+}
+
+// winit allows one event loop per process, and eframe keeps it on the thread
+// that made it, so every panel runs on this one thread, one after another.
+static PANEL_THREAD: OnceLock<Mutex<Sender<Panel>>> = OnceLock::new();
+
+fn panel_thread() -> Sender<Panel> {
+    PANEL_THREAD
+        .get_or_init(|| {
+            let (tx, rx) = channel::<Panel>();
+            thread::spawn(move || {
+                for panel in rx {
+                    run_panel(panel);
+                }
+            });
+            Mutex::new(tx)
+        })
+        .lock()
+        .unwrap()
+        .clone()
+}
+
+fn run_panel(panel: Panel) {
+    let mut viewport = ViewportBuilder::default()
+        .with_inner_size(PANEL_SIZE)
+        .with_decorations(false)
+        .with_resizable(false)
+        .with_always_on_top()
+        .with_taskbar(false);
+    if let Some(position) = panel.position {
+        viewport = viewport.with_position(position);
+    }
+    let options = eframe::NativeOptions {
+        viewport,
+        // The panel thread isn't the main thread, which winit insists on.
+        event_loop_builder: Some(Box::new(|builder| {
+            #[cfg(target_os = "macos")]
+            let _ = builder;
+            #[cfg(target_os = "linux")]
+            winit::platform::x11::EventLoopBuilderExtX11::with_any_thread(builder, true);
+            #[cfg(target_os = "windows")]
+            winit::platform::windows::EventLoopBuilderExtWindows::with_any_thread(builder, true);
+        })),
+        ..eframe::NativeOptions::default()
+    };
+    // The window may not have the focus yet on the first frames.
+    let mut focused = false;
+    let res = eframe::run_simple_native("systray2", options, move |ctx, _| {
+        match ctx.input(|i| i.viewport().focused) {
+            Some(true) => focused = true,
+            Some(false) if focused => ctx.send_viewport_cmd(ViewportCommand::Close),
+            _ => {}
+        }
+        (panel.ui_fn)(ctx);
+    });
+    if let Err(e) = res {
+        error!("Failed to show the egui panel: {}", e);
+    }
+}
+
+impl Application {
+    /// Opens a borderless window by the icon that `ui_fn` fills each frame,
+    /// e.g. with an egui::CentralPanel, and closes it once it loses the
+    /// focus. State shared with the rest of the app goes in an
+    /// `Arc<Mutex<T>>` captured by `ui_fn`, which runs on a thread of its
+    /// own. Panels opened while one is up wait for it to close.
+    ///
+    /// Where tray_icon_rect doesn't know the icon's place, the window
+    /// manager places the panel. winit allows one event loop per process,
+    /// so this doesn't go with a winit loop of the app's own. macOS, where
+    /// that loop would have to take over the main thread, returns
+    /// NotImplementedError.
+    pub fn show_egui_panel<F>(&mut self, ui_fn: F) -> Result<()>
+    where
+        F: Fn(&egui::Context) + Send + 'static,
+    {
+        if cfg!(target_os = "macos") {
+            return Err(Error::NotImplementedError);
+        }
+        let position = self.tray_icon_rect().ok().map(|rect| {
+            // Only Windows gives physical pixels.
+            let scale = if cfg!(target_os = "windows") {
+                self.scale_factor() as f32
+            } else {
+                1.0
+            };
+            // Above the icon where there's room, as on a taskbar at the
+            // bottom, and below it otherwise.
+            let y = if rect.y as f32 / scale > PANEL_SIZE[1] {
+                rect.y as f32 / scale - PANEL_SIZE[1]
+            } else {
+                (rect.y + rect.height as i32) as f32 / scale
+            };
+            egui::pos2(rect.x as f32 / scale, y)
+        });
+        panel_thread()
+            .send(Panel {
+                ui_fn: Box::new(ui_fn),
+                position,
+            })
+            .map_err(|_| Error::os("The egui panel thread is gone"))
+    }
+}
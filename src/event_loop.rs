@@ -0,0 +1,48 @@
+// Tray events delivered through a winit event loop, for apps whose main
+// thread is busy in EventLoop::run and can't block in wait_for_message.
+use crate::{Application, Result, SystrayEvent};
+use std::thread;
+use winit::event_loop::{EventLoopProxy, EventLoopWindowTarget};
+
+impl Application {
+    /// Sends every tray event to the winit event loop as a user event, made
+    /// with `T::from`. Hand them back to handle_event from the loop's
+    /// Event::UserEvent arm, which runs the callbacks wait_for_message would.
+    /// Taking `event_loop` makes sure this happens on the loop's thread,
+    /// where the Application has to stay.
+    ///
+    /// wait_event and poll_event get no more events after this, only None
+    /// once the tray has shut down.
+    pub fn attach_to_event_loop<T>(
+        &mut self,
+        event_loop: &EventLoopWindowTarget<T>,
+        proxy: EventLoopProxy<T>,
+    ) where
+        T: From<SystrayEvent> + Send + 'static,
+    {
+        let _ = event_loop;
+        let (tx, rx) = crate::api::event_channel(None);
+        let events = std::mem::replace(&mut self.rx, rx);
+        thread::spawn(move || {
+            // Held until the platform thread is gone, so the channel left to
+            // the Application closes along with it.
+            let _tx = tx;
+            for event in events {
+                if proxy.send_event(T::from(event)).is_err() {
+                    debug!("The winit event loop is gone, no more tray events");
+                    break;
+                }
+            }
+        });
+    }
+
+    /// Runs the callbacks for an event from attach_to_event_loop, as
+    /// wait_for_message does. Events it would drop, like clicks on removed
+    /// items, are dropped here too.
+    pub fn handle_event(&mut self, event: SystrayEvent) -> Result<()> {
+        match self.accept_event(event) {
+            Some(event) => self.dispatch_event(&event),
+            None => Ok(()),
+        }
+    }
+}
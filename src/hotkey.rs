@@ -0,0 +1,72 @@
+// Global shortcuts, which fire wherever the focus is.
+use crate::{make_callback, Application, Result};
+use std::error;
+
+bitflags::bitflags! {
+    /// Modifier keys to hold with a hotkey. The values are Windows' MOD_*
+    /// flags.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    pub struct HotkeyModifiers: u32 {
+        const ALT = 0x1;
+        const CTRL = 0x2;
+        const SHIFT = 0x4;
+        /// The Windows key, Super on Linux.
+        const WIN = 0x8;
+    }
+}
+
+impl Application {
+    /// Calls `f` whenever `key` is pressed with `modifiers` held, in any app.
+    /// `key` is a virtual-key code on Windows and an X keysym on Linux, which
+    /// for A to Z and 0 to 9 are both the character's upper case ASCII code.
+    /// Presses also come as SystrayEvent::HotkeyPressed with `id`, and
+    /// registering an `id` again replaces its hotkey.
+    ///
+    /// Fails when another app holds the key already. Linux needs
+    /// libkeybinder-3.0 and an X11 session, and doesn't have hotkeys with
+    /// the StatusNotifierItem backend. macOS returns NotImplementedError.
+    pub fn register_hotkey<F, E>(
+        &mut self,
+        id: u32,
+        modifiers: HotkeyModifiers,
+        key: u32,
+        f: F,
+    ) -> Result<()>
+    where
+        F: FnMut(&mut Application) -> std::result::Result<(), E> + Send + Sync + 'static,
+        E: error::Error + Send + Sync + 'static,
+    {
+        self.unregister_hotkey(id)?;
+        self.window
+            .register_hotkey(id, modifiers, key)
+            .inspect_err(|e| warn!("Failed to register hotkey {}: {}", id, e))?;
+        self.hotkeys.insert(id, Some(make_callback(f)));
+        Ok(())
+    }
+
+    /// Frees the hotkey registered under `id`, if there is one.
+    pub fn unregister_hotkey(&mut self, id: u32) -> Result<()> {
+        if self.hotkeys.remove(&id).is_none() {
+            return Ok(());
+        }
+        self.window.unregister_hotkey(id)
+    }
+
+    pub(crate) fn dispatch_hotkey(&mut self, id: u32) -> Result<()> {
+        let mut f = match self.hotkeys.get_mut(&id).and_then(Option::take) {
+            Some(f) => f,
+            None => {
+                debug!("Dropping press of unregistered hotkey {}", id);
+                return Ok(());
+            }
+        };
+        let res = self.guard_panic(|app| f(app));
+        // Put the callback back unless the hotkey was replaced or removed
+        // while it ran.
+        if let Some(slot @ None) = self.hotkeys.get_mut(&id) {
+            *slot = Some(f);
+        }
+        res?;
+        Ok(())
+    }
+}
@@ -0,0 +1,45 @@
+// Skipping icon changes to the icon already up, for apps that set their
+// icon from a polling loop. Putting up an icon is a bitmap conversion and
+// a round trip to the shell or panel, so only changes go through.
+use crate::{Application, Result};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+
+pub(crate) fn fingerprint(icon: impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    icon.hash(&mut hasher);
+    hasher.finish()
+}
+
+// Files count as the same icon while they're the same size and unchanged
+// since, so an icon rewritten in place still goes up. None where that
+// can't be told, which always sets the icon.
+pub(crate) fn file_fingerprint(file: &Path, size: Option<u32>) -> Option<u64> {
+    let meta = std::fs::metadata(file).ok()?;
+    Some(fingerprint(("file", file, size, meta.len(), meta.modified().ok())))
+}
+
+impl Application {
+    /// Makes the next `set_icon_*` call put its icon up even when it's the
+    /// one already shown. Setting the same icon again is skipped otherwise,
+    /// except after Explorer restarted or the display scale changed.
+    pub fn forget_shown_icon(&self) {
+        self.shown_icon.set(None);
+    }
+
+    // Runs `set` unless `icon`, a fingerprint of what it's made from, is
+    // already up.
+    pub(crate) fn set_icon_once<F>(&self, icon: Option<u64>, set: F) -> Result<()>
+    where
+        F: FnOnce() -> Result<()>,
+    {
+        if icon.is_some() && self.shown_icon.get() == icon {
+            debug!("Icon unchanged, not setting it again");
+            return Ok(());
+        }
+        let res = set();
+        self.shown_icon.set(icon.filter(|_| res.is_ok()));
+        res
+    }
+}
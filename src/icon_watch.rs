@@ -0,0 +1,105 @@
+// Setting the icon again whenever its file changes, for apps whose icon is
+// written by something else, like a theming tool. The file's directory is
+// watched rather than the file, so a file replaced by a rename, as editors
+// and atomic writers do, is still seen.
+use crate::api::EventSender;
+use crate::{Application, Error, Result, SystrayEvent};
+use notify::{RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+// How long the file has to be left alone before it's read, so a write in
+// several steps shows once, finished.
+const SETTLE: Duration = Duration::from_millis(250);
+
+pub(crate) struct IconWatch {
+    file: PathBuf,
+    // Dropping it ends the watch, and with it the thread.
+    _watcher: notify::RecommendedWatcher,
+}
+
+impl IconWatch {
+    fn new(file: &Path, event_tx: EventSender) -> Result<IconWatch> {
+        let dir = match file.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let name = file.file_name().map(|name| name.to_owned());
+        let (tx, rx) = channel();
+        let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+            match res {
+                Ok(event) => {
+                    if event.paths.iter().any(|p| p.file_name() == name.as_deref()) {
+                        tx.send(()).ok();
+                    }
+                }
+                Err(e) => warn!("Error watching the icon file: {}", e),
+            }
+        })
+        .map_err(|e| Error::os(format!("Failed to watch the icon file: {}", e)))?;
+        watcher
+            .watch(dir, RecursiveMode::NonRecursive)
+            .map_err(|e| Error::os(format!("Failed to watch {}: {}", dir.display(), e)))?;
+        let path = file.to_path_buf();
+        thread::spawn(move || {
+            while rx.recv().is_ok() {
+                loop {
+                    match rx.recv_timeout(SETTLE) {
+                        Ok(()) => continue,
+                        Err(RecvTimeoutError::Timeout) => break,
+                        Err(RecvTimeoutError::Disconnected) => return,
+                    }
+                }
+                if !path.is_file() {
+                    warn!("{} is gone, keeping the icon from before", path.display());
+                    continue;
+                }
+                if event_tx.send(SystrayEvent::IconFileChanged).is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(IconWatch {
+            file: file.to_path_buf(),
+            _watcher: watcher,
+        })
+    }
+}
+
+impl Application {
+    /// Like set_icon_from_file, and sets the icon again each time the file
+    /// changes, once it has been left alone for a moment so a half-written
+    /// file isn't shown. A file that goes away leaves the icon as it was.
+    /// Each reload comes as an IconFileChanged event, and is done before the
+    /// event callback runs, unless an icon animation is running then.
+    ///
+    /// The watch ends with the next icon set, or the Application.
+    pub fn set_icon_from_file_watched(&self, path: &Path) -> Result<()> {
+        self.set_file_icon(path)?;
+        let event_tx = self
+            .window
+            .event_sender()
+            .ok_or_else(|| Error::os("The platform thread is gone"))?;
+        self.icon_watch.replace(Some(IconWatch::new(path, event_tx)?));
+        Ok(())
+    }
+
+    // Sets the icon from the watched file again. Failures, as with a file
+    // that isn't an image (yet), leave the icon as it was.
+    pub(crate) fn reload_watched_icon(&self) {
+        let watch = match self.icon_watch.take() {
+            Some(watch) => watch,
+            None => return,
+        };
+        if let Err(e) = self.set_file_icon(&watch.file) {
+            warn!("Failed to reload the icon from {}: {}", watch.file.display(), e);
+        }
+        self.icon_watch.replace(Some(watch));
+    }
+
+    pub(crate) fn stop_icon_watch(&self) {
+        self.icon_watch.take();
+    }
+}
@@ -0,0 +1,57 @@
+// How Application::new_with_options sets up the platform, for apps that
+// already run a GUI toolkit of their own.
+
+/// COM apartment the tray's thread joins on Windows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ComApartment {
+    /// COINIT_APARTMENTTHREADED.
+    SingleThreaded,
+    /// COINIT_MULTITHREADED.
+    MultiThreaded,
+}
+
+/// Options for Application::new_with_options. The default is what
+/// Application::new does.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InitOptions {
+    /// On Linux, whether the tray initializes GTK and runs its own main loop
+    /// on a thread of its own, which every later Application shares. Turn
+    /// it off when the app runs GTK already: the tray then lives in the
+    /// app's default main context, and new_with_options has to be called on
+    /// the loop's thread or while the loop runs. The Application is to be
+    /// used from another thread than the loop's then, as it waits on the
+    /// loop for answers. Shutting it down leaves the loop running. Ignored
+    /// elsewhere and by the StatusNotifierItem backend, which doesn't use
+    /// GTK.
+    pub init_gtk: bool,
+    /// On Windows, the COM apartment the tray's thread is initialized into,
+    /// or None to leave COM alone there. COM is never initialized on the
+    /// app's own threads, so it can pick any apartment for those. Ignored
+    /// elsewhere.
+    pub com_apartment: Option<ComApartment>,
+    /// Tells the icons of an app with several apart across runs. On Windows
+    /// it's the icon's uID, by which, with the executable's path, the
+    /// taskbar remembers whether to show the icon; None keeps 1, the uID
+    /// the icon always had. On Linux the icon is named after the executable
+    /// and the id. Ignored on macOS.
+    pub tray_id: Option<u32>,
+    /// How many events may wait in the channel for the app, or None for no
+    /// limit. The platform's thread never waits for room, as the app may be
+    /// waiting on it, so events that don't fit are held back until there's
+    /// room again. Clicks and other input are all kept, in order, while of
+    /// the events that only say something changed, like ScaleFactorChanged
+    /// or ThemeChanged, only the latest of each kind is. Worth setting for
+    /// long running daemons that may stop taking events for a while.
+    pub event_capacity: Option<usize>,
+}
+
+impl Default for InitOptions {
+    fn default() -> InitOptions {
+        InitOptions {
+            init_gtk: true,
+            com_apartment: None,
+            tray_id: None,
+            event_capacity: None,
+        }
+    }
+}
@@ -0,0 +1,50 @@
+// Submenus filled in as they open, for contents that change too often to
+// keep the menu up to date.
+use crate::{Application, MenuItemId, Result};
+use std::sync::Arc;
+
+/// What a lazy submenu's builder hands back: the label of each item and
+/// what clicking it does.
+pub type LazyMenuItems = Vec<(String, Box<dyn FnMut()>)>;
+
+pub(crate) type LazyBuilder = Arc<dyn Fn() -> LazyMenuItems + Send + Sync + 'static>;
+
+impl Application {
+    /// Adds a submenu that calls `builder` each time it opens, and shows the
+    /// items it returns. They are taken out again when the submenu closes,
+    /// so nothing needs updating in between.
+    ///
+    /// The builder and the items' closures run on the tray's own thread,
+    /// the GTK thread on Linux, while the menu is open, not in
+    /// wait_for_message, so they should be quick. Labels are shown as they
+    /// are, without mnemonics. The StatusNotifierItem backend and macOS
+    /// return NotImplementedError.
+    pub fn add_lazy_submenu<F>(&mut self, label: &str, builder: F) -> Result<MenuItemId>
+    where
+        F: Fn() -> LazyMenuItems + Send + Sync + 'static,
+    {
+        let idx = self.add_submenu_entry(None, label)?;
+        if let Err(e) = self.window.set_lazy_submenu(idx, Arc::new(builder)) {
+            self.remove_menu_item(MenuItemId(idx)).ok();
+            return Err(e);
+        }
+        Ok(MenuItemId(idx))
+    }
+}
+
+// Lazy submenus come back empty from a builder that panicked.
+#[cfg(not(any(target_os = "macos", feature = "test-utils")))]
+pub(crate) fn build(builder: &LazyBuilder) -> LazyMenuItems {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| builder())).unwrap_or_else(|_| {
+        error!("Lazy submenu builder panicked");
+        Vec::new()
+    })
+}
+
+// The tray's thread can't unwind into the OS, so a panic only gets logged.
+#[cfg(not(any(target_os = "macos", feature = "test-utils")))]
+pub(crate) fn activate(f: &mut Box<dyn FnMut()>) {
+    if std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).is_err() {
+        error!("Lazy submenu item panicked");
+    }
+}
@@ -1,43 +1,324 @@
 // Systray Lib
+#[macro_use]
+mod trace;
+
 pub mod api;
+mod animation;
+mod attention;
+mod background;
+mod capability;
+#[cfg(feature = "image-icons")]
+mod badge;
+#[cfg(feature = "egui")]
+mod egui_panel;
+#[cfg(feature = "winit")]
+mod event_loop;
+mod hotkey;
+mod icon_cache;
+#[cfg(feature = "notify")]
+mod icon_watch;
+mod init;
+mod lazy_menu;
+mod menu;
+mod menu_style;
+mod model;
+mod no_wait;
+mod notification;
+mod pixels;
+mod snapshot;
+mod submenu;
+mod svg;
+mod themed_icon;
+mod tooltip;
+
+pub use animation::IconFrame;
+pub use background::TrayHandle;
+pub use capability::{supports, Capability};
+#[cfg(feature = "image-icons")]
+pub use badge::Badge;
+pub use hotkey::HotkeyModifiers;
+pub use init::{ComApartment, InitOptions};
+pub use lazy_menu::LazyMenuItems;
+pub use notification::NotificationAction;
+pub use pixels::PixelFormat;
+pub use snapshot::MenuSnapshot;
+pub use submenu::SubMenu;
+pub use themed_icon::IconSource;
+pub use menu::{MenuItemSpec, MenuItemState, MenuSpec, MenuState};
+pub use menu_style::MenuItemStyle;
+pub use model::MenuModel;
+pub use tooltip::TruncationBehavior;
 
+use api::{RecvError, RecvTimeoutError, TryRecvError};
 use std::{
+    any::Any,
+    borrow::Cow,
+    cell::Cell,
     collections::HashMap,
     error, fmt,
-    sync::mpsc::{channel, Receiver},
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    time::{Duration, Instant},
 };
+use themed_icon::OwnedIconSource;
 
-type BoxedError = Box<dyn error::Error + Send + Sync + 'static>;
+pub type BoxedError = Box<dyn error::Error + Send + Sync + 'static>;
 
-#[derive(Debug)]
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, thiserror::Error)]
 pub enum Error {
-    OsError(String),
+    /// `code` is GetLastError() on Windows and errno elsewhere, or 0 when the
+    /// failure didn't come from an OS call.
+    #[error("OsError: {message}{}", code_suffix(*.code))]
+    OsError { code: i32, message: String },
+    /// Image data that was read fine but isn't a valid or supported image.
+    #[error("DecodeError: {0}")]
+    DecodeError(String),
+    #[error("Functionality is not implemented yet")]
     NotImplementedError,
+    #[error("Unknown error occurrred")]
     UnknownError,
-    Error(BoxedError),
+    /// What a callback failed with, or an error from a library used here,
+    /// which is its source. The message is left to the source, or error
+    /// reports would print it twice.
+    #[error("Callback or library error")]
+    Error(#[source] BoxedError),
+}
+
+fn code_suffix(code: i32) -> String {
+    if code == 0 {
+        String::new()
+    } else {
+        format!(" (code {})", code)
+    }
 }
 
+impl Error {
+    pub(crate) fn os<S: Into<String>>(message: S) -> Error {
+        Error::OsError {
+            code: 0,
+            message: message.into(),
+        }
+    }
+
+    pub(crate) fn from_io<S: Into<String>>(message: S, e: &std::io::Error) -> Error {
+        Error::OsError {
+            code: e.raw_os_error().unwrap_or(0),
+            message: format!("{}: {}", message.into(), e),
+        }
+    }
+
+    #[cfg(any(feature = "image", target_os = "macos"))]
+    pub(crate) fn from_image<S: Into<String>>(message: S, e: &image::ImageError) -> Error {
+        match e {
+            image::ImageError::IoError(io) => Error::from_io(message, io),
+            image::ImageError::Decoding(_)
+            | image::ImageError::Unsupported(_)
+            | image::ImageError::Limits(_) => {
+                Error::DecodeError(format!("{}: {}", message.into(), e))
+            }
+            _ => Error::os(format!("{}: {}", message.into(), e)),
+        }
+    }
+}
+
+// Errors go out as their kind, code and message, e.g. to report them to another
+// process. There's no Deserialize, since the source of an Error::Error can't be
+// brought back.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Error {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeStruct;
+        let (kind, code) = match self {
+            Error::OsError { code, .. } => ("os_error", *code),
+            Error::DecodeError(_) => ("decode_error", 0),
+            Error::NotImplementedError => ("not_implemented", 0),
+            Error::UnknownError => ("unknown", 0),
+            Error::Error(_) => ("error", 0),
+        };
+        let mut s = serializer.serialize_struct("Error", 3)?;
+        s.serialize_field("kind", kind)?;
+        s.serialize_field("code", &code)?;
+        let message = match self {
+            Error::Error(e) => format!("{}: {}", self, e),
+            _ => self.to_string(),
+        };
+        s.serialize_field("message", &message)?;
+        s.end()
+    }
+}
+
+// A callback that failed with one of our own errors gets it back unwrapped;
+// anything else ends up in Error::Error, where it can be downcast.
 impl From<BoxedError> for Error {
     fn from(value: BoxedError) -> Self {
-        Error::Error(value)
+        match value.downcast::<Error>() {
+            Ok(e) => *e,
+            Err(e) => Error::Error(e),
+        }
     }
 }
 
-pub struct SystrayEvent {
-    menu_index: u32,
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        Error::Error(Box::new(value))
+    }
 }
 
-impl error::Error for Error {}
+impl From<std::ffi::NulError> for Error {
+    fn from(value: std::ffi::NulError) -> Self {
+        Error::Error(Box::new(value))
+    }
+}
 
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        use self::Error::*;
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Theme {
+    Light,
+    Dark,
+}
+
+/// An area of the screen, in pixels from its top left corner.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
 
-        match *self {
-            OsError(ref err_str) => write!(f, "OsError: {}", err_str),
-            NotImplementedError => write!(f, "Functionality is not implemented yet"),
-            UnknownError => write!(f, "Unknown error occurrred"),
-            Error(ref e) => write!(f, "Error: {}", e),
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SystrayEvent {
+    MenuItem(MenuItemId),
+    // A MenuClosed always follows a MenuOpened, however the menu was dismissed.
+    MenuOpened,
+    MenuClosed,
+    // Windows only: Explorer restarted and the icon was added back with its
+    // last icon and tooltip.
+    IconRecreated,
+    // The display scale changed, see scale_factor. GTK and Windows only.
+    ScaleFactorChanged,
+    // The file from set_icon_from_file_watched changed and the icon was set
+    // from it again.
+    IconFileChanged,
+    // Clicks on the icon, at their screen position. The menu still opens
+    // as set up with set_menu_on_left_click and set_suppress_default_menu.
+    // None on AppIndicator or macOS, and StatusNotifierItem hosts only
+    // report right clicks while the menu is suppressed.
+    LeftClick { x: i32, y: i32 },
+    RightClick { x: i32, y: i32 },
+    DoubleClick { x: i32, y: i32 },
+    // A hotkey from register_hotkey was pressed, with its id.
+    HotkeyPressed(u32),
+    // A button on a notification from show_notification was clicked.
+    NotificationAction { action_key: String },
+    // The taskbar or panel switched between light and dark.
+    ThemeChanged(Theme),
+}
+
+impl SystrayEvent {
+    // Anything that only comes from the user at the icon or its menu.
+    fn is_click(&self) -> bool {
+        matches!(
+            self,
+            SystrayEvent::MenuItem(_)
+                | SystrayEvent::LeftClick { .. }
+                | SystrayEvent::RightClick { .. }
+                | SystrayEvent::DoubleClick { .. }
+        )
+    }
+
+    // Events that only say something changed, where the latest of a kind
+    // tells all there is.
+    pub(crate) fn coalesces(&self) -> bool {
+        matches!(
+            self,
+            SystrayEvent::IconRecreated
+                | SystrayEvent::ScaleFactorChanged
+                | SystrayEvent::IconFileChanged
+                | SystrayEvent::ThemeChanged(_)
+        )
+    }
+}
+
+/// Names a menu item. Ids are handed out by the add_* methods, never twice.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MenuItemId(pub(crate) u32);
+
+impl From<u32> for MenuItemId {
+    fn from(value: u32) -> Self {
+        MenuItemId(value)
+    }
+}
+
+impl From<MenuItemId> for u32 {
+    fn from(value: MenuItemId) -> Self {
+        value.0
+    }
+}
+
+impl fmt::Display for MenuItemId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MenuItemKind {
+    Normal,
+    Separator,
+    Submenu,
+    /// A section heading from add_menu_label.
+    Label,
+}
+
+/// What a menu item currently is, see menu_item_info.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MenuItemInfo {
+    /// As it was given, with `&` escapes only for add_menu_item_with_mnemonic.
+    pub label: String,
+    pub enabled: bool,
+    /// `None` for items without a check mark.
+    pub checked: Option<bool>,
+    pub kind: MenuItemKind,
+}
+
+// Shadow copy of what was put into the native menu.
+struct MenuEntry {
+    parent: Option<u32>,
+    label: String,
+    kind: MenuItemKind,
+    // Stable name for saving state, see menu_state.
+    key: Option<String>,
+    checkable: bool,
+    checked: bool,
+    enabled: bool,
+    // Whether the label is in the syntax of add_menu_item_with_mnemonic.
+    mnemonic: bool,
+    // Where move_menu_item put it among the items next to it, which its id
+    // tells otherwise, see menu_children.
+    order: Option<u32>,
+    callback: Option<Callback>,
+    data: Option<Box<dyn Any + Send + Sync>>,
+}
+
+impl MenuEntry {
+    fn new(parent: Option<u32>, kind: MenuItemKind, label: &str) -> MenuEntry {
+        MenuEntry {
+            parent,
+            label: label.to_string(),
+            kind,
+            key: None,
+            checkable: false,
+            checked: false,
+            enabled: true,
+            mnemonic: false,
+            order: None,
+            callback: None,
+            data: None,
         }
     }
 }
@@ -45,19 +326,69 @@ impl fmt::Display for Error {
 pub struct Application {
     window: api::platform::Window,
     menu_idx: u32,
-    callback: HashMap<u32, Callback>,
+    menu: HashMap<u32, MenuEntry>,
+    event_callback: Option<EventCallback>,
+    panic_handler: Option<PanicHandler>,
+    spec: Option<menu::AppliedSpec>,
+    model_items: Vec<u32>,
+    // Run on a double click on the icon.
+    default_menu_item: Option<u32>,
+    tooltip_provider: tooltip::ProviderSlot,
+    notification_actions: HashMap<String, Callback>,
+    // Callbacks of registered hotkeys, taken out while they run.
+    hotkeys: HashMap<u32, Option<Callback>>,
+    // The SVG the icon was last rendered from, while it's still the icon.
+    #[cfg(feature = "svg")]
+    svg_icon: std::cell::RefCell<Option<Vec<u8>>>,
+    // Light and dark icons from set_themed_icons, while they're in use.
+    themed_icons: std::cell::RefCell<Option<(OwnedIconSource, OwnedIconSource)>>,
+    // What the icon shows under its badge, and the badge.
+    #[cfg(feature = "image-icons")]
+    base_icon: std::cell::RefCell<Option<OwnedIconSource>>,
+    #[cfg(feature = "image-icons")]
+    icon_badge: Cell<Option<Badge>>,
+    // Whether RGBA icons over the tray's size are scaled down to it first.
+    downscale_icons: Cell<bool>,
+    // Fingerprint of the icon the last set_icon_* call put up, see
+    // forget_shown_icon.
+    shown_icon: Cell<Option<u64>>,
+    // Set from start_icon_animation until another icon replaces it.
+    animating: Cell<bool>,
+    // The file set_icon_from_file_watched watches, while it's the icon.
+    #[cfg(feature = "notify")]
+    icon_watch: std::cell::RefCell<Option<icon_watch::IconWatch>>,
+    // Set once shutdown started, after which the event channel closing is
+    // expected.
+    stopping: Cell<bool>,
+    // Cleared by hide_icon, so clicks from before it don't go out.
+    icon_visible: Cell<bool>,
+    // Set between request_attention and clear_attention.
+    attention: Cell<bool>,
+    clear_attention_on_click: Cell<bool>,
+    // Whether wait_event and poll_event run callbacks like wait_for_message.
+    auto_dispatch: bool,
     // Each platform-specific window module will set up its own thread for
     // dealing with the OS main loop. Use this channel for receiving events from
     // that thread.
-    rx: Receiver<SystrayEvent>,
+    rx: api::EventReceiver,
 }
 
-type Callback =
-    Box<dyn FnMut(&mut Application) -> Result<(), BoxedError> + Send + Sync + 'static>;
+type Callback = Box<
+    dyn FnMut(&mut Application) -> std::result::Result<(), BoxedError> + Send + Sync + 'static,
+>;
+
+type EventCallback = Box<
+    dyn FnMut(&mut Application, &SystrayEvent) -> std::result::Result<(), BoxedError>
+        + Send
+        + Sync
+        + 'static,
+>;
+
+type PanicHandler = Box<dyn Fn(Box<dyn Any + Send>) + Send + Sync + 'static>;
 
 fn make_callback<F, E>(mut f: F) -> Callback
 where
-    F: FnMut(&mut Application) -> Result<(), E> + Send + Sync + 'static,
+    F: FnMut(&mut Application) -> std::result::Result<(), E> + Send + Sync + 'static,
     E: error::Error + Send + Sync + 'static,
 {
     Box::new(move |a: &mut Application| match f(a) {
@@ -67,142 +398,1406 @@ where
 }
 
 impl Application {
-    pub fn new() -> Result<Application, Error> {
-        let (event_tx, event_rx) = channel();
-        match api::platform::Window::new(event_tx) {
+    pub fn new() -> Result<Application> {
+        Application::new_with_options(InitOptions::default())
+    }
+
+    /// Like new, for one of several icons of an app, see InitOptions::tray_id.
+    pub fn new_with_id(id: u32) -> Result<Application> {
+        Application::new_with_options(InitOptions {
+            tray_id: Some(id),
+            ..InitOptions::default()
+        })
+    }
+
+    /// Like new, with a say in how GTK and COM get set up, see InitOptions.
+    pub fn new_with_options(options: InitOptions) -> Result<Application> {
+        let (event_tx, event_rx) = api::event_channel(options.event_capacity);
+        let tooltip_provider = tooltip::ProviderSlot::default();
+        match api::platform::Window::new(event_tx, tooltip_provider.clone(), &options) {
             Ok(w) => Ok(Application {
                 window: w,
                 menu_idx: 0,
-                callback: HashMap::new(),
+                menu: HashMap::new(),
+                event_callback: None,
+                panic_handler: None,
+                spec: None,
+                model_items: Vec::new(),
+                default_menu_item: None,
+                tooltip_provider,
+                notification_actions: HashMap::new(),
+                hotkeys: HashMap::new(),
+                #[cfg(feature = "svg")]
+                svg_icon: std::cell::RefCell::new(None),
+                themed_icons: std::cell::RefCell::new(None),
+                #[cfg(feature = "image-icons")]
+                base_icon: std::cell::RefCell::new(None),
+                #[cfg(feature = "image-icons")]
+                icon_badge: Cell::new(None),
+                downscale_icons: Cell::new(true),
+                shown_icon: Cell::new(None),
+                animating: Cell::new(false),
+                #[cfg(feature = "notify")]
+                icon_watch: std::cell::RefCell::new(None),
+                stopping: Cell::new(false),
+                icon_visible: Cell::new(true),
+                attention: Cell::new(false),
+                clear_attention_on_click: Cell::new(true),
+                auto_dispatch: false,
                 rx: event_rx,
             }),
             Err(e) => Err(e),
         }
     }
 
-    pub fn add_menu_item<F, E>(&mut self, item_name: &str, f: F) -> Result<u32, Error>
+    /// An Application on the in-process mock backend, which shows nothing
+    /// and records every call for mock_window to hand back.
+    #[cfg(feature = "test-utils")]
+    pub fn mock() -> Application {
+        Application::new().expect("the mock backend never fails")
+    }
+
+    #[cfg(feature = "test-utils")]
+    pub fn mock_window(&self) -> &api::platform::MockWindow {
+        &self.window
+    }
+
+    pub fn add_menu_item<F, E>(&mut self, item_name: &str, f: F) -> Result<MenuItemId>
+    where
+        F: FnMut(&mut Application) -> std::result::Result<(), E> + Send + Sync + 'static,
+        E: error::Error + Send + Sync + 'static,
+    {
+        let idx = self.add_entry(None, item_name)?;
+        self.set_callback(idx, make_callback(f));
+        Ok(MenuItemId(idx))
+    }
+
+    /// Like add_menu_item, but with `&` marking the next character as the
+    /// item's access key, as in `"&Quit"` for Alt+Q on Windows. Write `&&` for
+    /// an ampersand. GTK shows these as underscored mnemonics, macOS drops
+    /// them. Labels given anywhere else are shown exactly as written.
+    pub fn add_menu_item_with_mnemonic<F, E>(&mut self, label: &str, f: F) -> Result<MenuItemId>
+    where
+        F: FnMut(&mut Application) -> std::result::Result<(), E> + Send + Sync + 'static,
+        E: error::Error + Send + Sync + 'static,
+    {
+        let idx = self.add_label_entry(None, label)?;
+        self.set_callback(idx, make_callback(f));
+        Ok(MenuItemId(idx))
+    }
+
+    /// Like add_menu_item, for closures that already return boxed errors,
+    /// e.g. from anyhow or eyre through `?` and `.into()`.
+    pub fn add_menu_item_boxed<F>(&mut self, item_name: &str, f: F) -> Result<MenuItemId>
+    where
+        F: FnMut(&mut Application) -> std::result::Result<(), BoxedError>
+            + Send
+            + Sync
+            + 'static,
+    {
+        let idx = self.add_entry(None, item_name)?;
+        self.set_callback(idx, Box::new(f));
+        Ok(MenuItemId(idx))
+    }
+
+    /// Like add_menu_item, with the click's SystrayEvent::MenuItem passed to
+    /// `f`, so one closure, cloned for each item, can tell them apart by id.
+    pub fn add_menu_item_ctx<F, E>(&mut self, item_name: &str, mut f: F) -> Result<MenuItemId>
+    where
+        F: FnMut(&mut Application, &SystrayEvent) -> std::result::Result<(), E>
+            + Send
+            + Sync
+            + 'static,
+        E: error::Error + Send + Sync + 'static,
+    {
+        let idx = self.add_entry(None, item_name)?;
+        // Callbacks only ever run for clicks on their own item.
+        let event = SystrayEvent::MenuItem(MenuItemId(idx));
+        self.set_callback(idx, make_callback(move |app: &mut Application| f(app, &event)));
+        Ok(MenuItemId(idx))
+    }
+
+    /// Adds an item with a check mark that flips on every click. `key` names
+    /// it in menu_state and apply_menu_state.
+    pub fn add_check_menu_item<F, E>(
+        &mut self,
+        key: &str,
+        item_name: &str,
+        checked: bool,
+        f: F,
+    ) -> Result<MenuItemId>
     where
-        F: FnMut(&mut Application) -> Result<(), E> + Send + Sync + 'static,
+        F: FnMut(&mut Application) -> std::result::Result<(), E> + Send + Sync + 'static,
         E: error::Error + Send + Sync + 'static,
     {
+        let idx = self.add_check_entry(None, item_name, checked)?;
+        self.set_callback(idx, make_callback(f));
+        self.set_key(idx, key);
+        Ok(MenuItemId(idx))
+    }
+
+    pub fn add_menu_separator(&mut self) -> Result<MenuItemId> {
+        self.add_separator(None).map(MenuItemId)
+    }
+
+    /// Adds a heading for the items after it, as a grayed out row that
+    /// can't be clicked or enabled.
+    pub fn add_menu_label(&mut self, text: &str) -> Result<MenuItemId> {
+        self.add_heading(None, text).map(MenuItemId)
+    }
+
+    /// Same as add_menu_label.
+    pub fn add_menu_section_header(&mut self, label: &str) -> Result<MenuItemId> {
+        self.add_menu_label(label)
+    }
+
+    /// Moves an item, separator or submenu to `position` among the items in
+    /// the same menu or submenu, counted from 0. Positions past the end put
+    /// it last. Its id, callback and anything in it go along.
+    pub fn move_menu_item(&mut self, id: MenuItemId, position: u32) -> Result<()> {
+        let idx = id.0;
+        let parent = match self.menu.get(&idx) {
+            Some(e) => e.parent,
+            None => return Err(Error::os(format!("No menu item {}", idx))),
+        };
+        debug!("Moving menu item {} to position {}", idx, position);
+        self.window.move_menu_item(idx, position)?;
+        // The siblings keep the sort keys they had between them, handed out
+        // again in their new order.
+        let mut ids = self.menu_children(parent);
+        let keys: Vec<u32> = ids.iter().map(|i| self.menu[i].order.unwrap_or(*i)).collect();
+        ids.retain(|&i| i != idx);
+        ids.insert((position as usize).min(ids.len()), idx);
+        for (i, key) in ids.into_iter().zip(keys) {
+            if let Some(e) = self.menu.get_mut(&i) {
+                e.order = Some(key);
+            }
+        }
+        Ok(())
+    }
+
+    // The items in `parent`, or the menu itself, in menu order. Ids go up as
+    // items are added, so they sort into it, unless some were moved.
+    pub(crate) fn menu_children(&self, parent: Option<u32>) -> Vec<u32> {
+        let mut ids: Vec<u32> = self
+            .menu
+            .iter()
+            .filter(|(_, e)| e.parent == parent)
+            .map(|(idx, _)| *idx)
+            .collect();
+        ids.sort_unstable_by_key(|idx| self.menu[idx].order.unwrap_or(*idx));
+        ids
+    }
+
+    /// Runs `f` with the GTK menu on the GTK thread, for what this API can't
+    /// put in it, like sliders or images. What's added this way is the app's
+    /// own: its signals are the app's to connect, clicks on it reach neither
+    /// the callbacks nor wait_for_message, and it counts in the positions of
+    /// move_menu_item. Call show_all on new widgets, or they stay hidden.
+    ///
+    /// StatusNotifierItem sends its menu over D-Bus and has no GTK menu, so
+    /// there this returns NotImplementedError.
+    #[cfg(all(target_os = "linux", not(feature = "test-utils")))]
+    pub fn with_gtk_menu<F>(&self, f: F) -> Result<()>
+    where
+        F: FnOnce(&gtk::Menu) + Send + 'static,
+    {
+        self.window.with_gtk_menu(f)
+    }
+
+    /// Removes an item, separator or submenu along with everything in it.
+    pub fn remove_menu_item(&mut self, id: MenuItemId) -> Result<()> {
+        let idx = id.0;
+        if !self.menu.contains_key(&idx) {
+            return Err(Error::os(format!("No menu item {}", idx)));
+        }
+        debug!("Removing menu item {}", idx);
+        self.window.remove_menu_item(idx)?;
+        let mut removed = vec![idx];
+        while let Some(i) = removed.pop() {
+            self.menu.remove(&i);
+            if self.default_menu_item == Some(i) {
+                self.default_menu_item = None;
+            }
+            removed.extend(
+                self.menu
+                    .iter()
+                    .filter(|(_, e)| e.parent == Some(i))
+                    .map(|(child, _)| *child),
+            );
+        }
+        Ok(())
+    }
+
+    /// Checks or unchecks a checkable item. Checkable items also flip their
+    /// state by themselves when clicked, before their callback runs.
+    pub fn set_menu_item_checked(&mut self, id: MenuItemId, checked: bool) -> Result<()> {
+        let idx = id.0;
+        match self.menu.get_mut(&idx) {
+            Some(e) if e.checkable => {
+                self.window.set_menu_item_checked(idx, checked)?;
+                e.checked = checked;
+                Ok(())
+            }
+            _ => Err(Error::os(format!("No checkable menu item {}", idx))),
+        }
+    }
+
+    pub fn set_menu_item_enabled(&mut self, id: MenuItemId, enabled: bool) -> Result<()> {
+        let idx = id.0;
+        match self.menu.get_mut(&idx) {
+            Some(e) if e.kind == MenuItemKind::Label => {
+                Err(Error::os(format!("Menu item {} is a label", idx)))
+            }
+            Some(e) => {
+                self.window.set_menu_item_enabled(idx, enabled)?;
+                e.enabled = enabled;
+                Ok(())
+            }
+            None => Err(Error::os(format!("No menu item {}", idx))),
+        }
+    }
+
+    /// Makes an item the menu's default, which is shown in bold and runs on
+    /// a double click on the icon. There's one default at a time, so this
+    /// takes over from the previous one.
+    ///
+    /// StatusNotifierItem and macOS menus have no default item and show it
+    /// like the others, but the double click still runs it.
+    pub fn set_default_menu_item(&mut self, id: MenuItemId) -> Result<()> {
+        let idx = id.0;
+        match self.menu.get(&idx) {
+            Some(e) if e.kind == MenuItemKind::Normal => {
+                self.window.set_default_menu_item(idx)?;
+                self.default_menu_item = Some(idx);
+                Ok(())
+            }
+            _ => Err(Error::os(format!("No menu item {}", idx))),
+        }
+    }
+
+    /// Attaches a value to an item, replacing any earlier one. Callbacks can
+    /// look it up with menu_item_data.
+    pub fn set_menu_item_data(
+        &mut self,
+        id: MenuItemId,
+        data: Box<dyn Any + Send + Sync>,
+    ) -> Result<()> {
+        let idx = id.0;
+        match self.menu.get_mut(&idx) {
+            Some(e) => {
+                e.data = Some(data);
+                Ok(())
+            }
+            None => Err(Error::os(format!("No menu item {}", idx))),
+        }
+    }
+
+    pub fn menu_item_data(&self, id: MenuItemId) -> Option<&(dyn Any + Send + Sync)> {
+        let idx = id.0;
+        self.menu.get(&idx).and_then(|e| e.data.as_deref())
+    }
+
+    /// Shows an icon next to an item's label, replacing any earlier one.
+    pub fn set_menu_item_icon_rgba(
+        &self,
+        id: MenuItemId,
+        rgba: &[u8],
+        width: u32,
+        height: u32,
+    ) -> Result<()> {
+        let idx = id.0;
+        if !self.menu.contains_key(&idx) {
+            return Err(Error::os(format!("No menu item {}", idx)));
+        }
+        if width == 0 || height == 0 {
+            return Err(Error::os(format!("Empty {}x{} menu icon", width, height)));
+        }
+        if rgba.len() != (width as usize) * (height as usize) * 4 {
+            return Err(Error::os(format!(
+                "Menu icon buffer is {} bytes, expected {} for a {}x{} RGBA image",
+                rgba.len(),
+                (width as usize) * (height as usize) * 4,
+                width,
+                height
+            )));
+        }
+        self.window.set_menu_item_icon_rgba(idx, rgba, width, height)
+    }
+
+    #[cfg(feature = "image-icons")]
+    pub fn set_menu_item_icon(&self, id: MenuItemId, image: &image::DynamicImage) -> Result<()> {
+        let rgba = image.to_rgba8();
+        self.set_menu_item_icon_rgba(id, rgba.as_raw(), rgba.width(), rgba.height())
+    }
+
+    pub fn menu_item_info(&self, id: MenuItemId) -> Result<MenuItemInfo> {
+        let idx = id.0;
+        let e = self
+            .menu
+            .get(&idx)
+            .ok_or_else(|| Error::os(format!("No menu item {}", idx)))?;
+        Ok(MenuItemInfo {
+            label: e.label.clone(),
+            enabled: e.enabled,
+            checked: e.checkable.then_some(e.checked),
+            kind: e.kind,
+        })
+    }
+
+    pub fn menu_item_checked(&self, id: MenuItemId) -> Option<bool> {
+        let idx = id.0;
+        self.menu
+            .get(&idx)
+            .filter(|e| e.checkable)
+            .map(|e| e.checked)
+    }
+
+    // Ids are never handed out twice, so a click still queued for a removed
+    // item can't land on whatever was added after it. dbusmenu shifts them up
+    // by one into an i32, which is where they run out.
+    fn next_idx(&mut self) -> Result<u32> {
         let idx = self.menu_idx;
-        self.window.add_menu_entry(idx, item_name)?;
-        self.callback.insert(idx, make_callback(f));
+        if idx >= i32::MAX as u32 {
+            return Err(Error::os("Out of menu item ids"));
+        }
         self.menu_idx += 1;
         Ok(idx)
     }
 
-    pub fn add_menu_separator(&mut self) -> Result<u32, Error> {
-        let idx = self.menu_idx;
-        self.window.add_menu_separator(idx)?;
-        self.menu_idx += 1;
+    fn add_entry(&mut self, parent: Option<u32>, item_name: &str) -> Result<u32> {
+        let idx = self.add_label_entry(parent, &api::escape_mnemonic(item_name))?;
+        if let Some(e) = self.menu.get_mut(&idx) {
+            e.label = item_name.to_string();
+            e.mnemonic = false;
+        }
         Ok(idx)
     }
 
-    pub fn set_icon_from_file(&self, file: &str) -> Result<(), Error> {
-        self.window.set_icon_from_file(file)
-    }
-
-    pub fn set_icon_from_resource(&self, resource: &str) -> Result<(), Error> {
-        self.window.set_icon_from_resource(resource)
-    }
-
-    pub fn set_icon_from_image_file(&self, file: &str) -> Result<(), Error> {
-        use image::io::Reader as ImageReader;
-        use std::path::Path;
-        
-        let path = Path::new(file);
-        let extension = path.extension()
-            .and_then(|ext| ext.to_str())
-            .unwrap_or("")
-            .to_lowercase();
-        
-        match extension.as_str() {
-            "png" | "jpg" | "jpeg" => {
-                // 对于PNG和JPG格式，尝试直接加载
-                match self.window.set_icon_from_file(file) {
-                    Ok(()) => Ok(()),
-                    Err(_) => {
-                        // 如果平台不支持，转换为平台支持的格式
-                        let img = ImageReader::open(path)
-                            .map_err(|e| Error::OsError(format!("Failed to open image: {}", e)))?
-                            .decode()
-                            .map_err(|e| Error::OsError(format!("Failed to decode image: {}", e)))?;
-                        
-                        let (width, height) = (img.width(), img.height());
-                        let rgba_img = img.to_rgba8();
-                        let buffer = rgba_img.into_raw();
-                        
-                        #[cfg(target_os = "windows")]
-                         {
-                             // Windows: 转换为ICO格式或位图
-                             self.window.set_icon_from_buffer(&buffer, width, height)
-                         }
-                         
-                         #[cfg(target_os = "linux")]
-                         {
-                             // Linux: GTK支持PNG，JPG需要转换
-                             self.window.set_icon_from_image_buffer(&buffer, width, height)
-                         }
-                         
-                         #[cfg(not(any(target_os = "windows", target_os = "linux")))]
-                         {
-                             // 其他平台：需要实现
-                             Err(Error::NotImplementedError)
-                         }
-                    }
-                }
+    // `label` in the mnemonic syntax of add_menu_item_with_mnemonic.
+    fn add_label_entry(&mut self, parent: Option<u32>, label: &str) -> Result<u32> {
+        let idx = self.next_idx()?;
+        debug!("Adding menu item {} {:?} under {:?}", idx, label, parent);
+        self.window.add_menu_entry(idx, label, parent)?;
+        let mut entry = MenuEntry::new(parent, MenuItemKind::Normal, label);
+        entry.mnemonic = true;
+        self.menu.insert(idx, entry);
+        Ok(idx)
+    }
+
+    fn add_heading(&mut self, parent: Option<u32>, text: &str) -> Result<u32> {
+        let idx = self.add_entry(parent, text)?;
+        self.window.set_menu_item_enabled(idx, false)?;
+        if let Some(e) = self.menu.get_mut(&idx) {
+            e.kind = MenuItemKind::Label;
+            e.enabled = false;
+        }
+        Ok(idx)
+    }
+
+    fn add_check_entry(
+        &mut self,
+        parent: Option<u32>,
+        item_name: &str,
+        checked: bool,
+    ) -> Result<u32> {
+        let idx = self.next_idx()?;
+        debug!("Adding check menu item {} {:?} under {:?}", idx, item_name, parent);
+        self.window
+            .add_check_menu_entry(idx, &api::escape_mnemonic(item_name), checked, parent)?;
+        let mut entry = MenuEntry::new(parent, MenuItemKind::Normal, item_name);
+        entry.checkable = true;
+        entry.checked = checked;
+        self.menu.insert(idx, entry);
+        Ok(idx)
+    }
+
+    fn add_separator(&mut self, parent: Option<u32>) -> Result<u32> {
+        let idx = self.next_idx()?;
+        debug!("Adding menu separator {} under {:?}", idx, parent);
+        self.window.add_menu_separator(idx, parent)?;
+        self.menu
+            .insert(idx, MenuEntry::new(parent, MenuItemKind::Separator, ""));
+        Ok(idx)
+    }
+
+    fn add_submenu_entry(&mut self, parent: Option<u32>, item_name: &str) -> Result<u32> {
+        let idx = self.next_idx()?;
+        debug!("Adding submenu {} {:?} under {:?}", idx, item_name, parent);
+        self.window
+            .add_submenu(idx, &api::escape_mnemonic(item_name), parent)?;
+        self.menu
+            .insert(idx, MenuEntry::new(parent, MenuItemKind::Submenu, item_name));
+        Ok(idx)
+    }
+
+    fn set_key(&mut self, idx: u32, key: &str) {
+        if let Some(e) = self.menu.get_mut(&idx) {
+            e.key = Some(key.to_string());
+        }
+    }
+
+    fn set_callback(&mut self, idx: u32, f: Callback) {
+        if let Some(e) = self.menu.get_mut(&idx) {
+            e.callback = Some(f);
+        }
+    }
+
+    /// With the `svg` feature, SVG files are rasterized at the tray's icon
+    /// size, and again whenever the display scale changes. Without it only
+    /// the GTK backend takes them, as they are.
+    ///
+    /// The file is read again only once it changed, see forget_shown_icon.
+    #[deprecated(note = "use set_icon with IconSource::Path")]
+    pub fn set_icon_from_file(&self, file: &str) -> Result<()> {
+        self.set_icon(IconSource::Path(Path::new(file)))
+    }
+
+    pub(crate) fn set_file_icon(&self, file: &Path) -> Result<()> {
+        self.set_icon_once(icon_cache::file_fingerprint(file, None), || {
+            self.forget_icon_source();
+            if svg::has_svg_extension(file) {
+                return self.set_icon_from_svg_file(file);
             }
-            "ico" | "bmp" => {
-                // 对于ICO和BMP格式，使用原有的方法
+            self.show_base_icon(OwnedIconSource::Path(file.to_path_buf()), |_| {
                 self.window.set_icon_from_file(file)
-            }
-            _ => Err(Error::OsError(format!("Unsupported image format: {}", extension))),
+            })
+            .inspect_err(|e| warn!("Failed to set icon from {}: {}", file.display(), e))
+        })
+    }
+
+    /// Like set_icon_from_file, but makes the icon `size`x`size` pixels
+    /// instead of the tray's icon size. On Windows an ICO file gives its
+    /// closest frame, elsewhere the image is scaled from its largest one,
+    /// which needs the `image-icons` feature.
+    pub fn set_icon_from_file_sized(&self, file: &str, size: u32) -> Result<()> {
+        #[cfg(target_os = "windows")]
+        {
+            let file = Path::new(file);
+            self.set_icon_once(icon_cache::file_fingerprint(file, Some(size)), || {
+                self.forget_icon_source();
+                self.show_base_icon(OwnedIconSource::Path(file.to_path_buf()), |_| {
+                    self.window.set_icon_from_file_sized(file, size)
+                })
+                .inspect_err(|e| warn!("Failed to set icon from {}: {}", file.display(), e))
+            })
+        }
+        #[cfg(all(not(target_os = "windows"), feature = "image-icons"))]
+        {
+            self.set_icon_once(icon_cache::file_fingerprint(Path::new(file), Some(size)), || {
+                let img = image::open(file)
+                    .map_err(|e| Error::from_image(format!("Failed to load icon {}", file), &e))?
+                    .resize_exact(size, size, image::imageops::FilterType::Lanczos3)
+                    .to_rgba8();
+                self.set_icon_from_rgba(img.as_raw(), size, size)
+            })
         }
+        #[cfg(all(not(target_os = "windows"), not(feature = "image-icons")))]
+        {
+            let _ = (file, size);
+            Err(Error::NotImplementedError)
+        }
+    }
+
+    /// Sets the icon from an image built into the app: on Windows an icon
+    /// resource of the executable by name, on macOS an image in the app
+    /// bundle by name, and on Linux a GResource by its path, e.g.
+    /// "/org/example/app/tray.png", once the app registered the resource
+    /// bundle with gio::resources_register.
+    #[deprecated(note = "use set_icon with IconSource::Resource")]
+    pub fn set_icon_from_resource(&self, resource: &str) -> Result<()> {
+        self.set_icon(IconSource::Resource(resource))
+    }
+
+    pub(crate) fn set_resource_icon(&self, resource: &str) -> Result<()> {
+        let icon = icon_cache::fingerprint(("resource", resource));
+        self.set_icon_once(Some(icon), || {
+            self.forget_icon_source();
+            self.show_base_icon(OwnedIconSource::Resource(resource.to_string()), |_| {
+                self.window.set_icon_from_resource(resource)
+            })
+            .inspect_err(|e| warn!("Failed to set icon from resource {}: {}", resource, e))
+        })
+    }
+
+    /// Sets the icon by its freedesktop icon theme name, e.g.
+    /// "network-wireless-signal-good-symbolic", so it follows the user's
+    /// icon theme and panel size. Names the theme doesn't have are an error
+    /// rather than a broken image, and badges aren't drawn over it. Linux
+    /// only, elsewhere this returns NotImplementedError.
+    #[deprecated(note = "use set_icon with IconSource::ThemeName")]
+    pub fn set_icon_from_theme(&self, icon_name: &str) -> Result<()> {
+        self.set_icon(IconSource::ThemeName(icon_name))
+    }
+
+    pub(crate) fn set_theme_icon(&self, icon_name: &str) -> Result<()> {
+        let icon = icon_cache::fingerprint(("theme", icon_name));
+        self.set_icon_once(Some(icon), || {
+            self.forget_icon_source();
+            #[cfg(feature = "image-icons")]
+            self.base_icon.replace(None);
+            self.window
+                .set_icon_from_theme(icon_name)
+                .inspect_err(|e| warn!("Failed to set icon {}: {}", icon_name, e))
+        })
+    }
+
+    /// Goes back to a plain default icon: the stock application icon on
+    /// Windows, application-x-executable from the icon theme on Linux and
+    /// the app's own icon on macOS. Whatever icon the crate made before is
+    /// freed, and the next `set_icon_*` call always goes through.
+    pub fn clear_icon(&self) -> Result<()> {
+        self.forget_icon_source();
+        #[cfg(feature = "image-icons")]
+        self.base_icon.replace(None);
+        self.window
+            .clear_icon()
+            .inspect_err(|e| warn!("Failed to clear the icon: {}", e))
+    }
+
+    /// Shows an icon the app already has a handle to, e.g. from LoadImage.
+    /// The handle stays the app's: it's never destroyed here, but has to
+    /// stay valid while it's the icon, as it's shown again after Explorer
+    /// restarts. Badges aren't drawn over it.
+    #[cfg(target_os = "windows")]
+    pub fn set_icon_from_hicon(&self, hicon: winapi::shared::windef::HICON) -> Result<()> {
+        self.set_hicon(hicon, false)
     }
 
+    /// Like set_icon_from_hicon, but hands the handle over, to be destroyed
+    /// with DestroyIcon once another icon replaces it or the Application
+    /// goes. That holds even when this returns an error.
+    ///
+    /// # Safety
+    ///
+    /// `hicon` has to be a valid icon that nothing else destroys or hands
+    /// over again, and not a shared one such as LoadImage gives with
+    /// LR_SHARED.
     #[cfg(target_os = "windows")]
-    pub fn set_icon_from_buffer(
+    pub unsafe fn set_icon_from_owned_hicon(
         &self,
-        buffer: &[u8],
-        width: u32,
-        height: u32,
-    ) -> Result<(), Error> {
-        self.window.set_icon_from_buffer(buffer, width, height)
+        hicon: winapi::shared::windef::HICON,
+    ) -> Result<()> {
+        self.set_hicon(hicon, true)
+    }
+
+    #[cfg(target_os = "windows")]
+    fn set_hicon(&self, hicon: winapi::shared::windef::HICON, owned: bool) -> Result<()> {
+        self.forget_icon_source();
+        #[cfg(feature = "image-icons")]
+        self.base_icon.replace(None);
+        self.window
+            .set_icon_from_hicon(hicon, owned)
+            .inspect_err(|e| warn!("Failed to set icon from HICON: {}", e))
+    }
+
+    /// Sets the icon from an icon resource of the executable by its numeric
+    /// id, as in `IDI_TRAY = 101`. Windows only, elsewhere this returns
+    /// NotImplementedError.
+    #[deprecated(note = "use set_icon with IconSource::ResourceId")]
+    pub fn set_icon_from_resource_id(&self, id: u16) -> Result<()> {
+        self.set_icon(IconSource::ResourceId(id))
+    }
+
+    pub(crate) fn set_resource_id_icon(&self, id: u16) -> Result<()> {
+        let icon = icon_cache::fingerprint(("resource id", id));
+        self.set_icon_once(Some(icon), || {
+            self.forget_icon_source();
+            self.show_base_icon(OwnedIconSource::ResourceId(id), |_| {
+                self.window.set_icon_from_resource_id(id)
+            })
+            .inspect_err(|e| warn!("Failed to set icon from resource {}: {}", id, e))
+        })
+    }
+
+    /// Like set_icon_from_resource_id, for an icon in another module, e.g. a
+    /// resource DLL. The module stays loaded and the app's to free.
+    ///
+    /// # Safety
+    ///
+    /// `module` has to be the handle of a module that's loaded, as from
+    /// LoadLibrary or GetModuleHandle.
+    #[cfg(target_os = "windows")]
+    pub unsafe fn set_icon_from_module_resource_id(
+        &self,
+        module: winapi::shared::minwindef::HMODULE,
+        id: u16,
+    ) -> Result<()> {
+        self.forget_icon_source();
+        #[cfg(feature = "image-icons")]
+        self.base_icon.replace(None);
+        self.window
+            .set_icon_from_module_resource_id(module, id)
+            .inspect_err(|e| warn!("Failed to set icon from resource {}: {}", id, e))
+    }
+
+    /// Sets the icon from an image file in any format set_icon_from_memory
+    /// takes, told by its content rather than its extension. ICO and BMP
+    /// files go to the platform as they are, so Windows picks the frame for
+    /// the tray's size; anything else is decoded here from what was read.
+    #[cfg(feature = "image-icons")]
+    pub fn set_icon_from_image_file(&self, file: &str) -> Result<()> {
+        let data = std::fs::read(file)
+            .map_err(|e| Error::from_io(format!("Failed to read icon {}", file), &e))?;
+        match image::guess_format(&data) {
+            Ok(image::ImageFormat::Ico | image::ImageFormat::Bmp) => {
+                self.set_file_icon(Path::new(file))
+            }
+            _ => self.set_memory_icon(&data),
+        }
+    }
+
+    /// Sets the icon from several RGBA renditions of the same image, given as
+    /// `(width, height, pixels)`, and lets the platform pick the one matching
+    /// the tray's current icon size. On Windows the tray uses 16, 20, 24 and
+    /// 32 pixel icons at 100%, 125%, 150% and 200% scaling, so supplying
+    /// those four covers the common cases.
+    pub fn set_icon_multi(&self, images: &[(u32, u32, Vec<u8>)]) -> Result<()> {
+        let (width, height, buffer) = pick_icon_size(images, self.icon_size())
+            .ok_or_else(|| Error::os("No icon images given"))?;
+        self.set_icon_from_rgba(buffer, *width, *height)
+    }
+
+    /// The display scale factor, where 1.0 means 96 DPI.
+    pub fn scale_factor(&self) -> f64 {
+        self.window.scale_factor()
+    }
+
+    fn icon_size(&self) -> u32 {
+        self.window.icon_size()
+    }
+
+    /// Whether icons given as pixels, from buffers, decoded images or
+    /// rendered SVGs, are scaled down to the tray's icon size with a
+    /// Lanczos filter before the platform sees them. Left to the platform,
+    /// big icons come out jagged, or not at all on some panels. Small ones
+    /// are never scaled up. On by default; turn it off to have icons from
+    /// set_icon_from_file_sized bigger than the tray's size. Needs the
+    /// `image-icons` feature.
+    pub fn set_icon_downscaling(&self, enabled: bool) {
+        self.downscale_icons.set(enabled);
+        self.forget_shown_icon();
+    }
+
+    fn set_icon_from_rgba(&self, buffer: &[u8], width: u32, height: u32) -> Result<()> {
+        let icon = icon_cache::fingerprint(("rgba", width, height, buffer));
+        self.set_icon_once(Some(icon), || self.show_rgba_base(buffer.to_vec(), width, height))
+    }
+
+    // The pixels are kept as they are for what the icon gets redrawn from.
+    fn show_rgba_base(&self, rgba: Vec<u8>, width: u32, height: u32) -> Result<()> {
+        self.forget_icon_source();
+        let base = OwnedIconSource::Rgba {
+            data: rgba,
+            width,
+            height,
+        };
+        self.show_base_icon(base, |base| self.show_unbadged(base))
+            .inspect_err(|e| warn!("Failed to set {}x{} icon: {}", width, height, e))
+    }
+
+    // Puts pixels up as they are, or scaled down to the tray's size, see
+    // set_icon_downscaling.
+    pub(crate) fn show_rgba(&self, buffer: &[u8], width: u32, height: u32) -> Result<()> {
+        #[cfg(feature = "image-icons")]
+        if self.downscale_icons.get() {
+            let icon = image::ImageBuffer::<image::Rgba<u8>, _>::from_raw(width, height, buffer);
+            if let Some(icon) = icon.and_then(|icon| self.fitted_icon(&icon)) {
+                let (width, height) = icon.dimensions();
+                return self.window.set_icon_from_rgba(icon.into_raw(), width, height);
+            }
+        }
+        self.window.set_icon_from_image_buffer(buffer, width, height)
     }
 
-    pub fn shutdown(&self) -> Result<(), Error> {
+    /// Sets the icon from `width * height` RGBA pixels, in rows top to bottom
+    /// without padding. Zero sizes, and buffers of any other length, are an
+    /// error on every platform; ICO data goes to set_icon as
+    /// IconSource::Encoded. For RGB or BGRA pixels see set_icon_from_pixels.
+    #[deprecated(note = "use set_icon with IconSource::Rgba, or IconSource::Encoded for ICO data")]
+    pub fn set_icon_from_buffer(&self, buffer: &[u8], width: u32, height: u32) -> Result<()> {
+        self.set_rgba_icon(Cow::Borrowed(buffer), width, height)
+    }
+
+    /// Sets the icon from an encoded PNG, ICO, BMP or JPEG image, e.g. one
+    /// embedded with `include_bytes!`. The format is taken from the data.
+    /// SVG works too with the `svg` feature, see set_icon_from_file.
+    ///
+    /// Without the `image-icons` feature only SVG is taken, anything else
+    /// is NotImplementedError.
+    #[deprecated(note = "use set_icon with IconSource::Encoded")]
+    pub fn set_icon_from_memory(&self, data: &[u8]) -> Result<()> {
+        self.set_icon(IconSource::Encoded(data))
+    }
+
+    pub(crate) fn set_memory_icon(&self, data: &[u8]) -> Result<()> {
+        let icon = icon_cache::fingerprint(("memory", data));
+        self.set_icon_once(Some(icon), || self.decode_icon(data))
+    }
+
+    fn decode_icon(&self, data: &[u8]) -> Result<()> {
+        if svg::is_svg(data) {
+            return self.set_icon_from_svg_data(data.to_vec());
+        }
+        #[cfg(feature = "image-icons")]
+        {
+            let format = image::guess_format(data)
+                .map_err(|_| {
+                    Error::DecodeError("Icon data is in no image format known".to_string())
+                })
+                .inspect_err(|e| warn!("{}", e))?;
+            let img = image::load_from_memory_with_format(data, format)
+                .map_err(|e| Error::from_image(format!("Failed to decode {:?} icon", format), &e))
+                .inspect_err(|e| warn!("{}", e))?
+                .to_rgba8();
+            let (width, height) = img.dimensions();
+            self.set_icon_from_rgba(&img, width, height)
+        }
+        #[cfg(not(feature = "image-icons"))]
+        Err(Error::NotImplementedError)
+    }
+
+    /// Sets the icon from encoded image data read from `reader`, in any
+    /// format set_icon_from_memory takes. The data is read to the end first,
+    /// since the decoders need to seek.
+    ///
+    /// Failing reads come back as `Error::OsError`, data that isn't a usable
+    /// image as `Error::DecodeError`.
+    pub fn set_icon_from_reader<R: std::io::Read>(&self, reader: R) -> Result<()> {
+        use std::io::{BufReader, Read};
+
+        let mut data = Vec::new();
+        BufReader::new(reader)
+            .read_to_end(&mut data)
+            .map_err(|e| Error::from_io("Failed to read icon", &e))
+            .inspect_err(|e| warn!("{}", e))?;
+        self.set_icon_from_encoded(data)
+    }
+
+    #[cfg(not(feature = "image-icons"))]
+    fn set_icon_from_encoded(&self, data: Vec<u8>) -> Result<()> {
+        self.set_memory_icon(&data)
+    }
+
+    #[cfg(feature = "image-icons")]
+    fn set_icon_from_encoded(&self, data: Vec<u8>) -> Result<()> {
+        use std::io::Cursor;
+
+        let img = image::io::Reader::new(Cursor::new(data))
+            .with_guessed_format()
+            .map_err(|e| Error::from_io("Failed to read icon", &e))?;
+        if img.format().is_none() {
+            return Err(Error::DecodeError(
+                "Icon data is in no image format known".to_string(),
+            ));
+        }
+        let img = img
+            .decode()
+            .map_err(|e| Error::from_image("Failed to decode icon", &e))
+            .inspect_err(|e| warn!("{}", e))?
+            .to_rgba8();
+        let (width, height) = img.dimensions();
+        self.set_icon_from_rgba(&img, width, height)
+    }
+
+    /// Sets the icon from an image built at runtime. Images with 16-bit or
+    /// float channels are converted to 8 bits.
+    #[cfg(feature = "image-icons")]
+    pub fn set_icon_from_image(&self, img: &image::DynamicImage) -> Result<()> {
+        let rgba = img.to_rgba8();
+        let (width, height) = rgba.dimensions();
+        self.set_rgba_icon(Cow::Owned(rgba.into_raw()), width, height)
+    }
+
+    pub fn shutdown(&self) -> Result<()> {
+        self.stopping.set(true);
+        // The watch holds on to the event channel, which has to close.
+        #[cfg(feature = "notify")]
+        self.stop_icon_watch();
         self.window.shutdown()
     }
 
-    pub fn set_tooltip(&self, tooltip: &str) -> Result<(), Error> {
-        self.window.set_tooltip(tooltip)
+    /// Takes the icon down as soon as any thread panics, before the panic
+    /// unwinds or aborts. Dropping the Application removes it otherwise,
+    /// which a panic on another thread, `panic = "abort"` or
+    /// std::process::exit never get to, and Windows keeps showing a dead
+    /// icon until the pointer passes over it. The panic hook set before is
+    /// still called after.
+    ///
+    /// Panics in callbacks, which wait_for_message catches and goes on from,
+    /// leave the icon up. The hook stays set after the Application is gone,
+    /// but then only calls the one before it. This is for Windows; elsewhere
+    /// the icon goes along with the process anyway and this does nothing.
+    pub fn remove_icon_on_panic(&self) -> Result<()> {
+        self.window.remove_icon_on_panic()
+    }
+
+    /// Tooltips over the platform's limit are cut short, between whole
+    /// characters, see set_tooltip_ex.
+    pub fn set_tooltip(&self, tooltip: &str) -> Result<()> {
+        self.set_tooltip_ex(tooltip, TruncationBehavior::Truncate)
+    }
+
+    /// Sets a tooltip that may span several lines separated by `\n`.
+    ///
+    /// Windows shows at most 127 UTF-16 units, with every newline taking two;
+    /// Linux and macOS have no limit of their own.
+    pub fn set_tooltip_ex(&self, tooltip: &str, behavior: TruncationBehavior) -> Result<()> {
+        self.tooltip_provider.lock().unwrap().take();
+        self.window.set_tooltip(&tooltip::fit(tooltip, behavior)?)
+    }
+
+    /// Has the tooltip come from `f`, called again each time the pointer comes
+    /// over the icon, until the next set_tooltip. `f` runs on the platform's
+    /// thread and should be quick. What it returns is cut short like
+    /// set_tooltip's.
+    ///
+    /// AppIndicator and macOS don't say when the pointer is over the icon, so
+    /// there `f` is only called once, now. StatusNotifierItem hosts call it
+    /// whenever they read the tooltip, which most do on hover.
+    pub fn set_tooltip_from_fn<F>(&mut self, f: F) -> Result<()>
+    where
+        F: Fn() -> String + Send + Sync + 'static,
+    {
+        self.window.set_tooltip(&tooltip::fit(&f(), TruncationBehavior::Truncate)?)?;
+        *self.tooltip_provider.lock().unwrap() = Some(Box::new(f));
+        Ok(())
+    }
+
+    /// Where the icon is on screen, e.g. to open a window next to it. On
+    /// Windows the coordinates are physical pixels.
+    ///
+    /// Only Windows and GtkStatusIcon know; AppIndicator, StatusNotifierItem
+    /// and macOS return NotImplementedError. Fails while the icon is hidden
+    /// or not in a tray.
+    pub fn tray_icon_rect(&self) -> Result<Rect> {
+        self.window.tray_icon_rect()
+    }
+
+    /// The window behind the icon, for handing to other GUI code, e.g. to
+    /// own a popup: on Windows the hidden window that gets the tray's
+    /// messages, on macOS the status item's button view. Linux trays have
+    /// no window of the app's own, so there this returns
+    /// NotImplementedError.
+    #[cfg(feature = "rwh")]
+    pub fn platform_handle(&self) -> Result<raw_window_handle::RawWindowHandle> {
+        self.window.raw_window_handle()
+    }
+
+    /// Whether the taskbar or panel the icon sits on is light or dark.
+    pub fn system_theme(&self) -> Theme {
+        self.window.system_theme()
+    }
+
+    /// Whether the process runs elevated, as administrator. UIPI keeps an
+    /// elevated app and the shell apart in some setups, so tray errors then
+    /// say so too. Windows only, elsewhere this returns NotImplementedError.
+    pub fn is_elevated(&self) -> Result<bool> {
+        self.window.is_elevated()
+    }
+
+    /// Asks for the icon to be shown on the taskbar rather than in the
+    /// overflow flyout, where Windows 11 puts new icons. There's no API for
+    /// it, so this flips the same setting the user would, which they can
+    /// still turn back. Call it once the icon is up: Windows only knows an
+    /// icon after it was first shown, and until then this is an error.
+    ///
+    /// Windows 10 and the other platforms have no such setting and return
+    /// NotImplementedError; Linux panels and the macOS menu bar decide for
+    /// themselves.
+    pub fn request_always_visible(&self) -> Result<()> {
+        self.window.request_always_visible()
+    }
+
+    /// Puts `text` on the clipboard, e.g. for a "Copy address" menu item.
+    /// Native on Windows. Linux and macOS need the `clipboard` feature and
+    /// return NotImplementedError without it. On X11 the text stays
+    /// available only while the Application is alive, as X11 clipboards are
+    /// served by the app that copied, unless a clipboard manager takes it.
+    pub fn clipboard_set_text(&self, text: &str) -> Result<()> {
+        self.window.set_clipboard_text(text)
+    }
+
+    // What the icon gets redrawn from on scale or theme changes. A new icon
+    // replaces it.
+    fn forget_icon_source(&self) {
+        self.forget_svg_icon();
+        self.themed_icons.replace(None);
+        self.forget_shown_icon();
+        self.animating.set(false);
+        #[cfg(feature = "notify")]
+        self.stop_icon_watch();
+    }
+
+    // Shows the icon again from what it was made of, so pixels and files
+    // are fitted to the tray's icon size after a scale change. Windows
+    // already loaded files and resources again at the new size.
+    fn refit_icon(&self) {
+        #[cfg(feature = "image-icons")]
+        {
+            // Put back by show_base_icon.
+            let base = self.base_icon.take();
+            let reloaded = matches!(
+                base,
+                Some(
+                    OwnedIconSource::Path(_)
+                        | OwnedIconSource::Resource(_)
+                        | OwnedIconSource::ResourceId(_)
+                )
+            ) && cfg!(windows)
+                && self.icon_badge.get().is_none();
+            if reloaded {
+                self.base_icon.replace(base);
+            } else if let Some(base) = base {
+                if let Err(e) = self.show_base_icon(base, |base| self.show_unbadged(base)) {
+                    warn!("Failed to refit the icon to the new scale: {}", e);
+                }
+            }
+        }
+    }
+
+    // Without badges the icon always goes up as it is.
+    #[cfg(not(feature = "image-icons"))]
+    fn show_base_icon<F>(&self, base: OwnedIconSource, show: F) -> Result<()>
+    where
+        F: FnOnce(IconSource<'_>) -> Result<()>,
+    {
+        show(base.source())
+    }
+
+    fn show_unbadged(&self, base: IconSource<'_>) -> Result<()> {
+        match base {
+            #[cfg(feature = "image-icons")]
+            IconSource::Encoded(_) => {
+                let icon = self.icon_pixels(base)?;
+                let (width, height) = icon.dimensions();
+                self.show_rgba(&icon, width, height)
+            }
+            IconSource::Rgba {
+                data,
+                width,
+                height,
+            } => self.show_rgba(&data, width, height),
+            base => self.window.apply_icon(base),
+        }
+    }
+
+    /// Makes the icon a template, or a plain icon again. The menu bar on
+    /// macOS draws template icons by their shape alone, in its own colors,
+    /// light, dark or highlighted. On Linux, icons from the icon theme show
+    /// as their "-symbolic" variant where the theme has one, which panels
+    /// recolor too; StatusNotifierItem hosts decide for themselves. Windows
+    /// shows icons as they are and ignores this.
+    ///
+    /// It holds for every icon set after, as for the current one.
+    pub fn set_icon_template(&self, is_template: bool) -> Result<()> {
+        self.window.set_icon_template(is_template)
+    }
+
+    /// Hides or shows the icon. Its icon, tooltip and menu are kept while hidden,
+    /// and changes made to them in the meantime show once it's back. Clicks
+    /// and notifications need the icon shown.
+    pub fn set_icon_visible(&self, visible: bool) -> Result<()> {
+        self.window.set_icon_visible(visible)?;
+        self.icon_visible.set(visible);
+        Ok(())
+    }
+
+    /// Same as set_icon_visible(true).
+    pub fn show_icon(&self) -> Result<()> {
+        self.set_icon_visible(true)
+    }
+
+    /// Same as set_icon_visible(false). Clicks still queued from before are
+    /// dropped.
+    pub fn hide_icon(&self) -> Result<()> {
+        self.set_icon_visible(false)
+    }
+
+    pub fn icon_visible(&self) -> bool {
+        self.icon_visible.get()
+    }
+
+    /// Whether left clicks open the menu too, not only right clicks, which
+    /// is the default. While off, left clicks on the icon do nothing.
+    ///
+    /// AppIndicator and macOS open the menu on either button regardless.
+    /// StatusNotifierItem hosts may only pick the change up when the panel
+    /// restarts.
+    pub fn set_menu_on_left_click(&self, enabled: bool) {
+        self.window.set_menu_on_left_click(enabled)
+    }
+
+    /// Stops right clicks from opening the menu, for apps that show their
+    /// own popup on SystrayEvent::RightClick instead. Left clicks are still governed by
+    /// set_menu_on_left_click.
+    ///
+    /// AppIndicator and macOS always open the menu. StatusNotifierItem hosts
+    /// may only pick the change up when the panel restarts.
+    pub fn set_suppress_default_menu(&self, suppress: bool) {
+        self.window.set_suppress_default_menu(suppress)
     }
 
     pub fn quit(&mut self) {
+        self.stopping.set(true);
+        #[cfg(feature = "notify")]
+        self.stop_icon_watch();
         self.window.quit()
     }
 
-    pub fn wait_for_message(&mut self) -> Result<(), Error> {
+    /// Sets a callback that `wait_for_message` runs for every event, after
+    /// any menu item callback registered for it.
+    pub fn set_event_callback<F, E>(&mut self, mut f: F)
+    where
+        F: FnMut(&mut Application, &SystrayEvent) -> std::result::Result<(), E>
+            + Send
+            + Sync
+            + 'static,
+        E: error::Error + Send + Sync + 'static,
+    {
+        self.event_callback = Some(Box::new(move |a: &mut Application, ev: &SystrayEvent| {
+            f(a, ev).map_err(|e| Box::new(e) as BoxedError)
+        }));
+    }
+
+    /// Sets what runs when a callback panics during wait_for_message, with the
+    /// panic's payload. Either way the panic is logged and the loop goes on
+    /// with the next event.
+    pub fn set_panic_handler<F>(&mut self, f: F)
+    where
+        F: Fn(Box<dyn Any + Send>) + Send + Sync + 'static,
+    {
+        self.panic_handler = Some(Box::new(f));
+    }
+
+    pub fn wait_for_message(&mut self) -> Result<()> {
         loop {
-            
-            let msg = match self.rx.recv() {
+            let msg = match self.next_event() {
                 Ok(m) => m,
                 Err(_) => {
-                    self.quit();
+                    self.event_channel_closed();
                     break;
                 }
             };
-            if self.callback.contains_key(&msg.menu_index) {
-                if let Some(mut f) = self.callback.remove(&msg.menu_index) {
-                    f(self)?;
-                    self.callback.insert(msg.menu_index, f);
+            if let Some(msg) = self.accept_event(msg) {
+                self.dispatch_event(&msg)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Like wait_for_message, but returns Ok(None) once `dur` has passed, for
+    /// apps with work of their own to do every so often. Events coming in
+    /// until then run their callbacks as usual. Ok(Some(())) means the tray
+    /// shut down, where wait_for_message would have returned, so loop while
+    /// this gives None.
+    pub fn wait_for_message_timeout(&mut self, dur: Duration) -> Result<Option<()>> {
+        let deadline = match Instant::now().checked_add(dur) {
+            Some(deadline) => deadline,
+            None => return self.wait_for_message().map(Some),
+        };
+        loop {
+            let msg = match self.next_event_until(deadline) {
+                Ok(m) => m,
+                Err(RecvTimeoutError::Timeout) => return Ok(None),
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.event_channel_closed();
+                    return Ok(Some(()));
+                }
+            };
+            if let Some(msg) = self.accept_event(msg) {
+                self.dispatch_event(&msg)?;
+            }
+        }
+    }
+
+    /// For apps that run their own loop instead of wait_for_message: blocks
+    /// until the next event and hands it back, None once the tray has shut
+    /// down. Every click on a menu item comes as SystrayEvent::MenuItem,
+    /// whether the item has a callback or not. Callbacks only run with
+    /// set_auto_dispatch.
+    ///
+    /// Events wait_for_message drops, like clicks on removed items, labels
+    /// or the hidden icon, are dropped here too.
+    pub fn wait_event(&mut self) -> Result<Option<SystrayEvent>> {
+        loop {
+            let msg = match self.next_event() {
+                Ok(m) => m,
+                Err(_) => {
+                    self.event_channel_closed();
+                    return Ok(None);
+                }
+            };
+            if let Some(msg) = self.accept_event(msg) {
+                if self.auto_dispatch {
+                    self.dispatch_event(&msg)?;
+                }
+                return Ok(Some(msg));
+            }
+        }
+    }
+
+    /// Like wait_event, but returns None right away when no event is
+    /// waiting.
+    pub fn poll_event(&mut self) -> Result<Option<SystrayEvent>> {
+        loop {
+            let msg = match self.try_next_event() {
+                Ok(m) => m,
+                Err(TryRecvError::Empty) => return Ok(None),
+                Err(TryRecvError::Disconnected) => {
+                    self.event_channel_closed();
+                    return Ok(None);
+                }
+            };
+            if let Some(msg) = self.accept_event(msg) {
+                if self.auto_dispatch {
+                    self.dispatch_event(&msg)?;
                 }
+                return Ok(Some(msg));
             }
         }
+    }
+
+    /// Whether wait_event and poll_event run menu item, hotkey, notification
+    /// action and event callbacks before handing an event back, as
+    /// wait_for_message does. Off by default.
+    pub fn set_auto_dispatch(&mut self, enabled: bool) {
+        self.auto_dispatch = enabled;
+    }
+
+    /// Another end of the channel wait_for_message reads events from, for
+    /// apps that take them on other threads. Each event goes to only one
+    /// receiver, and those taken here skip the Application: no callbacks
+    /// run for them, and clicks on removed items or the hidden icon aren't
+    /// filtered out.
+    #[cfg(feature = "crossbeam")]
+    pub fn event_receiver(&self) -> crossbeam_channel::Receiver<SystrayEvent> {
+        self.rx.clone()
+    }
+}
+
+thread_local! {
+    // Set while guard_panic runs a callback on this thread.
+    static CATCHING_PANICS: Cell<bool> = const { Cell::new(false) };
+}
 
+// Whether a panic on this thread right now is one guard_panic catches, for
+// the panic hook of remove_icon_on_panic.
+#[cfg_attr(
+    not(all(target_os = "windows", not(feature = "test-utils"))),
+    allow(dead_code)
+)]
+pub(crate) fn panic_is_caught() -> bool {
+    CATCHING_PANICS.with(Cell::get)
+}
+
+// Smallest image that covers the wanted size, or the largest one if none does.
+fn pick_icon_size(images: &[(u32, u32, Vec<u8>)], size: u32) -> Option<&(u32, u32, Vec<u8>)> {
+    images
+        .iter()
+        .filter(|(w, h, _)| (*w).max(*h) >= size)
+        .min_by_key(|(w, h, _)| (*w).max(*h))
+        .or_else(|| images.iter().max_by_key(|(w, h, _)| (*w).max(*h)))
+}
+
+impl Application {
+    fn event_channel_closed(&mut self) {
+        if self.stopping.get() {
+            debug!("Event channel closed after shutdown");
+        } else {
+            error!("Event channel disconnected, the platform thread is gone");
+        }
+        self.quit();
+    }
+
+    // Keeps the Application's own state in step with the event, and drops
+    // the ones nobody should see.
+    fn accept_event(&mut self, msg: SystrayEvent) -> Option<SystrayEvent> {
+        if !self.icon_visible.get() && msg.is_click() {
+            debug!("Dropping {:?} on the hidden icon", msg);
+            return None;
+        }
+        if msg.is_click() || msg == SystrayEvent::MenuOpened {
+            self.attention_acknowledged();
+        }
+        if msg == SystrayEvent::ScaleFactorChanged {
+            self.forget_shown_icon();
+            // Setting the icon would stop an animation, which keeps its
+            // frames as they are.
+            if !self.animating.get() && !self.rerender_svg_icon() {
+                self.refit_icon();
+            }
+        }
+        if msg == SystrayEvent::IconRecreated {
+            self.forget_shown_icon();
+        }
+        #[cfg(feature = "notify")]
+        if msg == SystrayEvent::IconFileChanged && !self.animating.get() {
+            self.reload_watched_icon();
+        }
+        if let SystrayEvent::ThemeChanged(theme) = msg {
+            self.theme_changed(theme);
+        }
+        if let SystrayEvent::MenuItem(MenuItemId(menu_index)) = msg {
+            match self.menu.get(&menu_index) {
+                None => {
+                    debug!("Dropping click on removed menu item {}", menu_index);
+                    return None;
+                }
+                Some(e) if e.kind == MenuItemKind::Label => {
+                    debug!("Dropping click on menu label {}", menu_index);
+                    return None;
+                }
+                Some(_) => {}
+            }
+        }
+        Some(msg)
+    }
+
+    // Runs whatever callbacks the event has.
+    fn dispatch_event(&mut self, msg: &SystrayEvent) -> Result<()> {
+        if let SystrayEvent::MenuItem(MenuItemId(menu_index)) = *msg {
+            self.activate_menu_item(menu_index)?;
+        }
+        if let SystrayEvent::DoubleClick { .. } = msg {
+            let default = self.default_menu_item.filter(|i| self.menu.contains_key(i));
+            if let Some(menu_index) = default {
+                self.activate_menu_item(menu_index)?;
+            }
+        }
+        if let SystrayEvent::NotificationAction { action_key } = msg {
+            self.dispatch_notification_action(action_key)?;
+        }
+        if let SystrayEvent::HotkeyPressed(id) = *msg {
+            self.dispatch_hotkey(id)?;
+        }
+        if let Some(mut f) = self.event_callback.take() {
+            let res = self.guard_panic(|app| f(app, msg));
+            // The callback may have installed a replacement for itself.
+            if self.event_callback.is_none() {
+                self.event_callback = Some(f);
+            }
+            res?;
+        }
+        Ok(())
+    }
+
+    // What a click on a menu item does.
+    fn activate_menu_item(&mut self, menu_index: u32) -> Result<()> {
+        self.toggle_checkable(menu_index)?;
+        let cb = self
+            .menu
+            .get_mut(&menu_index)
+            .and_then(|e| e.callback.take());
+        if let Some(mut f) = cb {
+            let res = self.guard_panic(|app| f(app));
+            // Put the callback back unless it removed its own item.
+            self.set_callback(menu_index, f);
+            res?;
+        }
+        self.dispatch_spec_action(menu_index);
+        Ok(())
+    }
+
+    // Runs a callback, turning a panic into a log entry and a call to the
+    // panic handler.
+    pub(crate) fn guard_panic<F>(&mut self, f: F) -> std::result::Result<(), BoxedError>
+    where
+        F: FnOnce(&mut Application) -> std::result::Result<(), BoxedError>,
+    {
+        // Callbacks may dispatch events themselves, with guard_panic again.
+        let caught = CATCHING_PANICS.with(|c| c.replace(true));
+        let res = panic::catch_unwind(AssertUnwindSafe(|| f(self)));
+        CATCHING_PANICS.with(|c| c.set(caught));
+        match res {
+            Ok(res) => res,
+            Err(payload) => {
+                let message = payload
+                    .downcast_ref::<&str>()
+                    .copied()
+                    .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                    .unwrap_or("non-string payload");
+                error!("Callback panicked: {}", message);
+                if let Some(handler) = &self.panic_handler {
+                    handler(payload);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn toggle_checkable(&mut self, idx: u32) -> Result<()> {
+        if let Some(e) = self.menu.get_mut(&idx).filter(|e| e.checkable) {
+            e.checked = !e.checked;
+            let checked = e.checked;
+            self.window.set_menu_item_checked(idx, checked)?;
+        }
         Ok(())
     }
+
+    #[cfg(not(target_os = "macos"))]
+    fn next_event(&self) -> std::result::Result<SystrayEvent, RecvError> {
+        self.rx.recv()
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    fn try_next_event(&self) -> std::result::Result<SystrayEvent, TryRecvError> {
+        self.rx.try_recv()
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    fn next_event_until(
+        &self,
+        deadline: Instant,
+    ) -> std::result::Result<SystrayEvent, RecvTimeoutError> {
+        self.rx.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+    }
+
+    // AppKit only delivers menu actions while the main thread pumps its run
+    // loop, so poll the channel in between.
+    #[cfg(target_os = "macos")]
+    fn next_event(&self) -> std::result::Result<SystrayEvent, RecvError> {
+        loop {
+            match self.rx.try_recv() {
+                Ok(m) => return Ok(m),
+                Err(TryRecvError::Disconnected) => return Err(RecvError),
+                Err(TryRecvError::Empty) => self.window.pump_events(),
+            }
+        }
+    }
+
+    // Pumps the run loop once when nothing is waiting yet, which can take
+    // up to a tenth of a second.
+    #[cfg(target_os = "macos")]
+    fn try_next_event(&self) -> std::result::Result<SystrayEvent, TryRecvError> {
+        match self.rx.try_recv() {
+            Err(TryRecvError::Empty) => {
+                self.window.pump_events();
+                self.rx.try_recv()
+            }
+            res => res,
+        }
+    }
+
+    // Pumps the run loop until the deadline, which may go by a tenth of a
+    // second before it's noticed.
+    #[cfg(target_os = "macos")]
+    fn next_event_until(
+        &self,
+        deadline: Instant,
+    ) -> std::result::Result<SystrayEvent, RecvTimeoutError> {
+        loop {
+            match self.rx.try_recv() {
+                Ok(m) => return Ok(m),
+                Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+                Err(TryRecvError::Empty) if Instant::now() >= deadline => {
+                    return Err(RecvTimeoutError::Timeout)
+                }
+                Err(TryRecvError::Empty) => self.window.pump_events(),
+            }
+        }
+    }
 }
 
 impl Drop for Application {
@@ -210,3 +1805,310 @@ impl Drop for Application {
         self.shutdown().ok();
     }
 }
+
+#[cfg(all(test, feature = "test-utils"))]
+mod tests {
+    use super::*;
+    use crate::api::platform::MockCall;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    // Queues clicks on `ids` and runs wait_for_message until they're done.
+    fn click(app: &mut Application, ids: &[MenuItemId]) {
+        for id in ids {
+            app.mock_window().send_event(SystrayEvent::MenuItem(*id));
+        }
+        app.shutdown().unwrap();
+        app.wait_for_message().unwrap();
+    }
+
+    #[test]
+    fn callback_panics_are_caught_and_known_as_such() {
+        let mut app = Application::mock();
+        let caught = Arc::new(AtomicBool::new(false));
+        let runs = Arc::new(AtomicUsize::new(0));
+        let seen = caught.clone();
+        let panicking = app
+            .add_menu_item("Panic", move |_: &mut Application| -> std::io::Result<()> {
+                seen.store(panic_is_caught(), Ordering::SeqCst);
+                panic!("callback panic");
+            })
+            .unwrap();
+        let counted = runs.clone();
+        let next = app
+            .add_menu_item("Next", move |_: &mut Application| -> std::io::Result<()> {
+                counted.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            })
+            .unwrap();
+        click(&mut app, &[panicking, next]);
+        assert!(caught.load(Ordering::SeqCst));
+        assert!(!panic_is_caught());
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn wrapped_errors_are_the_source() {
+        use std::error::Error as _;
+
+        let e = Error::from(std::io::Error::other("disk gone"));
+        assert_eq!(e.source().unwrap().to_string(), "disk gone");
+        assert!(!e.to_string().contains("disk gone"));
+        assert!(Error::os("failed").source().is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "image-icons")]
+    fn resource_id_icons_are_set_again_after_a_scale_change() {
+        let mut app = Application::mock();
+        app.set_icon(IconSource::ResourceId(101)).unwrap();
+        app.mock_window().clear_recorded_calls();
+        app.mock_window().send_event(SystrayEvent::ScaleFactorChanged);
+        app.shutdown().unwrap();
+        app.wait_for_message().unwrap();
+        let calls = app.mock_window().recorded_calls();
+        assert!(calls.contains(&MockCall::SetIconFromResourceId(101)), "{:?}", calls);
+    }
+
+    #[test]
+    fn labels_reach_the_platform_escaped_unless_they_have_mnemonics() {
+        let mut app = Application::mock();
+        let none = |_: &mut Application| Ok::<_, Error>(());
+        let plain = app.add_menu_item("Save & Quit", none).unwrap();
+        let marked = app.add_menu_item_with_mnemonic("&Quit && Save", none).unwrap();
+        assert_eq!(
+            app.mock_window().recorded_calls(),
+            [
+                MockCall::AddMenuEntry {
+                    id: plain,
+                    label: "Save && Quit".to_string(),
+                    parent: None,
+                },
+                MockCall::AddMenuEntry {
+                    id: marked,
+                    label: "&Quit && Save".to_string(),
+                    parent: None,
+                },
+            ]
+        );
+        assert_eq!(app.menu_item_info(plain).unwrap().label, "Save & Quit");
+        assert_eq!(app.menu_item_info(marked).unwrap().label, "&Quit && Save");
+    }
+
+    type Runs = Arc<std::sync::Mutex<Vec<&'static str>>>;
+
+    // An item whose callback notes its label in `runs`.
+    fn logged_item(app: &mut Application, label: &'static str, runs: &Runs) -> MenuItemId {
+        let runs = runs.clone();
+        app.add_menu_item(label, move |_: &mut Application| -> std::io::Result<()> {
+            runs.lock().unwrap().push(label);
+            Ok(())
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn queued_clicks_on_removed_items_run_nothing() {
+        let mut app = Application::mock();
+        let runs = Runs::default();
+        let old = logged_item(&mut app, "old", &runs);
+        let kept = logged_item(&mut app, "kept", &runs);
+        app.mock_window().send_event(SystrayEvent::MenuItem(old));
+        app.remove_menu_item(old).unwrap();
+        let new = logged_item(&mut app, "new", &runs);
+        assert!(new != old && new != kept);
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let events = seen.clone();
+        app.set_event_callback(move |_, event| -> std::io::Result<()> {
+            events.lock().unwrap().push(event.clone());
+            Ok(())
+        });
+        click(&mut app, &[kept, new]);
+        assert_eq!(*runs.lock().unwrap(), ["kept", "new"]);
+        assert!(!seen.lock().unwrap().contains(&SystrayEvent::MenuItem(old)));
+    }
+
+    #[test]
+    fn items_removed_by_a_callback_miss_their_queued_clicks() {
+        let mut app = Application::mock();
+        let runs = Runs::default();
+        let doomed = logged_item(&mut app, "doomed", &runs);
+        let log = runs.clone();
+        let added = Arc::new(std::sync::Mutex::new(None));
+        let new_id = added.clone();
+        let remover = app
+            .add_menu_item("remover", move |app: &mut Application| -> Result<()> {
+                app.remove_menu_item(doomed)?;
+                let log = log.clone();
+                let id = app.add_menu_item("new", move |_: &mut Application| -> Result<()> {
+                    log.lock().unwrap().push("new");
+                    Ok(())
+                })?;
+                *new_id.lock().unwrap() = Some(id);
+                Ok(())
+            })
+            .unwrap();
+        click(&mut app, &[remover, doomed]);
+        assert!(runs.lock().unwrap().is_empty());
+        let new = added.lock().unwrap().unwrap();
+        assert!(new != doomed && new != remover);
+    }
+
+    #[test]
+    fn two_apps_get_only_their_own_events() {
+        let runs = Runs::default();
+        let mut apps = [Application::mock(), Application::mock()];
+        let mut items = Vec::new();
+        let names = [("a", "Account A"), ("b", "Account B")];
+        for (app, (name, tooltip)) in apps.iter_mut().zip(names) {
+            app.set_tooltip(tooltip).unwrap();
+            app.set_icon(IconSource::ThemeName(&format!("account-{}", name))).unwrap();
+            items.push(logged_item(app, name, &runs));
+        }
+        // Both hand out the same ids, so only the channel tells them apart.
+        assert_eq!(items[0], items[1]);
+        let [a, b] = &mut apps;
+        click(b, &[items[1]]);
+        assert_eq!(*runs.lock().unwrap(), ["b"]);
+        click(a, &[items[0], items[0]]);
+        assert_eq!(*runs.lock().unwrap(), ["b", "a", "a"]);
+        for (app, name) in apps.iter().zip(["A", "B"]) {
+            let calls = app.mock_window().recorded_calls();
+            assert!(calls.contains(&MockCall::SetTooltip(format!("Account {}", name))));
+            let icon = MockCall::SetIconFromTheme(format!("account-{}", name.to_lowercase()));
+            assert!(calls.contains(&icon));
+        }
+    }
+
+    fn images(sizes: &[(u32, u32)]) -> Vec<(u32, u32, Vec<u8>)> {
+        sizes
+            .iter()
+            .map(|&(w, h)| (w, h, vec![0xff; (w * h * 4) as usize]))
+            .collect()
+    }
+
+    fn picked(images: &[(u32, u32, Vec<u8>)], size: u32) -> Option<(u32, u32)> {
+        pick_icon_size(images, size).map(|(w, h, _)| (*w, *h))
+    }
+
+    #[test]
+    fn icon_sizes_are_picked_exact_else_next_larger_else_largest() {
+        let images = images(&[(32, 32), (16, 16), (24, 24), (20, 20)]);
+        assert_eq!(picked(&images, 20), Some((20, 20)));
+        assert_eq!(picked(&images, 22), Some((24, 24)));
+        assert_eq!(picked(&images, 8), Some((16, 16)));
+        assert_eq!(picked(&images, 48), Some((32, 32)));
+        assert_eq!(picked(&[], 16), None);
+    }
+
+    #[test]
+    fn icon_sizes_go_by_the_longer_side() {
+        let images = images(&[(8, 24), (20, 10), (32, 16)]);
+        assert_eq!(picked(&images, 20), Some((20, 10)));
+        assert_eq!(picked(&images, 22), Some((8, 24)));
+    }
+
+    #[test]
+    fn set_icon_multi_shows_the_image_for_the_tray_size() {
+        let app = Application::mock();
+        app.set_icon_multi(&images(&[(32, 32), (16, 16), (24, 24)])).unwrap();
+        assert!(app.set_icon_multi(&[]).is_err());
+        assert_eq!(
+            app.mock_window().recorded_calls(),
+            [MockCall::SetIconFromImageBuffer {
+                width: 16,
+                height: 16
+            }]
+        );
+    }
+
+    #[cfg(feature = "image-icons")]
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut data = std::io::Cursor::new(Vec::new());
+        image::RgbaImage::new(width, height)
+            .write_to(&mut data, image::ImageFormat::Png)
+            .unwrap();
+        data.into_inner()
+    }
+
+    // Counts what's read through it, and how often it was read at its end.
+    #[cfg(feature = "image-icons")]
+    struct CountingReader<R> {
+        inner: R,
+        bytes: usize,
+        ends: usize,
+    }
+
+    #[cfg(feature = "image-icons")]
+    impl<R: std::io::Read> std::io::Read for CountingReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = self.inner.read(buf)?;
+            self.bytes += n;
+            if n == 0 && !buf.is_empty() {
+                self.ends += 1;
+            }
+            Ok(n)
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "image-icons")]
+    fn readers_are_read_to_their_end_once() {
+        let app = Application::mock();
+        let data = png(5, 3);
+        let mut reader = CountingReader {
+            inner: std::io::Cursor::new(&data),
+            bytes: 0,
+            ends: 0,
+        };
+        app.set_icon_from_reader(&mut reader).unwrap();
+        assert_eq!((reader.bytes, reader.ends), (data.len(), 1));
+        assert_eq!(
+            app.mock_window().recorded_calls(),
+            [MockCall::SetIconFromImageBuffer {
+                width: 5,
+                height: 3
+            }]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "image-icons")]
+    fn image_files_go_by_their_content_and_only_ico_and_bmp_go_as_files() {
+        let app = Application::mock();
+        // Named for another format each, which mustn't matter.
+        let files = [
+            ("ico", png(4, 4)),
+            ("png", vec![0, 0, 1, 0, 1, 0]),
+            ("jpg", b"BM".to_vec()),
+        ];
+        let files: Vec<String> = files
+            .into_iter()
+            .map(|(ext, data)| {
+                let name = format!("systray2-test-{}.{}", std::process::id(), ext);
+                let file = std::env::temp_dir().join(name);
+                std::fs::write(&file, data).unwrap();
+                file.to_str().unwrap().to_string()
+            })
+            .collect();
+        let results: Vec<Result<()>> =
+            files.iter().map(|file| app.set_icon_from_image_file(file)).collect();
+        for file in &files {
+            let _ = std::fs::remove_file(file);
+        }
+        for result in results {
+            result.unwrap();
+        }
+        assert_eq!(
+            app.mock_window().recorded_calls(),
+            [
+                MockCall::SetIconFromImageBuffer {
+                    width: 4,
+                    height: 4
+                },
+                MockCall::SetIconFromFile(files[1].clone()),
+                MockCall::SetIconFromFile(files[2].clone()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,432 @@
+// Menus described as data, e.g. loaded from a config file.
+use crate::api::{escape_mnemonic, BatchEntry};
+use crate::{Application, BoxedError, MenuEntry, MenuItemId, MenuItemKind, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MenuSpec {
+    pub items: Vec<MenuItemSpec>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(tag = "type", rename_all = "snake_case")
+)]
+pub enum MenuItemSpec {
+    Item {
+        label: String,
+        action: String,
+        // Name in MenuState, for items whose state should be saved.
+        #[cfg_attr(feature = "serde", serde(default))]
+        key: Option<String>,
+        #[cfg_attr(feature = "serde", serde(default))]
+        checkable: bool,
+        #[cfg_attr(feature = "serde", serde(default))]
+        checked: bool,
+    },
+    Separator,
+    Submenu {
+        label: String,
+        items: Vec<MenuItemSpec>,
+    },
+}
+
+/// Saved check marks and enabled flags, by item key.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MenuState {
+    pub items: BTreeMap<String, MenuItemState>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MenuItemState {
+    /// `None` for items without a check mark.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub checked: Option<bool>,
+    #[cfg_attr(feature = "serde", serde(default = "enabled_default"))]
+    pub enabled: bool,
+}
+
+#[cfg(feature = "serde")]
+fn enabled_default() -> bool {
+    true
+}
+
+type Dispatch = Box<dyn FnMut(&str, &mut Application) + Send + Sync + 'static>;
+
+// What the last apply_menu_spec call put into the menu.
+pub(crate) struct AppliedSpec {
+    roots: Vec<u32>,
+    actions: HashMap<u32, String>,
+    dispatch: Option<Dispatch>,
+}
+
+impl Application {
+    /// Builds the menu described by `spec`, replacing whatever a previous call
+    /// built. Items added through add_menu_item are left alone. Clicks on spec
+    /// items call `dispatch` with the item's action id.
+    pub fn apply_menu_spec<F>(&mut self, spec: &MenuSpec, dispatch: F) -> Result<()>
+    where
+        F: FnMut(&str, &mut Application) + Send + Sync + 'static,
+    {
+        if let Some(old) = self.spec.take() {
+            for idx in old.roots {
+                self.remove_menu_item(MenuItemId(idx))?;
+            }
+        }
+        let mut applied = AppliedSpec {
+            roots: Vec::new(),
+            actions: HashMap::new(),
+            dispatch: Some(Box::new(dispatch)),
+        };
+        for item in &spec.items {
+            let idx = self.build_spec_item(item, None, &mut applied.actions)?;
+            applied.roots.push(idx);
+        }
+        self.spec = Some(applied);
+        Ok(())
+    }
+
+    /// Adds the items of `spec` after the current ones, for good. Clicks on any
+    /// of its items call `handler` with the clicked item's id, and its errors
+    /// come out of wait_for_message like those of add_menu_item callbacks.
+    pub fn add_menu_from_spec<F>(&mut self, spec: &[MenuItemSpec], handler: F) -> Result<()>
+    where
+        F: Fn(MenuItemId) -> std::result::Result<(), BoxedError> + Send + Sync + 'static,
+    {
+        let handler = Arc::new(handler);
+        let mut actions = HashMap::new();
+        for item in spec {
+            self.build_spec_item(item, None, &mut actions)?;
+        }
+        for idx in actions.into_keys() {
+            let handler = handler.clone();
+            self.set_callback(
+                idx,
+                Box::new(move |_: &mut Application| handler(MenuItemId(idx))),
+            );
+        }
+        Ok(())
+    }
+
+    /// Adds `items` after the current items, handing them to the platform all
+    /// at once instead of one call each, which is quicker for big menus that
+    /// are rebuilt often. Returns the ids of the top level items. Clicks on
+    /// them reach the event callback as SystrayEvent::MenuItem.
+    pub fn build_menu(&mut self, items: Vec<MenuItemSpec>) -> Result<Vec<MenuItemId>> {
+        let mut batch = Vec::new();
+        let mut roots = Vec::with_capacity(items.len());
+        for item in &items {
+            match self.plan_spec_item(item, None, &mut batch) {
+                Ok(idx) => roots.push(MenuItemId(idx)),
+                Err(e) => {
+                    self.forget_batch(&batch);
+                    return Err(e);
+                }
+            }
+        }
+        debug!("Adding {} menu entries at once", batch.len());
+        if let Err(e) = self.window.add_menu_entries(&batch) {
+            self.forget_batch(&batch);
+            return Err(e);
+        }
+        Ok(roots)
+    }
+
+    // Like build_spec_item, but only notes what the platform has to add.
+    fn plan_spec_item(
+        &mut self,
+        item: &MenuItemSpec,
+        parent: Option<u32>,
+        batch: &mut Vec<BatchEntry>,
+    ) -> Result<u32> {
+        let idx = self.next_idx()?;
+        let (entry, planned) = match item {
+            MenuItemSpec::Item {
+                label,
+                key,
+                checkable,
+                checked,
+                ..
+            } => {
+                let mut entry = MenuEntry::new(parent, MenuItemKind::Normal, label);
+                entry.key = key.clone();
+                entry.checkable = *checkable;
+                entry.checked = *checked;
+                let planned = BatchEntry::Item {
+                    idx,
+                    label: escape_mnemonic(label),
+                    checked: checkable.then_some(*checked),
+                    parent,
+                };
+                (entry, planned)
+            }
+            MenuItemSpec::Separator => (
+                MenuEntry::new(parent, MenuItemKind::Separator, ""),
+                BatchEntry::Separator { idx, parent },
+            ),
+            MenuItemSpec::Submenu { label, .. } => (
+                MenuEntry::new(parent, MenuItemKind::Submenu, label),
+                BatchEntry::Submenu {
+                    idx,
+                    label: escape_mnemonic(label),
+                    parent,
+                },
+            ),
+        };
+        self.menu.insert(idx, entry);
+        batch.push(planned);
+        if let MenuItemSpec::Submenu { items, .. } = item {
+            for child in items {
+                self.plan_spec_item(child, Some(idx), batch)?;
+            }
+        }
+        Ok(idx)
+    }
+
+    fn forget_batch(&mut self, batch: &[BatchEntry]) {
+        for entry in batch {
+            let idx = match entry {
+                BatchEntry::Item { idx, .. }
+                | BatchEntry::Separator { idx, .. }
+                | BatchEntry::Submenu { idx, .. } => idx,
+            };
+            self.menu.remove(idx);
+        }
+    }
+
+    fn build_spec_item(
+        &mut self,
+        item: &MenuItemSpec,
+        parent: Option<u32>,
+        actions: &mut HashMap<u32, String>,
+    ) -> Result<u32> {
+        match item {
+            MenuItemSpec::Item {
+                label,
+                action,
+                key,
+                checkable,
+                checked,
+            } => {
+                let idx = if *checkable {
+                    self.add_check_entry(parent, label, *checked)?
+                } else {
+                    self.add_entry(parent, label)?
+                };
+                if let Some(key) = key {
+                    self.set_key(idx, key);
+                }
+                actions.insert(idx, action.clone());
+                Ok(idx)
+            }
+            MenuItemSpec::Separator => self.add_separator(parent),
+            MenuItemSpec::Submenu { label, items } => {
+                let idx = self.add_submenu_entry(parent, label)?;
+                for child in items {
+                    self.build_spec_item(child, Some(idx), actions)?;
+                }
+                Ok(idx)
+            }
+        }
+    }
+
+    /// The state of every item that was given a key.
+    pub fn menu_state(&self) -> MenuState {
+        let items = self
+            .menu
+            .values()
+            .filter_map(|e| {
+                let state = MenuItemState {
+                    checked: e.checkable.then_some(e.checked),
+                    enabled: e.enabled,
+                };
+                e.key.clone().map(|key| (key, state))
+            })
+            .collect();
+        MenuState { items }
+    }
+
+    /// Restores a state saved by menu_state. Keys no item has any more are
+    /// skipped.
+    pub fn apply_menu_state(&mut self, state: &MenuState) -> Result<()> {
+        for (key, item) in &state.items {
+            let idx = self
+                .menu
+                .iter()
+                .find(|(_, e)| e.key.as_deref() == Some(key.as_str()))
+                .map(|(idx, _)| MenuItemId(*idx));
+            let idx = match idx {
+                Some(idx) => idx,
+                None => {
+                    info!("Ignoring state for unknown menu item {}", key);
+                    continue;
+                }
+            };
+            if let Some(checked) = item.checked {
+                if self.menu_item_checked(idx).is_some() {
+                    self.set_menu_item_checked(idx, checked)?;
+                }
+            }
+            self.set_menu_item_enabled(idx, item.enabled)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn dispatch_spec_action(&mut self, idx: u32) {
+        let (action, mut dispatch) = match self.spec.as_mut() {
+            Some(spec) => match spec.actions.get(&idx) {
+                Some(action) => (action.clone(), spec.dispatch.take()),
+                None => return,
+            },
+            None => return,
+        };
+        if let Some(f) = dispatch.as_mut() {
+            self.guard_panic(|app| {
+                f(&action, app);
+                Ok(())
+            })
+            .ok();
+        }
+        // The dispatcher may have applied a new spec, which brings its own.
+        if let Some(spec) = self.spec.as_mut() {
+            if spec.dispatch.is_none() {
+                spec.dispatch = dispatch;
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "test-utils"))]
+mod tests {
+    use super::*;
+    use crate::SystrayEvent;
+    use std::sync::Mutex;
+
+    fn item(label: &str, action: &str) -> MenuItemSpec {
+        MenuItemSpec::Item {
+            label: label.to_string(),
+            action: action.to_string(),
+            key: None,
+            checkable: false,
+            checked: false,
+        }
+    }
+
+    fn spec() -> MenuSpec {
+        MenuSpec {
+            items: vec![
+                item("Open", "open"),
+                MenuItemSpec::Separator,
+                MenuItemSpec::Submenu {
+                    label: "More".to_string(),
+                    items: vec![
+                        MenuItemSpec::Item {
+                            label: "Start with the session".to_string(),
+                            action: "autostart".to_string(),
+                            key: Some("autostart".to_string()),
+                            checkable: true,
+                            checked: true,
+                        },
+                        item("Support", "no-such-action"),
+                    ],
+                },
+            ],
+        }
+    }
+
+    // The menu as lines indented by depth, without the ids, which differ
+    // from one build of the same menu to the next.
+    fn outline(app: &Application, parent: Option<u32>, depth: usize) -> Vec<String> {
+        let mut lines = Vec::new();
+        for idx in app.menu_children(parent) {
+            let info = app.menu_item_info(MenuItemId(idx)).unwrap();
+            lines.push(format!(
+                "{}{:?} {:?} {:?}",
+                "  ".repeat(depth),
+                info.kind,
+                info.label,
+                info.checked
+            ));
+            lines.extend(outline(app, Some(idx), depth + 1));
+        }
+        lines
+    }
+
+    fn ids_of(app: &Application, parent: Option<u32>) -> Vec<u32> {
+        let mut ids = Vec::new();
+        for idx in app.menu_children(parent) {
+            ids.push(idx);
+            ids.extend(ids_of(app, Some(idx)));
+        }
+        ids
+    }
+
+    // Runs wait_for_message over clicks on `ids`.
+    fn click(app: &mut Application, ids: &[u32]) {
+        for idx in ids {
+            app.mock_window()
+                .send_event(SystrayEvent::MenuItem(MenuItemId(*idx)));
+        }
+        app.shutdown().unwrap();
+        app.wait_for_message().unwrap();
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn specs_round_trip_through_a_config_file() {
+        let text = toml::to_string(&spec()).unwrap();
+        assert_eq!(toml::from_str::<MenuSpec>(&text).unwrap(), spec());
+        // What support would write by hand, with the defaults left out.
+        let written = r#"
+            [[items]]
+            type = "item"
+            label = "Open"
+            action = "open"
+
+            [[items]]
+            type = "separator"
+        "#;
+        let parsed: MenuSpec = toml::from_str(written).unwrap();
+        assert_eq!(parsed.items, spec().items[..2]);
+    }
+
+    #[test]
+    fn applying_a_spec_again_gives_the_same_menu() {
+        let mut app = Application::mock();
+        let extra = app.add_menu_item("Quit", |_: &mut Application| Ok::<_, crate::Error>(()));
+        let extra = extra.unwrap();
+        app.apply_menu_spec(&spec(), |_, _| {}).unwrap();
+        let first = outline(&app, None, 0);
+        app.apply_menu_spec(&spec(), |_, _| {}).unwrap();
+        assert_eq!(outline(&app, None, 0), first);
+        assert_eq!(first.len(), 6);
+        assert!(app.menu_item_info(extra).is_ok());
+    }
+
+    #[test]
+    fn clicks_reach_dispatch_by_action_even_unknown_ones() {
+        let mut app = Application::mock();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = seen.clone();
+        app.apply_menu_spec(&spec(), move |action, _| {
+            log.lock().unwrap().push(action.to_string());
+        })
+        .unwrap();
+        // Open, the separator, More, the check item and Support.
+        let ids = ids_of(&app, None);
+        click(&mut app, &[ids[0], ids[4], ids[3], 9999]);
+        assert_eq!(*seen.lock().unwrap(), ["open", "no-such-action", "autostart"]);
+    }
+}
@@ -0,0 +1,41 @@
+// Menu items that stand out from the rest, e.g. a warning in red.
+use crate::{api, make_callback, Application, MenuItemId, Result};
+use std::error;
+
+/// How add_menu_item_styled draws an item's label. The default is the
+/// system's plain menu text.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MenuItemStyle {
+    pub bold: bool,
+    pub italic: bool,
+    /// Text color as RGB. Windows uses the system colors instead while the
+    /// item is highlighted or disabled.
+    pub color: Option<[u8; 3]>,
+}
+
+impl Application {
+    /// Like add_menu_item, with the label drawn in `style`. Windows draws
+    /// styled items itself, without the icons of set_menu_item_icon. GTK
+    /// styles the label. StatusNotifierItem and macOS show the plain label.
+    pub fn add_menu_item_styled<F, E>(
+        &mut self,
+        item_name: &str,
+        style: MenuItemStyle,
+        f: F,
+    ) -> Result<MenuItemId>
+    where
+        F: FnMut(&mut Application) -> std::result::Result<(), E> + Send + Sync + 'static,
+        E: error::Error + Send + Sync + 'static,
+    {
+        let idx = self.add_entry(None, item_name)?;
+        if style != MenuItemStyle::default() {
+            let label = api::escape_mnemonic(item_name);
+            if let Err(e) = self.window.set_menu_item_style(idx, &label, style) {
+                self.remove_menu_item(MenuItemId(idx)).ok();
+                return Err(e);
+            }
+        }
+        self.set_callback(idx, make_callback(f));
+        Ok(MenuItemId(idx))
+    }
+}
@@ -0,0 +1,101 @@
+// Menus generated from a Rust type, usually an enum of actions.
+use crate::{Application, MenuItemId, Result};
+use std::sync::{Arc, Mutex};
+
+/// A type whose values are the entries of a menu.
+///
+/// ```ignore
+/// #[derive(Clone)]
+/// enum Action { Open, Separator, TogglePause { checked: bool }, Quit }
+///
+/// impl MenuModel for Action {
+///     fn entries() -> Vec<Action> {
+///         let pause = Action::TogglePause { checked: false };
+///         vec![Action::Open, Action::Separator, pause, Action::Quit]
+///     }
+///     fn label(&self) -> String {
+///         match self {
+///             Action::Open => "Open",
+///             Action::Separator => "",
+///             Action::TogglePause { .. } => "Pause",
+///             Action::Quit => "Quit",
+///         }
+///         .to_string()
+///     }
+///     fn is_separator(&self) -> bool {
+///         matches!(self, Action::Separator)
+///     }
+///     fn checked(&self) -> Option<bool> {
+///         match self {
+///             Action::TogglePause { checked } => Some(*checked),
+///             _ => None,
+///         }
+///     }
+///     fn set_checked(&mut self, state: bool) {
+///         if let Action::TogglePause { checked } = self {
+///             *checked = state;
+///         }
+///     }
+/// }
+/// ```
+pub trait MenuModel: Clone + Send + Sync + 'static {
+    /// Every entry, in menu order.
+    fn entries() -> Vec<Self>;
+
+    fn label(&self) -> String;
+
+    fn is_separator(&self) -> bool {
+        false
+    }
+
+    /// `Some` with the initial state for check items.
+    fn checked(&self) -> Option<bool> {
+        None
+    }
+
+    /// Stores a check item's state in its payload before it's handed to the
+    /// click handler.
+    fn set_checked(&mut self, _checked: bool) {}
+}
+
+impl Application {
+    /// Adds the entries of `M`, replacing those of a previous install_model
+    /// call. Clicks call `f` with the clicked entry, check items carrying
+    /// their new state.
+    pub fn install_model<M, F>(&mut self, f: F) -> Result<()>
+    where
+        M: MenuModel,
+        F: FnMut(M, &mut Application) + Send + Sync + 'static,
+    {
+        for idx in std::mem::take(&mut self.model_items) {
+            self.remove_menu_item(MenuItemId(idx))?;
+        }
+        let f = Arc::new(Mutex::new(f));
+        for entry in M::entries() {
+            let idx = if entry.is_separator() {
+                self.add_separator(None)?
+            } else {
+                let label = entry.label();
+                let idx = match entry.checked() {
+                    Some(checked) => self.add_check_entry(None, &label, checked)?,
+                    None => self.add_entry(None, &label)?,
+                };
+                let f = f.clone();
+                self.set_callback(
+                    idx,
+                    Box::new(move |app: &mut Application| {
+                        let mut action = entry.clone();
+                        if let Some(checked) = app.menu_item_checked(MenuItemId(idx)) {
+                            action.set_checked(checked);
+                        }
+                        (*f.lock().unwrap())(action, app);
+                        Ok(())
+                    }),
+                );
+                idx
+            };
+            self.model_items.push(idx);
+        }
+        Ok(())
+    }
+}
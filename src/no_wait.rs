@@ -0,0 +1,89 @@
+// Setters that don't wait for the platform. Calls otherwise return once
+// they went through, which on GTK is a round trip to its thread, so their
+// results are what the platform made of them. These only queue the call,
+// for apps that update often and don't need to know.
+//
+// Only the GTK backends, AppIndicator and GtkStatusIcon, ever wait.
+// Windows, macOS and StatusNotifierItem make their calls on the caller's
+// thread, where there's nothing to skip.
+use crate::{Application, IconSource, MenuItemId, Result};
+use std::borrow::Borrow;
+
+// Puts waiting back as it was once dropped, also when the setter panicked.
+struct Queued<A: Borrow<Application>> {
+    app: A,
+    waiting: bool,
+}
+
+impl<A: Borrow<Application>> Drop for Queued<A> {
+    fn drop(&mut self) {
+        self.app.borrow().window.set_waiting(self.waiting);
+    }
+}
+
+impl Application {
+    // Runs `f` with calls to the platform queued rather than waited for.
+    // `app` is the Application, borrowed as the setter needs it.
+    fn without_waiting<A, T>(app: A, f: impl FnOnce(&mut A) -> T) -> T
+    where
+        A: Borrow<Application>,
+    {
+        let waiting = app.borrow().window.set_waiting(false);
+        let mut queued = Queued { app, waiting };
+        f(&mut queued.app)
+    }
+
+    /// set_tooltip without waiting for the platform. Errors found here, as
+    /// with bad arguments, are still returned; those on the platform's side
+    /// are only logged. Only GTK waits for its thread: on Windows, macOS and
+    /// StatusNotifierItem this is the same as set_tooltip, as are the other
+    /// `try_*` setters.
+    pub fn try_set_tooltip(&self, tooltip: &str) -> Result<()> {
+        Self::without_waiting(self, |app| app.set_tooltip(tooltip))
+    }
+
+    /// set_icon without waiting for the platform, see try_set_tooltip.
+    pub fn try_set_icon(&self, icon: IconSource<'_>) -> Result<()> {
+        Self::without_waiting(self, |app| app.set_icon(icon))
+    }
+
+    /// set_icon_from_file without waiting for the platform, see
+    /// try_set_tooltip.
+    #[deprecated(note = "use try_set_icon with IconSource::Path")]
+    pub fn try_set_icon_from_file(&self, file: &str) -> Result<()> {
+        self.try_set_icon(IconSource::Path(std::path::Path::new(file)))
+    }
+
+    /// set_icon_from_theme without waiting for the platform, see
+    /// try_set_tooltip.
+    #[deprecated(note = "use try_set_icon with IconSource::ThemeName")]
+    pub fn try_set_icon_from_theme(&self, icon_name: &str) -> Result<()> {
+        self.try_set_icon(IconSource::ThemeName(icon_name))
+    }
+
+    /// set_icon_from_buffer without waiting for the platform, see
+    /// try_set_tooltip.
+    #[deprecated(note = "use try_set_icon with IconSource::Rgba")]
+    #[allow(deprecated)]
+    pub fn try_set_icon_from_buffer(&self, buffer: &[u8], width: u32, height: u32) -> Result<()> {
+        Self::without_waiting(self, |app| app.set_icon_from_buffer(buffer, width, height))
+    }
+
+    /// set_icon_visible without waiting for the platform, see
+    /// try_set_tooltip.
+    pub fn try_set_icon_visible(&self, visible: bool) -> Result<()> {
+        Self::without_waiting(self, |app| app.set_icon_visible(visible))
+    }
+
+    /// set_menu_item_checked without waiting for the platform, see
+    /// try_set_tooltip.
+    pub fn try_set_menu_item_checked(&mut self, id: MenuItemId, checked: bool) -> Result<()> {
+        Self::without_waiting(self, |app| app.set_menu_item_checked(id, checked))
+    }
+
+    /// set_menu_item_enabled without waiting for the platform, see
+    /// try_set_tooltip.
+    pub fn try_set_menu_item_enabled(&mut self, id: MenuItemId, enabled: bool) -> Result<()> {
+        Self::without_waiting(self, |app| app.set_menu_item_enabled(id, enabled))
+    }
+}
@@ -0,0 +1,103 @@
+// Desktop notifications, with buttons whose callbacks run like menu items'.
+use crate::{make_callback, Application, Callback, Result};
+use std::error;
+
+/// A button on a notification. `key` identifies it to the platform and comes
+/// back in SystrayEvent::NotificationAction when it's clicked.
+pub struct NotificationAction {
+    key: String,
+    label: String,
+    callback: Callback,
+}
+
+impl NotificationAction {
+    pub fn new<F, E>(key: &str, label: &str, f: F) -> NotificationAction
+    where
+        F: FnMut(&mut Application) -> std::result::Result<(), E> + Send + Sync + 'static,
+        E: error::Error + Send + Sync + 'static,
+    {
+        NotificationAction {
+            key: key.to_string(),
+            label: label.to_string(),
+            callback: make_callback(f),
+        }
+    }
+}
+
+impl Application {
+    /// Shows a desktop notification with the given buttons. Action keys are
+    /// shared by all notifications, a later one's action replaces an earlier
+    /// one's under the same key. The key `"default"` is, by convention,
+    /// clicking the notification itself.
+    ///
+    /// Linux needs the `dbus-notifications` feature. Windows 10 and later
+    /// show toasts with the `windows-toast` feature, otherwise the
+    /// notification area's balloon, which has no buttons. On macOS this
+    /// returns NotImplementedError.
+    pub fn show_notification(
+        &mut self,
+        summary: &str,
+        body: &str,
+        actions: Vec<NotificationAction>,
+    ) -> Result<()> {
+        self.notify(summary, body, None, actions)
+    }
+
+    /// Sets the process's AppUserModelID, which Windows groups taskbar
+    /// buttons by and names notifications after. Toasts from the
+    /// `windows-toast` feature go out under it, but only show if a Start
+    /// menu shortcut to the app carries the same id; without one, leave it
+    /// unset. Windows only, elsewhere this returns NotImplementedError.
+    pub fn set_app_id(&self, app_id: &str) -> Result<()> {
+        self.window
+            .set_app_id(app_id)
+            .inspect_err(|e| warn!("Failed to set the app id {:?}: {}", app_id, e))
+    }
+
+    /// Like show_notification, with the image at the absolute path `image`
+    /// shown next to the text where the platform has room for one.
+    pub fn show_notification_with_image(
+        &mut self,
+        summary: &str,
+        body: &str,
+        image: &str,
+        actions: Vec<NotificationAction>,
+    ) -> Result<()> {
+        self.notify(summary, body, Some(image), actions)
+    }
+
+    fn notify(
+        &mut self,
+        summary: &str,
+        body: &str,
+        image: Option<&str>,
+        actions: Vec<NotificationAction>,
+    ) -> Result<()> {
+        let labels: Vec<(String, String)> = actions
+            .iter()
+            .map(|a| (a.key.clone(), a.label.clone()))
+            .collect();
+        self.window
+            .show_notification(summary, body, image, &labels)
+            .inspect_err(|e| warn!("Failed to show notification {:?}: {}", summary, e))?;
+        for action in actions {
+            self.notification_actions.insert(action.key, action.callback);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn dispatch_notification_action(&mut self, key: &str) -> Result<()> {
+        let mut f = match self.notification_actions.remove(key) {
+            Some(f) => f,
+            None => {
+                debug!("No callback for notification action {:?}", key);
+                return Ok(());
+            }
+        };
+        let res = self.guard_panic(|app| f(app));
+        // Keep the callback unless it was replaced while it ran.
+        self.notification_actions.entry(key.to_string()).or_insert(f);
+        res?;
+        Ok(())
+    }
+}
@@ -0,0 +1,216 @@
+// Raw icon pixels in layouts other than RGBA, e.g. JPEG images decoded
+// without alpha. They're turned into RGBA here, which is all the platforms
+// take.
+use crate::{icon_cache, Application, Error, IconSource, Result};
+use std::borrow::Cow;
+
+/// How the bytes of a pixel buffer are laid out, one byte per channel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PixelFormat {
+    Rgba8,
+    /// Without alpha, so every pixel is opaque.
+    Rgb8,
+    Bgra8,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Rgba8 | PixelFormat::Bgra8 => 4,
+            PixelFormat::Rgb8 => 3,
+        }
+    }
+
+    fn to_rgba(self, buffer: &[u8]) -> Cow<'_, [u8]> {
+        match self {
+            PixelFormat::Rgba8 => Cow::Borrowed(buffer),
+            PixelFormat::Rgb8 => Cow::Owned(
+                buffer
+                    .chunks_exact(3)
+                    .flat_map(|px| [px[0], px[1], px[2], 0xff])
+                    .collect(),
+            ),
+            PixelFormat::Bgra8 => Cow::Owned(
+                buffer
+                    .chunks_exact(4)
+                    .flat_map(|px| [px[2], px[1], px[0], px[3]])
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl Application {
+    /// Sets the icon from `width * height` pixels laid out as `format`, in
+    /// rows top to bottom without padding. The buffer has to be exactly
+    /// that long, and zero sizes are an error.
+    pub fn set_icon_from_pixels(
+        &self,
+        buffer: &[u8],
+        width: u32,
+        height: u32,
+        format: PixelFormat,
+    ) -> Result<()> {
+        check_size(buffer, width, height, format)?;
+        self.set_icon_from_rgba(&format.to_rgba(buffer), width, height)
+    }
+
+    /// Like set_icon_from_buffer, but takes the RGBA pixels over instead of
+    /// copying them, for apps that make a new buffer for every change
+    /// anyway; set_icon_from_buffer copies its pixels once they go up. The
+    /// buffer is kept as what badges and scale changes redraw the icon
+    /// from, and the platform's bitmap is made straight from it: the
+    /// DIB section on Windows, or on GTK the pixbuf, whose pixels cross
+    /// into the GTK thread, which a Vec can as it's Send.
+    #[deprecated(note = "use set_icon with IconSource::Rgba")]
+    pub fn set_icon_from_buffer_owned(
+        &self,
+        buffer: Vec<u8>,
+        width: u32,
+        height: u32,
+    ) -> Result<()> {
+        self.set_icon(IconSource::Rgba {
+            data: Cow::Owned(buffer),
+            width,
+            height,
+        })
+    }
+
+    // Borrowed pixels are only copied once they're to go up.
+    pub(crate) fn set_rgba_icon(
+        &self,
+        buffer: Cow<'_, [u8]>,
+        width: u32,
+        height: u32,
+    ) -> Result<()> {
+        check_size(&buffer, width, height, PixelFormat::Rgba8)?;
+        let icon = icon_cache::fingerprint(("rgba", width, height, &*buffer));
+        self.set_icon_once(Some(icon), || {
+            self.show_rgba_base(buffer.into_owned(), width, height)
+        })
+    }
+}
+
+fn check_size(buffer: &[u8], width: u32, height: u32, format: PixelFormat) -> Result<()> {
+    if width == 0 || height == 0 {
+        return Err(Error::os(format!("Empty {}x{} icon", width, height)));
+    }
+    let expected = (width as usize) * (height as usize) * format.bytes_per_pixel();
+    if buffer.len() != expected {
+        return Err(Error::os(format!(
+            "Icon buffer is {} bytes, expected {} for a {}x{} {:?} image",
+            buffer.len(),
+            expected,
+            width,
+            height,
+            format
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every pixel tells where it is, so a row read with the wrong stride shows.
+    fn positions(width: u32, height: u32, format: PixelFormat) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        for y in 0..height {
+            for x in 0..width {
+                let px = [x as u8, y as u8, (x ^ y) as u8, 0x80];
+                buffer.extend_from_slice(&px[..format.bytes_per_pixel()]);
+            }
+        }
+        buffer
+    }
+
+    #[test]
+    fn odd_width_pixels_turn_into_rgba_row_by_row() {
+        for (width, height) in [(3, 5), (5, 3), (1, 7), (15, 15), (17, 2)] {
+            let rgb = positions(width, height, PixelFormat::Rgb8);
+            let rgba = PixelFormat::Rgb8.to_rgba(&rgb);
+            let expected: Vec<u8> = rgb
+                .chunks_exact(3)
+                .flat_map(|px| [px[0], px[1], px[2], 0xff])
+                .collect();
+            assert_eq!(*rgba, expected[..], "{}x{}", width, height);
+            let bgra = positions(width, height, PixelFormat::Bgra8);
+            let rgba = PixelFormat::Bgra8.to_rgba(&bgra);
+            assert_eq!(rgba[4..8], [bgra[6], bgra[5], bgra[4], bgra[7]]);
+            assert_eq!(rgba.len(), (width * height * 4) as usize);
+        }
+    }
+
+    #[test]
+    fn zero_sizes_and_wrong_lengths_are_errors() {
+        assert!(check_size(&[], 0, 4, PixelFormat::Rgba8).is_err());
+        assert!(check_size(&[], 4, 0, PixelFormat::Rgba8).is_err());
+        assert!(check_size(&[0; 12], 3, 1, PixelFormat::Rgba8).is_ok());
+        assert!(check_size(&[0; 12], 3, 1, PixelFormat::Rgb8).is_err());
+        assert!(check_size(&[0; 9], 3, 1, PixelFormat::Rgb8).is_ok());
+    }
+
+    #[cfg(feature = "test-utils")]
+    mod app {
+        use super::*;
+        use crate::api::platform::MockCall;
+
+        #[test]
+        fn non_square_and_odd_width_icons_go_up_at_their_size() {
+            let app = Application::mock();
+            let sizes = [(15, 3), (3, 15), (15, 15), (1, 9), (16, 7)];
+            for (width, height) in sizes {
+                let rgba = positions(width, height, PixelFormat::Rgba8);
+                app.set_icon(IconSource::Rgba {
+                    data: Cow::Owned(rgba),
+                    width,
+                    height,
+                })
+                .unwrap();
+                let rgb = positions(width, height, PixelFormat::Rgb8);
+                app.forget_shown_icon();
+                app.set_icon_from_pixels(&rgb, width, height, PixelFormat::Rgb8)
+                    .unwrap();
+            }
+            let shown: Vec<MockCall> = sizes
+                .iter()
+                .flat_map(|&(width, height)| {
+                    vec![MockCall::SetIconFromImageBuffer { width, height }; 2]
+                })
+                .collect();
+            assert_eq!(app.mock_window().recorded_calls(), shown);
+        }
+
+        #[test]
+        fn zero_sized_icons_are_an_error() {
+            let app = Application::mock();
+            for (width, height) in [(0, 0), (0, 16), (16, 0)] {
+                let icon = IconSource::Rgba {
+                    data: Cow::Borrowed(&[]),
+                    width,
+                    height,
+                };
+                let e = app.set_icon(icon).unwrap_err();
+                assert!(e.to_string().contains("Empty"), "{}", e);
+                assert!(app
+                    .set_icon_from_pixels(&[], width, height, PixelFormat::Rgb8)
+                    .is_err());
+            }
+            assert!(app.mock_window().recorded_calls().is_empty());
+        }
+
+        // Also what once went through as ICO data on Windows.
+        #[test]
+        #[allow(deprecated)]
+        fn buffers_of_the_wrong_length_are_an_error() {
+            let app = Application::mock();
+            let ico = [0, 0, 1, 0, 1, 0, 16, 16];
+            for buffer in [&ico[..], &[0; 16 * 16 * 4 + 1][..], &[0; 16 * 16 * 3][..]] {
+                let e = app.set_icon_from_buffer(buffer, 16, 16).unwrap_err();
+                assert!(e.to_string().contains("expected 1024"), "{}", e);
+            }
+            assert!(app.mock_window().recorded_calls().is_empty());
+        }
+    }
+}
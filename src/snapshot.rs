@@ -0,0 +1,114 @@
+// Copies of the whole menu, for apps that tear it down and build it again,
+// e.g. on reconnecting to whatever the menu shows.
+use crate::{Application, MenuItemId, MenuItemInfo, MenuItemKind, Result};
+use std::collections::HashMap;
+
+/// The menu's items as snapshot_menu found them, with their labels, check
+/// marks, enabled flags and keys.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MenuSnapshot {
+    items: Vec<SnapshotItem>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct SnapshotItem {
+    id: MenuItemId,
+    info: MenuItemInfo,
+    key: Option<String>,
+    // Whether the label is in the syntax of add_menu_item_with_mnemonic.
+    mnemonic: bool,
+    children: Vec<SnapshotItem>,
+}
+
+impl Application {
+    /// Takes down every item in the menu, in order, to be put back by
+    /// restore_menu.
+    pub fn snapshot_menu(&self) -> MenuSnapshot {
+        MenuSnapshot {
+            items: self.snapshot_items(None),
+        }
+    }
+
+    fn snapshot_items(&self, parent: Option<u32>) -> Vec<SnapshotItem> {
+        self.menu_children(parent)
+            .into_iter()
+            .map(|idx| {
+                let e = &self.menu[&idx];
+                SnapshotItem {
+                    id: MenuItemId(idx),
+                    info: MenuItemInfo {
+                        label: e.label.clone(),
+                        enabled: e.enabled,
+                        checked: e.checkable.then_some(e.checked),
+                        kind: e.kind,
+                    },
+                    key: e.key.clone(),
+                    mnemonic: e.mnemonic,
+                    children: self.snapshot_items(Some(idx)),
+                }
+            })
+            .collect()
+    }
+
+    /// Adds the items of `snapshot` after the current ones, with the labels,
+    /// check marks, enabled flags and keys they had. Returns the new id of
+    /// every item by its id in the snapshot.
+    ///
+    /// Callbacks, data, icons and styles aren't part of a snapshot, so
+    /// clicks on the new items reach the event callback as
+    /// SystrayEvent::MenuItem, and lazy submenus come back empty. If an item
+    /// can't be added, those added before it are taken out again.
+    pub fn restore_menu(
+        &mut self,
+        snapshot: &MenuSnapshot,
+    ) -> Result<HashMap<MenuItemId, MenuItemId>> {
+        let mut ids = HashMap::new();
+        let mut roots = Vec::new();
+        for item in &snapshot.items {
+            match self.restore_item(item, None, &mut ids) {
+                Ok(idx) => roots.push(idx),
+                Err(e) => {
+                    // The item may be in already, with part of what's in it.
+                    if let Some(new) = ids.get(&item.id) {
+                        roots.push(new.0);
+                    }
+                    for idx in roots {
+                        self.remove_menu_item(MenuItemId(idx)).ok();
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(ids)
+    }
+
+    fn restore_item(
+        &mut self,
+        item: &SnapshotItem,
+        parent: Option<u32>,
+        ids: &mut HashMap<MenuItemId, MenuItemId>,
+    ) -> Result<u32> {
+        let info = &item.info;
+        let idx = match info.kind {
+            MenuItemKind::Normal => match info.checked {
+                Some(checked) => self.add_check_entry(parent, &info.label, checked)?,
+                None if item.mnemonic => self.add_label_entry(parent, &info.label)?,
+                None => self.add_entry(parent, &info.label)?,
+            },
+            MenuItemKind::Separator => self.add_separator(parent)?,
+            MenuItemKind::Submenu => self.add_submenu_entry(parent, &info.label)?,
+            MenuItemKind::Label => self.add_heading(parent, &info.label)?,
+        };
+        ids.insert(item.id, MenuItemId(idx));
+        if let Some(key) = &item.key {
+            self.set_key(idx, key);
+        }
+        if !info.enabled && info.kind != MenuItemKind::Label {
+            self.set_menu_item_enabled(MenuItemId(idx), false)?;
+        }
+        for child in &item.children {
+            self.restore_item(child, Some(idx), ids)?;
+        }
+        Ok(idx)
+    }
+}
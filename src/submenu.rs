@@ -0,0 +1,91 @@
+// Submenus built in code, to any depth, with a callback on each item.
+use crate::{make_callback, Application, Callback, MenuItemId, Result};
+use std::error;
+
+/// A submenu and everything in it, for add_submenu. Submenus nest through
+/// add_submenu as deep as needed, e.g. "Recent Files" > "By Date" >
+/// "2024-01".
+pub struct SubMenu {
+    label: String,
+    items: Vec<SubMenuItem>,
+}
+
+enum SubMenuItem {
+    Item { label: String, callback: Callback },
+    Separator,
+    Submenu(SubMenu),
+}
+
+impl SubMenu {
+    pub fn new(label: &str) -> SubMenu {
+        SubMenu {
+            label: label.to_string(),
+            items: Vec::new(),
+        }
+    }
+
+    /// Adds an item that calls `f` when clicked, like add_menu_item.
+    pub fn add_item<F, E>(mut self, label: &str, f: F) -> SubMenu
+    where
+        F: FnMut(&mut Application) -> std::result::Result<(), E> + Send + Sync + 'static,
+        E: error::Error + Send + Sync + 'static,
+    {
+        self.items.push(SubMenuItem::Item {
+            label: label.to_string(),
+            callback: make_callback(f),
+        });
+        self
+    }
+
+    pub fn add_separator(mut self) -> SubMenu {
+        self.items.push(SubMenuItem::Separator);
+        self
+    }
+
+    pub fn add_submenu(mut self, submenu: SubMenu) -> SubMenu {
+        self.items.push(SubMenuItem::Submenu(submenu));
+        self
+    }
+}
+
+impl Application {
+    /// Adds `submenu` after the current items, with every submenu and item
+    /// in it. Returns the id of the submenu itself, which remove_menu_item
+    /// takes to remove it along with its contents.
+    pub fn add_submenu(&mut self, submenu: SubMenu) -> Result<MenuItemId> {
+        let mut added = None;
+        let res = self.add_submenu_tree(submenu, None, &mut added);
+        if let (Err(_), Some(idx)) = (&res, added) {
+            self.remove_menu_item(MenuItemId(idx)).ok();
+        }
+        res.map(MenuItemId)
+    }
+
+    // Depth first, so a submenu is there before what goes into it. `added`
+    // gets the top level submenu as soon as it's in, to take out again if
+    // anything after it fails.
+    fn add_submenu_tree(
+        &mut self,
+        submenu: SubMenu,
+        parent: Option<u32>,
+        added: &mut Option<u32>,
+    ) -> Result<u32> {
+        let idx = self.add_submenu_entry(parent, &submenu.label)?;
+        added.get_or_insert(idx);
+        for item in submenu.items {
+            match item {
+                SubMenuItem::Item { label, callback } => {
+                    let child = self.add_entry(Some(idx), &label)?;
+                    self.set_callback(child, callback);
+                }
+                SubMenuItem::Separator => {
+                    self.add_separator(Some(idx))?;
+                }
+                SubMenuItem::Submenu(child) => {
+                    self.add_submenu_tree(child, Some(idx), added)?;
+                }
+            }
+        }
+        Ok(idx)
+    }
+}
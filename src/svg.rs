@@ -0,0 +1,122 @@
+use crate::{Application, Error, Result};
+use std::path::Path;
+
+pub(crate) fn has_svg_extension(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("svg") || ext.eq_ignore_ascii_case("svgz"))
+}
+
+// Gzip is taken as SVGZ, no other icon format comes compressed.
+pub(crate) fn is_svg(data: &[u8]) -> bool {
+    if data.starts_with(&[0x1f, 0x8b]) {
+        return true;
+    }
+    let head = String::from_utf8_lossy(&data[..data.len().min(1024)]);
+    let head = head.trim_start_matches('\u{feff}').trim_start();
+    head.starts_with('<') && head.contains("<svg")
+}
+
+// Draws the SVG centered in a `size`x`size` square, as straight RGBA.
+#[cfg(feature = "svg")]
+fn render(data: &[u8], size: u32) -> Result<Vec<u8>> {
+    use resvg::{tiny_skia, usvg};
+
+    let tree = usvg::Tree::from_data(data, &usvg::Options::default())
+        .map_err(|e| Error::DecodeError(format!("Failed to parse SVG: {}", e)))?;
+    let mut pixmap = tiny_skia::Pixmap::new(size, size)
+        .ok_or_else(|| Error::os(format!("Invalid SVG icon size: {}", size)))?;
+
+    // Fit the drawing into the square icon, keeping its aspect ratio.
+    let svg_size = tree.size();
+    let scale = size as f32 / svg_size.width().max(svg_size.height());
+    let transform = tiny_skia::Transform::from_scale(scale, scale).post_translate(
+        (size as f32 - svg_size.width() * scale) / 2.0,
+        (size as f32 - svg_size.height() * scale) / 2.0,
+    );
+    resvg::render(&tree, transform, &mut pixmap.as_mut());
+
+    // tiny-skia works in premultiplied alpha, the platform paths don't.
+    Ok(pixmap
+        .pixels()
+        .iter()
+        .flat_map(|p| {
+            let c = p.demultiply();
+            [c.red(), c.green(), c.blue(), c.alpha()]
+        })
+        .collect())
+}
+
+impl Application {
+    /// Rasterizes an SVG file to a `size`x`size` icon. Prefer this over
+    /// small bitmaps on scaled displays, where they end up blurry.
+    ///
+    /// Unlike set_icon_from_file, the icon keeps this size when the display
+    /// scale changes.
+    #[cfg(feature = "svg")]
+    pub fn set_icon_from_svg(&self, svg_path: &str, size: u32) -> Result<()> {
+        let data = std::fs::read(svg_path)
+            .map_err(|e| Error::from_io(format!("Failed to read SVG {}", svg_path), &e))?;
+        let buffer = render(&data, size)?;
+        self.set_icon_from_rgba(&buffer, size, size)
+    }
+
+    #[cfg(feature = "svg")]
+    pub(crate) fn set_icon_from_svg_file(&self, file: &Path) -> Result<()> {
+        let data = std::fs::read(file)
+            .map_err(|e| Error::from_io(format!("Failed to read SVG {}", file.display()), &e))?;
+        self.set_icon_from_svg_data(data)
+    }
+
+    // GTK loads SVG itself, it just won't follow scale changes. The SNI
+    // backend reports it can't decode the file.
+    #[cfg(all(not(feature = "svg"), target_os = "linux"))]
+    pub(crate) fn set_icon_from_svg_file(&self, file: &Path) -> Result<()> {
+        let base = crate::themed_icon::OwnedIconSource::Path(file.to_path_buf());
+        self.show_base_icon(base, |_| self.window.set_icon_from_file(file))
+            .inspect_err(|e| warn!("Failed to set icon from {}: {}", file.display(), e))
+    }
+
+    #[cfg(all(not(feature = "svg"), not(target_os = "linux")))]
+    pub(crate) fn set_icon_from_svg_file(&self, file: &Path) -> Result<()> {
+        Err(Error::os(format!(
+            "SVG icons need the `svg` feature on this platform: {}",
+            file.display()
+        )))
+    }
+
+    #[cfg(feature = "svg")]
+    pub(crate) fn set_icon_from_svg_data(&self, data: Vec<u8>) -> Result<()> {
+        let size = self.icon_size();
+        let buffer = render(&data, size)?;
+        self.set_icon_from_rgba(&buffer, size, size)?;
+        self.svg_icon.replace(Some(data));
+        Ok(())
+    }
+
+    #[cfg(not(feature = "svg"))]
+    pub(crate) fn set_icon_from_svg_data(&self, _data: Vec<u8>) -> Result<()> {
+        Err(Error::DecodeError("SVG icons need the `svg` feature".to_string()))
+    }
+
+    pub(crate) fn forget_svg_icon(&self) {
+        #[cfg(feature = "svg")]
+        self.svg_icon.replace(None);
+    }
+
+    // Called when the display scale changes, so the icon stays sharp.
+    // Whether the icon was an SVG.
+    pub(crate) fn rerender_svg_icon(&self) -> bool {
+        #[cfg(feature = "svg")]
+        if let Some(data) = self.svg_icon.take() {
+            // Still the same icon, so a themed pair stays in place.
+            let themed = self.themed_icons.take();
+            if let Err(e) = self.set_icon_from_svg_data(data) {
+                warn!("Failed to re-render SVG icon: {}", e);
+            }
+            self.themed_icons.replace(themed);
+            return true;
+        }
+        false
+    }
+}
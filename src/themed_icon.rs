@@ -0,0 +1,209 @@
+// A pair of icons following the taskbar between light and dark.
+use crate::{Application, Result, Theme};
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+
+/// Where an icon comes from, for set_icon and set_themed_icons, as with
+/// the matching `set_icon_from_*` method. Everything is borrowed from the
+/// caller, except RGBA pixels, which may be handed over instead.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IconSource<'a> {
+    /// An image file, see set_icon_from_file.
+    Path(&'a Path),
+    Resource(&'a str),
+    /// An icon resource of the executable by its numeric id, Windows only.
+    ResourceId(u16),
+    /// `width * height` RGBA pixels, in rows top to bottom without padding.
+    Rgba {
+        data: Cow<'a, [u8]>,
+        width: u32,
+        height: u32,
+    },
+    /// Encoded image data, see set_icon_from_memory.
+    Encoded(&'a [u8]),
+    /// A name from the freedesktop icon theme, Linux only.
+    ThemeName(&'a str),
+}
+
+// An IconSource kept past the call it came with: what the icon is redrawn
+// from, themed icons, and icons on their way to the tray thread.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum OwnedIconSource {
+    Path(PathBuf),
+    Resource(String),
+    ResourceId(u16),
+    Rgba {
+        data: Vec<u8>,
+        width: u32,
+        height: u32,
+    },
+    Encoded(Vec<u8>),
+    ThemeName(String),
+}
+
+impl OwnedIconSource {
+    pub(crate) fn source(&self) -> IconSource<'_> {
+        match self {
+            OwnedIconSource::Path(path) => IconSource::Path(path),
+            OwnedIconSource::Resource(resource) => IconSource::Resource(resource),
+            OwnedIconSource::ResourceId(id) => IconSource::ResourceId(*id),
+            OwnedIconSource::Rgba {
+                data,
+                width,
+                height,
+            } => IconSource::Rgba {
+                data: Cow::Borrowed(data),
+                width: *width,
+                height: *height,
+            },
+            OwnedIconSource::Encoded(data) => IconSource::Encoded(data),
+            OwnedIconSource::ThemeName(name) => IconSource::ThemeName(name),
+        }
+    }
+}
+
+// Pixels handed over in a Cow stay where they are.
+impl From<IconSource<'_>> for OwnedIconSource {
+    fn from(icon: IconSource<'_>) -> Self {
+        match icon {
+            IconSource::Path(path) => OwnedIconSource::Path(path.to_path_buf()),
+            IconSource::Resource(resource) => OwnedIconSource::Resource(resource.to_string()),
+            IconSource::ResourceId(id) => OwnedIconSource::ResourceId(id),
+            IconSource::Rgba {
+                data,
+                width,
+                height,
+            } => OwnedIconSource::Rgba {
+                data: data.into_owned(),
+                width,
+                height,
+            },
+            IconSource::Encoded(data) => OwnedIconSource::Encoded(data.to_vec()),
+            IconSource::ThemeName(name) => OwnedIconSource::ThemeName(name.to_string()),
+        }
+    }
+}
+
+impl Application {
+    /// Sets the icon from any of its sources, the way the matching
+    /// `set_icon_from_*` method does. Sources a platform can't show, like
+    /// theme names off Linux, give NotImplementedError. Borrowed RGBA pixels
+    /// are copied once, for what the icon is redrawn from, and owned ones
+    /// taken over; either have to be exactly `width * height` pixels. ICO
+    /// data goes in Encoded. GTK and macOS take UTF-8 paths only.
+    pub fn set_icon(&self, icon: IconSource<'_>) -> Result<()> {
+        match icon {
+            IconSource::Path(path) => self.set_file_icon(path),
+            IconSource::Resource(resource) => self.set_resource_icon(resource),
+            IconSource::ResourceId(id) => self.set_resource_id_icon(id),
+            IconSource::ThemeName(name) => self.set_theme_icon(name),
+            IconSource::Encoded(data) => self.set_memory_icon(data),
+            IconSource::Rgba {
+                data,
+                width,
+                height,
+            } => self.set_rgba_icon(data, width, height),
+        }
+    }
+
+    /// Shows `light` while the taskbar or panel is light and `dark` while it's
+    /// dark, switching over on SystrayEvent::ThemeChanged. Any other icon set
+    /// afterwards replaces the pair, which is kept as a copy until then.
+    pub fn set_themed_icons(&self, light: IconSource<'_>, dark: IconSource<'_>) -> Result<()> {
+        self.apply_themed_icons((light.into(), dark.into()), self.system_theme())
+    }
+
+    /// Checks the theme again and shows the matching themed icon, for when a
+    /// theme change went unnoticed. Does nothing without set_themed_icons.
+    pub fn refresh_theme(&self) -> Result<()> {
+        match self.themed_icons.take() {
+            Some(icons) => self.apply_themed_icons(icons, self.system_theme()),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn theme_changed(&self, theme: Theme) {
+        if let Some(icons) = self.themed_icons.take() {
+            if let Err(e) = self.apply_themed_icons(icons, theme) {
+                warn!("Failed to switch to the {:?} icon: {}", theme, e);
+            }
+        }
+    }
+
+    // Setting the icon forgets the pair, so it goes back in afterwards, even
+    // when that failed.
+    fn apply_themed_icons(
+        &self,
+        icons: (OwnedIconSource, OwnedIconSource),
+        theme: Theme,
+    ) -> Result<()> {
+        let source = match theme {
+            Theme::Light => &icons.0,
+            Theme::Dark => &icons.1,
+        };
+        let res = self.set_icon(source.source());
+        self.themed_icons.replace(Some(icons));
+        res
+    }
+}
+
+#[cfg(all(test, feature = "test-utils"))]
+mod tests {
+    use super::*;
+    use crate::api::platform::MockCall;
+
+    #[test]
+    #[allow(deprecated)]
+    fn rgba_goes_the_same_way_from_every_setter() {
+        let app = Application::mock();
+        let rgba = vec![0x80; 3 * 2 * 4];
+        app.set_icon(IconSource::Rgba {
+            data: Cow::Borrowed(&rgba),
+            width: 3,
+            height: 2,
+        })
+        .unwrap();
+        app.forget_shown_icon();
+        app.set_icon(IconSource::Rgba {
+            data: Cow::Owned(rgba.clone()),
+            width: 3,
+            height: 2,
+        })
+        .unwrap();
+        app.forget_shown_icon();
+        app.set_icon_from_buffer(&rgba, 3, 2).unwrap();
+        app.forget_shown_icon();
+        app.set_icon_from_buffer_owned(rgba, 3, 2).unwrap();
+        let shown = MockCall::SetIconFromImageBuffer {
+            width: 3,
+            height: 2,
+        };
+        assert_eq!(app.mock_window().recorded_calls(), vec![shown; 4]);
+    }
+
+    #[test]
+    fn rgba_of_the_wrong_length_is_an_error() {
+        let app = Application::mock();
+        let icon = IconSource::Rgba {
+            data: Cow::Borrowed(&[0; 5]),
+            width: 3,
+            height: 2,
+        };
+        assert!(app.set_icon(icon).is_err());
+        assert!(app.mock_window().recorded_calls().is_empty());
+    }
+
+    #[test]
+    fn themed_icons_go_through_set_icon() {
+        let app = Application::mock();
+        let light = IconSource::Path(Path::new("light.png"));
+        let dark = IconSource::ResourceId(7);
+        app.set_themed_icons(light, dark).unwrap();
+        app.mock_window().clear_recorded_calls();
+        app.theme_changed(Theme::Dark);
+        assert_eq!(
+            app.mock_window().recorded_calls(),
+            vec![MockCall::SetIconFromResourceId(7)]
+        );
+    }
+}
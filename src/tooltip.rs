@@ -0,0 +1,153 @@
+// Fitting tooltips into what the platform can show.
+use crate::{Error, Result};
+use std::sync::{Arc, Mutex};
+
+/// What set_tooltip_ex does with a tooltip that's over the platform's limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TruncationBehavior {
+    Truncate,
+    Error,
+    /// Break lines longer than 64 characters at spaces first, then truncate
+    /// whatever still doesn't fit.
+    Wrap,
+}
+
+pub(crate) type Provider = Box<dyn Fn() -> String + Send + Sync + 'static>;
+
+// Set by set_tooltip_from_fn and read from the platform thread, which asks
+// it for a fresh tooltip whenever the pointer comes over the icon.
+pub(crate) type ProviderSlot = Arc<Mutex<Option<Provider>>>;
+
+// The provider's tooltip, cut short like set_tooltip's.
+#[cfg(not(any(target_os = "macos", feature = "test-utils")))]
+pub(crate) fn provide(slot: &ProviderSlot) -> Option<String> {
+    let tooltip = slot.lock().unwrap().as_ref().map(|f| f())?;
+    fit(&tooltip, TruncationBehavior::Truncate).ok()
+}
+
+const WRAP_WIDTH: usize = 64;
+
+// szTip holds 128 UTF-16 units including the NUL, and every \n goes out as
+// \r\n. The other platforms take tooltips of any length.
+#[cfg(target_os = "windows")]
+const LIMIT: Option<usize> = Some(127);
+#[cfg(not(target_os = "windows"))]
+const LIMIT: Option<usize> = None;
+
+fn units(c: char) -> usize {
+    if cfg!(target_os = "windows") && c == '\n' {
+        2
+    } else {
+        c.len_utf16()
+    }
+}
+
+fn wrap(tooltip: &str) -> String {
+    let mut lines = Vec::new();
+    for line in tooltip.split('\n') {
+        let mut current = String::new();
+        for word in line.split(' ') {
+            let len = current.chars().count();
+            if len > 0 && len + 1 + word.chars().count() > WRAP_WIDTH {
+                lines.push(std::mem::take(&mut current));
+            } else if len > 0 {
+                current.push(' ');
+            }
+            current.push_str(word);
+        }
+        lines.push(current);
+    }
+    lines.join("\n")
+}
+
+pub(crate) fn fit(tooltip: &str, behavior: TruncationBehavior) -> Result<String> {
+    fit_within(tooltip, behavior, LIMIT)
+}
+
+// Cuts between whole characters, with \r\n taken as the one newline it is
+// so it's never split.
+fn fit_within(tooltip: &str, behavior: TruncationBehavior, limit: Option<usize>) -> Result<String> {
+    let tooltip = tooltip.replace("\r\n", "\n");
+    let tooltip = match behavior {
+        TruncationBehavior::Wrap => wrap(&tooltip),
+        _ => tooltip,
+    };
+    let limit = match limit {
+        Some(limit) => limit,
+        None => return Ok(tooltip),
+    };
+    let mut used = 0;
+    let end = tooltip
+        .char_indices()
+        .find(|(_, c)| {
+            used += units(*c);
+            used > limit
+        })
+        .map(|(i, _)| i);
+    match (end, behavior) {
+        (None, _) => Ok(tooltip),
+        (Some(_), TruncationBehavior::Error) => Err(Error::os(format!(
+            "Tooltip is longer than the {} characters this platform allows",
+            limit
+        ))),
+        (Some(end), _) => Ok(tooltip[..end].to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use TruncationBehavior::*;
+
+    #[test]
+    fn tooltips_within_the_limit_stay_as_they_are() {
+        let tooltip = "Syncing\n3 files left";
+        for behavior in [Truncate, Error, Wrap] {
+            assert_eq!(fit_within(tooltip, behavior, Some(127)).unwrap(), tooltip);
+        }
+        let long = "x".repeat(1000);
+        assert_eq!(fit_within(&long, Error, None).unwrap(), long);
+    }
+
+    #[test]
+    fn long_tooltips_are_cut_at_the_limit_or_refused() {
+        let tooltip = "x".repeat(130);
+        assert_eq!(fit_within(&tooltip, Truncate, Some(127)).unwrap(), tooltip[..127]);
+        assert!(fit_within(&tooltip, Error, Some(127)).is_err());
+        assert!(fit_within(&tooltip[..127], Error, Some(127)).is_ok());
+    }
+
+    #[test]
+    fn tooltips_are_cut_between_utf16_units_of_whole_characters() {
+        // The emoji takes two UTF-16 units, only one of which would fit.
+        assert_eq!(fit_within("ab\u{1F600}", Truncate, Some(3)).unwrap(), "ab");
+        assert_eq!(fit_within("äöü", Truncate, Some(2)).unwrap(), "äö");
+    }
+
+    #[test]
+    fn wrapping_breaks_long_lines_at_spaces_before_cutting() {
+        let words = ["word"; 20].join(" ");
+        let wrapped = fit_within(&words, Wrap, None).unwrap();
+        assert!(wrapped.lines().all(|line| line.chars().count() <= WRAP_WIDTH));
+        assert_eq!(wrapped.replace('\n', " "), words);
+        // Lines already short enough keep their breaks, words too long for
+        // a line of their own stay whole.
+        let long_word = "y".repeat(70);
+        let tooltip = format!("short\n{}", long_word);
+        assert_eq!(fit_within(&tooltip, Wrap, None).unwrap(), tooltip);
+        assert_eq!(fit_within(&words, Wrap, Some(10)).unwrap(), words[..10]);
+    }
+
+    #[test]
+    fn windows_newlines_are_never_split() {
+        let fitted = if cfg!(target_os = "windows") { "ab" } else { "ab\n" };
+        assert_eq!(fit_within("ab\r\ncd", Truncate, Some(3)).unwrap(), fitted);
+    }
+
+    #[cfg(target_os = "windows")]
+    #[test]
+    fn newlines_count_twice_on_windows() {
+        let tooltip = format!("{}\n", "x".repeat(126));
+        assert_eq!(fit(&tooltip, Truncate).unwrap(), "x".repeat(126));
+    }
+}
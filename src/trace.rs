@@ -0,0 +1,38 @@
+// Logging through tracing with the `tracing` feature, through log otherwise,
+// which costs nothing unless a logger is installed.
+
+macro_rules! debug {
+    ($($arg:tt)+) => {{
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($arg)+);
+        #[cfg(not(feature = "tracing"))]
+        log::debug!($($arg)+);
+    }};
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => {{
+        #[cfg(feature = "tracing")]
+        tracing::info!($($arg)+);
+        #[cfg(not(feature = "tracing"))]
+        log::info!($($arg)+);
+    }};
+}
+
+macro_rules! warn {
+    ($($arg:tt)+) => {{
+        #[cfg(feature = "tracing")]
+        tracing::warn!($($arg)+);
+        #[cfg(not(feature = "tracing"))]
+        log::warn!($($arg)+);
+    }};
+}
+
+macro_rules! error {
+    ($($arg:tt)+) => {{
+        #[cfg(feature = "tracing")]
+        tracing::error!($($arg)+);
+        #[cfg(not(feature = "tracing"))]
+        log::error!($($arg)+);
+    }};
+}
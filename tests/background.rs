@@ -0,0 +1,33 @@
+// A tray on a thread of its own, apart from tests/lifecycle.rs, whose
+// thread counts would take in this test's threads.
+#![cfg(target_os = "linux")]
+
+use systray2::{Application, InitOptions};
+
+#[test]
+fn background_setup_runs_first_and_its_error_comes_back() {
+    if Application::new().is_err() {
+        return;
+    }
+    let (tray, thread) = Application::into_background_thread(InitOptions::default(), |app| {
+        app.add_menu_item("Quit", |app: &mut Application| {
+            app.quit();
+            Ok::<_, systray2::Error>(())
+        })?;
+        app.set_tooltip("Set up")
+    })
+    .unwrap();
+    let check = tray
+        .add_check_menu_item("check", "Check", false, |_: &mut Application| {
+            Ok::<_, systray2::Error>(())
+        })
+        .unwrap();
+    tray.set_menu_item_checked(check, true).unwrap();
+    tray.quit().unwrap();
+    thread.join().unwrap().unwrap();
+
+    let failed = Application::into_background_thread(InitOptions::default(), |_| {
+        Err(systray2::Error::NotImplementedError)
+    });
+    assert!(matches!(failed, Err(systray2::Error::NotImplementedError)));
+}
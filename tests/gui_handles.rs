@@ -0,0 +1,64 @@
+// Icons set over and over on Windows, where every icon replaced has to be
+// destroyed or the process runs into its GDI and USER handle limits.
+#![cfg(target_os = "windows")]
+
+use std::borrow::Cow;
+use systray2::{Application, IconSource};
+use winapi::shared::minwindef::DWORD;
+use winapi::um::processthreadsapi::GetCurrentProcess;
+use winapi::um::winnt::HANDLE;
+
+const GR_GDIOBJECTS: DWORD = 0;
+const GR_USEROBJECTS: DWORD = 1;
+
+// Not in winapi.
+#[link(name = "user32")]
+extern "system" {
+    fn GetGuiResources(process: HANDLE, flags: DWORD) -> DWORD;
+}
+
+fn gui_handles() -> (DWORD, DWORD) {
+    unsafe {
+        let process = GetCurrentProcess();
+        (
+            GetGuiResources(process, GR_GDIOBJECTS),
+            GetGuiResources(process, GR_USEROBJECTS),
+        )
+    }
+}
+
+// A different icon for every `n`, so none is skipped as already shown.
+fn icon(n: u32) -> IconSource<'static> {
+    let mut rgba = vec![0x80; 16 * 16 * 4];
+    rgba[..4].copy_from_slice(&n.to_le_bytes());
+    IconSource::Rgba {
+        data: Cow::Owned(rgba),
+        width: 16,
+        height: 16,
+    }
+}
+
+#[test]
+fn setting_the_icon_1000_times_keeps_the_handle_count_flat() {
+    let app = match Application::new() {
+        Ok(app) => app,
+        // No tray to test with.
+        Err(_) => return,
+    };
+    // No taskbar to show icons on.
+    if app.set_icon(icon(0)).is_err() {
+        return;
+    }
+    let before = gui_handles();
+    for n in 1..=1000 {
+        app.set_icon(icon(n)).unwrap();
+    }
+    let after = gui_handles();
+    // The shell may take a few handles, a leak takes at least one an icon.
+    assert!(
+        after.0 <= before.0 + 10 && after.1 <= before.1 + 10,
+        "GDI and USER handles went from {:?} to {:?}",
+        before,
+        after
+    );
+}
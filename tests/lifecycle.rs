@@ -0,0 +1,48 @@
+// Applications made and dropped over and over. This is a process of its own,
+// so the threads counted are only this test's.
+#![cfg(target_os = "linux")]
+
+use std::time::{Duration, Instant};
+use systray2::{Application, InitOptions};
+
+fn threads() -> usize {
+    std::fs::read_dir("/proc/self/task").unwrap().count()
+}
+
+// Threads that were only told to stop may take a moment to be gone.
+fn settle_to(count: usize) -> usize {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while threads() > count && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    threads()
+}
+
+#[test]
+fn dropped_applications_leave_no_threads_behind() {
+    // The first one may start what's kept for the whole process, like the
+    // GTK thread.
+    match Application::new() {
+        Ok(app) => drop(app),
+        // No tray to test with.
+        Err(_) => return,
+    }
+    let before = threads();
+    for _ in 0..50 {
+        let mut app = Application::new().unwrap();
+        app.add_menu_item("Quit", |app: &mut Application| {
+            app.quit();
+            Ok::<_, systray2::Error>(())
+        })
+        .unwrap();
+        drop(app);
+    }
+    assert_eq!(settle_to(before), before);
+
+    for _ in 0..50 {
+        let (tray, thread) = Application::new_in_background(InitOptions::default()).unwrap();
+        tray.quit().unwrap();
+        thread.join().unwrap().unwrap();
+    }
+    assert_eq!(settle_to(before), before);
+}